@@ -0,0 +1,527 @@
+//! Queued commands for tokio, with the `tokio` feature.
+
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use crate::sbc::{
+    cdb_extent, parse_capacity16, read_capacity16_cdb, synchronize_cache16_cdb, write16_cdb,
+};
+use crate::{
+    inquiry_cdb, inquiry_len, inquiry_vpd_cdb, invalid_input, parse_vpd_80, std_inquiry,
+    vpd_page_len, BlockCount, Capacity, CommandResult, Completion, InquiryVpd80, InquiryVpd83,
+    InquiryVpdB0, Lba, QueuedData, Sg3Result, SgQueue, StdInquiry, DEFAULT_TIMEOUT,
+};
+
+/// A queue of commands that `AsyncSgQueue` can drive. Its fd must poll
+/// readable when a command has completed and can be collected, and
+/// writable when there is room to submit one, as that of `SgQueue` does.
+pub trait CommandQueue: AsRawFd {
+    /// Make `try_receive()` return None rather than wait when no command
+    /// is ready.
+    fn set_nonblocking(&self, nonblocking: bool) -> Sg3Result<()>;
+
+    /// How many commands have been submitted and not yet collected.
+    fn in_flight(&self) -> usize;
+
+    /// Whether a command with the given pack id is in flight.
+    fn is_in_flight(&self, pack_id: i32) -> bool;
+
+    /// Queue the given CDB, with a timeout in milliseconds, under a pack
+    /// id that isn't in flight already, or hand the data back if the
+    /// queue is full.
+    fn try_submit(
+        &mut self,
+        pack_id: i32,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<Option<QueuedData>>;
+
+    /// Collect the first command to finish, or None if none has.
+    fn try_receive(&mut self) -> Sg3Result<Option<Completion>>;
+}
+
+impl CommandQueue for SgQueue {
+    fn set_nonblocking(&self, nonblocking: bool) -> Sg3Result<()> {
+        SgQueue::set_nonblocking(self, nonblocking)
+    }
+
+    fn in_flight(&self) -> usize {
+        SgQueue::in_flight(self)
+    }
+
+    fn is_in_flight(&self, pack_id: i32) -> bool {
+        SgQueue::is_in_flight(self, pack_id)
+    }
+
+    fn try_submit(
+        &mut self,
+        pack_id: i32,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<Option<QueuedData>> {
+        SgQueue::try_submit(self, pack_id, cmd, data, timeout)
+    }
+
+    fn try_receive(&mut self) -> Sg3Result<Option<Completion>> {
+        SgQueue::try_receive(self)
+    }
+}
+
+/// An `SgQueue`, or another `CommandQueue`, driven by the tokio reactor:
+/// waiting for a command to complete yields to other tasks rather than
+/// blocking the thread.
+///
+/// As a `Stream`, it yields each command submitted with `submit()` as
+/// it completes, and ends once none are in flight.
+#[derive(Debug)]
+pub struct AsyncSgQueue<Q: CommandQueue = SgQueue> {
+    inner: AsyncFd<Q>,
+    // Completions collected while waiting for another command.
+    done: HashMap<i32, Completion>,
+    next_pack_id: i32,
+}
+
+impl<Q: CommandQueue> AsyncSgQueue<Q> {
+    /// Drive the given queue, which is made non-blocking. This must be
+    /// called within a tokio runtime.
+    pub fn new(queue: Q) -> Sg3Result<AsyncSgQueue<Q>> {
+        queue.set_nonblocking(true)?;
+        Ok(AsyncSgQueue {
+            inner: AsyncFd::with_interest(queue, Interest::READABLE | Interest::WRITABLE)?,
+            done: HashMap::new(),
+            next_pack_id: 0,
+        })
+    }
+
+    /// The queue being driven.
+    pub fn queue(&self) -> &Q {
+        self.inner.get_ref()
+    }
+
+    /// Queue the given CDB under a pack id chosen by the caller, to be
+    /// collected with `receive()` or from the stream. See
+    /// `SgQueue::submit()`.
+    ///
+    /// If the queue is full, this waits for its fd to poll writable.
+    /// Commands that complete meanwhile are collected, to be returned
+    /// later, as the driver counts them against the queue until they
+    /// are.
+    pub async fn submit(
+        &mut self,
+        pack_id: i32,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<()> {
+        if self.done.contains_key(&pack_id) {
+            return Err(invalid_input("pack id has not been collected"));
+        }
+        let mut data = data;
+        loop {
+            data = match self
+                .inner
+                .get_mut()
+                .try_submit(pack_id, cmd, data, timeout)?
+            {
+                Some(data) => data,
+                None => return Ok(()),
+            };
+
+            let mut guard = self
+                .inner
+                .ready_mut(Interest::READABLE | Interest::WRITABLE)
+                .await?;
+            let ready = guard.ready();
+            if ready.is_readable() {
+                if let Some(completion) = guard.get_inner_mut().try_receive()? {
+                    self.done.insert(completion.pack_id, completion);
+                    continue;
+                }
+            }
+            guard.clear_ready_matching(ready);
+        }
+    }
+
+    /// Collect the first command to finish.
+    pub async fn receive(&mut self) -> Sg3Result<Completion> {
+        let first = self.done.keys().next().cloned();
+        if let Some(completion) = first.and_then(|id| self.done.remove(&id)) {
+            return Ok(completion);
+        }
+        loop {
+            let mut guard = self.inner.readable_mut().await?;
+            match guard.get_inner_mut().try_receive()? {
+                Some(completion) => return Ok(completion),
+                None => guard.clear_ready(),
+            }
+        }
+    }
+
+    // Collect the given command, keeping any others that finish first.
+    async fn receive_pack_id(&mut self, pack_id: i32) -> Sg3Result<Completion> {
+        loop {
+            if let Some(completion) = self.done.remove(&pack_id) {
+                return Ok(completion);
+            }
+            let mut guard = self.inner.readable_mut().await?;
+            match guard.get_inner_mut().try_receive()? {
+                Some(completion) if completion.pack_id == pack_id => return Ok(completion),
+                Some(completion) => {
+                    self.done.insert(completion.pack_id, completion);
+                }
+                None => guard.clear_ready(),
+            }
+        }
+    }
+
+    // A pack id that is neither in flight nor waiting to be collected.
+    fn unused_pack_id(&mut self) -> i32 {
+        loop {
+            let id = self.next_pack_id;
+            self.next_pack_id = self.next_pack_id.checked_add(1).unwrap_or(0);
+            if !self.queue().is_in_flight(id) && !self.done.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    // Queue a command and wait for it, keeping its result whether it
+    // succeeded or not.
+    async fn complete(
+        &mut self,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<Completion> {
+        let pack_id = self.unused_pack_id();
+        self.submit(pack_id, cmd, data, timeout).await?;
+        self.receive_pack_id(pack_id).await
+    }
+
+    /// Issue the given CDB, with a timeout in milliseconds, and wait for
+    /// it to complete, returning its result and data buffer.
+    pub async fn execute(
+        &mut self,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<(CommandResult, Vec<u8>)> {
+        let completion = self.complete(cmd, data, timeout).await?;
+        Ok((completion.result?, completion.data))
+    }
+
+    // Issue a command reading up to `len` bytes, returning the buffer
+    // and how much of it the device transferred.
+    async fn read(&mut self, cmd: &[u8], len: usize) -> Sg3Result<(Vec<u8>, usize)> {
+        let (result, buf) = self
+            .execute(cmd, QueuedData::FromDevice(len), DEFAULT_TIMEOUT)
+            .await?;
+        Ok((buf, result.transferred))
+    }
+
+    // Read a whole VPD page in two passes, as `inquiry_vpd_page()` does.
+    async fn inquiry_vpd_page(&mut self, vpd: u8) -> Sg3Result<Vec<u8>> {
+        let (mut buf, mut transferred) = self.read(&inquiry_vpd_cdb(vpd, 4), 4).await?;
+
+        let len = vpd_page_len(&buf);
+        if len > buf.len() {
+            let (page, page_transferred) =
+                self.read(&inquiry_vpd_cdb(vpd, len as u16), len).await?;
+            buf = page;
+            transferred = page_transferred;
+        }
+        // the page may have shrunk in between
+        let len = vpd_page_len(&buf).min(transferred).max(4);
+        buf.truncate(len);
+
+        Ok(buf)
+    }
+
+    /// Send SCSI TEST UNIT READY.
+    pub async fn test_unit_ready(&mut self) -> Sg3Result<()> {
+        self.execute(&[0u8; 6], QueuedData::None, DEFAULT_TIMEOUT)
+            .await?;
+        Ok(())
+    }
+
+    /// Send SCSI INQUIRY for the standard inquiry data, as `inquiry()`
+    /// does.
+    pub async fn inquiry(&mut self) -> Sg3Result<StdInquiry> {
+        let (mut buf, transferred) = self.read(&inquiry_cdb(36), 36).await?;
+        buf.truncate(transferred);
+
+        let len = inquiry_len(&buf).min(u16::MAX as usize);
+        if len > 36 {
+            let (data, transferred) = self.read(&inquiry_cdb(len as u16), len).await?;
+            buf = data;
+            buf.truncate(transferred);
+        }
+        std_inquiry(buf)
+    }
+
+    /// Send SCSI INQUIRY for VPD 80 (Unit Serial Number).
+    pub async fn inquiry_vpd_80(&mut self) -> Sg3Result<InquiryVpd80> {
+        parse_vpd_80(self.inquiry_vpd_page(0x80).await?)
+    }
+
+    /// Send SCSI INQUIRY for VPD 83 (Device Identification).
+    pub async fn inquiry_vpd_83(&mut self) -> Sg3Result<InquiryVpd83> {
+        InquiryVpd83::from_buf(&self.inquiry_vpd_page(0x83).await?)
+    }
+
+    /// Send SCSI INQUIRY for VPD B0 (Block Limits).
+    pub async fn inquiry_vpd_b0(&mut self) -> Sg3Result<InquiryVpdB0> {
+        let (buf, _) = self.read(&inquiry_vpd_cdb(0xb0, 64), 64).await?;
+        Ok(InquiryVpdB0::from_buf(&buf))
+    }
+
+    /// Send SCSI READ CAPACITY(16).
+    pub async fn read_capacity16(&mut self) -> Sg3Result<Capacity> {
+        let (buf, _) = self.read(&read_capacity16_cdb(32), 32).await?;
+        Ok(parse_capacity16(&buf))
+    }
+
+    /// Send SCSI READ(16), as `read16()` does, returning a buffer of
+    /// `len` bytes, which must be `blocks` times the device's logical
+    /// block size.
+    pub async fn read16(
+        &mut self,
+        lba: Lba,
+        blocks: BlockCount,
+        len: usize,
+        fua: bool,
+        group: u8,
+    ) -> Sg3Result<Vec<u8>> {
+        let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+        let mut cmd = write16_cdb(lba, blocks as u32, fua, group)?;
+        cmd[0] = 0x88;
+        Ok(self.read(&cmd, len).await?.0)
+    }
+
+    /// Send SCSI WRITE(16), as `write16()` does.
+    pub async fn write16(
+        &mut self,
+        lba: Lba,
+        blocks: BlockCount,
+        buf: Vec<u8>,
+        fua: bool,
+        group: u8,
+    ) -> Sg3Result<()> {
+        let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+        let cmd = write16_cdb(lba, blocks as u32, fua, group)?;
+        self.execute(&cmd, QueuedData::ToDevice(buf), DEFAULT_TIMEOUT)
+            .await?;
+        Ok(())
+    }
+
+    /// Send SCSI SYNCHRONIZE CACHE(16), as `synchronize_cache16()` does.
+    pub async fn synchronize_cache16(
+        &mut self,
+        lba: Lba,
+        blocks: BlockCount,
+        immed: bool,
+    ) -> Sg3Result<()> {
+        let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+        let cmd = synchronize_cache16_cdb(lba, blocks as u32, immed);
+        self.execute(&cmd, QueuedData::None, DEFAULT_TIMEOUT)
+            .await?;
+        Ok(())
+    }
+}
+
+impl<Q: CommandQueue + Unpin> Stream for AsyncSgQueue<Q> {
+    type Item = Sg3Result<Completion>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let first = this.done.keys().next().cloned();
+        if let Some(completion) = first.and_then(|id| this.done.remove(&id)) {
+            return Poll::Ready(Some(Ok(completion)));
+        }
+        if this.queue().in_flight() == 0 {
+            return Poll::Ready(None);
+        }
+        loop {
+            let mut guard = match this.inner.poll_read_ready_mut(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Pending => return Poll::Pending,
+            };
+            match guard.get_inner_mut().try_receive() {
+                Ok(Some(completion)) => return Poll::Ready(Some(Ok(completion))),
+                Ok(None) => guard.clear_ready(),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::RawFd;
+    use std::os::unix::net::UnixStream;
+
+    use crate::{DataDirection, FakeDevice, SgTransport};
+
+    // A queue that runs each command on a FakeDevice as it is submitted,
+    // signalling its completion with a byte on a socket. It holds up to
+    // `capacity` commands that haven't been collected.
+    #[derive(Debug)]
+    struct FakeQueue {
+        dev: FakeDevice,
+        capacity: usize,
+        done: VecDeque<Completion>,
+        ready: UnixStream,
+        notify: UnixStream,
+    }
+
+    impl FakeQueue {
+        fn new(dev: FakeDevice) -> FakeQueue {
+            let (ready, notify) = UnixStream::pair().unwrap();
+            FakeQueue {
+                dev,
+                capacity: 16,
+                done: VecDeque::new(),
+                ready,
+                notify,
+            }
+        }
+    }
+
+    impl AsRawFd for FakeQueue {
+        fn as_raw_fd(&self) -> RawFd {
+            self.ready.as_raw_fd()
+        }
+    }
+
+    impl CommandQueue for FakeQueue {
+        fn set_nonblocking(&self, nonblocking: bool) -> Sg3Result<()> {
+            Ok(self.ready.set_nonblocking(nonblocking)?)
+        }
+
+        fn in_flight(&self) -> usize {
+            self.done.len()
+        }
+
+        fn is_in_flight(&self, pack_id: i32) -> bool {
+            self.done.iter().any(|c| c.pack_id == pack_id)
+        }
+
+        fn try_submit(
+            &mut self,
+            pack_id: i32,
+            cmd: &[u8],
+            data: QueuedData,
+            timeout: u32,
+        ) -> Sg3Result<Option<QueuedData>> {
+            if self.done.len() >= self.capacity {
+                return Ok(Some(data));
+            }
+            let (result, data) = match data {
+                QueuedData::None => (self.dev.execute(cmd, DataDirection::None, timeout), vec![]),
+                QueuedData::FromDevice(len) => {
+                    let mut buf = vec![0u8; len];
+                    let result =
+                        self.dev
+                            .execute(cmd, DataDirection::FromDevice(&mut buf), timeout);
+                    (result, buf)
+                }
+                QueuedData::ToDevice(buf) => {
+                    let result = self
+                        .dev
+                        .execute(cmd, DataDirection::ToDevice(&buf), timeout);
+                    (result, buf)
+                }
+            };
+            self.done.push_back(Completion {
+                pack_id,
+                result,
+                data,
+            });
+            self.notify.write_all(&[0])?;
+            Ok(None)
+        }
+
+        fn try_receive(&mut self) -> Sg3Result<Option<Completion>> {
+            match self.ready.read(&mut [0u8]) {
+                Ok(_) => Ok(self.done.pop_front()),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_queue() {
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        dev.set_vpd_page(0x80, b"6001405abc123");
+        dev.set_response(0x88, &[0xa5; 512]);
+        let mut queue = AsyncSgQueue::new(FakeQueue::new(dev)).unwrap();
+
+        queue.test_unit_ready().await.unwrap();
+
+        // the page length, then the page, each sent once
+        let vpd = queue.inquiry_vpd_80().await.unwrap();
+        assert_eq!(vpd.serial_number(), "6001405abc123");
+        let cmds = queue.queue().dev.commands();
+        assert_eq!(cmds.len(), 3);
+        assert_eq!(&cmds[1][..5], &[0x12, 0x01, 0x80, 0, 4]);
+        assert_eq!(&cmds[2][..5], &[0x12, 0x01, 0x80, 0, 17]);
+
+        // the device's error is passed on
+        let err = queue.read_capacity16().await.unwrap_err();
+        assert_eq!(err.opcode(), Some(0x9e));
+
+        // INQUIRY as the sync path sends it, 36 bytes first
+        let inquiry = queue.inquiry().await.unwrap();
+        assert_eq!(inquiry.vendor(), "LIO-ORG ");
+        assert_eq!(inquiry.as_buf().len(), 36);
+        assert_eq!(
+            queue.queue().dev.commands().last().unwrap(),
+            &[0x12, 0, 0, 0, 36, 0]
+        );
+
+        let buf = queue
+            .read16(Lba(8), BlockCount(1), 512, false, 0)
+            .await
+            .unwrap();
+        assert_eq!(buf, vec![0xa5; 512]);
+        assert_eq!(
+            &queue.queue().dev.commands().last().unwrap()[..14],
+            &[0x88, 0, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 1]
+        );
+
+        // a command submitted by the caller comes out of the stream, even
+        // when it has to be collected to make room for another
+        queue.inner.get_mut().capacity = 1;
+        queue
+            .submit(7, &[0u8; 6], QueuedData::None, DEFAULT_TIMEOUT)
+            .await
+            .unwrap();
+        queue.test_unit_ready().await.unwrap();
+        let completion = std::future::poll_fn(|cx| Pin::new(&mut queue).poll_next(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(completion.pack_id, 7);
+        assert!(completion.result.is_ok());
+        assert!(
+            std::future::poll_fn(|cx| Pin::new(&mut queue).poll_next(cx))
+                .await
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! Asymmetric logical unit access (ALUA) target port groups, and the
+//! ports of a device.
+
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    des_descs, inquiry_vpd_83, inquiry_vpd_page, invalid_input, read_variable_length, sg_io,
+    sg_io_read, DataDirection, DesignationDescriptor, Sg3Result, SgTransport,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsymmetricAccessState {
+    ActiveOptimized,
+    ActiveNonOptimized,
+    Standby,
+    Unavailable,
+    LogicalBlockDependent,
+    Offline,
+    Transitioning,
+    Reserved,
+}
+
+fn to_access_state(i: u8) -> AsymmetricAccessState {
+    match i {
+        0 => AsymmetricAccessState::ActiveOptimized,
+        1 => AsymmetricAccessState::ActiveNonOptimized,
+        2 => AsymmetricAccessState::Standby,
+        3 => AsymmetricAccessState::Unavailable,
+        4 => AsymmetricAccessState::LogicalBlockDependent,
+        0xe => AsymmetricAccessState::Offline,
+        0xf => AsymmetricAccessState::Transitioning,
+        _ => AsymmetricAccessState::Reserved,
+    }
+}
+
+/// A target port group descriptor from REPORT TARGET PORT GROUPS.
+#[derive(Debug)]
+pub struct TargetPortGroup {
+    /// Whether this group is a preferred path (PREF).
+    pub preferred: bool,
+    pub state: AsymmetricAccessState,
+    pub transitioning_supported: bool,
+    pub offline_supported: bool,
+    pub lba_dependent_supported: bool,
+    pub unavailable_supported: bool,
+    pub standby_supported: bool,
+    pub active_non_optimized_supported: bool,
+    pub active_optimized_supported: bool,
+    pub id: u16,
+    pub status_code: u8,
+    /// Relative target port identifiers of the ports in this group.
+    pub relative_port_ids: Vec<u16>,
+}
+
+fn parse_target_port_groups(buf: &[u8]) -> Vec<TargetPortGroup> {
+    let mut groups = Vec::new();
+    let mut offset = 4;
+
+    while offset + 8 <= buf.len() {
+        let desc = &buf[offset..];
+        let count = desc[7] as usize;
+        let ports = &desc[8..desc.len().min(8 + count * 4)];
+
+        groups.push(TargetPortGroup {
+            preferred: desc[0] & 0x80 != 0,
+            state: to_access_state(desc[0] & 0x0f),
+            transitioning_supported: desc[1] & 0x80 != 0,
+            offline_supported: desc[1] & 0x40 != 0,
+            lba_dependent_supported: desc[1] & 0x10 != 0,
+            unavailable_supported: desc[1] & 0x08 != 0,
+            standby_supported: desc[1] & 0x04 != 0,
+            active_non_optimized_supported: desc[1] & 0x02 != 0,
+            active_optimized_supported: desc[1] & 0x01 != 0,
+            id: BigEndian::read_u16(&desc[2..4]),
+            status_code: desc[5],
+            relative_port_ids: ports
+                .chunks_exact(4)
+                .map(|p| BigEndian::read_u16(&p[2..4]))
+                .collect(),
+        });
+        offset += 8 + count * 4;
+    }
+
+    groups
+}
+
+fn report_target_port_groups_cmd<T: SgTransport + ?Sized>(
+    path: &T,
+    buf: &mut [u8],
+) -> Sg3Result<usize> {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa3;
+    cmd[1] = 0x0a;
+    BigEndian::write_u32(&mut cmd[6..10], buf.len() as u32);
+
+    sg_io_read(path, &cmd, buf)
+}
+
+// Send SCSI REPORT TARGET PORT GROUPS to the SCSI device at the given
+// path, returning the ALUA state of each target port group.
+pub fn report_target_port_groups<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<Vec<TargetPortGroup>> {
+    let buf = read_variable_length(
+        4,
+        |buf| report_target_port_groups_cmd(path, buf),
+        |buf| 4 + BigEndian::read_u32(&buf[0..4]) as usize,
+    )?;
+
+    Ok(parse_target_port_groups(&buf))
+}
+
+fn set_target_port_groups_params(groups: &[(u16, AsymmetricAccessState)]) -> Sg3Result<Vec<u8>> {
+    let mut buf = vec![0u8; 4 + groups.len() * 4];
+
+    for (i, &(id, state)) in groups.iter().enumerate() {
+        let code = match state {
+            AsymmetricAccessState::ActiveOptimized => 0,
+            AsymmetricAccessState::ActiveNonOptimized => 1,
+            AsymmetricAccessState::Standby => 2,
+            AsymmetricAccessState::Unavailable => 3,
+            AsymmetricAccessState::Offline => 0xe,
+            _ => {
+                return Err(invalid_input(
+                    "asymmetric access state cannot be set explicitly",
+                ))
+            }
+        };
+        let desc = &mut buf[4 + i * 4..8 + i * 4];
+        desc[0] = code;
+        BigEndian::write_u16(&mut desc[2..4], id);
+    }
+
+    Ok(buf)
+}
+
+// Send SCSI SET TARGET PORT GROUPS to the SCSI device at the given
+// path, explicitly transitioning each (target port group, state) pair
+// given. Only devices that support explicit ALUA accept this.
+pub fn set_target_port_groups<T: SgTransport + ?Sized>(
+    path: &T,
+    groups: &[(u16, AsymmetricAccessState)],
+) -> Sg3Result<()> {
+    let params = set_target_port_groups_params(groups)?;
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa4;
+    cmd[1] = 0x0a;
+    BigEndian::write_u32(&mut cmd[6..10], params.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::ToDevice(&params))
+}
+
+/// A SCSI port of a device, from the SCSI Ports VPD page (0x88).
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScsiPort {
+    pub relative_port_id: u16,
+    /// The TransportID of the initiator port using this target port, if
+    /// the device reports it.
+    pub initiator_port_transport_id: Vec<u8>,
+    /// Designators of the target port, such as its SAS address.
+    pub target_port_descriptors: Vec<DesignationDescriptor>,
+}
+
+fn parse_scsi_ports(buf: &[u8]) -> Vec<ScsiPort> {
+    let mut ports = Vec::new();
+    let end = if buf.len() >= 4 {
+        buf.len().min(4 + BigEndian::read_u16(&buf[2..4]) as usize)
+    } else {
+        0
+    };
+    let mut offset = 4;
+
+    while offset + 8 <= end {
+        let desc = &buf[offset..end];
+        let tpd = 8 + BigEndian::read_u16(&desc[6..8]) as usize;
+        if tpd + 4 > desc.len() {
+            break;
+        }
+        let len = tpd + 4 + BigEndian::read_u16(&desc[tpd + 2..tpd + 4]) as usize;
+        if len > desc.len() {
+            break;
+        }
+        ports.push(ScsiPort {
+            relative_port_id: BigEndian::read_u16(&desc[2..4]),
+            initiator_port_transport_id: desc[8..tpd].to_vec(),
+            target_port_descriptors: des_descs(&desc[tpd + 4..len])
+                .to_result()
+                .unwrap_or_default(),
+        });
+        offset += len;
+    }
+
+    ports
+}
+
+// Send SCSI INQUIRY for VPD 88 (SCSI Ports) to the SCSI device at the
+// given path, returning each of its ports.
+pub fn inquiry_vpd_88<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<ScsiPort>> {
+    Ok(parse_scsi_ports(&inquiry_vpd_page(path, 0x88)?))
+}
+
+/// The ALUA state of one path to a logical unit, as found by
+/// `alua_path_states()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AluaPathState {
+    pub path: PathBuf,
+    /// The relative port identifier of the target port the path goes
+    /// through.
+    pub relative_port_id: Option<u16>,
+    /// The target port group of that port.
+    pub group: Option<u16>,
+    /// The access state of the group, or None if the path couldn't be
+    /// placed in a group.
+    pub state: Option<AsymmetricAccessState>,
+    /// Whether the group is a preferred path (PREF).
+    pub preferred: bool,
+}
+
+// Find the state of the path through the given device. The target port
+// it goes through is known by its relative port identifier in VPD 83,
+// or failing that, by matching its designators to a port in VPD 88.
+fn path_state<T: SgTransport + ?Sized>(path: &Path, dev: &T) -> AluaPathState {
+    let mut state = AluaPathState {
+        path: path.to_path_buf(),
+        relative_port_id: None,
+        group: None,
+        state: None,
+        preferred: false,
+    };
+
+    let vpd83 = match inquiry_vpd_83(dev) {
+        Ok(vpd83) => vpd83,
+        Err(_) => return state,
+    };
+    state.relative_port_id = vpd83.relative_port_id().or_else(|| {
+        let designators: Vec<_> = vpd83.target_port_identifiers().collect();
+        if designators.is_empty() {
+            return None;
+        }
+        inquiry_vpd_88(dev)
+            .ok()?
+            .into_iter()
+            .find(|port| {
+                port.target_port_descriptors
+                    .iter()
+                    .any(|desc| designators.contains(&&desc.designator))
+            })
+            .map(|port| port.relative_port_id)
+    });
+
+    let groups = match report_target_port_groups(dev) {
+        Ok(groups) => groups,
+        Err(_) => return state,
+    };
+    let group = match (vpd83.target_port_group(), state.relative_port_id) {
+        (Some(id), _) => groups.iter().find(|g| g.id == id),
+        (None, Some(port)) => groups.iter().find(|g| g.relative_port_ids.contains(&port)),
+        (None, None) => None,
+    };
+    if let Some(group) = group {
+        state.group = Some(group.id);
+        state.state = Some(group.state);
+        state.preferred = group.preferred;
+    }
+
+    state
+}
+
+// Find the ALUA state of each of the given sg or block device nodes,
+// which are paths to one logical unit, with INQUIRY and REPORT TARGET
+// PORT GROUPS on each. A path that fails is left without a state.
+pub fn alua_path_states<P: AsRef<Path>>(paths: &[P]) -> Vec<AluaPathState> {
+    paths
+        .iter()
+        .map(|p| path_state(p.as_ref(), p.as_ref()))
+        .collect()
+}
+
+// The active/optimized paths, those in a preferred group first.
+fn active_optimized(states: &[AluaPathState]) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = states
+        .iter()
+        .filter(|s| s.state == Some(AsymmetricAccessState::ActiveOptimized))
+        .collect();
+    paths.sort_by_key(|s| !s.preferred);
+    paths.into_iter().map(|s| s.path.clone()).collect()
+}
+
+// Which of the given paths to one logical unit are active/optimized,
+// and so the ones failover software should send I/O down, with those
+// in a preferred target port group first.
+pub fn active_optimized_paths<P: AsRef<Path>>(paths: &[P]) -> Vec<PathBuf> {
+    active_optimized(&alua_path_states(paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeDevice;
+
+    #[test]
+    fn test_parse_target_port_groups() {
+        let buf = [
+            0, 0, 0, 28, // header
+            0x80, 0x8f, 0, 1, 0, 0, 0, 2, // group 1, preferred, active/optimized
+            0, 0, 0, 1, 0, 0, 0, 2, // relative ports 1 and 2
+            0x02, 0x8f, 0, 2, 0, 0x02, 0, 1, // group 2, standby
+            0, 0, 0, 3, // relative port 3
+        ];
+        let groups = parse_target_port_groups(&buf);
+        assert_eq!(groups.len(), 2);
+        assert!(groups[0].preferred);
+        assert_eq!(groups[0].state, AsymmetricAccessState::ActiveOptimized);
+        assert_eq!(groups[0].relative_port_ids, vec![1, 2]);
+        assert_eq!(groups[1].id, 2);
+        assert_eq!(groups[1].state, AsymmetricAccessState::Standby);
+        assert_eq!(groups[1].status_code, 2);
+        assert_eq!(groups[1].relative_port_ids, vec![3]);
+    }
+
+    #[test]
+    fn test_set_target_port_groups_params() {
+        let params = set_target_port_groups_params(&[
+            (1, AsymmetricAccessState::ActiveOptimized),
+            (2, AsymmetricAccessState::Standby),
+        ])
+        .unwrap();
+        assert_eq!(params, vec![0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 2]);
+        assert!(
+            set_target_port_groups_params(&[(1, AsymmetricAccessState::Transitioning)]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_scsi_ports() {
+        let buf = [
+            0x00, 0x88, 0x00, 0x2c, // header
+            0, 0, 0, 1, 0, 0, 0, 0, // relative port 1, no TransportID
+            0, 0, 0, 12, // target port descriptors
+            0x61, 0x93, 0x00, 0x08, 0x50, 0x00, 0xc5, 0x00, 0xa1, 0xb2, 0xc3, 0xd5, //
+            0, 0, 0, 2, 0, 0, 0, 4, 0xaa, 0xbb, 0xcc, 0xdd, // relative port 2
+            0, 0, 0, 0, // no target port descriptors
+        ];
+        let ports = parse_scsi_ports(&buf);
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[0].relative_port_id, 1);
+        assert_eq!(ports[0].target_port_descriptors.len(), 1);
+        assert_eq!(ports[1].relative_port_id, 2);
+        assert_eq!(
+            ports[1].initiator_port_transport_id,
+            [0xaa, 0xbb, 0xcc, 0xdd]
+        );
+        assert!(ports[1].target_port_descriptors.is_empty());
+        // a truncated descriptor is left out
+        assert_eq!(parse_scsi_ports(&buf[..30]).len(), 1);
+    }
+
+    #[test]
+    fn test_path_states() {
+        let rtpg = [
+            0, 0, 0, 28, // header
+            0x80, 0x8f, 0, 1, 0, 0, 0, 2, // group 1, preferred, active/optimized
+            0, 0, 0, 1, 0, 0, 0, 2, // relative ports 1 and 2
+            0x02, 0x8f, 0, 2, 0, 0x02, 0, 1, // group 2, standby
+            0, 0, 0, 3, // relative port 3
+        ];
+        let lu_naa = [
+            0x01, 0x03, 0x00, 0x08, 0x50, 0x00, 0xc5, 0x00, 0xa1, 0xb2, 0xc3, 0xd4,
+        ];
+        let path = |vpd83: &[u8]| {
+            let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+            dev.set_response(0xa3, &rtpg);
+            let mut page = lu_naa.to_vec();
+            page.extend_from_slice(vpd83);
+            dev.set_vpd_page(0x83, &page);
+            dev
+        };
+
+        // relative port 1, in VPD 83
+        let a = path(&[0x61, 0x94, 0x00, 0x04, 0, 0, 0, 1]);
+        // a target port only VPD 88 gives the relative port of, 3
+        let port_naa = [
+            0x61, 0x93, 0x00, 0x08, 0x50, 0x00, 0xc5, 0x00, 0xa1, 0xb2, 0xc3, 0xd5,
+        ];
+        let mut b = path(&port_naa);
+        let mut vpd88 = vec![0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 12];
+        vpd88.extend_from_slice(&port_naa);
+        b.set_vpd_page(0x88, &vpd88);
+        // relative port 2
+        let c = path(&[0x61, 0x94, 0x00, 0x04, 0, 0, 0, 2]);
+
+        let states: Vec<_> = [("/dev/sg0", &a), ("/dev/sg1", &b), ("/dev/sg2", &c)]
+            .iter()
+            .map(|&(p, dev)| path_state(Path::new(p), dev))
+            .collect();
+        assert_eq!(states[0].relative_port_id, Some(1));
+        assert_eq!(states[0].group, Some(1));
+        assert!(states[0].preferred);
+        assert_eq!(states[1].relative_port_id, Some(3));
+        assert_eq!(states[1].state, Some(AsymmetricAccessState::Standby));
+        assert_eq!(
+            states[2].state,
+            Some(AsymmetricAccessState::ActiveOptimized)
+        );
+        assert_eq!(
+            active_optimized(&states),
+            [PathBuf::from("/dev/sg0"), PathBuf::from("/dev/sg2")]
+        );
+
+        let state = path_state(Path::new("/dev/sg3"), &FakeDevice::new("ATA", "x", "1"));
+        assert_eq!(state.state, None);
+    }
+}
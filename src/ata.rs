@@ -0,0 +1,570 @@
+//! ATA PASS-THROUGH commands for ATA devices behind a SCSI/ATA
+//! Translation layer (SAT), such as SATA drives on SAS HBAs or USB
+//! bridges.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{invalid_input, sg_io, DataDirection, Sense, SenseKey, Sg3Result, SgTransport};
+
+/// ATA protocol used to issue a passed-through ATA command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaProtocol {
+    HardwareReset,
+    SoftwareReset,
+    NonData,
+    PioDataIn,
+    PioDataOut,
+    Dma,
+    ExecuteDeviceDiagnostic,
+    DeviceReset,
+    UdmaDataIn,
+    UdmaDataOut,
+    Fpdma,
+    ReturnResponseInformation,
+}
+
+fn ata_protocol_code(protocol: AtaProtocol) -> u8 {
+    match protocol {
+        AtaProtocol::HardwareReset => 0,
+        AtaProtocol::SoftwareReset => 1,
+        AtaProtocol::NonData => 3,
+        AtaProtocol::PioDataIn => 4,
+        AtaProtocol::PioDataOut => 5,
+        AtaProtocol::Dma => 6,
+        AtaProtocol::ExecuteDeviceDiagnostic => 8,
+        AtaProtocol::DeviceReset => 9,
+        AtaProtocol::UdmaDataIn => 10,
+        AtaProtocol::UdmaDataOut => 11,
+        AtaProtocol::Fpdma => 12,
+        AtaProtocol::ReturnResponseInformation => 15,
+    }
+}
+
+/// ATA registers to pass to the device.
+#[derive(Debug, Default)]
+pub struct AtaTaskfile {
+    pub features: u16,
+    pub count: u16,
+    pub lba: u64,
+    pub device: u8,
+    pub command: u8,
+    /// Whether this is a 48-bit command using the upper halves of the
+    /// features, count, and LBA registers.
+    pub extend: bool,
+}
+
+/// ATA registers returned by the device.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AtaReturn {
+    pub extend: bool,
+    pub error: u8,
+    pub count: u16,
+    pub lba: u64,
+    pub device: u8,
+    pub status: u8,
+}
+
+impl AtaReturn {
+    /// Extract the returned ATA registers from the sense data of an
+    /// ATA PASS-THROUGH command, either from an ATA Status Return
+    /// descriptor or from fixed format sense data.
+    pub fn from_sense(sense: &Sense) -> Option<AtaReturn> {
+        if let Some(d) = sense.descriptor(0x09) {
+            if d.len() < 14 {
+                return None;
+            }
+            return Some(AtaReturn {
+                extend: d[2] & 0x01 != 0,
+                error: d[3],
+                count: u16::from(d[4]) << 8 | u16::from(d[5]),
+                lba: u64::from(d[10]) << 40
+                    | u64::from(d[8]) << 32
+                    | u64::from(d[6]) << 24
+                    | u64::from(d[11]) << 16
+                    | u64::from(d[9]) << 8
+                    | u64::from(d[7]),
+                device: d[12],
+                status: d[13],
+            });
+        }
+
+        let buf = sense.as_buf();
+        if sense.is_descriptor_format() || buf.len() < 12 {
+            return None;
+        }
+        if sense.asc() != 0x00 || sense.ascq() != 0x1d {
+            return None;
+        }
+
+        // Fixed format only has room for the lower 8 bits of count and
+        // 24 bits of LBA.
+        Some(AtaReturn {
+            extend: buf[8] & 0x80 != 0,
+            error: buf[3],
+            status: buf[4],
+            device: buf[5],
+            count: u16::from(buf[6]),
+            lba: u64::from(buf[11]) << 16 | u64::from(buf[10]) << 8 | u64::from(buf[9]),
+        })
+    }
+}
+
+// Fill in the flags byte common to both CDB sizes: transfer length in
+// 512-byte blocks given by the COUNT field, in the direction of `data`.
+fn ata_flags(data: &DataDirection, ck_cond: bool) -> u8 {
+    let mut flags = match *data {
+        DataDirection::None => 0,
+        DataDirection::FromDevice(_) => 0x08 | 0x04 | 0x02,
+        DataDirection::ToDevice(_) => 0x04 | 0x02,
+    };
+    if ck_cond {
+        flags |= 0x20;
+    }
+    flags
+}
+
+fn ata_pass_through12_cdb(
+    protocol: AtaProtocol,
+    tf: &AtaTaskfile,
+    data: &DataDirection,
+    ck_cond: bool,
+) -> Sg3Result<[u8; 12]> {
+    if tf.extend || tf.features > 0xff || tf.count > 0xff || tf.lba > 0xff_ffff {
+        return Err(invalid_input(
+            "48-bit ATA commands need ATA PASS-THROUGH(16)",
+        ));
+    }
+
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa1;
+    cmd[1] = ata_protocol_code(protocol) << 1;
+    cmd[2] = ata_flags(data, ck_cond);
+    cmd[3] = tf.features as u8;
+    cmd[4] = tf.count as u8;
+    cmd[5] = tf.lba as u8;
+    cmd[6] = (tf.lba >> 8) as u8;
+    cmd[7] = (tf.lba >> 16) as u8;
+    cmd[8] = tf.device;
+    cmd[9] = tf.command;
+
+    Ok(cmd)
+}
+
+fn ata_pass_through16_cdb(
+    protocol: AtaProtocol,
+    tf: &AtaTaskfile,
+    data: &DataDirection,
+    ck_cond: bool,
+) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x85;
+    cmd[1] = ata_protocol_code(protocol) << 1;
+    if tf.extend {
+        cmd[1] |= 0x01;
+    }
+    cmd[2] = ata_flags(data, ck_cond);
+    cmd[3] = (tf.features >> 8) as u8;
+    cmd[4] = tf.features as u8;
+    cmd[5] = (tf.count >> 8) as u8;
+    cmd[6] = tf.count as u8;
+    cmd[7] = (tf.lba >> 24) as u8;
+    cmd[8] = tf.lba as u8;
+    cmd[9] = (tf.lba >> 32) as u8;
+    cmd[10] = (tf.lba >> 8) as u8;
+    cmd[11] = (tf.lba >> 40) as u8;
+    cmd[12] = (tf.lba >> 16) as u8;
+    cmd[13] = tf.device;
+    cmd[14] = tf.command;
+
+    cmd
+}
+
+// Issue an ATA PASS-THROUGH CDB. With CK_COND set, the SATL reports
+// success as a RECOVERED ERROR carrying the ATA registers, so that is
+// turned back into success here.
+fn ata_pass_through<T: SgTransport + ?Sized>(
+    path: &T,
+    cmd: &[u8],
+    data: DataDirection,
+) -> Sg3Result<Option<AtaReturn>> {
+    match sg_io(path, cmd, data) {
+        Ok(()) => Ok(None),
+        Err(e) => match e.sense() {
+            Some(ref sense)
+                if sense.sense_key() == SenseKey::RecoveredError
+                    && sense.asc() == 0x00
+                    && sense.ascq() == 0x1d =>
+            {
+                Ok(AtaReturn::from_sense(sense))
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+// Send ATA PASS-THROUGH(12) to the SCSI device at the given path,
+// issuing the 28-bit ATA command in `tf` with the given protocol and
+// data transfer. Data is transferred in 512-byte blocks, whose number
+// is given by `tf.count`.
+//
+// If `ck_cond` is set, the device returns the ATA registers after the
+// command completes. If the ATA command fails, the returned error's
+// sense data can be passed to `AtaReturn::from_sense()`.
+pub fn ata_pass_through12<T: SgTransport + ?Sized>(
+    path: &T,
+    protocol: AtaProtocol,
+    tf: &AtaTaskfile,
+    data: DataDirection,
+    ck_cond: bool,
+) -> Sg3Result<Option<AtaReturn>> {
+    let cmd = ata_pass_through12_cdb(protocol, tf, &data, ck_cond)?;
+    ata_pass_through(path, &cmd, data)
+}
+
+// Send ATA PASS-THROUGH(16) to the SCSI device at the given path. Like
+// `ata_pass_through12()`, but also supports 48-bit commands.
+pub fn ata_pass_through16<T: SgTransport + ?Sized>(
+    path: &T,
+    protocol: AtaProtocol,
+    tf: &AtaTaskfile,
+    data: DataDirection,
+    ck_cond: bool,
+) -> Sg3Result<Option<AtaReturn>> {
+    let cmd = ata_pass_through16_cdb(protocol, tf, &data, ck_cond);
+    ata_pass_through(path, &cmd, data)
+}
+
+// Read one 512-byte block of PIO data-in for the given ATA command.
+fn ata_read_block<T: SgTransport + ?Sized>(path: &T, tf: &AtaTaskfile) -> Sg3Result<Vec<u8>> {
+    let mut buf = vec![0u8; 512];
+    ata_pass_through16(
+        path,
+        AtaProtocol::PioDataIn,
+        tf,
+        DataDirection::FromDevice(&mut buf),
+        false,
+    )?;
+    Ok(buf)
+}
+
+pub struct AtaIdentify {
+    buf: Vec<u8>,
+}
+
+/// Struct containing the ATA IDENTIFY DEVICE data, with field accessor
+/// methods.
+impl AtaIdentify {
+    /// Wrap IDENTIFY DEVICE data captured elsewhere, which must be the
+    /// full 512 bytes.
+    pub fn from_buf(buf: &[u8]) -> Option<AtaIdentify> {
+        if buf.len() < 512 {
+            return None;
+        }
+        Some(AtaIdentify {
+            buf: buf[..512].to_vec(),
+        })
+    }
+
+    /// Get the raw 512-byte IDENTIFY DEVICE data.
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn word(&self, idx: usize) -> u16 {
+        LittleEndian::read_u16(&self.buf[idx * 2..idx * 2 + 2])
+    }
+
+    // ATA strings are stored with the bytes of each word swapped.
+    fn string(&self, start: usize, end: usize) -> String {
+        let bytes: Vec<u8> = self.buf[start * 2..end * 2]
+            .chunks_exact(2)
+            .flat_map(|w| vec![w[1], w[0]])
+            .collect();
+        String::from_utf8_lossy(&bytes).trim().to_string()
+    }
+
+    pub fn serial_number(&self) -> String {
+        self.string(10, 20)
+    }
+
+    pub fn firmware_revision(&self) -> String {
+        self.string(23, 27)
+    }
+
+    pub fn model_number(&self) -> String {
+        self.string(27, 47)
+    }
+
+    pub fn smart_supported(&self) -> bool {
+        self.word(82) & 0x0001 != 0
+    }
+
+    pub fn smart_enabled(&self) -> bool {
+        self.word(85) & 0x0001 != 0
+    }
+
+    pub fn lba48_supported(&self) -> bool {
+        self.word(83) & 0x0400 != 0
+    }
+
+    /// Number of user-addressable logical sectors.
+    pub fn sectors(&self) -> u64 {
+        if self.lba48_supported() {
+            LittleEndian::read_u64(&self.buf[200..208])
+        } else {
+            u64::from(LittleEndian::read_u32(&self.buf[120..124]))
+        }
+    }
+
+    /// Logical sector size in bytes. 512 unless the drive reports
+    /// otherwise; a reported size of zero, or one too large to count in
+    /// bytes, is taken as 512 too.
+    pub fn logical_sector_size(&self) -> u32 {
+        let w106 = self.word(106);
+        if w106 & 0xc000 == 0x4000 && w106 & 0x1000 != 0 {
+            // words 117-118 give the size in 16-bit words
+            LittleEndian::read_u32(&self.buf[234..238])
+                .checked_mul(2)
+                .filter(|&size| size != 0)
+                .unwrap_or(512)
+        } else {
+            512
+        }
+    }
+
+    /// Nominal media rotation rate in rpm, 1 for solid state devices,
+    /// or 0 if not reported.
+    pub fn rotation_rate(&self) -> u16 {
+        self.word(217)
+    }
+}
+
+// Send ATA IDENTIFY DEVICE via ATA PASS-THROUGH to the ATA device
+// behind the SCSI device at the given path.
+pub fn ata_identify<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<AtaIdentify> {
+    let tf = AtaTaskfile {
+        count: 1,
+        command: 0xec,
+        ..Default::default()
+    };
+    Ok(AtaIdentify {
+        buf: ata_read_block(path, &tf)?,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SmartAttribute {
+    pub id: u8,
+    pub flags: u16,
+    /// Current normalized value.
+    pub value: u8,
+    /// Worst normalized value seen.
+    pub worst: u8,
+    /// Vendor-specific raw value.
+    pub raw: u64,
+}
+
+#[derive(Debug)]
+pub struct SmartData {
+    pub revision: u16,
+    pub attributes: Vec<SmartAttribute>,
+    pub offline_data_collection_status: u8,
+    pub self_test_execution_status: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SmartThreshold {
+    pub id: u8,
+    pub threshold: u8,
+}
+
+// SMART READ DATA and friends are ATA command 0xb0 with this signature
+// in LBA mid and high, and the subcommand in FEATURES.
+const SMART_LBA: u64 = 0xc2_4f00;
+
+fn smart_read_block<T: SgTransport + ?Sized>(path: &T, feature: u16) -> Sg3Result<Vec<u8>> {
+    let tf = AtaTaskfile {
+        features: feature,
+        count: 1,
+        lba: SMART_LBA,
+        command: 0xb0,
+        ..Default::default()
+    };
+    ata_read_block(path, &tf)
+}
+
+// The 30 attribute table entries of the SMART data and thresholds
+// structures, skipping unused entries.
+fn smart_entries(buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    buf[2..362].chunks_exact(12).filter(|e| e[0] != 0)
+}
+
+fn parse_smart_data(buf: &[u8]) -> SmartData {
+    SmartData {
+        revision: LittleEndian::read_u16(&buf[0..2]),
+        attributes: smart_entries(buf)
+            .map(|e| SmartAttribute {
+                id: e[0],
+                flags: LittleEndian::read_u16(&e[1..3]),
+                value: e[3],
+                worst: e[4],
+                raw: LittleEndian::read_uint(&e[5..11], 6),
+            })
+            .collect(),
+        offline_data_collection_status: buf[362],
+        self_test_execution_status: buf[363],
+    }
+}
+
+fn parse_smart_thresholds(buf: &[u8]) -> Vec<SmartThreshold> {
+    smart_entries(buf)
+        .map(|e| SmartThreshold {
+            id: e[0],
+            threshold: e[1],
+        })
+        .collect()
+}
+
+// Send ATA SMART READ DATA via ATA PASS-THROUGH to the ATA device
+// behind the SCSI device at the given path, returning its attribute
+// table.
+pub fn smart_read_data<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<SmartData> {
+    Ok(parse_smart_data(&smart_read_block(path, 0xd0)?))
+}
+
+// Send ATA SMART READ THRESHOLDS via ATA PASS-THROUGH to the ATA
+// device behind the SCSI device at the given path, returning the
+// failure threshold of each attribute.
+pub fn smart_read_thresholds<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<SmartThreshold>> {
+    Ok(parse_smart_thresholds(&smart_read_block(path, 0xd1)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ata_pass_through16_cdb() {
+        // READ LOG EXT, 1 block of log 0x30 page 1
+        let tf = AtaTaskfile {
+            count: 1,
+            lba: 0x0130,
+            command: 0x2f,
+            extend: true,
+            ..Default::default()
+        };
+        let mut buf = [0u8; 512];
+        let data = DataDirection::FromDevice(&mut buf);
+        let cmd = ata_pass_through16_cdb(AtaProtocol::PioDataIn, &tf, &data, false);
+        assert_eq!(
+            cmd,
+            [0x85, 0x09, 0x0e, 0, 0, 0, 1, 0, 0x30, 0, 0x01, 0, 0, 0, 0x2f, 0]
+        );
+    }
+
+    #[test]
+    fn test_ata_pass_through12_cdb() {
+        let tf = AtaTaskfile {
+            command: 0xe5,
+            ..Default::default()
+        };
+        let cmd =
+            ata_pass_through12_cdb(AtaProtocol::NonData, &tf, &DataDirection::None, true).unwrap();
+        assert_eq!(cmd, [0xa1, 0x06, 0x20, 0, 0, 0, 0, 0, 0, 0xe5, 0, 0]);
+
+        let tf = AtaTaskfile {
+            extend: true,
+            ..Default::default()
+        };
+        assert!(
+            ata_pass_through12_cdb(AtaProtocol::NonData, &tf, &DataDirection::None, true).is_err()
+        );
+    }
+
+    #[test]
+    fn test_ata_return_from_sense() {
+        let buf = [
+            0x72, 0x01, 0x00, 0x1d, 0, 0, 0, 14, 0x09, 0x0c, 0x01, 0x00, 0, 0xff, 0, 0x01, 0, 0x02,
+            0, 0x03, 0x40, 0x50,
+        ];
+        let sense = Sense::from_buf(&buf).unwrap();
+        assert_eq!(
+            AtaReturn::from_sense(&sense),
+            Some(AtaReturn {
+                extend: true,
+                error: 0,
+                count: 0xff,
+                lba: 0x030201,
+                device: 0x40,
+                status: 0x50,
+            })
+        );
+
+        let buf = [
+            0x70, 0, 0x01, 0x00, 0x50, 0x40, 0xff, 10, 0, 0, 0x4f, 0xc2, 0x00, 0x1d, 0, 0, 0, 0,
+        ];
+        let sense = Sense::from_buf(&buf).unwrap();
+        let ret = AtaReturn::from_sense(&sense).unwrap();
+        assert_eq!(ret.status, 0x50);
+        assert_eq!(ret.lba, 0xc24f00);
+    }
+
+    #[test]
+    fn test_ata_identify() {
+        let mut buf = vec![0u8; 512];
+        buf[20..24].copy_from_slice(b"BADC");
+        buf[24..40].copy_from_slice(&[b' '; 16]);
+        buf[166] = 0x00;
+        buf[167] = 0x04;
+        buf[200] = 0x00;
+        buf[201] = 0x10;
+        let id = AtaIdentify { buf };
+        assert_eq!(id.serial_number(), "ABCD");
+        assert!(id.lba48_supported());
+        assert_eq!(id.sectors(), 0x1000);
+        assert_eq!(id.logical_sector_size(), 512);
+
+        // 4096-byte logical sectors, given in words
+        let mut buf = id.buf;
+        buf[213] = 0x50;
+        LittleEndian::write_u32(&mut buf[234..238], 2048);
+        let id = AtaIdentify { buf };
+        assert_eq!(id.logical_sector_size(), 4096);
+        let mut buf = id.buf;
+        LittleEndian::write_u32(&mut buf[234..238], 0x8000_0000);
+        let id = AtaIdentify { buf };
+        assert_eq!(id.logical_sector_size(), 512);
+    }
+
+    #[test]
+    fn test_parse_smart() {
+        let mut buf = vec![0u8; 512];
+        buf[0] = 0x10;
+        buf[2..14].copy_from_slice(&[5, 0x33, 0, 100, 99, 0x10, 0x01, 0, 0, 0, 0, 0]);
+        buf[363] = 0x00;
+        let data = parse_smart_data(&buf);
+        assert_eq!(data.revision, 0x10);
+        assert_eq!(
+            data.attributes,
+            vec![SmartAttribute {
+                id: 5,
+                flags: 0x33,
+                value: 100,
+                worst: 99,
+                raw: 0x110,
+            }]
+        );
+
+        let mut buf = vec![0u8; 512];
+        buf[2..4].copy_from_slice(&[5, 36]);
+        assert_eq!(
+            parse_smart_thresholds(&buf),
+            vec![SmartThreshold {
+                id: 5,
+                threshold: 36
+            }]
+        );
+    }
+}
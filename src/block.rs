@@ -0,0 +1,193 @@
+//! Types for logical block addresses, counts of blocks and block sizes,
+//! so that they can't be mixed up with each other or with byte counts.
+
+use std::fmt;
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+/// A logical block address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lba(pub u64);
+
+/// A number of logical blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockCount(pub u64);
+
+/// The size of a logical block, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockSize(pub u32);
+
+impl Lba {
+    /// The byte offset of the block on a device with the given block
+    /// size, or None if it doesn't fit in a u64.
+    pub fn byte_offset(self, size: BlockSize) -> Option<u64> {
+        self.0.checked_mul(u64::from(size.0))
+    }
+}
+
+impl BlockCount {
+    /// How many bytes this many blocks of the given size hold, or None
+    /// if that doesn't fit in a u64.
+    pub fn bytes(self, size: BlockSize) -> Option<u64> {
+        self.0.checked_mul(u64::from(size.0))
+    }
+}
+
+impl BlockSize {
+    /// How many blocks `bytes` bytes is, or None if it isn't a whole
+    /// number of blocks.
+    pub fn blocks(self, bytes: u64) -> Option<BlockCount> {
+        if self.0 == 0 || !bytes.is_multiple_of(u64::from(self.0)) {
+            return None;
+        }
+        Some(BlockCount(bytes / u64::from(self.0)))
+    }
+
+    /// The block containing the given byte offset, or None for a block
+    /// size of zero.
+    pub fn lba(self, offset: u64) -> Option<Lba> {
+        if self.0 == 0 {
+            return None;
+        }
+        Some(Lba(offset / u64::from(self.0)))
+    }
+}
+
+impl Add<BlockCount> for Lba {
+    type Output = Lba;
+
+    fn add(self, count: BlockCount) -> Lba {
+        Lba(self.0 + count.0)
+    }
+}
+
+impl AddAssign<BlockCount> for Lba {
+    fn add_assign(&mut self, count: BlockCount) {
+        self.0 += count.0;
+    }
+}
+
+impl Sub<BlockCount> for Lba {
+    type Output = Lba;
+
+    fn sub(self, count: BlockCount) -> Lba {
+        Lba(self.0 - count.0)
+    }
+}
+
+/// The number of blocks from one LBA up to another.
+impl Sub for Lba {
+    type Output = BlockCount;
+
+    fn sub(self, other: Lba) -> BlockCount {
+        BlockCount(self.0 - other.0)
+    }
+}
+
+impl Add for BlockCount {
+    type Output = BlockCount;
+
+    fn add(self, other: BlockCount) -> BlockCount {
+        BlockCount(self.0 + other.0)
+    }
+}
+
+impl AddAssign for BlockCount {
+    fn add_assign(&mut self, other: BlockCount) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for BlockCount {
+    type Output = BlockCount;
+
+    fn sub(self, other: BlockCount) -> BlockCount {
+        BlockCount(self.0 - other.0)
+    }
+}
+
+/// The number of bytes in this many blocks, or None if that doesn't
+/// fit in a u64.
+impl Mul<BlockSize> for BlockCount {
+    type Output = Option<u64>;
+
+    fn mul(self, size: BlockSize) -> Option<u64> {
+        self.bytes(size)
+    }
+}
+
+impl From<u64> for Lba {
+    fn from(lba: u64) -> Lba {
+        Lba(lba)
+    }
+}
+
+impl From<Lba> for u64 {
+    fn from(lba: Lba) -> u64 {
+        lba.0
+    }
+}
+
+impl From<u64> for BlockCount {
+    fn from(count: u64) -> BlockCount {
+        BlockCount(count)
+    }
+}
+
+impl From<BlockCount> for u64 {
+    fn from(count: BlockCount) -> u64 {
+        count.0
+    }
+}
+
+impl From<u32> for BlockSize {
+    fn from(size: u32) -> BlockSize {
+        BlockSize(size)
+    }
+}
+
+impl From<BlockSize> for u32 {
+    fn from(size: BlockSize) -> u32 {
+        size.0
+    }
+}
+
+impl fmt::Display for Lba {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_arithmetic() {
+        let size = BlockSize(4096);
+        let start = Lba(100);
+        let end = start + BlockCount(8);
+        assert_eq!(end, Lba(108));
+        assert_eq!(end - start, BlockCount(8));
+        assert_eq!(BlockCount(8) * size, Some(32768));
+        assert_eq!(BlockCount(u64::MAX) * size, None);
+        assert_eq!(start.byte_offset(size), Some(409_600));
+        assert_eq!(Lba(u64::MAX).byte_offset(size), None);
+        assert_eq!(size.lba(409_700), Some(start));
+        assert_eq!(BlockSize(0).lba(512), None);
+        assert_eq!(size.blocks(32768), Some(BlockCount(8)));
+        assert_eq!(size.blocks(32769), None);
+        assert_eq!(BlockSize(0).blocks(512), None);
+    }
+}
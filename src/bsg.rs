@@ -0,0 +1,228 @@
+//! The Linux bsg driver, which takes commands in the v4 (struct
+//! sg_io_v4) form at /dev/bsg/H:C:T:L.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::libc::ioctl as nix_ioctl;
+
+use crate::{
+    check_cdb, ffi, open_device, CommandResult, DataDirection, Lun, ScsiAddress, Sg3Error,
+    Sg3Result, SgFile, SgTransport, STATUS_CONDITION_MET,
+};
+
+/// The outcome of a bidirectional command that completed successfully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BidiResult {
+    /// The SCSI status, either GOOD or CONDITION MET.
+    pub status: u8,
+    /// How many bytes of the data-out buffer were not sent.
+    pub dout_resid: usize,
+    /// How many bytes of the data-in buffer were not filled.
+    pub din_resid: usize,
+    /// How long the command took, as the driver measured it.
+    pub duration: Duration,
+}
+
+/// An open bsg device node, such as /dev/bsg/0:0:0:0. Commands sent
+/// through it use the v4 interface.
+#[derive(Debug)]
+pub struct BsgFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl BsgFile {
+    /// Open the bsg device at the given path, read-write if allowed.
+    pub fn open(path: &Path) -> Sg3Result<BsgFile> {
+        Ok(BsgFile {
+            file: open_device(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Open the bsg device of the given logical unit of the target at
+    /// `address`, whose own LUN is ignored. This reaches logical units
+    /// without an sg device, such as the REPORT LUNS well-known logical
+    /// unit on a target with no others mapped, if the kernel has
+    /// attached it.
+    pub fn open_lun(address: &ScsiAddress, lun: Lun) -> Sg3Result<BsgFile> {
+        BsgFile::open(&bsg_path(address, lun))
+    }
+
+    /// Issue a bidirectional command, such as XDWRITEREAD, sending
+    /// `dout` to the device and reading into `din` in the one command,
+    /// with a timeout in milliseconds.
+    pub fn execute_bidi(
+        &self,
+        cmd: &[u8],
+        dout: &[u8],
+        din: &mut [u8],
+        timeout: u32,
+    ) -> Sg3Result<BidiResult> {
+        let din_len = din.len();
+        let (hdr, _) = self.sg_io_v4(cmd, dout, din, timeout)?;
+        Ok(BidiResult {
+            status: hdr.device_status as u8,
+            dout_resid: resid(hdr.dout_resid, dout.len()),
+            din_resid: resid(hdr.din_resid, din_len),
+            duration: Duration::from_millis(hdr.duration as u64),
+        })
+    }
+
+    // Issue the given CDB with SG_IO in the v4 form, sending `dout` to
+    // the device and reading into `din`, either of which may be empty.
+    // Return the completed header and when the command was submitted.
+    fn sg_io_v4(
+        &self,
+        cmd: &[u8],
+        dout: &[u8],
+        din: &mut [u8],
+        timeout: u32,
+    ) -> Sg3Result<(ffi::sg_io_v4, Instant)> {
+        check_cdb(cmd)?;
+
+        let mut sb = [0u8; 64];
+        let mut hdr = ffi::sg_io_v4 {
+            guard: 'Q' as i32,
+            protocol: ffi::BSG_PROTOCOL_SCSI,
+            subprotocol: ffi::BSG_SUB_PROTOCOL_SCSI_CMD,
+            request_len: cmd.len() as u32,
+            request: cmd.as_ptr() as u64,
+            max_response_len: sb.len() as u32,
+            response: sb.as_mut_ptr() as u64,
+            dout_xfer_len: dout.len() as u32,
+            dout_xferp: dout.as_ptr() as u64,
+            din_xfer_len: din.len() as u32,
+            din_xferp: din.as_mut_ptr() as u64,
+            timeout,
+            ..Default::default()
+        };
+
+        let submitted = Instant::now();
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                ffi::SG_IO as u64,
+                &mut hdr
+            ))?
+        };
+
+        let status = hdr.device_status as u8;
+        let condition_met =
+            status == STATUS_CONDITION_MET && hdr.transport_status == 0 && hdr.driver_status == 0;
+
+        if hdr.info & ffi::SG_INFO_OK_MASK != ffi::SG_INFO_OK && !condition_met {
+            return Err(Sg3Error::Scsi {
+                device: self.path.clone(),
+                cdb: cmd.to_vec(),
+                status,
+                host_status: hdr.transport_status as u16,
+                driver_status: hdr.driver_status as u16,
+                sense: sb[..(hdr.response_len as usize).min(sb.len())].to_vec(),
+            });
+        }
+
+        Ok((hdr, submitted))
+    }
+}
+
+// The bsg device node of the given logical unit of the target at
+// `address`, which is named for its H:C:T:L.
+fn bsg_path(address: &ScsiAddress, lun: Lun) -> PathBuf {
+    let address = ScsiAddress {
+        lun: lun.to_linux(),
+        ..*address
+    };
+    PathBuf::from(format!("/dev/bsg/{}", address))
+}
+
+// How many of `len` bytes were left untransferred, per the driver.
+fn resid(resid: i32, len: usize) -> usize {
+    (resid.max(0) as usize).min(len)
+}
+
+impl SgTransport for BsgFile {
+    fn execute(&self, cmd: &[u8], data: DataDirection, timeout: u32) -> Sg3Result<CommandResult> {
+        let ((hdr, submitted), len, resid) = match data {
+            DataDirection::None => {
+                let res = self.sg_io_v4(cmd, &[], &mut [], timeout)?;
+                (res, 0, 0)
+            }
+            DataDirection::FromDevice(buf) => {
+                let len = buf.len();
+                let res = self.sg_io_v4(cmd, &[], buf, timeout)?;
+                let resid = resid(res.0.din_resid, len);
+                (res, len, resid)
+            }
+            DataDirection::ToDevice(buf) => {
+                let res = self.sg_io_v4(cmd, buf, &mut [], timeout)?;
+                let resid = resid(res.0.dout_resid, buf.len());
+                (res, buf.len(), resid)
+            }
+        };
+
+        Ok(CommandResult {
+            status: hdr.device_status as u8,
+            resid,
+            transferred: len - resid,
+            duration: Duration::from_millis(hdr.duration as u64),
+            submitted,
+            completed: Instant::now(),
+        })
+    }
+}
+
+/// The driver interface that commands are sent with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// SG_IO with the v3 (struct sg_io_hdr) interface, on sg and block
+    /// devices.
+    SgV3,
+    /// SG_IO with the v4 interface, on bsg devices.
+    BsgV4,
+}
+
+// Open the device at the given path to send commands to it with the
+// given backend, or if none is given, with the v4 interface for nodes
+// under /dev/bsg and the v3 interface for anything else.
+pub fn open_backend(path: &Path, backend: Option<Backend>) -> Sg3Result<Box<dyn SgTransport>> {
+    let backend = backend.unwrap_or(if path.starts_with("/dev/bsg") {
+        Backend::BsgV4
+    } else {
+        Backend::SgV3
+    });
+
+    Ok(match backend {
+        Backend::SgV3 => Box::new(SgFile::open(path)?),
+        Backend::BsgV4 => Box::new(BsgFile::open(path)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resid() {
+        assert_eq!(resid(-1, 512), 0);
+        assert_eq!(resid(12, 512), 12);
+        assert_eq!(resid(1024, 512), 512);
+    }
+
+    #[test]
+    fn test_bsg_path() {
+        let address = ScsiAddress {
+            host: 2,
+            channel: 0,
+            target: 1,
+            lun: 3,
+        };
+        assert_eq!(
+            bsg_path(&address, Lun::REPORT_LUNS),
+            Path::new("/dev/bsg/2:0:1:49409")
+        );
+    }
+}
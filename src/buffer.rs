@@ -0,0 +1,444 @@
+//! WRITE BUFFER and READ BUFFER, used for microcode download and for
+//! buffer diagnostics.
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    inquiry_vpd_b0, invalid_input, sg_io, sg_io_timeout, test_unit_ready, DataDirection, SenseKey,
+    Sg3Error, Sg3Result, SgTransport,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteBufferMode {
+    /// Write to the buffer (mode 0x02).
+    Data,
+    /// Download microcode in one command, save it, and activate it
+    /// (mode 0x05).
+    DownloadMicrocodeSave,
+    /// Download microcode in pieces at increasing offsets, save it, and
+    /// activate it once complete (mode 0x07).
+    DownloadMicrocodeOffsetsSave,
+    /// Write to the echo buffer (mode 0x0a).
+    EchoBuffer,
+    /// Download microcode with offsets and save it, deferring
+    /// activation until a selected activation event (mode 0x0d).
+    DownloadMicrocodeOffsetsSelectActivation,
+    /// Download microcode with offsets and save it, deferring
+    /// activation until `ActivateDeferredMicrocode` or a reset
+    /// (mode 0x0e).
+    DownloadMicrocodeOffsetsDefer,
+    /// Activate previously deferred microcode (mode 0x0f).
+    ActivateDeferredMicrocode,
+}
+
+fn write_buffer_mode_code(mode: WriteBufferMode) -> u8 {
+    match mode {
+        WriteBufferMode::Data => 0x02,
+        WriteBufferMode::DownloadMicrocodeSave => 0x05,
+        WriteBufferMode::DownloadMicrocodeOffsetsSave => 0x07,
+        WriteBufferMode::EchoBuffer => 0x0a,
+        WriteBufferMode::DownloadMicrocodeOffsetsSelectActivation => 0x0d,
+        WriteBufferMode::DownloadMicrocodeOffsetsDefer => 0x0e,
+        WriteBufferMode::ActivateDeferredMicrocode => 0x0f,
+    }
+}
+
+// Saving and activating microcode can take minutes.
+const WRITE_BUFFER_TIMEOUT: u32 = 5 * 60 * 1000;
+
+fn write_buffer_cdb(
+    mode: WriteBufferMode,
+    buffer_id: u8,
+    offset: u32,
+    len: usize,
+) -> Sg3Result<[u8; 10]> {
+    if offset > 0xff_ffff || len > 0xff_ffff {
+        return Err(invalid_input(
+            "buffer offset and length must fit in 24 bits",
+        ));
+    }
+
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x3b;
+    cmd[1] = write_buffer_mode_code(mode);
+    cmd[2] = buffer_id;
+    BigEndian::write_u24(&mut cmd[3..6], offset);
+    BigEndian::write_u24(&mut cmd[6..9], len as u32);
+
+    Ok(cmd)
+}
+
+// Send SCSI WRITE BUFFER to the SCSI device at the given path, writing
+// `buf` to the given buffer at `offset` using the given mode.
+pub fn write_buffer<T: SgTransport + ?Sized>(
+    path: &T,
+    mode: WriteBufferMode,
+    buffer_id: u8,
+    offset: u32,
+    buf: &[u8],
+) -> Sg3Result<()> {
+    let cmd = write_buffer_cdb(mode, buffer_id, offset, buf.len())?;
+    let data = if buf.is_empty() {
+        DataDirection::None
+    } else {
+        DataDirection::ToDevice(buf)
+    };
+    sg_io_timeout(path, &cmd, data, WRITE_BUFFER_TIMEOUT)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadBufferMode {
+    /// Read from the buffer (mode 0x02).
+    Data,
+    /// Read the buffer's offset boundary and capacity (mode 0x03).
+    Descriptor,
+    /// Read back what was last written to the echo buffer (mode 0x0a).
+    EchoBuffer,
+    /// Read the echo buffer's capacity (mode 0x0b).
+    EchoBufferDescriptor,
+}
+
+fn read_buffer_mode_code(mode: ReadBufferMode) -> u8 {
+    match mode {
+        ReadBufferMode::Data => 0x02,
+        ReadBufferMode::Descriptor => 0x03,
+        ReadBufferMode::EchoBuffer => 0x0a,
+        ReadBufferMode::EchoBufferDescriptor => 0x0b,
+    }
+}
+
+fn read_buffer_cdb(
+    mode: ReadBufferMode,
+    buffer_id: u8,
+    offset: u32,
+    len: usize,
+) -> Sg3Result<[u8; 10]> {
+    if offset > 0xff_ffff || len > 0xff_ffff {
+        return Err(invalid_input(
+            "buffer offset and length must fit in 24 bits",
+        ));
+    }
+
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x3c;
+    cmd[1] = read_buffer_mode_code(mode);
+    cmd[2] = buffer_id;
+    BigEndian::write_u24(&mut cmd[3..6], offset);
+    BigEndian::write_u24(&mut cmd[6..9], len as u32);
+
+    Ok(cmd)
+}
+
+// Send SCSI READ BUFFER to the SCSI device at the given path, reading
+// from the given buffer at `offset` into `buf` using the given mode.
+pub fn read_buffer<T: SgTransport + ?Sized>(
+    path: &T,
+    mode: ReadBufferMode,
+    buffer_id: u8,
+    offset: u32,
+    buf: &mut [u8],
+) -> Sg3Result<()> {
+    let cmd = read_buffer_cdb(mode, buffer_id, offset, buf.len())?;
+    sg_io(path, &cmd, DataDirection::FromDevice(buf))
+}
+
+/// Buffer offset alignment and capacity, from READ BUFFER in
+/// descriptor mode.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BufferDescriptor {
+    /// Buffer offsets must be a multiple of 2 to this power. 0xff
+    /// means only an offset of zero is allowed.
+    pub offset_boundary: u8,
+    /// Size of the buffer in bytes.
+    pub capacity: u32,
+}
+
+// Send SCSI READ BUFFER in descriptor mode to the SCSI device at the
+// given path, returning the offset alignment and capacity of the given
+// buffer.
+pub fn read_buffer_descriptor<T: SgTransport + ?Sized>(
+    path: &T,
+    buffer_id: u8,
+) -> Sg3Result<BufferDescriptor> {
+    let mut buf = [0u8; 4];
+    read_buffer(path, ReadBufferMode::Descriptor, buffer_id, 0, &mut buf)?;
+
+    Ok(BufferDescriptor {
+        offset_boundary: buf[0],
+        capacity: BigEndian::read_u24(&buf[1..4]),
+    })
+}
+
+// Send SCSI READ BUFFER in echo buffer descriptor mode to the SCSI
+// device at the given path, returning the capacity of the echo buffer
+// in bytes.
+pub fn echo_buffer_capacity<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<u16> {
+    let mut buf = [0u8; 4];
+    read_buffer(path, ReadBufferMode::EchoBufferDescriptor, 0, 0, &mut buf)?;
+
+    Ok(BigEndian::read_u16(&buf[2..4]) & 0x1fff)
+}
+
+// Write `pattern` to the echo buffer of the SCSI device at the given
+// path and read it back, returning whether the data survived the round
+// trip intact. Patterns longer than the echo buffer capacity are
+// rejected by the device.
+pub fn echo_buffer_test<T: SgTransport + ?Sized>(path: &T, pattern: &[u8]) -> Sg3Result<bool> {
+    write_buffer(path, WriteBufferMode::EchoBuffer, 0, 0, pattern)?;
+
+    let mut buf = vec![0u8; pattern.len()];
+    read_buffer(path, ReadBufferMode::EchoBuffer, 0, 0, &mut buf)?;
+
+    Ok(buf == pattern)
+}
+
+// Used when a device reports no limits of its own.
+const DEFAULT_MICROCODE_CHUNK: usize = 32 * 1024;
+
+// Work out the largest piece of `len` bytes of microcode to send per
+// WRITE BUFFER: no more than the buffer capacity or the maximum
+// transfer length (assuming the smallest possible 512-byte blocks),
+// rounded down to a multiple of the offset boundary. An offset boundary
+// of FFh allows only offset 0, so the whole image must go at once.
+fn microcode_chunk_size(
+    desc: Option<&BufferDescriptor>,
+    max_transfer_blocks: u32,
+    len: usize,
+) -> Sg3Result<usize> {
+    let mut chunk = DEFAULT_MICROCODE_CHUNK;
+
+    if max_transfer_blocks != 0 {
+        chunk = chunk.min(max_transfer_blocks as usize * 512);
+    }
+    if let Some(desc) = desc {
+        if desc.offset_boundary == 0xff {
+            if desc.capacity != 0 && len > desc.capacity as usize {
+                return Err(invalid_input(
+                    "image is larger than the buffer, which takes it only at offset 0",
+                ));
+            }
+            return Ok(len.max(1));
+        }
+        if desc.capacity != 0 {
+            chunk = chunk.min(desc.capacity as usize);
+        }
+        if desc.offset_boundary < 24 {
+            let align = 1usize << desc.offset_boundary;
+            chunk = (chunk / align * align).max(align);
+        }
+    }
+
+    Ok(chunk)
+}
+
+// Download microcode `image` to the SCSI device at the given path with
+// WRITE BUFFER. Modes that take offsets split the image into chunks
+// that respect the device's buffer capacity, maximum transfer length,
+// and offset alignment; `DownloadMicrocodeSave` sends it whole.
+//
+// With a deferring mode, follow this with `write_buffer()` in
+// `ActivateDeferredMicrocode` mode to switch to the new microcode.
+pub fn download_microcode<T: SgTransport + ?Sized>(
+    path: &T,
+    mode: WriteBufferMode,
+    buffer_id: u8,
+    image: &[u8],
+) -> Sg3Result<()> {
+    download_microcode_chunks(path, mode, buffer_id, image, |_, _| {})
+}
+
+// Like download_microcode(), but calls `progress` with the number of
+// bytes sent so far and the image size after each chunk.
+fn download_microcode_chunks<T: SgTransport + ?Sized, F>(
+    path: &T,
+    mode: WriteBufferMode,
+    buffer_id: u8,
+    image: &[u8],
+    mut progress: F,
+) -> Sg3Result<()>
+where
+    F: FnMut(usize, usize),
+{
+    match mode {
+        WriteBufferMode::DownloadMicrocodeSave => {
+            write_buffer(path, mode, buffer_id, 0, image)?;
+            progress(image.len(), image.len());
+            return Ok(());
+        }
+        WriteBufferMode::DownloadMicrocodeOffsetsSave
+        | WriteBufferMode::DownloadMicrocodeOffsetsSelectActivation
+        | WriteBufferMode::DownloadMicrocodeOffsetsDefer => {}
+        _ => return Err(invalid_input("not a microcode download mode")),
+    }
+
+    // Both of these are optional, so carry on with defaults if the
+    // device rejects them.
+    let desc = match read_buffer_descriptor(path, buffer_id) {
+        Ok(desc) => Some(desc),
+        Err(Sg3Error::Scsi { .. }) => None,
+        Err(e) => return Err(e),
+    };
+    let max_transfer = match inquiry_vpd_b0(path) {
+        Ok(limits) => limits.max_transfer_length(),
+        Err(Sg3Error::Scsi { .. }) => 0,
+        Err(e) => return Err(e),
+    };
+    let chunk = microcode_chunk_size(desc.as_ref(), max_transfer, image.len())?;
+
+    let mut offset = 0;
+    for piece in image.chunks(chunk) {
+        write_buffer(path, mode, buffer_id, offset as u32, piece)?;
+        offset += piece.len();
+        progress(offset, image.len());
+    }
+
+    Ok(())
+}
+
+/// Microcode image to pass to `update_firmware()`.
+#[derive(Debug)]
+pub enum FirmwareImage<'a> {
+    File(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+/// Stage of a firmware update, as reported to the progress callback of
+/// `update_firmware()`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FirmwareUpdateStage {
+    /// Downloading the image, with the number of bytes sent so far and
+    /// the image size.
+    Downloading(usize, usize),
+    /// The image has been downloaded and activation has been requested.
+    Activating,
+    /// Waiting for the device to report that its microcode changed.
+    WaitingForDevice,
+}
+
+// How long to wait for the device to come back after activation, and
+// how often to check.
+const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(120);
+const ACTIVATION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Update the microcode of the SCSI device at the given path: download
+// the image in deferred-activation chunks, activate it, then wait for
+// the device to become ready again. `progress` is told about each
+// stage as it happens.
+//
+// Returns true if the device reported that its microcode changed (a
+// MICROCODE HAS BEEN CHANGED or reset unit attention), or false if it
+// became ready again without saying so.
+pub fn update_firmware<T: SgTransport + ?Sized, F>(
+    path: &T,
+    image: FirmwareImage,
+    mut progress: F,
+) -> Sg3Result<bool>
+where
+    F: FnMut(FirmwareUpdateStage),
+{
+    let contents;
+    let image = match image {
+        FirmwareImage::File(file) => {
+            contents = fs::read(file)?;
+            &contents[..]
+        }
+        FirmwareImage::Bytes(bytes) => bytes,
+    };
+
+    download_microcode_chunks(
+        path,
+        WriteBufferMode::DownloadMicrocodeOffsetsDefer,
+        0,
+        image,
+        |sent, total| progress(FirmwareUpdateStage::Downloading(sent, total)),
+    )?;
+
+    progress(FirmwareUpdateStage::Activating);
+    write_buffer(path, WriteBufferMode::ActivateDeferredMicrocode, 0, 0, &[])?;
+
+    progress(FirmwareUpdateStage::WaitingForDevice);
+    let start = Instant::now();
+    let mut changed = false;
+    loop {
+        match test_unit_ready(path) {
+            Ok(()) => return Ok(changed),
+            Err(e) => {
+                match e.sense() {
+                    // MICROCODE HAS BEEN CHANGED, or any POWER ON,
+                    // RESET, OR BUS DEVICE RESET OCCURRED
+                    Some(ref sense)
+                        if sense.sense_key() == SenseKey::UnitAttention
+                            && ((sense.asc() == 0x3f && sense.ascq() == 0x01)
+                                || sense.asc() == 0x29) =>
+                    {
+                        changed = true;
+                        continue;
+                    }
+                    _ => {}
+                }
+                // The device may be not ready or gone entirely while it
+                // restarts, so only give up once the timeout expires.
+                if start.elapsed() > ACTIVATION_TIMEOUT {
+                    return Err(e);
+                }
+            }
+        }
+        thread::sleep(ACTIVATION_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_buffer_cdb() {
+        let cmd = write_buffer_cdb(
+            WriteBufferMode::DownloadMicrocodeOffsetsDefer,
+            0,
+            0x8000,
+            0x8000,
+        )
+        .unwrap();
+        assert_eq!(cmd, [0x3b, 0x0e, 0, 0, 0x80, 0, 0, 0x80, 0, 0]);
+        assert!(write_buffer_cdb(WriteBufferMode::Data, 0, 0x100_0000, 0).is_err());
+    }
+
+    #[test]
+    fn test_read_buffer_cdb() {
+        let cmd = read_buffer_cdb(ReadBufferMode::EchoBuffer, 0, 0, 0x1000).unwrap();
+        assert_eq!(cmd, [0x3c, 0x0a, 0, 0, 0, 0, 0, 0x10, 0, 0]);
+        let cmd = read_buffer_cdb(ReadBufferMode::Data, 1, 0x10203, 4).unwrap();
+        assert_eq!(cmd, [0x3c, 0x02, 1, 0x01, 0x02, 0x03, 0, 0, 4, 0]);
+    }
+
+    #[test]
+    fn test_microcode_chunk_size() {
+        assert_eq!(microcode_chunk_size(None, 0, 1 << 20).unwrap(), 32 * 1024);
+        assert_eq!(microcode_chunk_size(None, 16, 1 << 20).unwrap(), 8192);
+        let desc = BufferDescriptor {
+            offset_boundary: 12,
+            capacity: 10000,
+        };
+        assert_eq!(microcode_chunk_size(Some(&desc), 0, 1 << 20).unwrap(), 8192);
+
+        // only offset 0 allowed
+        let desc = BufferDescriptor {
+            offset_boundary: 0xff,
+            capacity: 0x10_0000,
+        };
+        assert_eq!(
+            microcode_chunk_size(Some(&desc), 16, 0x8_0000).unwrap(),
+            0x8_0000
+        );
+        assert!(microcode_chunk_size(Some(&desc), 0, 0x10_0001).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Building CDBs for commands the crate doesn't otherwise have, in the
+//! common layouts of SPC-4 4.2.5.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{invalid_input, Sg3Result};
+
+/// Builds a CDB in the common format for its length: 6, 10, 12 or 16
+/// bytes, as given by the group code of the operation code, or 32
+/// bytes for a variable-length CDB. Fields that don't fit the format
+/// are reported by `build()`, rather than being silently truncated.
+///
+/// For example, READ(16) of 8 blocks at LBA 0x1000 with FUA set:
+///
+/// ```
+/// let cmd = sg3::CdbBuilder::new(0x88)
+///     .byte(1, 0x08)
+///     .lba(0x1000)
+///     .transfer_length(8)
+///     .build()
+///     .unwrap();
+/// assert_eq!(cmd.len(), 16);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CdbBuilder {
+    opcode: u8,
+    service_action: Option<u16>,
+    lba: Option<u64>,
+    transfer_length: Option<u32>,
+    control: u8,
+    bytes: Vec<(usize, u8)>,
+}
+
+impl CdbBuilder {
+    pub fn new(opcode: u8) -> CdbBuilder {
+        CdbBuilder {
+            opcode,
+            service_action: None,
+            lba: None,
+            transfer_length: None,
+            control: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    /// A 32-byte variable-length CDB (operation code 7Fh) with the given
+    /// service action.
+    pub fn variable(service_action: u16) -> CdbBuilder {
+        CdbBuilder::new(0x7f).service_action(service_action)
+    }
+
+    /// The service action, which must fit in five bits except in a
+    /// variable-length CDB. 6-byte CDBs have none.
+    pub fn service_action(mut self, service_action: u16) -> CdbBuilder {
+        self.service_action = Some(service_action);
+        self
+    }
+
+    /// The logical block address, which must fit in 21 bits in a 6-byte
+    /// CDB and 32 bits in a 10- or 12-byte CDB.
+    pub fn lba(mut self, lba: u64) -> CdbBuilder {
+        self.lba = Some(lba);
+        self
+    }
+
+    /// The transfer, parameter list or allocation length, which must
+    /// fit in 8 bits in a 6-byte CDB and 16 bits in a 10-byte CDB.
+    pub fn transfer_length(mut self, len: u32) -> CdbBuilder {
+        self.transfer_length = Some(len);
+        self
+    }
+
+    /// The CONTROL byte, which is the last byte of the CDB, or byte 1 of
+    /// a variable-length CDB.
+    pub fn control(mut self, control: u8) -> CdbBuilder {
+        self.control = control;
+        self
+    }
+
+    /// Set a byte that none of the other fields cover, such as one
+    /// holding flag bits, ORed with what the other fields put there.
+    pub fn byte(mut self, index: usize, value: u8) -> CdbBuilder {
+        self.bytes.push((index, value));
+        self
+    }
+
+    // The length of the CDB, from its operation code.
+    fn cdb_len(&self) -> Sg3Result<usize> {
+        if self.opcode == 0x7f {
+            return Ok(32);
+        }
+        match self.opcode >> 5 {
+            0 => Ok(6),
+            1 | 2 => Ok(10),
+            4 => Ok(16),
+            5 => Ok(12),
+            _ => Err(invalid_input("operation code has no common CDB format")),
+        }
+    }
+
+    /// Pack the fields into a CDB, checking that each fits.
+    pub fn build(&self) -> Sg3Result<Vec<u8>> {
+        let len = self.cdb_len()?;
+        let mut cmd = vec![0u8; len];
+        cmd[0] = self.opcode;
+
+        if let Some(sa) = self.service_action {
+            match len {
+                6 => return Err(invalid_input("6-byte CDBs have no service action")),
+                32 => BigEndian::write_u16(&mut cmd[8..10], sa),
+                _ if sa > 0x1f => return Err(invalid_input("service action is too large")),
+                _ => cmd[1] = sa as u8,
+            }
+        }
+
+        if let Some(lba) = self.lba {
+            match len {
+                6 if lba > 0x1f_ffff => return Err(invalid_input("LBA is too large")),
+                6 => {
+                    cmd[1] |= (lba >> 16) as u8;
+                    BigEndian::write_u16(&mut cmd[2..4], lba as u16);
+                }
+                10 | 12 if lba > u32::MAX as u64 => return Err(invalid_input("LBA is too large")),
+                10 | 12 => BigEndian::write_u32(&mut cmd[2..6], lba as u32),
+                16 => BigEndian::write_u64(&mut cmd[2..10], lba),
+                _ => BigEndian::write_u64(&mut cmd[12..20], lba),
+            }
+        }
+
+        if let Some(tl) = self.transfer_length {
+            match len {
+                6 if tl > 0xff => return Err(invalid_input("transfer length is too large")),
+                6 => cmd[4] = tl as u8,
+                10 if tl > 0xffff => return Err(invalid_input("transfer length is too large")),
+                10 => BigEndian::write_u16(&mut cmd[7..9], tl as u16),
+                12 => BigEndian::write_u32(&mut cmd[6..10], tl),
+                16 => BigEndian::write_u32(&mut cmd[10..14], tl),
+                _ => BigEndian::write_u32(&mut cmd[28..32], tl),
+            }
+        }
+
+        if len == 32 {
+            cmd[1] = self.control;
+            // ADDITIONAL CDB LENGTH
+            cmd[7] = 0x18;
+        } else {
+            cmd[len - 1] = self.control;
+        }
+
+        for &(index, value) in &self.bytes {
+            if index == 0 || index >= len {
+                return Err(invalid_input("byte is outside the CDB"));
+            }
+            cmd[index] |= value;
+        }
+
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cdb_builder() {
+        // READ(6)
+        let cmd = CdbBuilder::new(0x08)
+            .lba(0x23_4567)
+            .transfer_length(16)
+            .build();
+        assert!(cmd.is_err());
+        let cmd = CdbBuilder::new(0x08)
+            .lba(0x1_3456)
+            .transfer_length(16)
+            .build()
+            .unwrap();
+        assert_eq!(cmd, [0x08, 0x01, 0x34, 0x56, 16, 0]);
+
+        // READ(10) can't take a 64-bit LBA
+        assert!(CdbBuilder::new(0x28).lba(1 << 32).build().is_err());
+
+        // READ(16) with FUA
+        let cmd = CdbBuilder::new(0x88)
+            .byte(1, 0x08)
+            .lba(0x1000)
+            .transfer_length(8)
+            .build()
+            .unwrap();
+        assert_eq!(
+            cmd,
+            [0x88, 0x08, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 8, 0, 0]
+        );
+
+        // READ CAPACITY(16)
+        let cmd = CdbBuilder::new(0x9e)
+            .service_action(0x10)
+            .transfer_length(32)
+            .build()
+            .unwrap();
+        assert_eq!(cmd[1], 0x10);
+        assert_eq!(&cmd[10..14], &[0, 0, 0, 32]);
+        assert!(CdbBuilder::new(0x9e).service_action(0x20).build().is_err());
+
+        // READ(32)
+        let cmd = CdbBuilder::variable(0x0009)
+            .lba(0x1000)
+            .transfer_length(8)
+            .build()
+            .unwrap();
+        assert_eq!(cmd.len(), 32);
+        assert_eq!(&cmd[7..10], &[0x18, 0x00, 0x09]);
+        assert_eq!(cmd[31], 8);
+
+        assert!(CdbBuilder::new(0xc0).build().is_err());
+        assert!(CdbBuilder::new(0x00).byte(6, 1).build().is_err());
+    }
+}
@@ -0,0 +1,732 @@
+//! Third-party copy commands, used to offload copies to the storage.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    inquiry_vpd_b0, inquiry_vpd_page, invalid_data, invalid_input, read16, read_capacity16, sg_io,
+    write16, BlockCount, DataDirection, Lba, Sense, Sg3Error, Sg3Result, SgTransport,
+};
+
+fn receive_copy_results_cmd<T: SgTransport + ?Sized>(
+    path: &T,
+    service_action: u8,
+    list_id: u32,
+    buf: &mut [u8],
+) -> Sg3Result<()> {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x84;
+    cmd[1] = service_action;
+    // The ROD token service actions take a four-byte list identifier.
+    if service_action >= 0x05 {
+        BigEndian::write_u32(&mut cmd[2..6], list_id);
+    } else {
+        cmd[2] = list_id as u8;
+    }
+    BigEndian::write_u32(&mut cmd[10..14], buf.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::FromDevice(buf))
+}
+
+/// Limits of a device's copy manager, from RECEIVE COPY RESULTS with
+/// the OPERATING PARAMETERS service action.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CopyOperatingParameters {
+    /// The copy manager supports list identifiers.
+    pub supports_list_id: bool,
+    pub max_target_descriptors: u16,
+    pub max_segment_descriptors: u16,
+    pub max_descriptor_list_length: u32,
+    /// Largest number of bytes a single segment may copy.
+    pub max_segment_length: u32,
+    pub max_inline_data_length: u32,
+    pub held_data_limit: u32,
+    pub max_stream_transfer_size: u32,
+    pub total_concurrent_copies: u16,
+    pub max_concurrent_copies: u8,
+    /// Segment lengths should be a multiple of 2 to this power.
+    pub data_segment_granularity: u8,
+    pub inline_data_granularity: u8,
+    pub held_data_granularity: u8,
+    /// Descriptor type codes the copy manager implements.
+    pub descriptor_types: Vec<u8>,
+}
+
+fn parse_copy_operating_parameters(buf: &[u8]) -> CopyOperatingParameters {
+    let end = buf.len().min(4 + BigEndian::read_u32(&buf[0..4]) as usize);
+    let types_end = end.min(44 + buf[43] as usize);
+
+    CopyOperatingParameters {
+        supports_list_id: buf[4] & 0x01 != 0,
+        max_target_descriptors: BigEndian::read_u16(&buf[8..10]),
+        max_segment_descriptors: BigEndian::read_u16(&buf[10..12]),
+        max_descriptor_list_length: BigEndian::read_u32(&buf[12..16]),
+        max_segment_length: BigEndian::read_u32(&buf[16..20]),
+        max_inline_data_length: BigEndian::read_u32(&buf[20..24]),
+        held_data_limit: BigEndian::read_u32(&buf[24..28]),
+        max_stream_transfer_size: BigEndian::read_u32(&buf[28..32]),
+        total_concurrent_copies: BigEndian::read_u16(&buf[34..36]),
+        max_concurrent_copies: buf[36],
+        data_segment_granularity: buf[37],
+        inline_data_granularity: buf[38],
+        held_data_granularity: buf[39],
+        descriptor_types: buf[44..types_end.max(44)].to_vec(),
+    }
+}
+
+// Send SCSI RECEIVE COPY RESULTS with the OPERATING PARAMETERS service
+// action to the SCSI device at the given path, returning the limits of
+// its copy manager. Use these to size EXTENDED COPY segments.
+pub fn copy_operating_parameters<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<CopyOperatingParameters> {
+    let mut buf = [0u8; 300];
+    receive_copy_results_cmd(path, 0x03, 0, &mut buf)?;
+    Ok(parse_copy_operating_parameters(&buf))
+}
+
+/// Progress of a copy, from RECEIVE COPY RESULTS with the COPY STATUS
+/// service action.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CopyStatus {
+    /// Held data was discarded.
+    pub held_data_discarded: bool,
+    /// The COPY MANAGER STATUS field: 0x00 means in progress, 0x01
+    /// completed successfully, and 0x02 completed with errors.
+    pub status: u8,
+    pub segments_processed: u16,
+    /// Units of `transfer_count`, as a power of 2 in bytes (0 for
+    /// bytes, 9 for 512-byte blocks, and so on).
+    pub transfer_count_units: u8,
+    pub transfer_count: u32,
+}
+
+fn parse_copy_status(buf: &[u8]) -> CopyStatus {
+    CopyStatus {
+        held_data_discarded: buf[4] & 0x80 != 0,
+        status: buf[4] & 0x7f,
+        segments_processed: BigEndian::read_u16(&buf[5..7]),
+        transfer_count_units: buf[7],
+        transfer_count: BigEndian::read_u32(&buf[8..12]),
+    }
+}
+
+// Send SCSI RECEIVE COPY RESULTS with the COPY STATUS service action
+// to the SCSI device at the given path, returning the progress of the
+// copy with the given list identifier.
+pub fn copy_status<T: SgTransport + ?Sized>(path: &T, list_id: u8) -> Sg3Result<CopyStatus> {
+    let mut buf = [0u8; 12];
+    receive_copy_results_cmd(path, 0x00, u32::from(list_id), &mut buf)?;
+    Ok(parse_copy_status(&buf))
+}
+
+/// Why a copy failed, from RECEIVE COPY RESULTS with the FAILED
+/// SEGMENT DETAILS service action.
+#[derive(Debug)]
+pub struct CopyFailedSegment {
+    /// The status the EXTENDED COPY command completed with.
+    pub status: u8,
+    pub sense: Option<Sense>,
+}
+
+fn parse_copy_failed_segment(buf: &[u8]) -> CopyFailedSegment {
+    let end = buf
+        .len()
+        .min(60 + BigEndian::read_u16(&buf[58..60]) as usize);
+
+    CopyFailedSegment {
+        status: buf[56],
+        sense: Sense::from_buf(&buf[60..end]),
+    }
+}
+
+// Send SCSI RECEIVE COPY RESULTS with the FAILED SEGMENT DETAILS
+// service action to the SCSI device at the given path, returning the
+// status and sense data of the failed copy with the given list
+// identifier.
+pub fn copy_failed_segment<T: SgTransport + ?Sized>(
+    path: &T,
+    list_id: u8,
+) -> Sg3Result<CopyFailedSegment> {
+    let mut buf = [0u8; 60 + 252];
+    receive_copy_results_cmd(path, 0x04, u32::from(list_id), &mut buf)?;
+    Ok(parse_copy_failed_segment(&buf))
+}
+
+/// A representation of data (ROD) token, naming a point-in-time copy
+/// of a set of LBA ranges that WRITE USING TOKEN can copy from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RodToken {
+    buf: Vec<u8>,
+}
+
+const ROD_TOKEN_LEN: usize = 512;
+
+impl RodToken {
+    pub fn from_buf(buf: &[u8]) -> Option<RodToken> {
+        if buf.len() == ROD_TOKEN_LEN {
+            Some(RodToken { buf: buf.to_vec() })
+        } else {
+            None
+        }
+    }
+
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+// Fill in a block device range descriptor list, each range being a
+// starting LBA and a number of blocks.
+fn write_range_descriptors(buf: &mut [u8], ranges: &[(u64, u32)]) {
+    for (desc, &(lba, blocks)) in buf.chunks_exact_mut(16).zip(ranges) {
+        BigEndian::write_u64(&mut desc[0..8], lba);
+        BigEndian::write_u32(&mut desc[8..12], blocks);
+    }
+}
+
+fn populate_token_params(ranges: &[(u64, u32)]) -> Sg3Result<Vec<u8>> {
+    if ranges.is_empty() || 16 + ranges.len() * 16 > 0xffff {
+        return Err(invalid_input("bad number of token ranges"));
+    }
+
+    let mut buf = vec![0u8; 16 + ranges.len() * 16];
+    BigEndian::write_u16(&mut buf[0..2], (14 + ranges.len() * 16) as u16);
+    BigEndian::write_u16(&mut buf[14..16], (ranges.len() * 16) as u16);
+    write_range_descriptors(&mut buf[16..], ranges);
+
+    Ok(buf)
+}
+
+fn write_using_token_params(
+    token: &RodToken,
+    offset: u64,
+    ranges: &[(u64, u32)],
+) -> Sg3Result<Vec<u8>> {
+    if ranges.is_empty() || 536 + ranges.len() * 16 > 0xffff {
+        return Err(invalid_input("bad number of token ranges"));
+    }
+
+    let mut buf = vec![0u8; 536 + ranges.len() * 16];
+    BigEndian::write_u16(&mut buf[0..2], (534 + ranges.len() * 16) as u16);
+    BigEndian::write_u64(&mut buf[8..16], offset);
+    buf[16..16 + ROD_TOKEN_LEN].copy_from_slice(token.as_buf());
+    BigEndian::write_u16(&mut buf[534..536], (ranges.len() * 16) as u16);
+    write_range_descriptors(&mut buf[536..], ranges);
+
+    Ok(buf)
+}
+
+fn third_party_copy_out_cdb(service_action: u8, list_id: u32, len: usize) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x83;
+    cmd[1] = service_action;
+    BigEndian::write_u32(&mut cmd[6..10], list_id);
+    BigEndian::write_u32(&mut cmd[10..14], len as u32);
+
+    cmd
+}
+
+// Send SCSI POPULATE TOKEN to the SCSI device at the given path,
+// asking it to create a ROD token for the given (LBA, blocks) ranges.
+// Fetch the token afterwards with `receive_rod_token_information()`
+// using the same list identifier.
+pub fn populate_token<T: SgTransport + ?Sized>(
+    path: &T,
+    list_id: u32,
+    ranges: &[(u64, u32)],
+) -> Sg3Result<()> {
+    let params = populate_token_params(ranges)?;
+    let cmd = third_party_copy_out_cdb(0x10, list_id, params.len());
+    sg_io(path, &cmd, DataDirection::ToDevice(&params))
+}
+
+// Send SCSI WRITE USING TOKEN to the SCSI device at the given path,
+// copying the data named by `token`, starting `offset` blocks into it,
+// to the given (LBA, blocks) ranges.
+pub fn write_using_token<T: SgTransport + ?Sized>(
+    path: &T,
+    list_id: u32,
+    token: &RodToken,
+    offset: u64,
+    ranges: &[(u64, u32)],
+) -> Sg3Result<()> {
+    let params = write_using_token_params(token, offset, ranges)?;
+    let cmd = third_party_copy_out_cdb(0x11, list_id, params.len());
+    sg_io(path, &cmd, DataDirection::ToDevice(&params))
+}
+
+/// The response to RECEIVE ROD TOKEN INFORMATION.
+#[derive(Debug)]
+pub struct RodTokenInformation {
+    /// The COPY OPERATION STATUS field: 0x01 means completed
+    /// successfully, 0x02 completed with errors, and 0x10-0x12 still
+    /// in progress.
+    pub copy_status: u8,
+    pub operation_counter: u16,
+    /// Suggested delay before asking again, in seconds.
+    pub estimated_delay: u32,
+    pub transfer_count_units: u8,
+    pub transfer_count: u64,
+    pub segments_processed: u16,
+    pub sense: Option<Sense>,
+    /// The token created by POPULATE TOKEN, if any.
+    pub token: Option<RodToken>,
+}
+
+fn parse_rod_token_information(buf: &[u8]) -> RodTokenInformation {
+    let end = buf.len().min(4 + BigEndian::read_u32(&buf[0..4]) as usize);
+    let sense_end = end.min(32 + buf[14] as usize);
+    let token_offset = 32 + buf[13] as usize;
+
+    let token = if token_offset + 6 + ROD_TOKEN_LEN <= end
+        && BigEndian::read_u32(&buf[token_offset..token_offset + 4]) as usize >= ROD_TOKEN_LEN
+    {
+        RodToken::from_buf(&buf[token_offset + 6..token_offset + 6 + ROD_TOKEN_LEN])
+    } else {
+        None
+    };
+
+    RodTokenInformation {
+        copy_status: buf[5],
+        operation_counter: BigEndian::read_u16(&buf[6..8]),
+        estimated_delay: BigEndian::read_u32(&buf[8..12]),
+        transfer_count_units: buf[15],
+        transfer_count: BigEndian::read_u64(&buf[16..24]),
+        segments_processed: BigEndian::read_u16(&buf[24..26]),
+        sense: Sense::from_buf(&buf[32..sense_end.max(32)]),
+        token,
+    }
+}
+
+// Send SCSI RECEIVE ROD TOKEN INFORMATION to the SCSI device at the
+// given path, returning the status of the POPULATE TOKEN or WRITE USING
+// TOKEN with the given list identifier, and any token it created.
+pub fn receive_rod_token_information<T: SgTransport + ?Sized>(
+    path: &T,
+    list_id: u32,
+) -> Sg3Result<RodTokenInformation> {
+    let mut buf = vec![0u8; 32 + 252 + 6 + ROD_TOKEN_LEN];
+    receive_copy_results_cmd(path, 0x07, list_id, &mut buf)?;
+    Ok(parse_rod_token_information(&buf))
+}
+
+// Create a ROD token for the given (LBA, blocks) ranges of the SCSI
+// device at the given path, with POPULATE TOKEN followed by RECEIVE ROD
+// TOKEN INFORMATION.
+pub fn get_rod_token<T: SgTransport + ?Sized>(
+    path: &T,
+    list_id: u32,
+    ranges: &[(u64, u32)],
+) -> Sg3Result<RodToken> {
+    populate_token(path, list_id, ranges)?;
+    receive_rod_token_information(path, list_id)?
+        .token
+        .ok_or_else(|| invalid_data("device returned no ROD token"))
+}
+
+pub struct InquiryVpd8F {
+    buf: Vec<u8>,
+}
+
+/// Struct containing the Third-party Copy VPD page, with accessors for
+/// the parts of its descriptors used to plan offloaded copies.
+impl InquiryVpd8F {
+    pub fn from_buf(buf: &[u8]) -> InquiryVpd8F {
+        InquiryVpd8F { buf: buf.to_vec() }
+    }
+
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Find the third-party copy descriptor of the given type,
+    /// including its four-byte header.
+    pub fn descriptor(&self, desc_type: u16) -> Option<&[u8]> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+        let end = self
+            .buf
+            .len()
+            .min(4 + BigEndian::read_u16(&self.buf[2..4]) as usize);
+        let mut offset = 4;
+        while offset + 4 <= end {
+            let len = 4 + BigEndian::read_u16(&self.buf[offset + 2..offset + 4]) as usize;
+            if offset + len > end {
+                break;
+            }
+            if BigEndian::read_u16(&self.buf[offset..offset + 2]) == desc_type {
+                return Some(&self.buf[offset..offset + len]);
+            }
+            offset += len;
+        }
+        None
+    }
+
+    /// Whether the Supported Commands descriptor lists the given
+    /// third-party copy command and service action.
+    pub fn supports_command(&self, opcode: u8, service_action: u8) -> bool {
+        let desc = match self.descriptor(0x0001) {
+            Some(desc) if desc.len() > 4 => desc,
+            _ => return false,
+        };
+        let end = desc.len().min(5 + desc[4] as usize);
+        let mut offset = 5;
+        while offset + 2 <= end {
+            let len = desc[offset + 1] as usize;
+            let actions = &desc[(offset + 2).min(end)..(offset + 2 + len).min(end)];
+            if desc[offset] == opcode && actions.contains(&service_action) {
+                return true;
+            }
+            offset += 2 + len;
+        }
+        false
+    }
+
+    /// Largest number of blocks a ROD token may represent, from the
+    /// Block Device ROD Token Limits descriptor. Zero means no limit
+    /// reported.
+    pub fn max_token_transfer_size(&self) -> u64 {
+        match self.descriptor(0x0000) {
+            Some(desc) if desc.len() >= 28 => BigEndian::read_u64(&desc[20..28]),
+            _ => 0,
+        }
+    }
+
+    /// Largest number of bytes an EXTENDED COPY segment may copy, from
+    /// the General Copy Operations descriptor. Zero means no limit
+    /// reported.
+    pub fn max_segment_length(&self) -> u32 {
+        match self.descriptor(0x8001) {
+            Some(desc) if desc.len() >= 16 => BigEndian::read_u32(&desc[12..16]),
+            _ => 0,
+        }
+    }
+}
+
+// Send SCSI INQUIRY for VPD 8F (Third-party Copy) to the SCSI device
+// at the given path.
+pub fn inquiry_vpd_8f<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<InquiryVpd8F> {
+    Ok(InquiryVpd8F::from_buf(&inquiry_vpd_page(path, 0x8f)?))
+}
+
+// Find the NAA logical unit designation descriptor (header included)
+// of the SCSI device at the given path, which EXTENDED COPY uses to
+// name it.
+fn naa_designation_descriptor<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<u8>> {
+    let buf = inquiry_vpd_page(path, 0x83)?;
+
+    let end = buf.len().min(4 + BigEndian::read_u16(&buf[2..4]) as usize);
+    let mut offset = 4;
+    while offset + 4 <= end {
+        let len = 4 + buf[offset + 3] as usize;
+        if offset + len > end {
+            break;
+        }
+        // association 0 (logical unit), designator type 3 (NAA)
+        if buf[offset + 1] & 0x3f == 0x03 && len <= 20 {
+            return Ok(buf[offset..offset + len].to_vec());
+        }
+        offset += len;
+    }
+
+    Err(invalid_input(
+        "device has no NAA designator for EXTENDED COPY",
+    ))
+}
+
+// Build an identification CSCD descriptor naming a block device by its
+// designation descriptor.
+fn xcopy_identification_descriptor(designation: &[u8], block_size: u32) -> [u8; 32] {
+    let mut desc = [0u8; 32];
+
+    desc[0] = 0xe4;
+    desc[4..4 + designation.len()].copy_from_slice(designation);
+    BigEndian::write_u24(&mut desc[29..32], block_size);
+
+    desc
+}
+
+// Build EXTENDED COPY(LID1) parameters copying `blocks` blocks from the
+// first CSCD descriptor to the second, with no list identifier.
+fn xcopy_params(
+    src: &[u8; 32],
+    dst: &[u8; 32],
+    src_lba: u64,
+    dst_lba: u64,
+    blocks: u16,
+) -> Vec<u8> {
+    let mut buf = vec![0u8; 16 + 64 + 28];
+
+    buf[1] = 0x18;
+    BigEndian::write_u16(&mut buf[2..4], 64);
+    BigEndian::write_u32(&mut buf[8..12], 28);
+    buf[16..48].copy_from_slice(src);
+    buf[48..80].copy_from_slice(dst);
+
+    // block device to block device segment descriptor
+    let seg = &mut buf[80..];
+    seg[0] = 0x02;
+    BigEndian::write_u16(&mut seg[2..4], 0x18);
+    BigEndian::write_u16(&mut seg[4..6], 0);
+    BigEndian::write_u16(&mut seg[6..8], 1);
+    BigEndian::write_u16(&mut seg[10..12], blocks);
+    BigEndian::write_u64(&mut seg[12..20], src_lba);
+    BigEndian::write_u64(&mut seg[20..28], dst_lba);
+
+    buf
+}
+
+fn extended_copy<T: SgTransport + ?Sized>(path: &T, params: &[u8]) -> Sg3Result<()> {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x83;
+    cmd[1] = 0x00;
+    BigEndian::write_u32(&mut cmd[10..14], params.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::ToDevice(params))
+}
+
+// Split `blocks` blocks into pieces of at most `max` blocks, as
+// (offset, length) pairs.
+fn copy_chunks(blocks: u64, max: u64) -> Vec<(u64, u64)> {
+    let max = max.max(1);
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < blocks {
+        let len = (blocks - offset).min(max);
+        chunks.push((offset, len));
+        offset += len;
+    }
+    chunks
+}
+
+fn third_party_copy_vpd<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Option<InquiryVpd8F>> {
+    match inquiry_vpd_8f(path) {
+        Ok(vpd) => Ok(Some(vpd)),
+        Err(Sg3Error::Scsi { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Bytes to move per READ/WRITE when a copy cannot be offloaded.
+const FALLBACK_COPY_BYTES: u64 = 1024 * 1024;
+
+// Copy `blocks` blocks starting at `src_lba` on the SCSI device at
+// `src` to `dst_lba` on the SCSI device at `dst`, offloading the copy
+// to the storage where possible. Token copy (POPULATE TOKEN and WRITE
+// USING TOKEN) is preferred, then EXTENDED COPY, as reported by each
+// device's Third-party Copy VPD page; copies are split to respect the
+// reported limits. If neither is supported, the data is read and
+// written through the host instead.
+pub fn offload_copy<S: SgTransport + ?Sized, D: SgTransport + ?Sized>(
+    src: &S,
+    src_lba: Lba,
+    dst: &D,
+    dst_lba: Lba,
+    blocks: BlockCount,
+) -> Sg3Result<()> {
+    let (src_lba, dst_lba, blocks) = (src_lba.0, dst_lba.0, blocks.0);
+    let block_size = read_capacity16(src)?.block_size.0;
+    if block_size == 0 {
+        return Err(invalid_data("device reports a block size of zero"));
+    }
+    if read_capacity16(dst)?.block_size.0 != block_size {
+        return Err(invalid_input("devices have different block sizes"));
+    }
+
+    let src_vpd = third_party_copy_vpd(src)?;
+    let dst_vpd = third_party_copy_vpd(dst)?;
+    let supports = |vpd: &Option<InquiryVpd8F>, sa| {
+        vpd.as_ref()
+            .map(|v| v.supports_command(0x83, sa))
+            .unwrap_or(false)
+    };
+
+    if supports(&src_vpd, 0x10) && supports(&dst_vpd, 0x11) {
+        let list_id = std::process::id();
+        let mut max = u64::from(u32::MAX);
+        for vpd in [&src_vpd, &dst_vpd].iter().filter_map(|v| v.as_ref()) {
+            if vpd.max_token_transfer_size() != 0 {
+                max = max.min(vpd.max_token_transfer_size());
+            }
+        }
+        for (offset, len) in copy_chunks(blocks, max) {
+            let token = get_rod_token(src, list_id, &[(src_lba + offset, len as u32)])?;
+            write_using_token(dst, list_id, &token, 0, &[(dst_lba + offset, len as u32)])?;
+        }
+        return Ok(());
+    }
+
+    if supports(&src_vpd, 0x00) {
+        let src_desc =
+            xcopy_identification_descriptor(&naa_designation_descriptor(src)?, block_size);
+        let dst_desc =
+            xcopy_identification_descriptor(&naa_designation_descriptor(dst)?, block_size);
+        let mut max = 0xffff;
+        if let Some(ref vpd) = src_vpd {
+            if vpd.max_segment_length() != 0 {
+                max = max.min(u64::from(vpd.max_segment_length() / block_size));
+            }
+        }
+        for (offset, len) in copy_chunks(blocks, max) {
+            let params = xcopy_params(
+                &src_desc,
+                &dst_desc,
+                src_lba + offset,
+                dst_lba + offset,
+                len as u16,
+            );
+            extended_copy(src, &params)?;
+        }
+        return Ok(());
+    }
+
+    let mut max = FALLBACK_COPY_BYTES / u64::from(block_size);
+    for limits in [inquiry_vpd_b0(src), inquiry_vpd_b0(dst)].iter().flatten() {
+        if limits.max_transfer_length() != 0 {
+            max = max.min(u64::from(limits.max_transfer_length()));
+        }
+    }
+    let mut buf = vec![0u8; (max.max(1) * u64::from(block_size)) as usize];
+    for (offset, len) in copy_chunks(blocks, max) {
+        let data = &mut buf[..(len * u64::from(block_size)) as usize];
+        read16(src, Lba(src_lba + offset), BlockCount(len), data, false, 0)?;
+        write16(dst, Lba(dst_lba + offset), BlockCount(len), data, false, 0)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::SenseKey;
+
+    #[test]
+    fn test_parse_copy_operating_parameters() {
+        let mut buf = [0u8; 300];
+        BigEndian::write_u32(&mut buf[0..4], 42);
+        buf[4] = 0x01;
+        BigEndian::write_u16(&mut buf[8..10], 2);
+        BigEndian::write_u16(&mut buf[10..12], 1);
+        BigEndian::write_u32(&mut buf[16..20], 0x100_0000);
+        buf[37] = 9;
+        buf[43] = 2;
+        buf[44] = 0x02;
+        buf[45] = 0xe4;
+        let params = parse_copy_operating_parameters(&buf);
+        assert!(params.supports_list_id);
+        assert_eq!(params.max_target_descriptors, 2);
+        assert_eq!(params.max_segment_descriptors, 1);
+        assert_eq!(params.max_segment_length, 0x100_0000);
+        assert_eq!(params.data_segment_granularity, 9);
+        assert_eq!(params.descriptor_types, vec![0x02, 0xe4]);
+    }
+
+    #[test]
+    fn test_parse_copy_status() {
+        let buf = [0, 0, 0, 8, 0x01, 0, 3, 9, 0, 0, 0x10, 0];
+        let status = parse_copy_status(&buf);
+        assert_eq!(status.status, 1);
+        assert_eq!(status.segments_processed, 3);
+        assert_eq!(status.transfer_count, 0x1000);
+    }
+
+    #[test]
+    fn test_token_params() {
+        let params = populate_token_params(&[(0x1000, 8)]).unwrap();
+        assert_eq!(params.len(), 32);
+        assert_eq!(&params[..4], &[0, 30, 0, 0]);
+        assert_eq!(&params[14..16], &[0, 16]);
+        assert_eq!(&params[16..28], &[0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 8]);
+        assert!(populate_token_params(&[]).is_err());
+
+        let token = RodToken::from_buf(&[0xab; 512]).unwrap();
+        let params = write_using_token_params(&token, 4, &[(0x2000, 8)]).unwrap();
+        assert_eq!(params.len(), 552);
+        assert_eq!(&params[..2], &[0x02, 0x26]);
+        assert_eq!(params[15], 4);
+        assert_eq!(params[16], 0xab);
+        assert_eq!(params[527], 0xab);
+        assert_eq!(&params[534..536], &[0, 16]);
+        assert_eq!(params[542], 0x20);
+    }
+
+    #[test]
+    fn test_parse_rod_token_information() {
+        let mut buf = vec![0u8; 32 + 6 + 512];
+        BigEndian::write_u32(&mut buf[0..4], 28 + 6 + 512);
+        buf[4] = 0x10;
+        buf[5] = 0x01;
+        BigEndian::write_u32(&mut buf[32..36], 514);
+        for b in &mut buf[38..] {
+            *b = 0x5a;
+        }
+        let info = parse_rod_token_information(&buf);
+        assert_eq!(info.copy_status, 0x01);
+        assert!(info.sense.is_none());
+        assert_eq!(info.token.unwrap().as_buf(), &[0x5a; 512][..]);
+    }
+
+    #[test]
+    fn test_inquiry_vpd_8f() {
+        let mut buf = vec![0x00, 0x8f, 0, 0];
+        // supported commands: POPULATE TOKEN and WRITE USING TOKEN
+        buf.extend_from_slice(&[0x00, 0x01, 0, 5, 4, 0x83, 2, 0x10, 0x11]);
+        // block device ROD token limits
+        let mut limits = vec![0u8; 32];
+        limits[3] = 28;
+        BigEndian::write_u64(&mut limits[20..28], 0x8000);
+        buf.extend_from_slice(&limits);
+        let len = buf.len() as u16 - 4;
+        BigEndian::write_u16(&mut buf[2..4], len);
+
+        let vpd = InquiryVpd8F::from_buf(&buf);
+        assert!(vpd.supports_command(0x83, 0x10));
+        assert!(vpd.supports_command(0x83, 0x11));
+        assert!(!vpd.supports_command(0x83, 0x00));
+        assert_eq!(vpd.max_token_transfer_size(), 0x8000);
+        assert_eq!(vpd.max_segment_length(), 0);
+    }
+
+    #[test]
+    fn test_xcopy_params() {
+        let designation = [0x01, 0x03, 0, 8, 0x60, 0, 0, 0, 0, 0, 0, 1];
+        let desc = xcopy_identification_descriptor(&designation, 512);
+        assert_eq!(desc[0], 0xe4);
+        assert_eq!(&desc[4..16], &designation);
+        assert_eq!(&desc[29..32], &[0, 0x02, 0]);
+
+        let params = xcopy_params(&desc, &desc, 0x100, 0x200, 16);
+        assert_eq!(params.len(), 108);
+        assert_eq!(&params[..12], &[0, 0x18, 0, 64, 0, 0, 0, 0, 0, 0, 0, 28]);
+        assert_eq!(&params[80..84], &[0x02, 0, 0, 0x18]);
+        assert_eq!(&params[86..88], &[0, 1]);
+        assert_eq!(&params[90..92], &[0, 16]);
+        assert_eq!(params[98], 0x01);
+        assert_eq!(params[106], 0x02);
+    }
+
+    #[test]
+    fn test_copy_chunks() {
+        assert_eq!(copy_chunks(10, 4), vec![(0, 4), (4, 4), (8, 2)]);
+        assert_eq!(copy_chunks(0, 4), vec![]);
+    }
+
+    #[test]
+    fn test_parse_copy_failed_segment() {
+        let mut buf = [0u8; 60 + 252];
+        buf[56] = 0x02;
+        buf[59] = 18;
+        buf[60] = 0x70;
+        buf[62] = 0x0a;
+        buf[67] = 10;
+        let failed = parse_copy_failed_segment(&buf);
+        assert_eq!(failed.status, 0x02);
+        assert_eq!(failed.sense.unwrap().sense_key(), SenseKey::CopyAborted);
+    }
+}
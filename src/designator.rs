@@ -0,0 +1,604 @@
+//! Structured forms of the designators in the Device Identification VPD
+//! page (0x83), and the WWID udev derives from them.
+
+use std::fmt;
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    inquiry, inquiry_vpd_80, inquiry_vpd_83, invalid_data, invalid_input, Association,
+    DesignationDescriptor, Designator, DesignatorType, InquiryVpd80, InquiryVpd83, Sg3Error,
+    Sg3Result, SgTransport, StdInquiry,
+};
+
+// Lowercase hex, two digits per byte.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The bytes of a string of hex digits, of either case.
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The NAA field of an NAA designator, which gives its format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NaaType {
+    /// NAA 2: an IEEE company ID and 36 vendor-specific bits.
+    IeeeExtended,
+    /// NAA 3: a 60-bit locally assigned value.
+    LocallyAssigned,
+    /// NAA 5: an IEEE company ID and 36 vendor-specific bits.
+    IeeeRegistered,
+    /// NAA 6: NAA 5, followed by a 64-bit vendor-specific extension.
+    IeeeRegisteredExtended,
+}
+
+/// An NAA designator, such as the World Wide Name of a logical unit.
+///
+/// It displays as lowercase hex, the form used after `naa.` in SCSI
+/// name strings and in /dev/disk/by-id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NaaDesignator {
+    buf: Vec<u8>,
+}
+
+impl NaaDesignator {
+    /// Parse the designator field of an NAA designation descriptor,
+    /// returning None if its NAA type isn't one of the four defined,
+    /// or it is the wrong length for its type.
+    pub fn from_buf(buf: &[u8]) -> Option<NaaDesignator> {
+        let len = match buf.first()? >> 4 {
+            2 | 3 | 5 => 8,
+            6 => 16,
+            _ => return None,
+        };
+        if buf.len() != len {
+            return None;
+        }
+        Some(NaaDesignator { buf: buf.to_vec() })
+    }
+
+    /// Get the raw designator.
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn naa_type(&self) -> NaaType {
+        match self.buf[0] >> 4 {
+            2 => NaaType::IeeeExtended,
+            3 => NaaType::LocallyAssigned,
+            5 => NaaType::IeeeRegistered,
+            // checked by from_buf()
+            _ => NaaType::IeeeRegisteredExtended,
+        }
+    }
+
+    /// The 24-bit IEEE company ID (OUI) of the assigning organization,
+    /// which a locally assigned designator has none of.
+    pub fn company_id(&self) -> Option<u32> {
+        match self.naa_type() {
+            NaaType::IeeeExtended => Some(BigEndian::read_u24(&self.buf[2..5])),
+            NaaType::LocallyAssigned => None,
+            _ => Some((BigEndian::read_u32(&self.buf[0..4]) >> 4) & 0xff_ffff),
+        }
+    }
+
+    /// The vendor-specific identifier. For NAA 2 this joins vendor
+    /// specific identifiers A and B, and for NAA 3 it is the whole
+    /// locally administered value.
+    pub fn vendor_specific_id(&self) -> u64 {
+        let id = BigEndian::read_u64(&self.buf[0..8]);
+        match self.naa_type() {
+            NaaType::IeeeExtended => (((id >> 48) & 0xfff) << 24) | (id & 0xff_ffff),
+            NaaType::LocallyAssigned => id & 0x0fff_ffff_ffff_ffff,
+            _ => id & 0xf_ffff_ffff,
+        }
+    }
+
+    /// The vendor-specific identifier extension of an NAA 6 designator.
+    pub fn vendor_specific_id_extension(&self) -> Option<u64> {
+        match self.naa_type() {
+            NaaType::IeeeRegisteredExtended => Some(BigEndian::read_u64(&self.buf[8..16])),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for NaaDesignator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", to_hex(&self.buf))
+    }
+}
+
+/// Parses the lowercase hex form, or an uppercase one.
+impl FromStr for NaaDesignator {
+    type Err = Sg3Error;
+
+    fn from_str(s: &str) -> Sg3Result<NaaDesignator> {
+        from_hex(s)
+            .as_deref()
+            .and_then(NaaDesignator::from_buf)
+            .ok_or_else(|| invalid_input("not an NAA designator"))
+    }
+}
+
+/// Serialized as its hex form.
+#[cfg(feature = "serde")]
+impl serde::Serialize for NaaDesignator {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for NaaDesignator {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An EUI-64 based designator, in its 8, 12 or 16 byte form.
+///
+/// It displays as lowercase hex, the form used after `eui.` in SCSI
+/// name strings.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Eui64Designator {
+    buf: Vec<u8>,
+}
+
+impl Eui64Designator {
+    /// Parse the designator field of an EUI-64 designation descriptor,
+    /// returning None if it isn't one of the three lengths defined.
+    pub fn from_buf(buf: &[u8]) -> Option<Eui64Designator> {
+        match buf.len() {
+            8 | 12 | 16 => Some(Eui64Designator { buf: buf.to_vec() }),
+            _ => None,
+        }
+    }
+
+    /// Get the raw designator.
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    // The EUI-64 itself, which the 16-byte form puts after the
+    // identifier extension.
+    fn eui64(&self) -> &[u8] {
+        match self.buf.len() {
+            16 => &self.buf[8..16],
+            _ => &self.buf[0..8],
+        }
+    }
+
+    /// The 24-bit IEEE company ID (OUI).
+    pub fn company_id(&self) -> u32 {
+        BigEndian::read_u24(&self.eui64()[0..3])
+    }
+
+    /// The 40-bit vendor specific extension identifier.
+    pub fn vendor_specific_extension_id(&self) -> u64 {
+        BigEndian::read_uint(&self.eui64()[3..8], 5)
+    }
+
+    /// The directory ID of the 12-byte form.
+    pub fn directory_id(&self) -> Option<u32> {
+        match self.buf.len() {
+            12 => Some(BigEndian::read_u32(&self.buf[8..12])),
+            _ => None,
+        }
+    }
+
+    /// The identifier extension of the 16-byte form.
+    pub fn identifier_extension(&self) -> Option<u64> {
+        match self.buf.len() {
+            16 => Some(BigEndian::read_u64(&self.buf[0..8])),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Eui64Designator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", to_hex(&self.buf))
+    }
+}
+
+/// Parses the lowercase hex form, or an uppercase one.
+impl FromStr for Eui64Designator {
+    type Err = Sg3Error;
+
+    fn from_str(s: &str) -> Sg3Result<Eui64Designator> {
+        from_hex(s)
+            .as_deref()
+            .and_then(Eui64Designator::from_buf)
+            .ok_or_else(|| invalid_input("not an EUI-64 designator"))
+    }
+}
+
+/// Serialized as its hex form.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Eui64Designator {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Eui64Designator {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The port part of an iSCSI port name, after the iSCSI name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IscsiPort {
+    /// `,t,0x` and the target portal group tag.
+    Target(u16),
+    /// `,i,0x` and the 48-bit initiator session identifier (ISID).
+    Initiator(u64),
+}
+
+/// A SCSI name string designator, by the kind of name it holds.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScsiName {
+    /// An iSCSI qualified name, such as
+    /// `iqn.2003-01.org.linux-iscsi.host:target`, with the port it
+    /// names, if any.
+    Iqn {
+        name: String,
+        port: Option<IscsiPort>,
+    },
+    /// `naa.` and an NAA designator.
+    Naa(NaaDesignator),
+    /// `eui.` and an EUI-64 based designator.
+    Eui64(Eui64Designator),
+}
+
+// Split the port suffix from an iSCSI port name.
+fn parse_iscsi_port(s: &str) -> Option<(&str, Option<IscsiPort>)> {
+    let (name, port) = match s.find(',') {
+        Some(idx) => (&s[..idx], &s[idx..]),
+        None => return Some((s, None)),
+    };
+    if let Some(tag) = port.strip_prefix(",t,0x") {
+        if tag.is_empty() || tag.len() > 4 {
+            return None;
+        }
+        let tag = u16::from_str_radix(tag, 16).ok()?;
+        Some((name, Some(IscsiPort::Target(tag))))
+    } else if let Some(isid) = port.strip_prefix(",i,0x") {
+        if isid.len() != 12 {
+            return None;
+        }
+        let isid = u64::from_str_radix(isid, 16).ok()?;
+        Some((name, Some(IscsiPort::Initiator(isid))))
+    } else {
+        None
+    }
+}
+
+// Whether `s` is an iSCSI qualified name: `iqn.`, a yyyy-mm date, then
+// a dot and the naming authority.
+fn is_iqn(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() > 12
+        && b.starts_with(b"iqn.")
+        && b[4..8].iter().all(u8::is_ascii_digit)
+        && b[8] == b'-'
+        && b[9..11].iter().all(u8::is_ascii_digit)
+        && b[11] == b'.'
+        && !s.contains(char::is_whitespace)
+}
+
+impl ScsiName {
+    /// Parse the designator field of a SCSI name string designation
+    /// descriptor, which is null terminated and padded.
+    pub fn from_buf(buf: &[u8]) -> Option<ScsiName> {
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        std::str::from_utf8(&buf[..len]).ok()?.parse().ok()
+    }
+
+    /// The iSCSI name, without any port suffix, if this is one.
+    pub fn iscsi_name(&self) -> Option<&str> {
+        match self {
+            ScsiName::Iqn { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ScsiName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScsiName::Iqn { name, port: None } => write!(f, "{}", name),
+            ScsiName::Iqn {
+                name,
+                port: Some(IscsiPort::Target(tag)),
+            } => write!(f, "{},t,0x{:04x}", name, tag),
+            ScsiName::Iqn {
+                name,
+                port: Some(IscsiPort::Initiator(isid)),
+            } => write!(f, "{},i,0x{:012x}", name, isid),
+            ScsiName::Naa(naa) => write!(f, "naa.{}", naa),
+            ScsiName::Eui64(eui) => write!(f, "eui.{}", eui),
+        }
+    }
+}
+
+/// Parses a name beginning `iqn.`, `naa.` or `eui.`, as SPC-4 defines
+/// them.
+impl FromStr for ScsiName {
+    type Err = Sg3Error;
+
+    fn from_str(s: &str) -> Sg3Result<ScsiName> {
+        if let Some(hex) = s.strip_prefix("naa.") {
+            return Ok(ScsiName::Naa(hex.parse()?));
+        }
+        if let Some(hex) = s.strip_prefix("eui.") {
+            return Ok(ScsiName::Eui64(hex.parse()?));
+        }
+        match parse_iscsi_port(s) {
+            Some((name, port)) if is_iqn(name) => Ok(ScsiName::Iqn {
+                name: name.to_string(),
+                port,
+            }),
+            _ => Err(invalid_input("not a SCSI name string")),
+        }
+    }
+}
+
+// How well a designator identifies a logical unit, lower being better:
+// NAA IEEE Registered Extended, IEEE Registered, IEEE Extended and
+// Locally Assigned, in that order, then EUI-64, as udev's scsi_id ranks
+// them. Other designators, and those of anything but the logical unit,
+// are left for the caller to rank after these.
+pub(crate) fn lu_designator_rank(desc: &DesignationDescriptor) -> Option<u8> {
+    if desc.association != Association::AddressedLogicalUnit {
+        return None;
+    }
+    match desc.designator {
+        Designator::Naa(ref naa) => Some(match naa.naa_type() {
+            NaaType::IeeeRegisteredExtended => 0,
+            NaaType::IeeeRegistered => 1,
+            NaaType::IeeeExtended => 2,
+            NaaType::LocallyAssigned => 3,
+        }),
+        Designator::Eui64(_) => Some(4),
+        _ => None,
+    }
+}
+
+// How udev's scsi_id ranks a designator for the WWID, lower being
+// preferred, and the WWID it gives: the designator type as a hex digit,
+// then the designator. Only logical unit designators are used, T10
+// vendor IDs after those `lu_designator_rank()` ranks.
+fn wwid_candidate(desc: &DesignationDescriptor) -> Option<(u8, String)> {
+    if desc.association != Association::AddressedLogicalUnit {
+        return None;
+    }
+    match desc.designator {
+        Designator::Naa(ref naa) => Some((lu_designator_rank(desc)?, format!("3{}", naa))),
+        Designator::Eui64(ref eui) => Some((lu_designator_rank(desc)?, format!("2{}", eui))),
+        Designator::String(ref s) if desc.designator_type == DesignatorType::T10VendorId => {
+            Some((5, format!("1{}", s)))
+        }
+        Designator::Binary(ref b) if desc.designator_type == DesignatorType::T10VendorId => {
+            Some((5, format!("1{}", to_hex(b))))
+        }
+        _ => None,
+    }
+}
+
+// Replace each run of whitespace with an underscore, dropping any at
+// either end, as udev does for ID_SERIAL.
+fn replace_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+// The WWID from whichever of the Device Identification and Unit Serial
+// Number pages the device returned, preferring the former. A serial
+// number is prefixed with "S" and the vendor and product.
+fn build_wwid(
+    inquiry: &StdInquiry,
+    vpd83: Option<&InquiryVpd83>,
+    vpd80: Option<&InquiryVpd80>,
+) -> Option<String> {
+    let designator = vpd83.and_then(|vpd| {
+        vpd.descriptors
+            .iter()
+            .filter_map(wwid_candidate)
+            .min_by_key(|&(rank, _)| rank)
+    });
+    if let Some((_, wwid)) = designator {
+        return Some(replace_whitespace(&wwid));
+    }
+
+    let serial = vpd80.map(|vpd| vpd.serial_number())?;
+    if serial.trim().is_empty() {
+        return None;
+    }
+    Some(replace_whitespace(&format!(
+        "S{}{}{}",
+        inquiry.vendor(),
+        inquiry.product_id(),
+        serial
+    )))
+}
+
+// Get the WWID of the SCSI device at the given path, as udev's scsi_id
+// and multipath derive it for ID_SERIAL and /dev/disk/by-id: preferring
+// an NAA designator, then EUI-64, then T10 vendor ID, then the serial
+// number. Devices lacking either VPD page are handled.
+pub fn wwid<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<String> {
+    let inquiry = inquiry(path)?;
+    let vpd83 = inquiry_vpd_83(path).ok();
+    let vpd80 = inquiry_vpd_80(path).ok();
+
+    build_wwid(&inquiry, vpd83.as_ref(), vpd80.as_ref())
+        .ok_or_else(|| invalid_data("device reports no identifier for a WWID"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeDevice;
+
+    #[test]
+    fn test_naa_designator() {
+        let naa =
+            NaaDesignator::from_buf(&[0x50, 0x00, 0xc5, 0x00, 0xa1, 0xb2, 0xc3, 0xd4]).unwrap();
+        assert_eq!(naa.naa_type(), NaaType::IeeeRegistered);
+        assert_eq!(naa.company_id(), Some(0x000c50));
+        assert_eq!(naa.vendor_specific_id(), 0x0_a1b2_c3d4);
+        assert_eq!(naa.vendor_specific_id_extension(), None);
+        assert_eq!(naa.to_string(), "5000c500a1b2c3d4");
+        assert_eq!("5000C500A1B2C3D4".parse::<NaaDesignator>().unwrap(), naa);
+
+        let mut buf = vec![0x60, 0x01, 0x40, 0x5a];
+        buf.extend_from_slice(&[0xbc; 12]);
+        let naa = NaaDesignator::from_buf(&buf).unwrap();
+        assert_eq!(naa.naa_type(), NaaType::IeeeRegisteredExtended);
+        assert_eq!(naa.company_id(), Some(0x001405));
+        assert_eq!(naa.vendor_specific_id(), 0xa_bcbc_bcbc);
+        assert_eq!(
+            naa.vendor_specific_id_extension(),
+            Some(0xbcbc_bcbc_bcbc_bcbc)
+        );
+
+        let naa =
+            NaaDesignator::from_buf(&[0x21, 0x23, 0x00, 0x10, 0x86, 0x0a, 0x0b, 0x0c]).unwrap();
+        assert_eq!(naa.naa_type(), NaaType::IeeeExtended);
+        assert_eq!(naa.company_id(), Some(0x001086));
+        assert_eq!(naa.vendor_specific_id(), 0x123_0a0b0c);
+
+        let naa =
+            NaaDesignator::from_buf(&[0x31, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]).unwrap();
+        assert_eq!(naa.company_id(), None);
+        assert_eq!(naa.vendor_specific_id(), 0x123_4567_89ab_cdef);
+
+        // NAA 6 cut short, and an undefined NAA type
+        assert!(NaaDesignator::from_buf(&buf[..8]).is_none());
+        assert!(NaaDesignator::from_buf(&[0x10; 8]).is_none());
+        assert!(NaaDesignator::from_buf(&[]).is_none());
+        assert!("5000c5".parse::<NaaDesignator>().is_err());
+    }
+
+    #[test]
+    fn test_eui64_designator() {
+        let eui =
+            Eui64Designator::from_buf(&[0x00, 0x25, 0x38, 0x01, 0x02, 0x03, 0x04, 0x05]).unwrap();
+        assert_eq!(eui.company_id(), 0x002538);
+        assert_eq!(eui.vendor_specific_extension_id(), 0x01_0203_0405);
+        assert_eq!(eui.directory_id(), None);
+        assert_eq!(eui.identifier_extension(), None);
+        assert_eq!(eui.to_string(), "0025380102030405");
+        assert_eq!("0025380102030405".parse::<Eui64Designator>().unwrap(), eui);
+
+        let mut buf = eui.as_buf().to_vec();
+        buf.extend_from_slice(&[0, 0, 0x12, 0x34]);
+        let eui = Eui64Designator::from_buf(&buf).unwrap();
+        assert_eq!(eui.company_id(), 0x002538);
+        assert_eq!(eui.directory_id(), Some(0x1234));
+
+        let mut buf = vec![0xaa; 8];
+        buf.extend_from_slice(&[0x00, 0x25, 0x38, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        let eui = Eui64Designator::from_buf(&buf).unwrap();
+        assert_eq!(eui.company_id(), 0x002538);
+        assert_eq!(eui.vendor_specific_extension_id(), 0x01_0203_0405);
+        assert_eq!(eui.identifier_extension(), Some(0xaaaa_aaaa_aaaa_aaaa));
+
+        assert!(Eui64Designator::from_buf(&[0; 10]).is_none());
+    }
+
+    #[test]
+    fn test_scsi_name() {
+        let name =
+            ScsiName::from_buf(b"iqn.2003-01.org.linux-iscsi.host:tgt,t,0x0001\0\0\0").unwrap();
+        assert_eq!(
+            name,
+            ScsiName::Iqn {
+                name: "iqn.2003-01.org.linux-iscsi.host:tgt".to_string(),
+                port: Some(IscsiPort::Target(1)),
+            }
+        );
+        assert_eq!(
+            name.iscsi_name(),
+            Some("iqn.2003-01.org.linux-iscsi.host:tgt")
+        );
+        assert_eq!(
+            name.to_string(),
+            "iqn.2003-01.org.linux-iscsi.host:tgt,t,0x0001"
+        );
+
+        let name: ScsiName = "iqn.1994-05.com.redhat:client,i,0x00023d000001"
+            .parse()
+            .unwrap();
+        match name {
+            ScsiName::Iqn { port, .. } => {
+                assert_eq!(port, Some(IscsiPort::Initiator(0x00023d000001)))
+            }
+            _ => panic!("not an iSCSI name"),
+        }
+
+        match ScsiName::from_buf(b"naa.5000C500A1B2C3D4\0\0\0\0").unwrap() {
+            ScsiName::Naa(naa) => assert_eq!(naa.company_id(), Some(0x000c50)),
+            _ => panic!("not an NAA name"),
+        }
+        let name: ScsiName = "eui.0025380102030405".parse().unwrap();
+        assert_eq!(name.to_string(), "eui.0025380102030405");
+        assert_eq!(name.iscsi_name(), None);
+
+        assert!("iqn.example".parse::<ScsiName>().is_err());
+        assert!("iqn.2003-01.org.example:tgt,x,1"
+            .parse::<ScsiName>()
+            .is_err());
+        assert!("naa.5000".parse::<ScsiName>().is_err());
+        assert!("something else".parse::<ScsiName>().is_err());
+        assert!(ScsiName::from_buf(b"\xffiqn.").is_none());
+    }
+
+    #[test]
+    fn test_wwid() {
+        let mut dev = FakeDevice::new("ATA", "Samsung SSD 860", "4B6Q");
+        assert!(wwid(&dev).is_err());
+        dev.set_vpd_page(0x80, b"  S3Z9NB0K123456A");
+        assert_eq!(wwid(&dev).unwrap(), "SATA_Samsung_SSD_860_S3Z9NB0K123456A");
+
+        let mut vpd83 = vec![0x02, 0x01, 0x00, 0x08];
+        vpd83.extend_from_slice(b"ATA  ABC");
+        dev.set_vpd_page(0x83, &vpd83);
+        assert_eq!(wwid(&dev).unwrap(), "1ATA_ABC");
+
+        vpd83.extend_from_slice(&[0x01, 0x02, 0x00, 0x08, 0x00, 0x25, 0x38, 1, 2, 3, 4, 5]);
+        dev.set_vpd_page(0x83, &vpd83);
+        assert_eq!(wwid(&dev).unwrap(), "20025380102030405");
+
+        // an NAA 6 target port designator is passed over for an NAA 5
+        // logical unit one, and that for an NAA 6 logical unit one
+        let mut naa6 = vec![0x01, 0x03, 0x00, 0x10, 0x60, 0x01, 0x40, 0x5a];
+        naa6.extend_from_slice(&[0xbc; 12]);
+        let mut port = naa6.clone();
+        port[1] = 0x13;
+        vpd83.extend_from_slice(&port);
+        vpd83.extend_from_slice(&[0x01, 0x03, 0x00, 0x08, 0x50, 0x00, 0xc5, 0, 1, 2, 3, 4]);
+        dev.set_vpd_page(0x83, &vpd83);
+        assert_eq!(wwid(&dev).unwrap(), "35000c50001020304");
+        vpd83.extend_from_slice(&naa6);
+        dev.set_vpd_page(0x83, &vpd83);
+        assert_eq!(wwid(&dev).unwrap(), "36001405abcbcbcbcbcbcbcbcbcbcbcbc");
+    }
+}
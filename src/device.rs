@@ -0,0 +1,300 @@
+//! Wrappers for tapes, medium changers and enclosures like `ScsiDisk`,
+//! each offering just the commands of its device type, and picking the
+//! right one for a device from its INQUIRY data.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::{
+    enclosure_slot_devices, exchange_medium, initialize_element_status, inquiry, load_unload,
+    locate, move_medium, read6, read_block_limits, read_element_status, read_position, rewind,
+    send_enclosure_control, ses_configuration, ses_enclosure, ses_enclosure_status, set_slot_fault,
+    set_slot_ident, space, write6, write_filemarks, BlockLimits, ChangerElement,
+    ChangerElementType, Enclosure, EnclosureControl, EnclosureStatus, LoadAction, LocateTarget,
+    PeripheralDeviceType, ScsiDisk, SesConfiguration, Sg3Result, SgTransport, SpaceCode,
+    TapePosition, TapeRead, TapeSpace,
+};
+
+/// A sequential-access (tape) device.
+#[derive(Debug)]
+pub struct ScsiTape<T: SgTransport> {
+    dev: T,
+    block_size: Option<u32>,
+}
+
+impl<T: SgTransport> ScsiTape<T> {
+    /// Wrap the device, reading and writing variable-length blocks until
+    /// `set_block_size()` says otherwise.
+    pub fn new(dev: T) -> ScsiTape<T> {
+        ScsiTape {
+            dev,
+            block_size: None,
+        }
+    }
+
+    /// The wrapped device.
+    pub fn get_ref(&self) -> &T {
+        &self.dev
+    }
+
+    /// Unwrap the device, discarding the block size.
+    pub fn into_inner(self) -> T {
+        self.dev
+    }
+
+    /// Read and write blocks of the given fixed size, which must be the
+    /// one the drive is set to, or variable-length blocks with None.
+    pub fn set_block_size(&mut self, block_size: Option<u32>) {
+        self.block_size = block_size;
+    }
+
+    pub fn block_size(&self) -> Option<u32> {
+        self.block_size
+    }
+
+    /// Read one variable-length block, or as many fixed-size blocks as
+    /// fill `buf`.
+    pub fn read(&self, buf: &mut [u8]) -> Sg3Result<TapeRead> {
+        read6(&self.dev, buf, self.block_size)
+    }
+
+    /// Write `buf` as one variable-length block, or as fixed-size blocks.
+    /// Returns true past the early warning point.
+    pub fn write(&self, buf: &[u8]) -> Sg3Result<bool> {
+        write6(&self.dev, buf, self.block_size)
+    }
+
+    pub fn write_filemarks(&self, count: u32) -> Sg3Result<()> {
+        write_filemarks(&self.dev, count, false)
+    }
+
+    pub fn rewind(&self) -> Sg3Result<()> {
+        rewind(&self.dev, false)
+    }
+
+    pub fn space(&self, code: SpaceCode, count: i64) -> Sg3Result<TapeSpace> {
+        space(&self.dev, code, count)
+    }
+
+    pub fn locate(&self, target: LocateTarget, partition: Option<u8>) -> Sg3Result<()> {
+        locate(&self.dev, target, partition, false)
+    }
+
+    pub fn position(&self) -> Sg3Result<TapePosition> {
+        read_position(&self.dev)
+    }
+
+    pub fn block_limits(&self) -> Sg3Result<BlockLimits> {
+        read_block_limits(&self.dev)
+    }
+
+    pub fn load_unload(&self, action: LoadAction) -> Sg3Result<()> {
+        load_unload(&self.dev, action, false)
+    }
+}
+
+/// A medium changer, such as the robot in a tape library.
+#[derive(Debug)]
+pub struct ScsiChanger<T: SgTransport> {
+    dev: T,
+}
+
+impl<T: SgTransport> ScsiChanger<T> {
+    pub fn new(dev: T) -> ScsiChanger<T> {
+        ScsiChanger { dev }
+    }
+
+    /// The wrapped device.
+    pub fn get_ref(&self) -> &T {
+        &self.dev
+    }
+
+    /// Unwrap the device.
+    pub fn into_inner(self) -> T {
+        self.dev
+    }
+
+    /// The status of every element of the given type, or of all of them,
+    /// with their barcodes if `volume_tags` is set.
+    pub fn elements(
+        &self,
+        element_type: Option<ChangerElementType>,
+        volume_tags: bool,
+    ) -> Sg3Result<Vec<ChangerElement>> {
+        read_element_status(&self.dev, element_type, 0, u16::MAX, volume_tags)
+    }
+
+    /// Move the medium in `source` to `dest` with the given transport
+    /// element.
+    pub fn move_medium(&self, transport: u16, source: u16, dest: u16) -> Sg3Result<()> {
+        move_medium(&self.dev, transport, source, dest, false)
+    }
+
+    /// Move the medium in `source` to `first_dest`, and the medium that
+    /// was there to `second_dest`.
+    pub fn exchange_medium(
+        &self,
+        transport: u16,
+        source: u16,
+        first_dest: u16,
+        second_dest: u16,
+    ) -> Sg3Result<()> {
+        exchange_medium(
+            &self.dev,
+            transport,
+            source,
+            first_dest,
+            second_dest,
+            (false, false),
+        )
+    }
+
+    /// Have the changer take a fresh inventory of all its elements.
+    pub fn inventory(&self, volume_tags: bool) -> Sg3Result<()> {
+        initialize_element_status(&self.dev, None, volume_tags)
+    }
+}
+
+/// An enclosure services device.
+#[derive(Debug)]
+pub struct ScsiEnclosure<T: SgTransport> {
+    dev: T,
+}
+
+impl<T: SgTransport> ScsiEnclosure<T> {
+    pub fn new(dev: T) -> ScsiEnclosure<T> {
+        ScsiEnclosure { dev }
+    }
+
+    /// The wrapped device.
+    pub fn get_ref(&self) -> &T {
+        &self.dev
+    }
+
+    /// Unwrap the device.
+    pub fn into_inner(self) -> T {
+        self.dev
+    }
+
+    pub fn configuration(&self) -> Sg3Result<SesConfiguration> {
+        ses_configuration(&self.dev)
+    }
+
+    pub fn status(&self) -> Sg3Result<(SesConfiguration, EnclosureStatus)> {
+        ses_enclosure_status(&self.dev)
+    }
+
+    /// The configuration, status and descriptors, tied together by slot.
+    pub fn enclosure(&self) -> Sg3Result<Enclosure> {
+        ses_enclosure(&self.dev)
+    }
+
+    pub fn send_control(&self, control: &EnclosureControl) -> Sg3Result<()> {
+        send_enclosure_control(&self.dev, control)
+    }
+
+    pub fn set_slot_ident(&self, slot: usize, on: bool) -> Sg3Result<()> {
+        set_slot_ident(&self.dev, slot, on)
+    }
+
+    pub fn set_slot_fault(&self, slot: usize, on: bool) -> Sg3Result<()> {
+        set_slot_fault(&self.dev, slot, on)
+    }
+
+    /// The block devices of the drives in the slots of a SAS enclosure.
+    pub fn slot_devices(&self) -> Sg3Result<BTreeMap<usize, PathBuf>> {
+        enclosure_slot_devices(&self.dev)
+    }
+}
+
+/// A device, wrapped for the commands of its peripheral device type.
+#[derive(Debug)]
+pub enum ScsiDevice<T: SgTransport> {
+    Disk(ScsiDisk<T>),
+    Tape(ScsiTape<T>),
+    Changer(ScsiChanger<T>),
+    Enclosure(ScsiEnclosure<T>),
+    /// A device of a type with no wrapper of its own.
+    Other(T, PeripheralDeviceType),
+}
+
+impl<T: SgTransport> ScsiDevice<T> {
+    /// Wrap the device for the peripheral device type its INQUIRY data
+    /// gives. A direct-access device's capacity must be readable, as for
+    /// `ScsiDisk::new()`.
+    pub fn open(dev: T) -> Sg3Result<ScsiDevice<T>> {
+        Ok(match inquiry(&dev)?.peripheral_device_type() {
+            PeripheralDeviceType::DirectAccess => ScsiDevice::Disk(ScsiDisk::new(dev)?),
+            PeripheralDeviceType::SequentialAccess => ScsiDevice::Tape(ScsiTape::new(dev)),
+            PeripheralDeviceType::MediaChanger => ScsiDevice::Changer(ScsiChanger::new(dev)),
+            PeripheralDeviceType::EnclosureServices => {
+                ScsiDevice::Enclosure(ScsiEnclosure::new(dev))
+            }
+            device_type => ScsiDevice::Other(dev, device_type),
+        })
+    }
+
+    /// Unwrap the device, whatever it was wrapped as.
+    pub fn into_inner(self) -> T {
+        match self {
+            ScsiDevice::Disk(disk) => disk.into_inner(),
+            ScsiDevice::Tape(tape) => tape.into_inner(),
+            ScsiDevice::Changer(changer) => changer.into_inner(),
+            ScsiDevice::Enclosure(enclosure) => enclosure.into_inner(),
+            ScsiDevice::Other(dev, _) => dev,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeDevice;
+
+    fn device(device_type: u8) -> FakeDevice {
+        let mut dev = FakeDevice::new("IBM", "ULT3580-TD8", "1.0");
+        let mut inquiry = vec![0u8; 36];
+        inquiry[0] = device_type;
+        inquiry[2] = 0x06;
+        inquiry[3] = 0x02;
+        inquiry[4] = 31;
+        dev.set_inquiry(&inquiry);
+        dev
+    }
+
+    #[test]
+    fn test_open() {
+        let mut disk = device(0x00);
+        assert!(ScsiDevice::open(&disk).is_err());
+        let mut capacity = vec![0u8; 32];
+        capacity[10] = 0x02;
+        disk.set_response(0x9e, &capacity);
+        assert!(matches!(
+            ScsiDevice::open(&disk).unwrap(),
+            ScsiDevice::Disk(_)
+        ));
+
+        let mut tape = device(0x01);
+        tape.set_response(0x01, &[]);
+        match ScsiDevice::open(&tape).unwrap() {
+            ScsiDevice::Tape(tape) => tape.rewind().unwrap(),
+            _ => panic!("not a tape"),
+        }
+        assert_eq!(tape.commands().last().unwrap(), &[0x01, 0, 0, 0, 0, 0]);
+
+        assert!(matches!(
+            ScsiDevice::open(device(0x08)).unwrap(),
+            ScsiDevice::Changer(_)
+        ));
+        assert!(matches!(
+            ScsiDevice::open(device(0x0d)).unwrap(),
+            ScsiDevice::Enclosure(_)
+        ));
+        match ScsiDevice::open(device(0x05)).unwrap() {
+            ScsiDevice::Other(_, device_type) => {
+                assert_eq!(device_type, PeripheralDeviceType::CdDvd)
+            }
+            _ => panic!("not another device type"),
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! A direct-access device that can be read and written like a file, for
+//! tools that copy or image whole devices.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{
+    inquiry_vpd_b0, invalid_data, invalid_input, read10, read16, read_capacity16,
+    synchronize_cache16, write10, write16, BlockCount, BlockSize, Lba, Sg3Result, SgTransport,
+};
+
+// The most to transfer with one command, whatever the device allows,
+// so that a transfer stays within what the kernel will map for one
+// SG_IO.
+const MAX_TRANSFER_BYTES: u64 = 512 * 1024;
+
+// Whether READ(10) and WRITE(10) can address the extent, as the Linux
+// sd driver prefers them to the 16-byte commands when they can.
+fn fits_10(lba: Lba, blocks: BlockCount) -> bool {
+    (lba + blocks).0 <= u64::from(u32::MAX) && blocks.0 <= u64::from(u16::MAX)
+}
+
+/// A direct-access device, with its capacity and block size read once
+/// when it is opened.
+///
+/// Besides reading and writing whole blocks, it implements `Read`,
+/// `Write` and `Seek` at any byte offset: partial blocks are read, and
+/// written back with the new data merged in. Each call transfers at most
+/// one command's worth of data, so `read_exact()` and `write_all()` are
+/// the usual way to use them.
+#[derive(Debug)]
+pub struct ScsiDisk<T: SgTransport> {
+    dev: T,
+    blocks: BlockCount,
+    block_size: BlockSize,
+    // the most blocks to transfer with one command
+    max_transfer: BlockCount,
+    pos: u64,
+}
+
+impl<T: SgTransport> ScsiDisk<T> {
+    /// Wrap the device, reading its capacity with READ CAPACITY(16), and
+    /// its transfer limit from the Block Limits VPD page if it has one.
+    pub fn new(dev: T) -> Sg3Result<ScsiDisk<T>> {
+        let capacity = read_capacity16(&dev)?;
+        if capacity.block_size.0 == 0 {
+            return Err(invalid_data("device reports a block size of zero"));
+        }
+        let blocks = capacity
+            .blocks()
+            .filter(|_| capacity.bytes().is_some())
+            .ok_or_else(|| invalid_data("device reports a capacity too large to address"))?;
+
+        let mut max_transfer = MAX_TRANSFER_BYTES / u64::from(capacity.block_size.0);
+        if let Some(limit) = inquiry_vpd_b0(&dev)
+            .ok()
+            .map(|b0| b0.max_transfer_length())
+            .filter(|&limit| limit != 0)
+        {
+            max_transfer = max_transfer.min(limit.into());
+        }
+
+        Ok(ScsiDisk {
+            dev,
+            blocks,
+            block_size: capacity.block_size,
+            max_transfer: BlockCount(max_transfer.max(1)),
+            pos: 0,
+        })
+    }
+
+    /// The wrapped device.
+    pub fn get_ref(&self) -> &T {
+        &self.dev
+    }
+
+    /// Unwrap the device, discarding the position.
+    pub fn into_inner(self) -> T {
+        self.dev
+    }
+
+    pub fn blocks(&self) -> BlockCount {
+        self.blocks
+    }
+
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /// The size of the device in bytes.
+    pub fn size(&self) -> u64 {
+        self.blocks
+            .bytes(self.block_size)
+            .expect("new() rejects a size that overflows")
+    }
+
+    // Check that `len` bytes from `lba` is a whole number of blocks, all
+    // on the device.
+    fn check_extent(&self, lba: Lba, len: usize) -> Sg3Result<()> {
+        let blocks = self
+            .block_size
+            .blocks(len as u64)
+            .ok_or_else(|| invalid_input("buffer is not a whole number of blocks"))?;
+        if lba.0 > self.blocks.0 || blocks.0 > self.blocks.0 - lba.0 {
+            return Err(invalid_input("blocks are past the end of the device"));
+        }
+        Ok(())
+    }
+
+    fn transfer_bytes(&self) -> usize {
+        self.max_transfer
+            .bytes(self.block_size)
+            .expect("new() limits transfers to MAX_TRANSFER_BYTES") as usize
+    }
+
+    /// Read blocks from `lba` to fill `buf`, which must be a whole
+    /// number of blocks long, with as many commands as the transfer
+    /// limit takes.
+    pub fn read_blocks(&self, lba: Lba, buf: &mut [u8]) -> Sg3Result<()> {
+        self.check_extent(lba, buf.len())?;
+
+        let mut lba = lba;
+        for chunk in buf.chunks_mut(self.transfer_bytes()) {
+            let blocks = BlockCount((chunk.len() / self.block_size.0 as usize) as u64);
+            if fits_10(lba, blocks) {
+                read10(&self.dev, lba, blocks, chunk, false, 0)?;
+            } else {
+                read16(&self.dev, lba, blocks, chunk, false, 0)?;
+            }
+            lba += blocks;
+        }
+        Ok(())
+    }
+
+    /// Write `buf`, which must be a whole number of blocks long, to the
+    /// blocks from `lba`.
+    pub fn write_blocks(&self, lba: Lba, buf: &[u8]) -> Sg3Result<()> {
+        self.check_extent(lba, buf.len())?;
+
+        let mut lba = lba;
+        for chunk in buf.chunks(self.transfer_bytes()) {
+            let blocks = BlockCount((chunk.len() / self.block_size.0 as usize) as u64);
+            if fits_10(lba, blocks) {
+                write10(&self.dev, lba, blocks, chunk, false, 0)?;
+            } else {
+                write16(&self.dev, lba, blocks, chunk, false, 0)?;
+            }
+            lba += blocks;
+        }
+        Ok(())
+    }
+
+    /// Flush the device's whole volatile cache to the medium.
+    pub fn flush(&self) -> Sg3Result<()> {
+        synchronize_cache16(&self.dev, Lba(0), BlockCount(0), false)
+    }
+
+    // The block the current position is in.
+    fn pos_lba(&self) -> Lba {
+        self.block_size
+            .lba(self.pos)
+            .expect("new() rejects a block size of zero")
+    }
+
+    // How much of a `len`-byte transfer at the current position can go
+    // straight to or from the caller's buffer as whole blocks, or None
+    // if it starts or ends within one block and has to go through a
+    // block-sized buffer.
+    fn aligned_len(&self, len: u64) -> Option<usize> {
+        let size = u64::from(self.block_size.0);
+        if !self.pos.is_multiple_of(size) || len < size {
+            return None;
+        }
+        Some((len - len % size).min(self.transfer_bytes() as u64) as usize)
+    }
+}
+
+impl<T: SgTransport> Read for ScsiDisk<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.size().saturating_sub(self.pos));
+        if len == 0 {
+            return Ok(0);
+        }
+        let lba = self.pos_lba();
+
+        let len = match self.aligned_len(len) {
+            Some(len) => {
+                self.read_blocks(lba, &mut buf[..len])?;
+                len
+            }
+            None => {
+                let mut block = vec![0u8; self.block_size.0 as usize];
+                self.read_blocks(lba, &mut block)?;
+                let offset = (self.pos % u64::from(self.block_size.0)) as usize;
+                let len = (len as usize).min(block.len() - offset);
+                buf[..len].copy_from_slice(&block[offset..offset + len]);
+                len
+            }
+        };
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<T: SgTransport> Write for ScsiDisk<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.size().saturating_sub(self.pos));
+        if len == 0 {
+            return Ok(0);
+        }
+        let lba = self.pos_lba();
+
+        let len = match self.aligned_len(len) {
+            Some(len) => {
+                self.write_blocks(lba, &buf[..len])?;
+                len
+            }
+            None => {
+                let mut block = vec![0u8; self.block_size.0 as usize];
+                self.read_blocks(lba, &mut block)?;
+                let offset = (self.pos % u64::from(self.block_size.0)) as usize;
+                let len = (len as usize).min(block.len() - offset);
+                block[offset..offset + len].copy_from_slice(&buf[..len]);
+                self.write_blocks(lba, &block)?;
+                len
+            }
+        };
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(ScsiDisk::flush(self)?)
+    }
+}
+
+impl<T: SgTransport> Seek for ScsiDisk<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::End(offset) => (self.size(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeDevice;
+
+    // A device of 16 512-byte blocks, reading as the byte pattern
+    // 0, 1, 2... from whichever block is read.
+    fn disk() -> ScsiDisk<FakeDevice> {
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        let mut capacity = vec![0u8; 32];
+        capacity[7] = 15;
+        capacity[10] = 0x02;
+        dev.set_response(0x9e, &capacity);
+        let data: Vec<u8> = (0..8192).map(|i| i as u8).collect();
+        dev.set_response(0x28, &data);
+        dev.set_response(0x2a, &[]);
+        dev.set_response(0x91, &[]);
+        ScsiDisk::new(dev).unwrap()
+    }
+
+    #[test]
+    fn test_disk_blocks() {
+        let disk = disk();
+        assert_eq!(disk.blocks(), BlockCount(16));
+        assert_eq!(disk.block_size(), BlockSize(512));
+        assert_eq!(disk.size(), 8192);
+
+        let mut buf = vec![0u8; 1024];
+        disk.read_blocks(Lba(14), &mut buf).unwrap();
+        assert_eq!(buf[513], 1);
+        assert!(disk.read_blocks(Lba(15), &mut buf).is_err());
+        assert!(disk.read_blocks(Lba(0), &mut buf[..100]).is_err());
+        disk.write_blocks(Lba(2), &buf).unwrap();
+        disk.flush().unwrap();
+
+        let cmds = disk.get_ref().commands();
+        let cmds = &cmds[cmds.len() - 3..];
+        assert_eq!(cmds[0], [0x28, 0, 0, 0, 0, 14, 0, 0, 2, 0]);
+        assert_eq!(cmds[1], [0x2a, 0, 0, 0, 0, 2, 0, 0, 2, 0]);
+        assert_eq!(cmds[2][0], 0x91);
+
+        // a capacity whose size in bytes overflows is rejected
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        let mut capacity = vec![0xffu8; 32];
+        capacity[8..12].copy_from_slice(&[0, 0, 0x02, 0]);
+        dev.set_response(0x9e, &capacity);
+        assert!(ScsiDisk::new(dev).is_err());
+    }
+
+    #[test]
+    fn test_disk_io() {
+        let mut disk = disk();
+
+        // within one block
+        disk.seek(SeekFrom::Start(1000)).unwrap();
+        let mut buf = [0u8; 10];
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], (1000 - 512) as u8);
+        assert_eq!(disk.stream_position().unwrap(), 1010);
+
+        // across a block boundary, then whole blocks
+        disk.seek(SeekFrom::Start(500)).unwrap();
+        let mut buf = vec![0u8; 2048];
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], 500u16 as u8);
+        assert_eq!(buf[12], 0);
+
+        // a partial block is read and written back
+        let start = disk.get_ref().commands().len();
+        disk.seek(SeekFrom::Start(1030)).unwrap();
+        disk.write_all(&[0xff; 4]).unwrap();
+        let cmds = disk.get_ref().commands();
+        assert_eq!(cmds.len() - start, 2);
+        assert_eq!(cmds[start][0], 0x28);
+        assert_eq!(cmds[start + 1], [0x2a, 0, 0, 0, 0, 2, 0, 0, 1, 0]);
+
+        // up to the end of the device, and no further
+        assert_eq!(disk.seek(SeekFrom::End(-4)).unwrap(), 8188);
+        let mut buf = [0u8; 8];
+        assert_eq!(disk.read(&mut buf).unwrap(), 4);
+        assert_eq!(disk.read(&mut buf).unwrap(), 0);
+        assert_eq!(disk.write(&buf).unwrap(), 0);
+        assert!(disk.seek(SeekFrom::Current(-9000)).is_err());
+        disk.flush().unwrap();
+    }
+}
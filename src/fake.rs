@@ -0,0 +1,321 @@
+//! A stand-in for a SCSI device, answering commands from canned data,
+//! for testing code that uses this crate without real hardware.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{check_cdb, CommandResult, DataDirection, SenseKey, Sg3Error, Sg3Result, SgTransport};
+
+const STATUS_CHECK_CONDITION: u8 = 0x02;
+
+// Fixed-format sense data with the given key and additional sense code.
+fn fixed_sense(key: SenseKey, asc: u8, ascq: u8) -> Vec<u8> {
+    let mut sense = vec![0u8; 18];
+    sense[0] = 0x70;
+    sense[2] = key as u8;
+    sense[7] = 10;
+    sense[12] = asc;
+    sense[13] = ascq;
+    sense
+}
+
+/// A device that answers INQUIRY, VPD pages and MODE SENSE from data it
+/// is given, and any other command it is given a response for. Errors
+/// can be scripted for particular commands, and every CDB it receives
+/// is recorded.
+///
+/// Commands it has no response for fail with ILLEGAL REQUEST, as they
+/// would on a device that doesn't support them.
+#[derive(Debug)]
+pub struct FakeDevice {
+    path: PathBuf,
+    inquiry: Vec<u8>,
+    vpd_pages: BTreeMap<u8, Vec<u8>>,
+    mode_pages: BTreeMap<u8, Vec<u8>>,
+    responses: BTreeMap<u8, Vec<u8>>,
+    errors: RefCell<VecDeque<(u8, u8, Vec<u8>)>>,
+    commands: RefCell<Vec<Vec<u8>>>,
+}
+
+impl FakeDevice {
+    /// A direct-access device with the given INQUIRY identification,
+    /// supporting TEST UNIT READY and no VPD or mode pages. Longer
+    /// strings are truncated.
+    pub fn new(vendor: &str, product: &str, revision: &str) -> FakeDevice {
+        let mut inquiry = vec![0u8; 36];
+        // SPC-4
+        inquiry[2] = 0x06;
+        inquiry[3] = 0x02;
+        inquiry[4] = 31;
+        for (field, s) in [(8..16, vendor), (16..32, product), (32..36, revision)]
+            .iter()
+            .cloned()
+        {
+            let field = &mut inquiry[field];
+            for (i, b) in field.iter_mut().enumerate() {
+                *b = s.as_bytes().get(i).cloned().unwrap_or(b' ');
+            }
+        }
+
+        let mut responses = BTreeMap::new();
+        responses.insert(0x00, Vec::new());
+
+        FakeDevice {
+            path: PathBuf::from("fake"),
+            inquiry,
+            vpd_pages: BTreeMap::new(),
+            mode_pages: BTreeMap::new(),
+            responses,
+            errors: RefCell::new(VecDeque::new()),
+            commands: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Replace the whole standard INQUIRY data.
+    pub fn set_inquiry(&mut self, data: &[u8]) {
+        self.inquiry = data.to_vec();
+    }
+
+    /// Answer INQUIRY for the given VPD page with `data`, the page
+    /// without its four-byte header. Page 0, the list of supported
+    /// pages, is built from the pages that are set.
+    pub fn set_vpd_page(&mut self, page: u8, data: &[u8]) {
+        self.vpd_pages.insert(page, data.to_vec());
+    }
+
+    /// Answer MODE SENSE for the given page with `data`, the page
+    /// without its two-byte header, so no more than 255 bytes long.
+    pub fn set_mode_page(&mut self, page: u8, data: &[u8]) {
+        assert!(data.len() <= 0xff, "mode page {:#04x} is too long", page);
+        self.mode_pages.insert(page, data.to_vec());
+    }
+
+    /// Answer every command with the given operation code with `data`.
+    /// Commands that send data to the device, or that transfer none,
+    /// are accepted given an empty response.
+    pub fn set_response(&mut self, opcode: u8, data: &[u8]) {
+        self.responses.insert(opcode, data.to_vec());
+    }
+
+    /// Fail the next command with the given operation code with CHECK
+    /// CONDITION and fixed-format sense data holding the given key and
+    /// additional sense code. Errors queued for the same command are
+    /// returned in turn.
+    pub fn push_sense_error(&mut self, opcode: u8, key: SenseKey, asc: u8, ascq: u8) {
+        self.push_error(opcode, STATUS_CHECK_CONDITION, &fixed_sense(key, asc, ascq));
+    }
+
+    /// Fail the next command with the given operation code with the
+    /// given status and sense data, such as BUSY with none.
+    pub fn push_error(&mut self, opcode: u8, status: u8, sense: &[u8]) {
+        self.errors
+            .borrow_mut()
+            .push_back((opcode, status, sense.to_vec()));
+    }
+
+    /// The CDBs of the commands received so far, oldest first.
+    pub fn commands(&self) -> Vec<Vec<u8>> {
+        self.commands.borrow().clone()
+    }
+
+    fn error(&self, cmd: &[u8], status: u8, sense: Vec<u8>) -> Sg3Error {
+        Sg3Error::Scsi {
+            device: self.path.clone(),
+            cdb: cmd.to_vec(),
+            status,
+            host_status: 0,
+            driver_status: 0,
+            sense,
+        }
+    }
+
+    fn invalid_field(&self, cmd: &[u8]) -> Sg3Error {
+        self.error(
+            cmd,
+            STATUS_CHECK_CONDITION,
+            fixed_sense(SenseKey::IllegalRequest, 0x24, 0x00),
+        )
+    }
+
+    fn vpd_page(&self, cmd: &[u8], page: u8) -> Sg3Result<Vec<u8>> {
+        let data = match page {
+            0x00 => {
+                let mut pages = vec![0x00];
+                pages.extend(self.vpd_pages.keys().filter(|&&p| p != 0x00));
+                pages
+            }
+            _ => match self.vpd_pages.get(&page) {
+                Some(data) => data.clone(),
+                None => return Err(self.invalid_field(cmd)),
+            },
+        };
+
+        let mut buf = vec![self.inquiry[0], page, 0, 0];
+        BigEndian::write_u16(&mut buf[2..4], data.len() as u16);
+        buf.extend_from_slice(&data);
+        Ok(buf)
+    }
+
+    fn mode_pages(&self, cmd: &[u8], page: u8) -> Sg3Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        for (&code, data) in &self.mode_pages {
+            if page == 0x3f || page == code {
+                buf.push(code);
+                buf.push(data.len() as u8);
+                buf.extend_from_slice(data);
+            }
+        }
+        if buf.is_empty() {
+            return Err(self.invalid_field(cmd));
+        }
+        Ok(buf)
+    }
+
+    fn response(&self, cmd: &[u8]) -> Sg3Result<Vec<u8>> {
+        let opcode = cmd[0];
+
+        match opcode {
+            // INQUIRY
+            0x12 if cmd.len() >= 6 => {
+                if cmd[1] & 0x01 != 0 {
+                    self.vpd_page(cmd, cmd[2])
+                } else if cmd[2] != 0 {
+                    Err(self.invalid_field(cmd))
+                } else {
+                    Ok(self.inquiry.clone())
+                }
+            }
+            // MODE SENSE(6)
+            0x1a if cmd.len() >= 6 => {
+                let pages = self.mode_pages(cmd, cmd[2] & 0x3f)?;
+                // the MODE DATA LENGTH is a byte, so what doesn't fit
+                // is cut off
+                let len = (pages.len() + 3).min(0xff);
+                let mut buf = vec![len as u8, 0, 0, 0];
+                buf.extend_from_slice(&pages);
+                buf.truncate(len + 1);
+                Ok(buf)
+            }
+            // MODE SENSE(10)
+            0x5a if cmd.len() >= 10 => {
+                let pages = self.mode_pages(cmd, cmd[2] & 0x3f)?;
+                let mut buf = vec![0u8; 8];
+                BigEndian::write_u16(&mut buf[0..2], (pages.len() + 6) as u16);
+                buf.extend_from_slice(&pages);
+                Ok(buf)
+            }
+            _ => match self.responses.get(&opcode) {
+                Some(data) => Ok(data.clone()),
+                None => Err(self.error(
+                    cmd,
+                    STATUS_CHECK_CONDITION,
+                    fixed_sense(SenseKey::IllegalRequest, 0x20, 0x00),
+                )),
+            },
+        }
+    }
+}
+
+impl SgTransport for FakeDevice {
+    fn execute(&self, cmd: &[u8], data: DataDirection, _timeout: u32) -> Sg3Result<CommandResult> {
+        check_cdb(cmd)?;
+        self.commands.borrow_mut().push(cmd.to_vec());
+
+        let opcode = cmd.first().cloned().unwrap_or(0);
+        let scripted = {
+            let mut errors = self.errors.borrow_mut();
+            let pos = errors.iter().position(|e| e.0 == opcode);
+            pos.and_then(|pos| errors.remove(pos))
+        };
+        if let Some((_, status, sense)) = scripted {
+            return Err(self.error(cmd, status, sense));
+        }
+
+        let response = self.response(cmd)?;
+
+        let (len, transferred) = match data {
+            DataDirection::None => (0, 0),
+            DataDirection::ToDevice(buf) => (buf.len(), buf.len()),
+            DataDirection::FromDevice(buf) => {
+                let n = buf.len().min(response.len());
+                buf[..n].copy_from_slice(&response[..n]);
+                (buf.len(), n)
+            }
+        };
+
+        let now = Instant::now();
+        Ok(CommandResult {
+            status: 0,
+            resid: len - transferred,
+            transferred,
+            duration: Duration::from_millis(0),
+            submitted: now,
+            completed: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_device() {
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        dev.set_mode_page(0x08, &[0x04; 18]);
+        dev.push_sense_error(0x00, SenseKey::UnitAttention, 0x29, 0x00);
+
+        let mut buf = [0u8; 64];
+        let res = dev
+            .execute(
+                &[0x5a, 0, 0x3f, 0, 0, 0, 0, 0, 64, 0],
+                DataDirection::FromDevice(&mut buf),
+                0,
+            )
+            .unwrap();
+        assert_eq!(res.transferred, 28);
+        assert_eq!(res.resid, 36);
+        assert_eq!(&buf[..10], &[0, 26, 0, 0, 0, 0, 0, 0, 0x08, 18]);
+
+        // the unit attention is reported once
+        let cmd = [0u8; 6];
+        let err = dev.execute(&cmd, DataDirection::None, 0).unwrap_err();
+        assert_eq!(err.sense().unwrap().sense_key(), SenseKey::UnitAttention);
+        assert!(dev.execute(&cmd, DataDirection::None, 0).is_ok());
+
+        // unknown commands and pages are rejected
+        let err = dev.execute(&[0xff; 6], DataDirection::None, 0).unwrap_err();
+        assert_eq!(err.sense().unwrap().asc(), 0x20);
+        let err = dev
+            .execute(
+                &[0x1a, 0, 0x1c, 0, 0xff, 0],
+                DataDirection::FromDevice(&mut buf),
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(err.sense().unwrap().asc(), 0x24);
+
+        assert_eq!(dev.commands().len(), 5);
+    }
+
+    #[test]
+    fn test_mode_sense6_length() {
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        dev.set_mode_page(0x08, &[0x04; 200]);
+        dev.set_mode_page(0x0a, &[0x02; 200]);
+
+        let mut buf = [0u8; 512];
+        dev.execute(
+            &[0x1a, 0, 0x3f, 0, 0xff, 0],
+            DataDirection::FromDevice(&mut buf),
+            0,
+        )
+        .unwrap();
+        assert_eq!(buf[0], 0xff);
+        assert_eq!(buf[256], 0);
+    }
+}
@@ -0,0 +1,107 @@
+//! Identifying a device in one call, from the INQUIRY data nearly every
+//! user of the crate reads.
+
+use crate::{
+    inquiry, inquiry_vpd_80, inquiry_vpd_83, lu_designator_rank, Association,
+    DesignationDescriptor, Designator, DesignatorType, InquiryVpd80, InquiryVpd83, Sg3Result,
+    SgTransport, StdInquiry,
+};
+
+/// Who made a device, what it is, and which one it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceId {
+    /// The INQUIRY vendor, product and revision, without trailing
+    /// spaces.
+    pub vendor: String,
+    pub model: String,
+    pub revision: String,
+    /// The unit serial number, without surrounding spaces, if the device
+    /// reports one.
+    pub serial: Option<String>,
+    /// The logical unit designator that best identifies it: NAA, then
+    /// EUI-64, then SCSI name string, then T10 vendor ID.
+    pub designator: Option<Designator>,
+}
+
+// How well a logical unit designator identifies it, lower being better:
+// SCSI name strings and then T10 vendor IDs come after the designators
+// `lu_designator_rank()` ranks.
+fn designator_rank(desc: &DesignationDescriptor) -> Option<u8> {
+    if desc.association != Association::AddressedLogicalUnit {
+        return None;
+    }
+    match desc.designator {
+        Designator::Naa(_) | Designator::Eui64(_) => lu_designator_rank(desc),
+        Designator::ScsiName(_) => Some(5),
+        _ if desc.designator_type == DesignatorType::T10VendorId => Some(6),
+        _ => None,
+    }
+}
+
+fn build_device_id(
+    inquiry: &StdInquiry,
+    vpd80: Option<InquiryVpd80>,
+    vpd83: Option<InquiryVpd83>,
+) -> DeviceId {
+    let serial = vpd80
+        .map(|vpd| vpd.serial_number().trim().to_string())
+        .filter(|serial| !serial.is_empty());
+    let designator = vpd83.and_then(|vpd| {
+        vpd.descriptors
+            .into_iter()
+            .filter_map(|desc| designator_rank(&desc).map(|rank| (rank, desc)))
+            .min_by_key(|&(rank, _)| rank)
+            .map(|(_, desc)| desc.designator)
+    });
+
+    DeviceId {
+        vendor: inquiry.vendor().trim_end().to_string(),
+        model: inquiry.product_id().trim_end().to_string(),
+        revision: inquiry.product_revision().trim_end().to_string(),
+        serial,
+        designator,
+    }
+}
+
+// Send SCSI INQUIRY for the standard inquiry data, VPD 80 and VPD 83 to
+// the SCSI device at the given path, and combine them. Only the first
+// must succeed: a device without either VPD page has no serial number
+// or designator.
+pub fn identify<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<DeviceId> {
+    let inquiry = inquiry(path)?;
+    Ok(build_device_id(
+        &inquiry,
+        inquiry_vpd_80(path).ok(),
+        inquiry_vpd_83(path).ok(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeDevice;
+
+    #[test]
+    fn test_identify() {
+        let mut dev = FakeDevice::new("SEAGATE", "ST4000NM0023", "0004");
+        let id = identify(&dev).unwrap();
+        assert_eq!(id.vendor, "SEAGATE");
+        assert_eq!(id.model, "ST4000NM0023");
+        assert_eq!(id.revision, "0004");
+        assert_eq!(id.serial, None);
+        assert_eq!(id.designator, None);
+
+        dev.set_vpd_page(0x80, b"    Z1Z0ABCD");
+        let mut vpd83 = vec![0x02, 0x01, 0x00, 0x08];
+        vpd83.extend_from_slice(b"SEAGATE ");
+        vpd83.extend_from_slice(&[0x01, 0x03, 0x00, 0x08, 0x50, 0x00, 0xc5, 0, 1, 2, 3, 4]);
+        dev.set_vpd_page(0x83, &vpd83);
+        let id = identify(&dev).unwrap();
+        assert_eq!(id.serial.as_deref(), Some("Z1Z0ABCD"));
+        match id.designator {
+            Some(Designator::Naa(naa)) => assert_eq!(naa.to_string(), "5000c50001020304"),
+            d => panic!("not an NAA designator: {:?}", d),
+        }
+    }
+}
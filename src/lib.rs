@@ -19,14 +19,15 @@ extern crate byteorder;
 #[macro_use]
 extern crate nom;
 
+use std::borrow::Cow;
 use std::error;
 use std::fmt;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::os::raw::c_void;
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
-use std::str::from_utf8;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use byteorder::{BigEndian, ByteOrder};
 use nix::libc::ioctl as nix_ioctl;
@@ -36,6 +37,17 @@ use nom::{be_u16, be_u8};
 pub enum Sg3Error {
     Nix(nix::Error),
     Io(io::Error),
+    /// The command was issued but did not complete successfully. The
+    /// device and CDB are included, along with any sense data returned
+    /// by the device.
+    Scsi {
+        device: PathBuf,
+        cdb: Vec<u8>,
+        status: u8,
+        host_status: u16,
+        driver_status: u16,
+        sense: Vec<u8>,
+    },
 }
 
 pub type Sg3Result<T> = Result<T, Sg3Error>;
@@ -52,11 +64,68 @@ impl From<nix::Error> for Sg3Error {
     }
 }
 
+/// For `std::io` trait impls; I/O errors are passed through as they
+/// are, and others wrapped.
+impl From<Sg3Error> for io::Error {
+    fn from(err: Sg3Error) -> io::Error {
+        match err {
+            Sg3Error::Io(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
+
 impl fmt::Display for Sg3Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Sg3Error::Io(ref err) => write!(f, "IO error: {}", err),
             Sg3Error::Nix(ref err) => write!(f, "Nix error: {}", err),
+            Sg3Error::Scsi {
+                ref device,
+                ref cdb,
+                status,
+                host_status,
+                driver_status,
+                ..
+            } => {
+                write!(f, "SCSI error: {}: CDB", device.display())?;
+                for b in cdb {
+                    write!(f, " {:02x}", b)?;
+                }
+                write!(
+                    f,
+                    ": status {:#04x}, host status {:#06x}, driver status {:#06x}",
+                    status, host_status, driver_status
+                )?;
+                if let Some(sense) = self.sense() {
+                    write!(
+                        f,
+                        ", sense key {:?}, asc/ascq {:#04x}/{:#04x}",
+                        sense.sense_key(),
+                        sense.asc(),
+                        sense.ascq()
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Sg3Error {
+    /// The operation code of the command that failed, if it was issued.
+    pub fn opcode(&self) -> Option<u8> {
+        match *self {
+            Sg3Error::Scsi { ref cdb, .. } => cdb.first().cloned(),
+            _ => None,
+        }
+    }
+
+    /// Parse the sense data returned with a failed command, if any.
+    pub fn sense(&self) -> Option<Sense> {
+        match *self {
+            Sg3Error::Scsi { ref sense, .. } => Sense::from_buf(sense),
+            _ => None,
         }
     }
 }
@@ -66,6 +135,7 @@ impl error::Error for Sg3Error {
         match *self {
             Sg3Error::Io(ref err) => err.description(),
             Sg3Error::Nix(ref err) => err.description(),
+            Sg3Error::Scsi { .. } => "SCSI command failed",
         }
     }
 }
@@ -80,7 +150,76 @@ mod ffi {
     include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 }
 
+#[cfg(feature = "tokio")]
+mod aio;
+mod alua;
+mod ata;
+mod block;
+mod bsg;
+mod buffer;
+mod cdb;
+mod copy;
+mod designator;
+mod device;
+mod disk;
+mod fake;
+mod identify;
+mod log;
+mod lun;
+mod mam;
+mod mmc;
+mod multipath;
+mod pi;
+mod pr;
+mod probe;
+mod retry;
+mod sbc;
+mod sense;
+mod ses;
+mod sg;
+mod smc;
+mod spc;
+mod ssc;
+mod sysfs;
+mod version;
+mod zbc;
+
+#[cfg(feature = "tokio")]
+pub use crate::aio::*;
+pub use crate::alua::*;
+pub use crate::ata::*;
+pub use crate::block::*;
+pub use crate::bsg::*;
+pub use crate::buffer::*;
+pub use crate::cdb::*;
+pub use crate::copy::*;
+pub use crate::designator::*;
+pub use crate::device::*;
+pub use crate::disk::*;
+pub use crate::fake::*;
+pub use crate::identify::*;
+pub use crate::log::*;
+pub use crate::lun::*;
+pub use crate::mam::*;
+pub use crate::mmc::*;
+pub use crate::multipath::*;
+pub use crate::pi::*;
+pub use crate::pr::*;
+pub use crate::probe::*;
+pub use crate::retry::*;
+pub use crate::sbc::*;
+pub use crate::sense::*;
+pub use crate::ses::*;
+pub use crate::sg::*;
+pub use crate::smc::*;
+pub use crate::spc::*;
+pub use crate::ssc::*;
+pub use crate::sysfs::*;
+pub use crate::version::*;
+pub use crate::zbc::*;
+
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PeripheralQualifier {
     Connected,
     NotConnected,
@@ -90,6 +229,7 @@ pub enum PeripheralQualifier {
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PeripheralDeviceType {
     DirectAccess,
     SequentialAccess,
@@ -110,6 +250,7 @@ pub enum PeripheralDeviceType {
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ProtocolIdentifier {
     Fcp,
     Spi,
@@ -127,6 +268,7 @@ pub enum ProtocolIdentifier {
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Association {
     AddressedLogicalUnit,
     TargetPort,
@@ -135,6 +277,7 @@ pub enum Association {
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DesignatorType {
     VS,
     T10VendorId,
@@ -149,33 +292,372 @@ pub enum DesignatorType {
     Reserved,
 }
 
-// Send SCSI INQUIRY command to the SCSI device at the given path.
-pub fn inquiry(path: &Path) -> Sg3Result<StdInquiry> {
-    let f = OpenOptions::new().read(true).open(path)?;
+/// Direction of the data phase of a command, along with the buffer
+/// that data is transferred from or into.
+#[derive(Debug)]
+pub enum DataDirection<'a> {
+    None,
+    FromDevice(&'a mut [u8]),
+    ToDevice(&'a [u8]),
+}
+
+// Open a SCSI device for issuing commands. Commands that modify the
+// device need a writable fd, but fall back to read-only so that
+// INQUIRY and friends still work without write permission.
+fn open_device(path: &Path) -> Sg3Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Ok(OpenOptions::new().read(true).open(path)?)
+        }
+        res => Ok(res?),
+    }
+}
+
+// An error for arguments the caller got wrong.
+pub(crate) fn invalid_input(msg: &str) -> Sg3Error {
+    Sg3Error::Io(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+// An error for data from the device that doesn't make sense.
+pub(crate) fn invalid_data(msg: &str) -> Sg3Error {
+    Sg3Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// SCSI status returned by commands such as PRE-FETCH that completed
+// successfully and whose condition was satisfied.
+const STATUS_CONDITION_MET: u8 = 0x04;
+
+// Command timeout, in milliseconds, used unless a command is known to
+// take longer.
+const DEFAULT_TIMEOUT: u32 = 60_000;
+
+// The longest CDB the sg driver accepts.
+const MAX_CDB_LEN: usize = 252;
+
+// Check that a CDB is at least as long as its operation code needs,
+// from the group code in its top three bits, and that a variable-length
+// CDB (operation code 7Fh) matches the length in its header. Vendor
+// specific operation codes may be any length.
+fn check_cdb(cmd: &[u8]) -> Sg3Result<()> {
+    let invalid = |msg| Err(invalid_input(msg));
+
+    let opcode = match cmd.first() {
+        Some(&opcode) => opcode,
+        None => return invalid("CDB is empty"),
+    };
+    if cmd.len() > MAX_CDB_LEN {
+        return invalid("CDB is too long");
+    }
+    if opcode == 0x7f {
+        if cmd.len() < 8 || cmd.len() != 8 + cmd[7] as usize {
+            return invalid("variable-length CDB doesn't match its additional length");
+        }
+        return Ok(());
+    }
+    let min_len = match opcode >> 5 {
+        0 => 6,
+        1 | 2 => 10,
+        4 => 16,
+        5 => 12,
+        _ => 1,
+    };
+    if cmd.len() < min_len {
+        return invalid("CDB is too short for its operation code");
+    }
+    Ok(())
+}
+
+// Issue a single command to the SCSI device at the given path via the
+// SG_IO ioctl, transferring data in the given direction.
+fn sg_io<T: SgTransport + ?Sized>(path: &T, cmd: &[u8], data: DataDirection) -> Sg3Result<()> {
+    sg_io_timeout(path, cmd, data, DEFAULT_TIMEOUT).map(|_| ())
+}
+
+// Like sg_io(), but return the SCSI status of the successfully
+// completed command, which is either GOOD or CONDITION MET.
+fn sg_io_status<T: SgTransport + ?Sized>(
+    path: &T,
+    cmd: &[u8],
+    data: DataDirection,
+) -> Sg3Result<u8> {
+    sg_io_timeout(path, cmd, data, DEFAULT_TIMEOUT)
+}
+
+// Like sg_io_status(), but with a timeout in milliseconds for commands
+// that may run for a long time.
+fn sg_io_timeout<T: SgTransport + ?Sized>(
+    path: &T,
+    cmd: &[u8],
+    data: DataDirection,
+    timeout: u32,
+) -> Sg3Result<u8> {
+    Ok(path.execute(cmd, data, timeout)?.status)
+}
+
+// Like sg_io(), reading into `buf`, but return how many bytes of it the
+// device transferred.
+fn sg_io_read<T: SgTransport + ?Sized>(path: &T, cmd: &[u8], buf: &mut [u8]) -> Sg3Result<usize> {
+    Ok(path
+        .execute(cmd, DataDirection::FromDevice(buf), DEFAULT_TIMEOUT)?
+        .transferred)
+}
+
+// The most to allocate for a variable-length response, whatever length
+// the device reports for it.
+const MAX_RESPONSE_LEN: usize = 1024 * 1024;
+
+// Read a whole variable-length response in two passes. `fetch` sends the
+// command with the length of the buffer it is given as the allocation
+// length, returning how much was transferred, and `total` gives the
+// length of the whole response, header included, from its first
+// `header_len` bytes. The header is read first, and the response then
+// fetched at the length it gives, up to MAX_RESPONSE_LEN. Short data
+// reads as zero.
+fn read_variable_length<F, L>(header_len: usize, mut fetch: F, total: L) -> Sg3Result<Vec<u8>>
+where
+    F: FnMut(&mut [u8]) -> Sg3Result<usize>,
+    L: Fn(&[u8]) -> usize,
+{
+    let mut buf = vec![0u8; header_len];
+    let mut transferred = fetch(&mut buf)?;
+
+    let len = total(&buf).min(MAX_RESPONSE_LEN);
+    if len > buf.len() {
+        buf = vec![0u8; len];
+        transferred = fetch(&mut buf)?;
+    }
+    // the response may have shrunk in between
+    let len = total(&buf).min(transferred).max(header_len);
+    buf.truncate(len);
+
+    Ok(buf)
+}
+
+/// The outcome of a command that completed successfully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandResult {
+    /// The SCSI status, either GOOD or CONDITION MET.
+    pub status: u8,
+    /// How many bytes of the data buffer were not transferred, as
+    /// reported by the driver. Bytes past `transferred` may be stale.
+    pub resid: usize,
+    /// How many bytes of the data buffer were transferred.
+    pub transferred: usize,
+    /// How long the command took, as the driver measured it, to the
+    /// millisecond.
+    pub duration: Duration,
+    /// When the command was handed to the driver.
+    pub submitted: Instant,
+    /// When its result was collected from the driver.
+    pub completed: Instant,
+}
+
+impl CommandResult {
+    /// The time from submitting the command to collecting its result,
+    /// as measured by the crate.
+    pub fn latency(&self) -> Duration {
+        self.completed.duration_since(self.submitted)
+    }
+}
+
+/// Carries SCSI commands to a device. Every command in this crate is
+/// sent through this trait, so it can be implemented to send them some
+/// other way, or to stand in for a device in tests.
+///
+/// It is implemented for `Path`, as the path of a Linux sg or block
+/// device that commands are issued to with the SG_IO ioctl, and for
+/// `SgFile`, which keeps the device open between commands, and for
+/// `BsgFile`, which uses the v4 interface of the bsg driver.
+pub trait SgTransport {
+    /// Issue the given CDB, with a timeout in milliseconds, transferring
+    /// data in the given direction. A command that doesn't complete
+    /// with GOOD or CONDITION MET status returns `Sg3Error::Scsi`.
+    fn execute(&self, cmd: &[u8], data: DataDirection, timeout: u32) -> Sg3Result<CommandResult>;
+}
+
+impl SgTransport for Path {
+    fn execute(&self, cmd: &[u8], data: DataDirection, timeout: u32) -> Sg3Result<CommandResult> {
+        scsi_command(self, cmd, data, timeout)
+    }
+}
+
+impl SgTransport for PathBuf {
+    fn execute(&self, cmd: &[u8], data: DataDirection, timeout: u32) -> Sg3Result<CommandResult> {
+        scsi_command(self, cmd, data, timeout)
+    }
+}
+
+impl<T: SgTransport + ?Sized> SgTransport for &T {
+    fn execute(&self, cmd: &[u8], data: DataDirection, timeout: u32) -> Sg3Result<CommandResult> {
+        (**self).execute(cmd, data, timeout)
+    }
+}
+
+impl<T: SgTransport + ?Sized> SgTransport for Box<T> {
+    fn execute(&self, cmd: &[u8], data: DataDirection, timeout: u32) -> Sg3Result<CommandResult> {
+        (**self).execute(cmd, data, timeout)
+    }
+}
+
+// Issue the given CDB to the SCSI device at the given path, with a
+// timeout in milliseconds, transferring data in the given direction.
+// This is the way to send commands the crate doesn't otherwise know.
+pub fn scsi_command(
+    path: &Path,
+    cmd: &[u8],
+    data: DataDirection,
+    timeout: u32,
+) -> Sg3Result<CommandResult> {
+    let f = open_device(path)?;
+    sg_io_file(&f, path, cmd, data, timeout, 0)
+}
+
+// Issue the given CDB with SG_IO on an open device, which was opened
+// from the given path, with the given SG_FLAG_* flags.
+fn sg_io_file(
+    f: &File,
+    path: &Path,
+    cmd: &[u8],
+    data: DataDirection,
+    timeout: u32,
+    flags: u32,
+) -> Sg3Result<CommandResult> {
+    check_cdb(cmd)?;
 
     let mut sgbuf: ffi::sg_io_hdr = Default::default();
     let mut sb = [0u8; 64];
-    let mut inquiry = StdInquiry::new();
-    let mut cmd = [0u8; 6];
+    let mut cmd = cmd.to_vec();
 
-    cmd[0] = 0x12;
-    cmd[4] = inquiry.as_buf().len() as u8;
+    match data {
+        DataDirection::None => {
+            sgbuf.dxfer_direction = ffi::SG_DXFER_NONE;
+        }
+        DataDirection::FromDevice(buf) => {
+            sgbuf.dxfer_direction = ffi::SG_DXFER_FROM_DEV;
+            sgbuf.dxfer_len = buf.len() as u32;
+            sgbuf.dxferp = buf.as_mut_ptr() as *mut c_void;
+        }
+        DataDirection::ToDevice(buf) => {
+            sgbuf.dxfer_direction = ffi::SG_DXFER_TO_DEV;
+            sgbuf.dxfer_len = buf.len() as u32;
+            sgbuf.dxferp = buf.as_ptr() as *mut c_void;
+        }
+    }
 
     sgbuf.interface_id = 'S' as i32;
-    sgbuf.dxfer_direction = ffi::SG_DXFER_FROM_DEV;
-    sgbuf.cmd_len = 6;
+    sgbuf.cmd_len = cmd.len() as u8;
     sgbuf.mx_sb_len = sb.len() as u8;
-    sgbuf.dxfer_len = inquiry.as_buf().len() as u32;
-    sgbuf.dxferp = inquiry.as_mut_buf().as_mut_ptr() as *mut c_void;
     sgbuf.cmdp = cmd.as_mut_ptr();
     sgbuf.sbp = sb.as_mut_ptr();
+    sgbuf.timeout = timeout;
+    sgbuf.flags = flags;
 
+    let submitted = Instant::now();
     if let Err(e) =
         unsafe { convert_ioctl_res!(nix_ioctl(f.as_raw_fd(), ffi::SG_IO as u64, &sgbuf)) }
     {
         return Err(Sg3Error::Nix(e));
     }
 
+    sg_io_result(&sgbuf, path, cmd, &sb, submitted)
+}
+
+// Turn the header of a finished command into its result, or into the
+// error for a command that didn't complete successfully, with the
+// sense data the driver wrote into `sb`. The command was handed to the
+// driver at `submitted`.
+fn sg_io_result(
+    sgbuf: &ffi::sg_io_hdr,
+    path: &Path,
+    cmd: Vec<u8>,
+    sb: &[u8],
+    submitted: Instant,
+) -> Sg3Result<CommandResult> {
+    let completed = Instant::now();
+    let condition_met =
+        sgbuf.status == STATUS_CONDITION_MET && sgbuf.host_status == 0 && sgbuf.driver_status == 0;
+
+    if sgbuf.info & ffi::SG_INFO_OK_MASK != ffi::SG_INFO_OK && !condition_met {
+        return Err(Sg3Error::Scsi {
+            device: path.to_path_buf(),
+            cdb: cmd,
+            status: sgbuf.status,
+            host_status: sgbuf.host_status,
+            driver_status: sgbuf.driver_status,
+            sense: sb[..(sgbuf.sb_len_wr as usize).min(sb.len())].to_vec(),
+        });
+    }
+
+    let resid = (sgbuf.resid.max(0) as usize).min(sgbuf.dxfer_len as usize);
+    Ok(CommandResult {
+        status: sgbuf.status,
+        resid,
+        transferred: sgbuf.dxfer_len as usize - resid,
+        duration: Duration::from_millis(sgbuf.duration as u64),
+        submitted,
+        completed,
+    })
+}
+
+// Send SCSI INQUIRY command to the SCSI device at the given path. The
+// length of the standard inquiry data is read first, and all of it then
+// fetched.
+pub fn inquiry<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<StdInquiry> {
+    inquiry_max_len(path, u16::MAX)
+}
+
+fn inquiry_cdb(len: u16) -> [u8; 6] {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x12;
+    BigEndian::write_u16(&mut cmd[3..5], len);
+
+    cmd
+}
+
+// The length of the whole of the standard inquiry data, from the
+// ADDITIONAL LENGTH in what has been read of it.
+fn inquiry_len(buf: &[u8]) -> usize {
+    5 + buf.get(4).cloned().unwrap_or(0) as usize
+}
+
+// Issue a standard INQUIRY with the given allocation length, returning
+// the data the device sent, and how long it says the whole of it is.
+fn inquiry_cmd<T: SgTransport + ?Sized>(path: &T, len: u16) -> Sg3Result<(Vec<u8>, usize)> {
+    let mut buf = vec![0u8; len as usize];
+    let cmd = inquiry_cdb(len);
+
+    let transferred = path
+        .execute(&cmd, DataDirection::FromDevice(&mut buf), DEFAULT_TIMEOUT)?
+        .transferred;
+    buf.truncate(transferred);
+    let total = inquiry_len(&buf);
+
+    Ok((buf, total))
+}
+
+// Send SCSI INQUIRY to the SCSI device at the given path, as inquiry()
+// does, but fetching no more than `max_len` bytes of the standard
+// inquiry data, or 36 if less. Some older devices misbehave when asked
+// for more than they have, or than 36 bytes.
+pub fn inquiry_max_len<T: SgTransport + ?Sized>(path: &T, max_len: u16) -> Sg3Result<StdInquiry> {
+    let (mut buf, total) = inquiry_cmd(path, 36)?;
+    let len = total.min(max_len as usize);
+    if len > 36 {
+        buf = inquiry_cmd(path, len as u16)?.0;
+        buf.truncate(len);
+    }
+    std_inquiry(buf)
+}
+
+// Check standard inquiry data as the device sent it. Short data reads
+// as zero.
+fn std_inquiry(mut buf: Vec<u8>) -> Sg3Result<StdInquiry> {
+    if buf.len() < 36 {
+        buf.resize(36, 0);
+    }
+
+    let inquiry = StdInquiry { buf };
     if inquiry.response_data_format() != 2 {
         return Err(Sg3Error::Io(io::Error::new(
             io::ErrorKind::Other,
@@ -186,14 +668,29 @@ pub fn inquiry(path: &Path) -> Sg3Result<StdInquiry> {
     Ok(inquiry)
 }
 
+// The raw bytes of a buffer-backed structure, which is all that needs
+// reading back to deserialize it: the decoded fields it is serialized
+// with are ignored.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawBuf {
+    raw: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
 pub struct StdInquiry {
     buf: Vec<u8>,
 }
 
 /// Struct containing the standard inquiry result, with field accessor methods.
 impl StdInquiry {
-    fn new() -> StdInquiry {
-        StdInquiry { buf: vec![0; 96] }
+    /// Wrap standard INQUIRY data captured elsewhere. It must hold at
+    /// least the first 36 bytes, which the accessors read.
+    pub fn from_buf(buf: &[u8]) -> Option<StdInquiry> {
+        if buf.len() < 36 {
+            return None;
+        }
+        Some(StdInquiry { buf: buf.to_vec() })
     }
 
     /// Get the raw return buffer containing the inquiry response.
@@ -201,10 +698,6 @@ impl StdInquiry {
         &self.buf
     }
 
-    fn as_mut_buf(&mut self) -> &mut [u8] {
-        &mut self.buf
-    }
-
     pub fn peripheral_qualifier(&self) -> PeripheralQualifier {
         to_qualifier(self.buf[0] >> 5)
     }
@@ -281,47 +774,127 @@ impl StdInquiry {
         (self.buf[7] & 0x02) >> 1
     }
 
-    pub fn vendor(&self) -> &str {
-        from_utf8(&self.buf[8..16]).unwrap()
+    /// The T10 vendor identification. Bytes that aren't valid UTF-8,
+    /// such as 0xFF padding, are replaced.
+    pub fn vendor(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.vendor_bytes())
+    }
+
+    pub fn vendor_bytes(&self) -> &[u8] {
+        &self.buf[8..16]
+    }
+
+    /// The product identification, with invalid UTF-8 replaced.
+    pub fn product_id(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.product_id_bytes())
+    }
+
+    pub fn product_id_bytes(&self) -> &[u8] {
+        &self.buf[16..32]
     }
 
-    pub fn product_id(&self) -> &str {
-        from_utf8(&self.buf[16..32]).unwrap()
+    /// The product revision level, with invalid UTF-8 replaced.
+    pub fn product_revision(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.product_revision_bytes())
     }
 
-    pub fn product_revision(&self) -> &str {
-        from_utf8(&self.buf[32..36]).unwrap()
+    pub fn product_revision_bytes(&self) -> &[u8] {
+        &self.buf[32..36]
+    }
+
+    /// The standards the device claims conformance to, from the version
+    /// descriptors in bytes 58 to 73. Unused descriptors, and any past
+    /// the end of the data, are left out.
+    pub fn version_descriptors(&self) -> Vec<VersionDescriptor> {
+        let end = self.buf.len().min(74);
+        self.buf[58.min(end)..end]
+            .chunks_exact(2)
+            .map(|d| VersionDescriptor(BigEndian::read_u16(d)))
+            .filter(|d| d.0 != 0)
+            .collect()
+    }
+
+    /// The vendor specific parameters from byte 96 on, which some
+    /// devices return, such as firmware build details.
+    pub fn vendor_specific_parameters(&self) -> &[u8] {
+        &self.buf[96.min(self.buf.len())..]
     }
 }
 
-fn inquiry_vpd(path: &Path, vpd: u8, buf: &mut [u8]) -> Sg3Result<()> {
-    let f = OpenOptions::new().read(true).open(path)?;
+/// Serialized as its decoded fields, along with the raw data as `raw`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for StdInquiry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("StdInquiry", 24)?;
+        s.serialize_field("peripheral_qualifier", &self.peripheral_qualifier())?;
+        s.serialize_field("peripheral_device_type", &self.peripheral_device_type())?;
+        s.serialize_field("rmb", &self.rmb())?;
+        s.serialize_field("lu_cong", &self.lu_cong())?;
+        s.serialize_field("version", &self.version())?;
+        s.serialize_field("norm_aca", &self.norm_aca())?;
+        s.serialize_field("hi_sup", &self.hi_sup())?;
+        s.serialize_field("response_data_format", &self.response_data_format())?;
+        s.serialize_field("sccs", &self.sccs())?;
+        s.serialize_field("acc", &self.acc())?;
+        s.serialize_field("tpgs", &self.tpgs())?;
+        s.serialize_field("third_party_copy", &self.third_party_copy())?;
+        s.serialize_field("protect", &self.protect())?;
+        s.serialize_field("enc_serv", &self.enc_serv())?;
+        s.serialize_field("multi_p", &self.multi_p())?;
+        s.serialize_field("addr16", &self.addr16())?;
+        s.serialize_field("wbus16", &self.wbus16())?;
+        s.serialize_field("sync", &self.sync())?;
+        s.serialize_field("cmd_que", &self.cmd_que())?;
+        s.serialize_field("vendor", &self.vendor())?;
+        s.serialize_field("product_id", &self.product_id())?;
+        s.serialize_field("product_revision", &self.product_revision())?;
+        s.serialize_field("version_descriptors", &self.version_descriptors())?;
+        s.serialize_field("raw", &self.buf)?;
+        s.end()
+    }
+}
 
-    let mut sgbuf: ffi::sg_io_hdr = Default::default();
-    let mut sb = [0u8; 64];
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for StdInquiry {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw: RawBuf = serde::Deserialize::deserialize(deserializer)?;
+        StdInquiry::from_buf(&raw.raw)
+            .ok_or_else(|| serde::de::Error::custom("INQUIRY data is too short"))
+    }
+}
+
+fn inquiry_vpd_cdb(vpd: u8, len: u16) -> [u8; 6] {
     let mut cmd = [0u8; 6];
 
     cmd[0] = 0x12;
     cmd[1] = 1;
     cmd[2] = vpd;
-    BigEndian::write_u16(&mut cmd[3..5], buf.len() as u16);
+    BigEndian::write_u16(&mut cmd[3..5], len);
 
-    sgbuf.interface_id = 'S' as i32;
-    sgbuf.dxfer_direction = ffi::SG_DXFER_FROM_DEV;
-    sgbuf.cmd_len = 6;
-    sgbuf.mx_sb_len = sb.len() as u8;
-    sgbuf.dxfer_len = buf.len() as u32;
-    sgbuf.dxferp = buf.as_mut_ptr() as *mut c_void;
-    sgbuf.cmdp = cmd.as_mut_ptr();
-    sgbuf.sbp = sb.as_mut_ptr();
+    cmd
+}
 
-    if let Err(e) =
-        unsafe { convert_ioctl_res!(nix_ioctl(f.as_raw_fd(), ffi::SG_IO as u64, &sgbuf)) }
-    {
-        return Err(Sg3Error::Nix(e));
-    }
+// The length of a whole VPD page, header included, from its header.
+fn vpd_page_len(buf: &[u8]) -> usize {
+    // the allocation length is 16 bits
+    (4 + BigEndian::read_u16(&buf[2..4]) as usize).min(0xffff)
+}
 
-    Ok(())
+// Returns the number of bytes the device actually returned.
+fn inquiry_vpd<T: SgTransport + ?Sized>(path: &T, vpd: u8, buf: &mut [u8]) -> Sg3Result<usize> {
+    let cmd = inquiry_vpd_cdb(vpd, buf.len() as u16);
+
+    Ok(path
+        .execute(&cmd, DataDirection::FromDevice(buf), DEFAULT_TIMEOUT)?
+        .transferred)
+}
+
+// Get a whole VPD page from the SCSI device at the given path, header
+// included, at the length it reports.
+fn inquiry_vpd_page<T: SgTransport + ?Sized>(path: &T, vpd: u8) -> Sg3Result<Vec<u8>> {
+    read_variable_length(4, |buf| inquiry_vpd(path, vpd, buf), vpd_page_len)
 }
 
 pub struct InquiryVpd80 {
@@ -330,8 +903,9 @@ pub struct InquiryVpd80 {
 
 /// Struct containing the standard inquiry result, with field accessor methods.
 impl InquiryVpd80 {
-    fn new() -> InquiryVpd80 {
-        InquiryVpd80 { buf: vec![0; 96] }
+    /// Parse a VPD 80 page captured elsewhere, header included.
+    pub fn from_buf(buf: &[u8]) -> Sg3Result<InquiryVpd80> {
+        parse_vpd_80(buf.to_vec())
     }
 
     /// Get the raw return buffer containing the inquiry response.
@@ -339,10 +913,6 @@ impl InquiryVpd80 {
         &self.buf
     }
 
-    fn as_mut_buf(&mut self) -> &mut [u8] {
-        &mut self.buf
-    }
-
     pub fn peripheral_qualifier(&self) -> PeripheralQualifier {
         to_qualifier(self.buf[0] >> 5)
     }
@@ -351,18 +921,63 @@ impl InquiryVpd80 {
         to_device_type(self.buf[0] & 0x1f)
     }
 
-    pub fn serial_number(&self) -> &str {
-        let length = BigEndian::read_u16(&self.buf[2..4]);
-        from_utf8(&self.buf[4..length as usize + 3]).unwrap()
+    /// The product serial number, with invalid UTF-8 replaced.
+    pub fn serial_number(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.serial_number_bytes())
+    }
+
+    pub fn serial_number_bytes(&self) -> &[u8] {
+        // checked against the buffer by parse_vpd_80()
+        let length = BigEndian::read_u16(&self.buf[2..4]) as usize;
+        &self.buf[4..4 + length]
+    }
+}
+
+/// Serialized as its decoded fields, along with the raw page as `raw`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for InquiryVpd80 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("InquiryVpd80", 4)?;
+        s.serialize_field("peripheral_qualifier", &self.peripheral_qualifier())?;
+        s.serialize_field("peripheral_device_type", &self.peripheral_device_type())?;
+        s.serialize_field("serial_number", &self.serial_number())?;
+        s.serialize_field("raw", &self.buf)?;
+        s.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for InquiryVpd80 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw: RawBuf = serde::Deserialize::deserialize(deserializer)?;
+        parse_vpd_80(raw.raw).map_err(serde::de::Error::custom)
     }
 }
 
+// Check that a VPD 80 page is well formed, in particular that its page
+// length doesn't run past the data the device returned.
+fn parse_vpd_80(buf: Vec<u8>) -> Sg3Result<InquiryVpd80> {
+    let invalid = |msg| Err(invalid_data(msg));
+
+    if buf.len() < 4 {
+        return invalid("VPD 80 page is too short");
+    }
+    if buf[1] != 0x80 {
+        return invalid("not a VPD 80 page");
+    }
+    if 4 + BigEndian::read_u16(&buf[2..4]) as usize > buf.len() {
+        return invalid("VPD 80 page length is longer than the data returned");
+    }
+
+    Ok(InquiryVpd80 { buf })
+}
+
 // Send SCSI INQUIRY for VPD 80 (Unit Serial Number) to the SCSI
 // device at the given path.
-pub fn inquiry_vpd_80(path: &Path) -> Sg3Result<InquiryVpd80> {
-    let mut inquiry = InquiryVpd80::new();
-    inquiry_vpd(path, 0x80, inquiry.as_mut_buf())?;
-    Ok(inquiry)
+pub fn inquiry_vpd_80<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<InquiryVpd80> {
+    parse_vpd_80(inquiry_vpd_page(path, 0x80)?)
 }
 
 fn to_protocol(ident: u8, assoc: Association, piv: u8) -> ProtocolIdentifier {
@@ -423,21 +1038,38 @@ fn slice_to_null(slc: &[u8]) -> &[u8] {
     slc
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Designator {
     Binary(Vec<u8>),
     String(String),
+    Naa(NaaDesignator),
+    Eui64(Eui64Designator),
+    ScsiName(ScsiName),
 }
 
-fn to_designator(code: u8, data: &[u8]) -> Designator {
+fn to_designator(code: u8, designator_type: u8, data: &[u8]) -> Designator {
     match code {
+        1 if designator_type == 3 => match NaaDesignator::from_buf(data) {
+            Some(naa) => Designator::Naa(naa),
+            None => Designator::Binary(Vec::from(data)),
+        },
+        1 if designator_type == 2 => match Eui64Designator::from_buf(data) {
+            Some(eui) => Designator::Eui64(eui),
+            None => Designator::Binary(Vec::from(data)),
+        },
         0..=1 => Designator::Binary(Vec::from(data)),
+        3 if designator_type == 8 => match ScsiName::from_buf(data) {
+            Some(name) => Designator::ScsiName(name),
+            None => Designator::String(String::from_utf8_lossy(slice_to_null(data)).into_owned()),
+        },
         2..=3 => Designator::String(String::from_utf8_lossy(slice_to_null(data)).into_owned()),
         _ => Designator::Binary(Vec::from(data)),
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DesignationDescriptor {
     pub protocol: ProtocolIdentifier,
     pub association: Association,
@@ -471,7 +1103,7 @@ named!(
                 protocol: to_protocol(byte0.0, to_association(byte1.2), byte1.0),
                 association: to_association(byte1.2),
                 designator_type: to_designator_type(byte1.3),
-                designator: to_designator(byte0.1, designator),
+                designator: to_designator(byte0.1, byte1.3, designator),
             })
     ))
 );
@@ -479,6 +1111,7 @@ named!(
 named!(des_descs<Vec<DesignationDescriptor>>, many0!(des_desc));
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InquiryVpd83 {
     pub qualifier: PeripheralQualifier,
     pub device_type: PeripheralDeviceType,
@@ -538,33 +1171,322 @@ named!(
     ))
 );
 
+impl InquiryVpd83 {
+    /// Parse a VPD 83 page captured elsewhere, header included.
+    pub fn from_buf(buf: &[u8]) -> Sg3Result<InquiryVpd83> {
+        let res = vpd83(buf)
+            .to_result()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "nom parse error"))?;
+        Ok(res)
+    }
+
+    // The descriptors with the given association.
+    fn associated(&self, association: Association) -> impl Iterator<Item = &DesignationDescriptor> {
+        self.descriptors
+            .iter()
+            .filter(move |desc| desc.association == association)
+    }
+
+    /// The NAA designator of the logical unit, if it has one.
+    pub fn lun_naa(&self) -> Option<&NaaDesignator> {
+        self.associated(Association::AddressedLogicalUnit)
+            .find_map(|desc| match desc.designator {
+                Designator::Naa(ref naa) => Some(naa),
+                _ => None,
+            })
+    }
+
+    /// The EUI-64 based designator of the logical unit, if it has one.
+    pub fn lun_eui64(&self) -> Option<&Eui64Designator> {
+        self.associated(Association::AddressedLogicalUnit)
+            .find_map(|desc| match desc.designator {
+                Designator::Eui64(ref eui) => Some(eui),
+                _ => None,
+            })
+    }
+
+    /// The designators naming the target port the page was read
+    /// through, such as its SAS address or iSCSI port name, leaving out
+    /// its relative port and group numbers.
+    pub fn target_port_identifiers(&self) -> impl Iterator<Item = &Designator> {
+        self.associated(Association::TargetPort)
+            .filter(|desc| {
+                matches!(
+                    desc.designator_type,
+                    DesignatorType::Naa
+                        | DesignatorType::Eui64
+                        | DesignatorType::ScsiNameString
+                        | DesignatorType::ProtocolSpecificPortIdentifier
+                )
+            })
+            .map(|desc| &desc.designator)
+    }
+
+    // The 16-bit number in a four-byte binary designator of the given
+    // type, associated with the target port.
+    fn port_number(&self, designator_type: DesignatorType) -> Option<u16> {
+        self.associated(Association::TargetPort)
+            .filter(|desc| desc.designator_type == designator_type)
+            .find_map(|desc| match desc.designator {
+                Designator::Binary(ref b) if b.len() == 4 => Some(BigEndian::read_u16(&b[2..4])),
+                _ => None,
+            })
+    }
+
+    /// The relative port identifier of the target port the page was
+    /// read through.
+    pub fn relative_port_id(&self) -> Option<u16> {
+        self.port_number(DesignatorType::RelativeTargetPortIdentifier)
+    }
+
+    /// The target port group the target port belongs to, for ALUA.
+    pub fn target_port_group(&self) -> Option<u16> {
+        self.port_number(DesignatorType::TargetPortGroup)
+    }
+}
+
 // Send SCSI INQUIRY for VPD 83 (Device Identification) to the SCSI
 // device at the given path.
-pub fn inquiry_vpd_83(path: &Path) -> Sg3Result<InquiryVpd83> {
-    let mut inquiry = [0u8; 1024];
-    inquiry_vpd(path, 0x83, &mut inquiry)?;
-    let res = vpd83(&inquiry)
-        .to_result()
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "nom parse error"))?;
-    Ok(res)
+pub fn inquiry_vpd_83<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<InquiryVpd83> {
+    InquiryVpd83::from_buf(&inquiry_vpd_page(path, 0x83)?)
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::Path;
 
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::FakeDevice;
+
+    fn fake_disk() -> FakeDevice {
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        dev.set_vpd_page(0x80, b"6001405abc123");
+        let mut naa = vec![0x01, 0x03, 0x00, 0x10, 0x60, 0x01, 0x40, 0x5a];
+        naa.extend_from_slice(&[0xbc; 12]);
+        dev.set_vpd_page(0x83, &naa);
+        dev
+    }
+
     #[test]
     fn test_inquiry() {
-        super::inquiry(Path::new("/dev/sda")).unwrap();
+        let inq = super::inquiry(&fake_disk()).unwrap();
+        assert_eq!(inq.vendor(), "LIO-ORG ");
+        assert_eq!(inq.product_revision(), "4.0 ");
     }
 
     #[test]
     fn test_inquiry_80() {
-        super::inquiry_vpd_80(Path::new("/dev/sda")).unwrap();
+        let vpd = super::inquiry_vpd_80(&fake_disk()).unwrap();
+        assert_eq!(vpd.serial_number(), "6001405abc123");
     }
 
     #[test]
     fn test_inquiry_83() {
-        super::inquiry_vpd_83(Path::new("/dev/sda")).unwrap();
+        let vpd = super::inquiry_vpd_83(&fake_disk()).unwrap();
+        assert_eq!(vpd.descriptors.len(), 1);
+    }
+
+    #[test]
+    fn test_inquiry_strings() {
+        let mut buf = vec![0u8; 96];
+        buf[8..16].copy_from_slice(b"ATA     ");
+        buf[16..32].copy_from_slice(b"Samsung SSD\xff\xff\xff\xff\xff");
+        let inquiry = super::StdInquiry { buf };
+        assert_eq!(inquiry.vendor(), "ATA     ");
+        assert_eq!(
+            inquiry.product_id(),
+            "Samsung SSD\u{fffd}\u{fffd}\u{fffd}\u{fffd}\u{fffd}"
+        );
+        assert_eq!(&inquiry.product_id_bytes()[11..], &[0xff; 5]);
+    }
+
+    #[test]
+    fn test_version_descriptors() {
+        let mut buf = vec![0u8; 96];
+        buf[58..64].copy_from_slice(&[0x00, 0xa0, 0x04, 0x60, 0x04, 0xc3]);
+        buf[66..68].copy_from_slice(&[0x0c, 0x75]);
+        let inquiry = super::StdInquiry { buf };
+        let names: Vec<_> = inquiry
+            .version_descriptors()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(names, ["SAM-5", "SPC-4", "SBC-3", "SAS-3"]);
+
+        let inquiry = super::StdInquiry::from_buf(&[0u8; 36]).unwrap();
+        assert!(inquiry.version_descriptors().is_empty());
+    }
+
+    #[test]
+    fn test_inquiry_two_step() {
+        let mut dev = fake_disk();
+        super::inquiry(&dev).unwrap();
+        assert_eq!(dev.commands(), vec![vec![0x12, 0, 0, 0, 36, 0]]);
+
+        let mut data = vec![0u8; 104];
+        data[2] = 0x06;
+        data[3] = 0x02;
+        data[4] = 99;
+        data[96..].copy_from_slice(b"build-42");
+        dev.set_inquiry(&data);
+        let inq = super::inquiry(&dev).unwrap();
+        assert_eq!(inq.as_buf().len(), 104);
+        assert_eq!(inq.vendor_specific_parameters(), b"build-42");
+        assert_eq!(dev.commands()[2], vec![0x12, 0, 0, 0, 104, 0]);
+
+        let inq = super::inquiry_max_len(&dev, 96).unwrap();
+        assert_eq!(inq.as_buf().len(), 96);
+        assert!(inq.vendor_specific_parameters().is_empty());
+    }
+
+    #[test]
+    fn test_read_variable_length() {
+        // a response of a 4-byte header giving the length of what follows
+        let respond = |data: &[u8], lens: &mut Vec<usize>, buf: &mut [u8]| {
+            lens.push(buf.len());
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        };
+        let total = |buf: &[u8]| 4 + BigEndian::read_u32(&buf[0..4]) as usize;
+
+        let mut lens = Vec::new();
+        let data = [0, 0, 0, 3, 1, 2, 3];
+        let buf =
+            super::read_variable_length(4, |buf| respond(&data, &mut lens, buf), total).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(lens, [4, 7]);
+
+        // a bogus length is capped, and what was sent kept
+        let mut lens = Vec::new();
+        let data = [0xff, 0xff, 0xff, 0xff, 1, 2];
+        let buf =
+            super::read_variable_length(4, |buf| respond(&data, &mut lens, buf), total).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(lens, [4, super::MAX_RESPONSE_LEN]);
+
+        // short data reads as zero
+        let mut lens = Vec::new();
+        let buf =
+            super::read_variable_length(4, |buf| respond(&[0], &mut lens, buf), total).unwrap();
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(lens, [4]);
+    }
+
+    #[test]
+    fn test_parse_vpd_80() {
+        let vpd = super::parse_vpd_80(b"\x00\x80\x00\x04S1N0".to_vec()).unwrap();
+        assert_eq!(vpd.serial_number(), "S1N0");
+        assert!(super::parse_vpd_80(b"\x00\x80\x00\x40S1N0".to_vec()).is_err());
+        assert!(super::parse_vpd_80(b"\x00\x83\x00\x04S1N0".to_vec()).is_err());
+        assert!(super::parse_vpd_80(vec![0, 0x80]).is_err());
+    }
+
+    #[test]
+    fn test_from_buf() {
+        assert!(super::StdInquiry::from_buf(&[0u8; 35]).is_none());
+        let mut buf = [0u8; 36];
+        buf[0] = 0x0d;
+        buf[8..16].copy_from_slice(b"LSI     ");
+        let inquiry = super::StdInquiry::from_buf(&buf).unwrap();
+        assert_eq!(
+            inquiry.peripheral_device_type(),
+            super::PeripheralDeviceType::EnclosureServices
+        );
+        assert_eq!(inquiry.vendor(), "LSI     ");
+
+        let vpd = super::InquiryVpd83::from_buf(&[
+            0x00, 0x83, 0x00, 0x0c, // header
+            0x01, 0x03, 0x00, 0x08, 0x50, 0x00, 0xc5, 0x00, 0xa1, 0xb2, 0xc3, 0xd4, // NAA
+        ])
+        .unwrap();
+        assert_eq!(vpd.descriptors.len(), 1);
+        assert_eq!(
+            vpd.descriptors[0].designator_type,
+            super::DesignatorType::Naa
+        );
+        match vpd.descriptors[0].designator {
+            super::Designator::Naa(ref naa) => assert_eq!(naa.to_string(), "5000c500a1b2c3d4"),
+            ref d => panic!("not an NAA designator: {:?}", d),
+        }
+    }
+
+    #[test]
+    fn test_vpd_83_queries() {
+        let vpd = super::InquiryVpd83::from_buf(&[
+            0x00, 0x83, 0x00, 0x34, // header
+            0x01, 0x03, 0x00, 0x08, 0x50, 0x00, 0xc5, 0x00, 0xa1, 0xb2, 0xc3, 0xd4, // LU NAA
+            0x61, 0x93, 0x00, 0x08, 0x50, 0x00, 0xc5, 0x00, 0xa1, 0xb2, 0xc3,
+            0xd5, // port NAA
+            0x61, 0x94, 0x00, 0x04, 0x00, 0x00, 0x00, 0x02, // relative target port
+            0x61, 0x95, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, // target port group
+            0x01, 0x02, 0x00, 0x08, 0x00, 0x25, 0x38, 0x01, 0x02, 0x03, 0x04,
+            0x05, // LU EUI-64
+        ])
+        .unwrap();
+        assert_eq!(vpd.lun_naa().unwrap().to_string(), "5000c500a1b2c3d4");
+        assert_eq!(vpd.lun_eui64().unwrap().company_id(), 0x002538);
+        let ports: Vec<_> = vpd.target_port_identifiers().collect();
+        assert_eq!(ports.len(), 1);
+        match ports[0] {
+            super::Designator::Naa(naa) => assert_eq!(naa.to_string(), "5000c500a1b2c3d5"),
+            d => panic!("not an NAA designator: {:?}", d),
+        }
+        assert_eq!(vpd.relative_port_id(), Some(2));
+        assert_eq!(vpd.target_port_group(), Some(1));
+
+        let vpd = super::InquiryVpd83::from_buf(&[0x00, 0x83, 0x00, 0x00]).unwrap();
+        assert!(vpd.lun_naa().is_none());
+        assert_eq!(vpd.relative_port_id(), None);
+    }
+
+    #[test]
+    fn test_transport() {
+        let dev = fake_disk();
+        super::inquiry_vpd_80(&dev).unwrap();
+        // the header, then the whole page
+        assert_eq!(
+            dev.commands(),
+            vec![vec![0x12, 1, 0x80, 0, 4, 0], vec![0x12, 1, 0x80, 0, 17, 0]]
+        );
+    }
+
+    #[test]
+    fn test_check_cdb() {
+        assert!(super::check_cdb(&[0u8; 6]).is_ok());
+        assert!(super::check_cdb(&[]).is_err());
+        // READ(10) cut short
+        assert!(super::check_cdb(&[0x28, 0, 0, 0, 0, 0]).is_err());
+        assert!(super::check_cdb(&[0x88; 16]).is_ok());
+        assert!(super::check_cdb(&[0xa0; 12]).is_ok());
+        // a 32-byte variable-length CDB
+        let mut cmd = [0u8; 32];
+        cmd[0] = 0x7f;
+        cmd[7] = 0x18;
+        assert!(super::check_cdb(&cmd).is_ok());
+        assert!(super::check_cdb(&cmd[..16]).is_err());
+        assert!(super::check_cdb(&[0u8; 253]).is_err());
+    }
+
+    #[test]
+    fn test_scsi_error_display() {
+        let err = super::Sg3Error::Scsi {
+            device: Path::new("/dev/sg1").to_path_buf(),
+            cdb: vec![0x28, 0, 0, 0, 0x10, 0, 0, 0, 8, 0],
+            status: 0x02,
+            host_status: 0,
+            driver_status: 0x08,
+            sense: vec![
+                0x70, 0, 0x03, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x11, 0x00, 0, 0, 0, 0,
+            ],
+        };
+        assert_eq!(err.opcode(), Some(0x28));
+        assert_eq!(
+            err.to_string(),
+            "SCSI error: /dev/sg1: CDB 28 00 00 00 10 00 00 00 08 00: status 0x02, \
+             host status 0x0000, driver status 0x0008, sense key MediumError, \
+             asc/ascq 0x11/0x00"
+        );
     }
 }
@@ -0,0 +1,630 @@
+//! LOG SENSE and the log pages it returns.
+
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    read_variable_length, send_diagnostic, sg_io_read, SelfTest, SenseKey, Sg3Result, SgTransport,
+};
+
+fn log_sense_cmd<T: SgTransport + ?Sized>(
+    path: &T,
+    page: u8,
+    subpage: u8,
+    buf: &mut [u8],
+) -> Sg3Result<usize> {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x4d;
+    // cumulative values
+    cmd[2] = 0x40 | (page & 0x3f);
+    cmd[3] = subpage;
+    BigEndian::write_u16(&mut cmd[7..9], buf.len() as u16);
+
+    sg_io_read(path, &cmd, buf)
+}
+
+// Send SCSI LOG SENSE to the SCSI device at the given path, returning
+// the cumulative values of the given log page and subpage, including
+// its four-byte header.
+pub fn log_sense<T: SgTransport + ?Sized>(path: &T, page: u8, subpage: u8) -> Sg3Result<Vec<u8>> {
+    read_variable_length(
+        4,
+        |buf| log_sense_cmd(path, page, subpage, buf),
+        // the allocation length is 16 bits
+        |buf| (4 + BigEndian::read_u16(&buf[2..4]) as usize).min(0xffff),
+    )
+}
+
+/// A log parameter from a log page.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogParameter<'a> {
+    pub code: u16,
+    /// The control byte (DU, TSD, ETC, TMC, and FORMAT AND LINKING).
+    pub control: u8,
+    pub value: &'a [u8],
+}
+
+// Split a log page, including its header, into its parameters.
+// A truncated final parameter is left out.
+pub fn log_parameters(page: &[u8]) -> Vec<LogParameter<'_>> {
+    let mut params = Vec::new();
+    let end = if page.len() >= 4 {
+        page.len()
+            .min(4 + BigEndian::read_u16(&page[2..4]) as usize)
+    } else {
+        0
+    };
+    let mut offset = 4;
+
+    while offset + 4 <= end {
+        let len = page[offset + 3] as usize;
+        if offset + 4 + len > end {
+            break;
+        }
+        params.push(LogParameter {
+            code: BigEndian::read_u16(&page[offset..offset + 2]),
+            control: page[offset + 2],
+            value: &page[offset + 4..offset + 4 + len],
+        });
+        offset += 4 + len;
+    }
+
+    params
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SelfTestStatus {
+    Completed,
+    /// Aborted by SEND DIAGNOSTIC with `SelfTest::AbortBackground`.
+    AbortedByCommand,
+    /// Aborted some other way, such as by a reset.
+    Aborted,
+    /// Could not complete because of an error.
+    Error,
+    FailedUnknownSegment,
+    FailedFirstSegment,
+    FailedSecondSegment,
+    FailedOtherSegment,
+    InProgress,
+    Reserved,
+}
+
+fn to_self_test_status(i: u8) -> SelfTestStatus {
+    match i {
+        0 => SelfTestStatus::Completed,
+        1 => SelfTestStatus::AbortedByCommand,
+        2 => SelfTestStatus::Aborted,
+        3 => SelfTestStatus::Error,
+        4 => SelfTestStatus::FailedUnknownSegment,
+        5 => SelfTestStatus::FailedFirstSegment,
+        6 => SelfTestStatus::FailedSecondSegment,
+        7 => SelfTestStatus::FailedOtherSegment,
+        0xf => SelfTestStatus::InProgress,
+        _ => SelfTestStatus::Reserved,
+    }
+}
+
+/// An entry from the Self-Test Results log page (0x10).
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SelfTestResult {
+    /// The SELF-TEST CODE the test was started with.
+    pub self_test_code: u8,
+    pub status: SelfTestStatus,
+    /// The segment that failed, if the status says so.
+    pub segment: u8,
+    /// Power-on hours when the test completed.
+    pub power_on_hours: u16,
+    /// The first LBA that failed, if any.
+    pub failure_lba: Option<u64>,
+    pub sense_key: SenseKey,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+fn parse_self_test_results(page: &[u8]) -> Vec<SelfTestResult> {
+    log_parameters(page)
+        .into_iter()
+        .filter(|p| p.value.len() >= 16 && p.value.iter().any(|&b| b != 0))
+        .map(|p| {
+            let v = p.value;
+            let lba = BigEndian::read_u64(&v[4..12]);
+            SelfTestResult {
+                self_test_code: v[0] >> 5,
+                status: to_self_test_status(v[0] & 0x0f),
+                segment: v[1],
+                power_on_hours: BigEndian::read_u16(&v[2..4]),
+                failure_lba: if lba == u64::MAX { None } else { Some(lba) },
+                sense_key: SenseKey::from(v[12] & 0x0f),
+                asc: v[13],
+                ascq: v[14],
+            }
+        })
+        .collect()
+}
+
+// Get the Self-Test Results log page from the SCSI device at the given
+// path, most recent first.
+pub fn self_test_results<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<SelfTestResult>> {
+    Ok(parse_self_test_results(&log_sense(path, 0x10, 0)?))
+}
+
+// Start a self-test on the SCSI device at the given path. Foreground
+// tests complete before this returns; background tests keep running,
+// and `wait_for_self_test()` waits for them.
+pub fn start_self_test<T: SgTransport + ?Sized>(path: &T, kind: SelfTest) -> Sg3Result<()> {
+    send_diagnostic(path, Some(kind), false, &[])
+}
+
+// Wait for a self-test running on the SCSI device at the given path to
+// finish, checking the Self-Test Results log page every `interval`.
+// Returns the result of the most recent test, or None if the device has
+// never run one.
+pub fn wait_for_self_test<T: SgTransport + ?Sized>(
+    path: &T,
+    interval: Duration,
+) -> Sg3Result<Option<SelfTestResult>> {
+    loop {
+        let latest = self_test_results(path)?.into_iter().next();
+        match latest {
+            Some(ref r) if r.status == SelfTestStatus::InProgress => thread::sleep(interval),
+            _ => return Ok(latest),
+        }
+    }
+}
+
+/// A TapeAlert flag, from the TapeAlert log page (0x2E).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TapeAlertFlag {
+    ReadWarning,
+    WriteWarning,
+    HardError,
+    Media,
+    ReadFailure,
+    WriteFailure,
+    MediaLife,
+    NotDataGrade,
+    WriteProtect,
+    NoRemoval,
+    CleaningMedia,
+    UnsupportedFormat,
+    RecoverableMechanicalCartridgeFailure,
+    UnrecoverableMechanicalCartridgeFailure,
+    MemoryChipInCartridgeFailure,
+    ForcedEject,
+    ReadOnlyFormat,
+    TapeDirectoryCorruptedOnLoad,
+    NearingMediaLife,
+    CleanNow,
+    CleanPeriodic,
+    ExpiredCleaningMedia,
+    InvalidCleaningTape,
+    RetensionRequested,
+    DualPortInterfaceError,
+    CoolingFanFailure,
+    PowerSupplyFailure,
+    PowerConsumption,
+    DriveMaintenance,
+    HardwareA,
+    HardwareB,
+    Interface,
+    EjectMedia,
+    MicrocodeUpdateFail,
+    DriveHumidity,
+    DriveTemperature,
+    DriveVoltage,
+    PredictiveFailure,
+    DiagnosticsRequired,
+    LostStatistics,
+    TapeDirectoryInvalidAtUnload,
+    TapeSystemAreaWriteFailure,
+    TapeSystemAreaReadFailure,
+    NoStartOfData,
+    LoadingOrThreadingFailure,
+    UnrecoverableUnloadFailure,
+    AutomationInterfaceFailure,
+    MicrocodeFailure,
+    WormMediumIntegrityCheckFailed,
+    WormMediumOverwriteAttempted,
+    /// An obsolete or reserved flag, by number.
+    Other(u8),
+}
+
+/// How urgent a TapeAlert flag is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TapeAlertSeverity {
+    Information,
+    Warning,
+    Critical,
+}
+
+fn to_tape_alert_flag(i: u8) -> TapeAlertFlag {
+    match i {
+        1 => TapeAlertFlag::ReadWarning,
+        2 => TapeAlertFlag::WriteWarning,
+        3 => TapeAlertFlag::HardError,
+        4 => TapeAlertFlag::Media,
+        5 => TapeAlertFlag::ReadFailure,
+        6 => TapeAlertFlag::WriteFailure,
+        7 => TapeAlertFlag::MediaLife,
+        8 => TapeAlertFlag::NotDataGrade,
+        9 => TapeAlertFlag::WriteProtect,
+        10 => TapeAlertFlag::NoRemoval,
+        11 => TapeAlertFlag::CleaningMedia,
+        12 => TapeAlertFlag::UnsupportedFormat,
+        13 => TapeAlertFlag::RecoverableMechanicalCartridgeFailure,
+        14 => TapeAlertFlag::UnrecoverableMechanicalCartridgeFailure,
+        15 => TapeAlertFlag::MemoryChipInCartridgeFailure,
+        16 => TapeAlertFlag::ForcedEject,
+        17 => TapeAlertFlag::ReadOnlyFormat,
+        18 => TapeAlertFlag::TapeDirectoryCorruptedOnLoad,
+        19 => TapeAlertFlag::NearingMediaLife,
+        20 => TapeAlertFlag::CleanNow,
+        21 => TapeAlertFlag::CleanPeriodic,
+        22 => TapeAlertFlag::ExpiredCleaningMedia,
+        23 => TapeAlertFlag::InvalidCleaningTape,
+        24 => TapeAlertFlag::RetensionRequested,
+        25 => TapeAlertFlag::DualPortInterfaceError,
+        26 => TapeAlertFlag::CoolingFanFailure,
+        27 => TapeAlertFlag::PowerSupplyFailure,
+        28 => TapeAlertFlag::PowerConsumption,
+        29 => TapeAlertFlag::DriveMaintenance,
+        30 => TapeAlertFlag::HardwareA,
+        31 => TapeAlertFlag::HardwareB,
+        32 => TapeAlertFlag::Interface,
+        33 => TapeAlertFlag::EjectMedia,
+        34 => TapeAlertFlag::MicrocodeUpdateFail,
+        35 => TapeAlertFlag::DriveHumidity,
+        36 => TapeAlertFlag::DriveTemperature,
+        37 => TapeAlertFlag::DriveVoltage,
+        38 => TapeAlertFlag::PredictiveFailure,
+        39 => TapeAlertFlag::DiagnosticsRequired,
+        50 => TapeAlertFlag::LostStatistics,
+        51 => TapeAlertFlag::TapeDirectoryInvalidAtUnload,
+        52 => TapeAlertFlag::TapeSystemAreaWriteFailure,
+        53 => TapeAlertFlag::TapeSystemAreaReadFailure,
+        54 => TapeAlertFlag::NoStartOfData,
+        55 => TapeAlertFlag::LoadingOrThreadingFailure,
+        56 => TapeAlertFlag::UnrecoverableUnloadFailure,
+        57 => TapeAlertFlag::AutomationInterfaceFailure,
+        58 => TapeAlertFlag::MicrocodeFailure,
+        59 => TapeAlertFlag::WormMediumIntegrityCheckFailed,
+        60 => TapeAlertFlag::WormMediumOverwriteAttempted,
+        i => TapeAlertFlag::Other(i),
+    }
+}
+
+impl TapeAlertFlag {
+    pub fn severity(&self) -> TapeAlertSeverity {
+        use TapeAlertFlag::*;
+
+        match *self {
+            Media
+            | ReadFailure
+            | WriteFailure
+            | WriteProtect
+            | RecoverableMechanicalCartridgeFailure
+            | UnrecoverableMechanicalCartridgeFailure
+            | ForcedEject
+            | CleanNow
+            | ExpiredCleaningMedia
+            | InvalidCleaningTape
+            | HardwareA
+            | HardwareB
+            | EjectMedia
+            | PredictiveFailure
+            | TapeSystemAreaWriteFailure
+            | TapeSystemAreaReadFailure
+            | NoStartOfData
+            | LoadingOrThreadingFailure
+            | UnrecoverableUnloadFailure
+            | AutomationInterfaceFailure => TapeAlertSeverity::Critical,
+            NoRemoval | CleaningMedia | UnsupportedFormat | NearingMediaLife | Other(_) => {
+                TapeAlertSeverity::Information
+            }
+            _ => TapeAlertSeverity::Warning,
+        }
+    }
+
+    /// Whether the flag asks for the drive to be cleaned.
+    pub fn is_cleaning_required(&self) -> bool {
+        matches!(
+            *self,
+            TapeAlertFlag::CleanNow | TapeAlertFlag::CleanPeriodic
+        )
+    }
+
+    /// Whether the flag reports a problem with the loaded medium rather
+    /// than the drive.
+    pub fn is_media_warning(&self) -> bool {
+        use TapeAlertFlag::*;
+
+        matches!(
+            *self,
+            Media
+                | MediaLife
+                | NotDataGrade
+                | NearingMediaLife
+                | MemoryChipInCartridgeFailure
+                | RecoverableMechanicalCartridgeFailure
+                | UnrecoverableMechanicalCartridgeFailure
+                | TapeDirectoryCorruptedOnLoad
+                | TapeDirectoryInvalidAtUnload
+                | WormMediumIntegrityCheckFailed
+        )
+    }
+}
+
+fn parse_tape_alerts(page: &[u8]) -> Vec<TapeAlertFlag> {
+    log_parameters(page)
+        .into_iter()
+        .filter(|p| (1..=64).contains(&p.code) && p.value.first().is_some_and(|v| v & 1 != 0))
+        .map(|p| to_tape_alert_flag(p.code as u8))
+        .collect()
+}
+
+// Get the TapeAlert flags that are set on the tape device at the given
+// path. Depending on how the drive is set up in the Informational
+// Exceptions mode page, reading the page may clear them.
+pub fn tape_alerts<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<TapeAlertFlag>> {
+    Ok(parse_tape_alerts(&log_sense(path, 0x2e, 0)?))
+}
+
+// The value of a counter parameter, which may be up to eight bytes.
+fn counter_value(p: &LogParameter<'_>) -> Option<u64> {
+    if p.value.is_empty() || p.value.len() > 8 {
+        return None;
+    }
+    Some(BigEndian::read_uint(p.value, p.value.len()))
+}
+
+/// Counters from the Data Compression log page (0x1B). Byte counts are
+/// split by the drive into megabytes and a remainder in bytes; they are
+/// combined here.
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompressionStatistics {
+    /// Ratio of host data to data on the medium for reads, times 100.
+    pub read_ratio: Option<u64>,
+    /// Ratio of host data to data on the medium for writes, times 100.
+    pub write_ratio: Option<u64>,
+    /// Bytes sent to the host.
+    pub bytes_to_host: Option<u64>,
+    /// Bytes read from the medium.
+    pub bytes_read_from_medium: Option<u64>,
+    /// Bytes received from the host.
+    pub bytes_from_host: Option<u64>,
+    /// Bytes written to the medium.
+    pub bytes_written_to_medium: Option<u64>,
+}
+
+fn parse_compression_statistics(page: &[u8]) -> CompressionStatistics {
+    let mut counters = [None; 10];
+    for p in log_parameters(page) {
+        if let Some(counter) = counters.get_mut(p.code as usize) {
+            *counter = counter_value(&p);
+        }
+    }
+
+    // The megabytes and bytes parameters for one count, as a total in
+    // bytes, or None if that overflows.
+    let bytes = |mb: usize| match (counters[mb], counters[mb + 1]) {
+        (None, None) => None,
+        (mb, b) => mb
+            .unwrap_or(0)
+            .checked_mul(1024 * 1024)?
+            .checked_add(b.unwrap_or(0)),
+    };
+
+    CompressionStatistics {
+        read_ratio: counters[0],
+        write_ratio: counters[1],
+        bytes_to_host: bytes(2),
+        bytes_read_from_medium: bytes(4),
+        bytes_from_host: bytes(6),
+        bytes_written_to_medium: bytes(8),
+    }
+}
+
+// Get the Data Compression log page from the tape device at the given
+// path.
+pub fn compression_statistics<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<CompressionStatistics> {
+    Ok(parse_compression_statistics(&log_sense(path, 0x1b, 0)?))
+}
+
+/// Counters from the Volume Statistics log page (0x17), about the
+/// mounted volume.
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VolumeStatistics {
+    /// The counters are for the mounted volume.
+    pub valid: bool,
+    /// How many times the volume has been threaded.
+    pub thread_count: Option<u64>,
+    pub data_sets_written: Option<u64>,
+    pub write_retries: Option<u64>,
+    pub unrecovered_write_errors: Option<u64>,
+    pub data_sets_read: Option<u64>,
+    pub read_retries: Option<u64>,
+    pub unrecovered_read_errors: Option<u64>,
+    /// Megabytes written while the volume was last mounted.
+    pub last_mount_mb_written: Option<u64>,
+    /// Megabytes read while the volume was last mounted.
+    pub last_mount_mb_read: Option<u64>,
+    pub lifetime_mb_written: Option<u64>,
+    pub lifetime_mb_read: Option<u64>,
+    /// Native capacity of the volume, in MB.
+    pub native_capacity: Option<u64>,
+    /// Native capacity used, in MB.
+    pub used_native_capacity: Option<u64>,
+    pub volume_serial_number: Option<String>,
+}
+
+fn parse_volume_statistics(page: &[u8]) -> VolumeStatistics {
+    let mut stats = VolumeStatistics::default();
+
+    for p in log_parameters(page) {
+        let value = counter_value(&p);
+        match p.code {
+            0x0000 => stats.valid = value.unwrap_or(0) != 0,
+            0x0001 => stats.thread_count = value,
+            0x0002 => stats.data_sets_written = value,
+            0x0003 => stats.write_retries = value,
+            0x0004 => stats.unrecovered_write_errors = value,
+            0x0007 => stats.data_sets_read = value,
+            0x0008 => stats.read_retries = value,
+            0x0009 => stats.unrecovered_read_errors = value,
+            0x000e => stats.last_mount_mb_written = value,
+            0x000f => stats.last_mount_mb_read = value,
+            0x0010 => stats.lifetime_mb_written = value,
+            0x0011 => stats.lifetime_mb_read = value,
+            0x0016 => stats.native_capacity = value,
+            0x0017 => stats.used_native_capacity = value,
+            0x0040 => {
+                stats.volume_serial_number = Some(
+                    String::from_utf8_lossy(p.value)
+                        .trim_end_matches([' ', '\0'])
+                        .to_string(),
+                )
+            }
+            _ => {}
+        }
+    }
+
+    stats
+}
+
+// Get the Volume Statistics log page from the tape device at the given
+// path.
+pub fn volume_statistics<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<VolumeStatistics> {
+    Ok(parse_volume_statistics(&log_sense(path, 0x17, 0)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_parameters() {
+        let page = [
+            0x10, 0, 0, 11, // header
+            0, 1, 0x03, 2, 0xaa, 0xbb, // parameter 1
+            0, 2, 0x03, 1, 0xcc, // parameter 2
+            0, 3, // truncated
+        ];
+        let params = log_parameters(&page);
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].code, 1);
+        assert_eq!(params[0].value, &[0xaa, 0xbb]);
+        assert_eq!(params[1].code, 2);
+        assert_eq!(params[1].value, &[0xcc]);
+    }
+
+    #[test]
+    fn test_parse_self_test_results() {
+        let mut page = vec![0x10, 0, 0, 40];
+        // background extended, failed second segment at LBA 0x1000
+        page.extend_from_slice(&[0, 1, 0x03, 0x10, 0x46, 2, 0x01, 0x00]);
+        page.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x10, 0, 0x03, 0x11, 0x00, 0]);
+        // unused entry
+        page.extend_from_slice(&[0, 2, 0x03, 0x10]);
+        page.extend_from_slice(&[0u8; 16]);
+        let results = parse_self_test_results(&page);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].self_test_code, 2);
+        assert_eq!(results[0].status, SelfTestStatus::FailedSecondSegment);
+        assert_eq!(results[0].power_on_hours, 0x100);
+        assert_eq!(results[0].failure_lba, Some(0x1000));
+        assert_eq!(results[0].sense_key, SenseKey::MediumError);
+        assert_eq!(results[0].asc, 0x11);
+    }
+
+    #[test]
+    fn test_parse_tape_alerts() {
+        let mut page = vec![0x2e, 0, 0, 0];
+        for code in 1..=64u16 {
+            let set = code == 4 || code == 20 || code == 45;
+            page.extend_from_slice(&[0, code as u8, 0x03, 1, set as u8]);
+        }
+        let len = page.len() as u16 - 4;
+        BigEndian::write_u16(&mut page[2..4], len);
+
+        let flags = parse_tape_alerts(&page);
+        assert_eq!(
+            flags,
+            vec![
+                TapeAlertFlag::Media,
+                TapeAlertFlag::CleanNow,
+                TapeAlertFlag::Other(45)
+            ]
+        );
+        assert_eq!(flags[0].severity(), TapeAlertSeverity::Critical);
+        assert!(flags[0].is_media_warning());
+        assert!(flags[1].is_cleaning_required());
+        assert_eq!(flags[2].severity(), TapeAlertSeverity::Information);
+    }
+
+    #[test]
+    fn test_parse_compression_statistics() {
+        let page = [
+            0x1b, 0, 0, 30, // header
+            0, 0, 0x03, 2, 0x01, 0x2c, // read ratio 3.00
+            0, 1, 0x03, 2, 0x00, 0xfa, // write ratio 2.50
+            0, 6, 0x03, 4, 0, 0, 0, 2, // 2 MB from host
+            0, 7, 0x03, 4, 0, 0, 0x10, 0, // plus 4096 bytes
+        ];
+        let stats = parse_compression_statistics(&page);
+        assert_eq!(stats.read_ratio, Some(300));
+        assert_eq!(stats.write_ratio, Some(250));
+        assert_eq!(stats.bytes_from_host, Some(2 * 1024 * 1024 + 4096));
+        assert_eq!(stats.bytes_to_host, None);
+
+        // a megabyte count too large to total in bytes
+        let page = [
+            0x1b, 0, 0, 12, // header
+            0, 2, 0x03, 8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ];
+        let stats = parse_compression_statistics(&page);
+        assert_eq!(stats.bytes_to_host, None);
+    }
+
+    #[test]
+    fn test_parse_volume_statistics() {
+        let mut page = vec![0x17, 0, 0, 0];
+        // page valid, thread count
+        page.extend_from_slice(&[0, 0x00, 0x03, 1, 1]);
+        page.extend_from_slice(&[0, 0x01, 0x03, 4, 0, 0, 0, 9]);
+        // last mount MB written and read, lifetime MB written
+        page.extend_from_slice(&[0, 0x0e, 0x03, 2, 0x01, 0x00]);
+        page.extend_from_slice(&[0, 0x0f, 0x03, 2, 0x02, 0x00]);
+        page.extend_from_slice(&[0, 0x10, 0x03, 4, 0, 0x01, 0, 0]);
+        // native capacity
+        page.extend_from_slice(&[0, 0x16, 0x03, 4, 0, 0x5b, 0x8d, 0x80]);
+        // medium mount time, which isn't one of the counters
+        page.extend_from_slice(&[0, 0x14, 0x03, 2, 0, 7]);
+        page.extend_from_slice(&[0, 0x40, 0x01, 8]);
+        page.extend_from_slice(b"A00001  ");
+        let len = page.len() as u16 - 4;
+        BigEndian::write_u16(&mut page[2..4], len);
+
+        let stats = parse_volume_statistics(&page);
+        assert!(stats.valid);
+        assert_eq!(stats.thread_count, Some(9));
+        assert_eq!(stats.last_mount_mb_written, Some(0x100));
+        assert_eq!(stats.last_mount_mb_read, Some(0x200));
+        assert_eq!(stats.lifetime_mb_written, Some(0x1_0000));
+        assert_eq!(stats.lifetime_mb_read, None);
+        assert_eq!(stats.native_capacity, Some(6_000_000));
+        assert_eq!(stats.used_native_capacity, None);
+        assert_eq!(stats.volume_serial_number.as_deref(), Some("A00001"));
+    }
+}
@@ -0,0 +1,236 @@
+//! Logical unit numbers, REPORT LUNS, and scanning the logical units of
+//! a target.
+
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    enumerate, inquiry, read_variable_length, sg_io_read, ScsiAddress, Sg3Result, SgDevice, SgFile,
+    SgTransport, StdInquiry,
+};
+
+/// An eight-byte logical unit number, in the form SAM-5 gives it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lun(pub [u8; 8]);
+
+impl Lun {
+    /// The REPORT LUNS well-known logical unit.
+    pub const REPORT_LUNS: Lun = Lun([0xc1, 0x01, 0, 0, 0, 0, 0, 0]);
+
+    /// The LUN the Linux SCSI midlayer numbers `lun`, as in the L of
+    /// H:C:T:L.
+    pub fn from_linux(lun: u64) -> Lun {
+        let mut buf = [0u8; 8];
+        for i in 0..4 {
+            BigEndian::write_u16(&mut buf[i * 2..i * 2 + 2], (lun >> (i * 16)) as u16);
+        }
+        Lun(buf)
+    }
+
+    /// How the Linux SCSI midlayer numbers this LUN: each two-byte
+    /// level of the LUN, first level lowest.
+    pub fn to_linux(self) -> u64 {
+        (0..4).fold(0, |lun, i| {
+            lun | u64::from(BigEndian::read_u16(&self.0[i * 2..i * 2 + 2])) << (i * 16)
+        })
+    }
+
+    /// Whether this is a well-known logical unit, addressed with the
+    /// extended address method.
+    pub fn is_well_known(self) -> bool {
+        self.0[0] == 0xc1
+    }
+}
+
+/// Which logical units REPORT LUNS should report, by its SELECT REPORT
+/// field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectReport {
+    /// All logical units other than well-known ones.
+    Normal,
+    WellKnown,
+    All,
+    Administrative,
+    /// Administrative logical units and their subsidiaries.
+    AdministrativeAndSubsidiary,
+    /// The subsidiaries of the addressed administrative logical unit.
+    Subsidiary,
+}
+
+fn select_report_code(select: SelectReport) -> u8 {
+    match select {
+        SelectReport::Normal => 0x00,
+        SelectReport::WellKnown => 0x01,
+        SelectReport::All => 0x02,
+        SelectReport::Administrative => 0x10,
+        SelectReport::AdministrativeAndSubsidiary => 0x11,
+        SelectReport::Subsidiary => 0x12,
+    }
+}
+
+fn report_luns_cdb(select: SelectReport, len: usize) -> [u8; 12] {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa0;
+    cmd[2] = select_report_code(select);
+    BigEndian::write_u32(&mut cmd[6..10], len as u32);
+
+    cmd
+}
+
+fn parse_luns(buf: &[u8]) -> Vec<Lun> {
+    let end = buf.len().min(8 + BigEndian::read_u32(&buf[0..4]) as usize);
+
+    buf[8.min(end)..end]
+        .chunks_exact(8)
+        .map(|chunk| {
+            let mut lun = [0u8; 8];
+            lun.copy_from_slice(chunk);
+            Lun(lun)
+        })
+        .collect()
+}
+
+// Send SCSI REPORT LUNS to the SCSI device at the given path, returning
+// the logical units of its target that `select` asks for.
+pub fn report_luns<T: SgTransport + ?Sized>(path: &T, select: SelectReport) -> Sg3Result<Vec<Lun>> {
+    let buf = read_variable_length(
+        // the smallest allocation length the command allows
+        16,
+        |buf| sg_io_read(path, &report_luns_cdb(select, buf.len()), buf),
+        |buf| 8 + BigEndian::read_u32(&buf[0..4]) as usize,
+    )?;
+
+    Ok(parse_luns(&buf))
+}
+
+/// A logical unit found by `scan_target()`.
+#[derive(Debug, Clone)]
+pub struct ScannedLun {
+    pub lun: Lun,
+    /// The sg device node of the logical unit, if the kernel has one.
+    pub device: Option<PathBuf>,
+    /// Its standard INQUIRY data, if it has a node and answered.
+    pub inquiry: Option<StdInquiry>,
+}
+
+// Match the reported LUNs of the target at `address` to the sg devices
+// on the system, and inquire of those that have one.
+fn scan<F>(
+    luns: Vec<Lun>,
+    address: ScsiAddress,
+    devices: &[SgDevice],
+    inquire: F,
+) -> Vec<ScannedLun>
+where
+    F: Fn(&Path) -> Option<StdInquiry>,
+{
+    luns.into_iter()
+        .map(|lun| {
+            let device = devices
+                .iter()
+                .find(|dev| {
+                    dev.address
+                        == ScsiAddress {
+                            lun: lun.to_linux(),
+                            ..address
+                        }
+                })
+                .map(|dev| dev.path.clone());
+            let inquiry = device.as_deref().and_then(&inquire);
+            ScannedLun {
+                lun,
+                device,
+                inquiry,
+            }
+        })
+        .collect()
+}
+
+// Send SCSI REPORT LUNS through the sg device at the given path, and
+// SCSI INQUIRY to each logical unit of its target that the kernel has
+// an sg device for. Logical units without one, such as well-known ones,
+// are listed without INQUIRY data.
+pub fn scan_target(path: &Path, select: SelectReport) -> Sg3Result<Vec<ScannedLun>> {
+    let sg = SgFile::open(path)?;
+    let address = sg.scsi_id()?.address;
+    let luns = report_luns(&sg, select)?;
+
+    Ok(scan(luns, address, &enumerate()?, |path| {
+        inquiry(path).ok()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FakeDevice, PeripheralDeviceType};
+
+    #[test]
+    fn test_lun() {
+        assert_eq!(Lun::from_linux(5).0, [0, 5, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(Lun([0x40, 0x01, 0, 0, 0, 0, 0, 0]).to_linux(), 0x4001);
+        assert_eq!(Lun::REPORT_LUNS.to_linux(), 49409);
+        assert!(Lun::REPORT_LUNS.is_well_known());
+        let lun = Lun([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+        assert_eq!(lun.to_linux(), 0x0708_0506_0304_0102);
+        assert_eq!(Lun::from_linux(lun.to_linux()), lun);
+    }
+
+    #[test]
+    fn test_report_luns() {
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        let mut data = vec![0, 0, 0, 24, 0, 0, 0, 0];
+        data.extend_from_slice(&Lun::from_linux(0).0);
+        data.extend_from_slice(&Lun::from_linux(1).0);
+        data.extend_from_slice(&Lun::REPORT_LUNS.0);
+        dev.set_response(0xa0, &data);
+
+        let luns = report_luns(&dev, SelectReport::All).unwrap();
+        assert_eq!(
+            luns,
+            [Lun::from_linux(0), Lun::from_linux(1), Lun::REPORT_LUNS]
+        );
+        let cmds = dev.commands();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(cmds[0], [0xa0, 0, 0x02, 0, 0, 0, 0, 0, 0, 16, 0, 0]);
+        assert_eq!(&cmds[1][6..10], &[0, 0, 0, 32]);
+    }
+
+    #[test]
+    fn test_scan() {
+        let address = ScsiAddress {
+            host: 2,
+            channel: 0,
+            target: 1,
+            lun: 0,
+        };
+        let device = |sg: &str, lun| SgDevice {
+            path: PathBuf::from(sg),
+            address: ScsiAddress { lun, ..address },
+            device_type: PeripheralDeviceType::DirectAccess,
+            vendor: String::new(),
+            model: String::new(),
+            revision: String::new(),
+        };
+        let mut other = device("/dev/sg2", 1);
+        other.address.target = 2;
+        let devices = [device("/dev/sg0", 0), device("/dev/sg1", 1), other];
+
+        let luns = vec![Lun::from_linux(0), Lun::from_linux(1), Lun::REPORT_LUNS];
+        let scanned = scan(luns, address, &devices, |path| {
+            if path == Path::new("/dev/sg1") {
+                StdInquiry::from_buf(&[0; 36])
+            } else {
+                None
+            }
+        });
+        assert_eq!(scanned.len(), 3);
+        assert_eq!(scanned[0].device, Some(PathBuf::from("/dev/sg0")));
+        assert!(scanned[0].inquiry.is_none());
+        assert_eq!(scanned[1].device, Some(PathBuf::from("/dev/sg1")));
+        assert!(scanned[1].inquiry.is_some());
+        assert_eq!(scanned[2].device, None);
+    }
+}
@@ -0,0 +1,282 @@
+//! READ ATTRIBUTE and WRITE ATTRIBUTE, for the Medium Auxiliary Memory
+//! (MAM) in tape cartridges.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    invalid_input, read_variable_length, sg_io, sg_io_read, DataDirection, Sg3Result, SgTransport,
+};
+
+pub const MAM_REMAINING_CAPACITY: u16 = 0x0000;
+pub const MAM_MAXIMUM_CAPACITY: u16 = 0x0001;
+pub const MAM_LOAD_COUNT: u16 = 0x0003;
+pub const MAM_SPACE_REMAINING: u16 = 0x0004;
+pub const MAM_MEDIUM_MANUFACTURER: u16 = 0x0400;
+pub const MAM_MEDIUM_SERIAL_NUMBER: u16 = 0x0401;
+pub const MAM_MEDIUM_MANUFACTURE_DATE: u16 = 0x0406;
+pub const MAM_APPLICATION_VENDOR: u16 = 0x0800;
+pub const MAM_APPLICATION_NAME: u16 = 0x0801;
+pub const MAM_APPLICATION_VERSION: u16 = 0x0802;
+pub const MAM_USER_MEDIUM_TEXT_LABEL: u16 = 0x0803;
+pub const MAM_BARCODE: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MamFormat {
+    Binary,
+    /// Printable ASCII, padded with spaces.
+    Ascii,
+    /// UTF-8 text, padded with NULs.
+    Text,
+    Reserved,
+}
+
+fn to_mam_format(i: u8) -> MamFormat {
+    match i & 0x03 {
+        0 => MamFormat::Binary,
+        1 => MamFormat::Ascii,
+        2 => MamFormat::Text,
+        _ => MamFormat::Reserved,
+    }
+}
+
+/// An attribute from a medium's auxiliary memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MamAttribute {
+    pub id: u16,
+    pub read_only: bool,
+    pub format: MamFormat,
+    pub value: Vec<u8>,
+}
+
+impl MamAttribute {
+    /// A binary attribute holding `value` in `len` bytes, for
+    /// `write_attributes()`.
+    pub fn binary(id: u16, value: u64, len: usize) -> MamAttribute {
+        let mut buf = [0u8; 8];
+        BigEndian::write_u64(&mut buf, value);
+        let len = len.min(8);
+        MamAttribute {
+            id,
+            read_only: false,
+            format: MamFormat::Binary,
+            value: buf[8 - len..].to_vec(),
+        }
+    }
+
+    /// An ASCII attribute holding `value` padded with spaces to `len`
+    /// bytes, for `write_attributes()`. A longer `value` is truncated.
+    pub fn ascii(id: u16, value: &str, len: usize) -> MamAttribute {
+        let mut buf: Vec<u8> = value.bytes().take(len).collect();
+        buf.resize(len, b' ');
+        MamAttribute {
+            id,
+            read_only: false,
+            format: MamFormat::Ascii,
+            value: buf,
+        }
+    }
+
+    /// The value of a binary attribute of up to eight bytes.
+    pub fn as_u64(&self) -> Option<u64> {
+        if self.format != MamFormat::Binary || self.value.is_empty() || self.value.len() > 8 {
+            return None;
+        }
+        Some(BigEndian::read_uint(&self.value, self.value.len()))
+    }
+
+    /// The value of an ASCII or text attribute, without its padding.
+    pub fn as_str(&self) -> Option<&str> {
+        match self.format {
+            MamFormat::Ascii | MamFormat::Text => std::str::from_utf8(&self.value)
+                .ok()
+                .map(|s| s.trim_end_matches([' ', '\0'])),
+            _ => None,
+        }
+    }
+}
+
+fn parse_attributes(buf: &[u8]) -> Vec<MamAttribute> {
+    let mut attrs = Vec::new();
+    if buf.len() < 4 {
+        return attrs;
+    }
+    let end = buf.len().min(4 + BigEndian::read_u32(&buf[0..4]) as usize);
+    let mut offset = 4;
+
+    while offset + 5 <= end {
+        let len = BigEndian::read_u16(&buf[offset + 3..offset + 5]) as usize;
+        if offset + 5 + len > end {
+            break;
+        }
+        attrs.push(MamAttribute {
+            id: BigEndian::read_u16(&buf[offset..offset + 2]),
+            read_only: buf[offset + 2] & 0x80 != 0,
+            format: to_mam_format(buf[offset + 2]),
+            value: buf[offset + 5..offset + 5 + len].to_vec(),
+        });
+        offset += 5 + len;
+    }
+
+    attrs
+}
+
+/// The attributes read from a medium's auxiliary memory.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MediumAuxiliaryMemory {
+    pub attributes: Vec<MamAttribute>,
+}
+
+impl MediumAuxiliaryMemory {
+    pub fn attribute(&self, id: u16) -> Option<&MamAttribute> {
+        self.attributes.iter().find(|a| a.id == id)
+    }
+
+    /// Space left in the partition, in MiB.
+    pub fn remaining_capacity(&self) -> Option<u64> {
+        self.attribute(MAM_REMAINING_CAPACITY)
+            .and_then(|a| a.as_u64())
+    }
+
+    /// Total space in the partition, in MiB.
+    pub fn maximum_capacity(&self) -> Option<u64> {
+        self.attribute(MAM_MAXIMUM_CAPACITY)
+            .and_then(|a| a.as_u64())
+    }
+
+    /// How many times the medium has been loaded.
+    pub fn load_count(&self) -> Option<u64> {
+        self.attribute(MAM_LOAD_COUNT).and_then(|a| a.as_u64())
+    }
+
+    pub fn medium_manufacturer(&self) -> Option<&str> {
+        self.attribute(MAM_MEDIUM_MANUFACTURER)
+            .and_then(|a| a.as_str())
+    }
+
+    pub fn medium_serial_number(&self) -> Option<&str> {
+        self.attribute(MAM_MEDIUM_SERIAL_NUMBER)
+            .and_then(|a| a.as_str())
+    }
+
+    pub fn barcode(&self) -> Option<&str> {
+        self.attribute(MAM_BARCODE).and_then(|a| a.as_str())
+    }
+}
+
+fn read_attribute_cdb(partition: u8, first_id: u16, len: u32) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x8c;
+    // ATTRIBUTE VALUES
+    cmd[1] = 0x00;
+    cmd[7] = partition;
+    BigEndian::write_u16(&mut cmd[8..10], first_id);
+    BigEndian::write_u32(&mut cmd[10..14], len);
+
+    cmd
+}
+
+// Send SCSI READ ATTRIBUTE to the tape device at the given path, reading
+// the attributes of the given partition of the loaded medium, starting
+// from `first_id`.
+pub fn read_attributes<T: SgTransport + ?Sized>(
+    path: &T,
+    partition: u8,
+    first_id: u16,
+) -> Sg3Result<MediumAuxiliaryMemory> {
+    let buf = read_variable_length(
+        4,
+        |buf| {
+            let cmd = read_attribute_cdb(partition, first_id, buf.len() as u32);
+            sg_io_read(path, &cmd, buf)
+        },
+        |buf| 4 + BigEndian::read_u32(&buf[0..4]) as usize,
+    )?;
+
+    Ok(MediumAuxiliaryMemory {
+        attributes: parse_attributes(&buf),
+    })
+}
+
+fn attribute_params(attrs: &[MamAttribute]) -> Sg3Result<Vec<u8>> {
+    let mut buf = vec![0u8; 4];
+
+    for attr in attrs {
+        if attr.value.len() > 0xffff {
+            return Err(invalid_input("attribute value is too long"));
+        }
+        let mut header = [0u8; 5];
+        BigEndian::write_u16(&mut header[0..2], attr.id);
+        header[2] = match attr.format {
+            MamFormat::Binary => 0,
+            MamFormat::Ascii => 1,
+            MamFormat::Text => 2,
+            MamFormat::Reserved => 3,
+        };
+        BigEndian::write_u16(&mut header[3..5], attr.value.len() as u16);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(&attr.value);
+    }
+    let len = buf.len() as u32 - 4;
+    BigEndian::write_u32(&mut buf[0..4], len);
+
+    Ok(buf)
+}
+
+// Send SCSI WRITE ATTRIBUTE to the tape device at the given path,
+// setting the given attributes of the given partition of the loaded
+// medium. An attribute with an empty value is deleted.
+pub fn write_attributes<T: SgTransport + ?Sized>(
+    path: &T,
+    partition: u8,
+    attrs: &[MamAttribute],
+) -> Sg3Result<()> {
+    let params = attribute_params(attrs)?;
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x8d;
+    // WRITE-THROUGH CACHE
+    cmd[1] = 0x01;
+    cmd[7] = partition;
+    BigEndian::write_u32(&mut cmd[10..14], params.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::ToDevice(&params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_attributes() {
+        let attrs = [
+            MamAttribute::binary(MAM_REMAINING_CAPACITY, 1_500_000, 8),
+            MamAttribute::binary(MAM_LOAD_COUNT, 42, 8),
+            MamAttribute::ascii(MAM_MEDIUM_SERIAL_NUMBER, "ABC123", 32),
+            MamAttribute::ascii(MAM_BARCODE, "000001L8", 32),
+        ];
+        let mut buf = attribute_params(&attrs).unwrap();
+        // the drive marks the serial number read-only
+        buf[4 + 13 * 2 + 2] |= 0x80;
+
+        let mam = MediumAuxiliaryMemory {
+            attributes: parse_attributes(&buf),
+        };
+        assert_eq!(mam.attributes.len(), 4);
+        assert_eq!(mam.remaining_capacity(), Some(1_500_000));
+        assert_eq!(mam.maximum_capacity(), None);
+        assert_eq!(mam.load_count(), Some(42));
+        assert_eq!(mam.medium_serial_number(), Some("ABC123"));
+        assert!(mam.attribute(MAM_MEDIUM_SERIAL_NUMBER).unwrap().read_only);
+        assert_eq!(mam.barcode(), Some("000001L8"));
+    }
+
+    #[test]
+    fn test_read_attribute_cdb() {
+        let cmd = read_attribute_cdb(1, MAM_MEDIUM_SERIAL_NUMBER, 0x1000);
+        assert_eq!(
+            cmd,
+            [0x8c, 0, 0, 0, 0, 0, 0, 1, 0x04, 0x01, 0, 0, 0x10, 0, 0, 0]
+        );
+    }
+}
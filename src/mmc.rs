@@ -0,0 +1,435 @@
+//! SCSI Multi-Media Commands (MMC) for optical drives.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{read_variable_length, sg_io, sg_io_read, DataDirection, Sg3Result, SgTransport};
+
+/// An MMC profile: the kind of medium a drive can handle, or has loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmcProfile {
+    /// No medium is loaded.
+    None,
+    CdRom,
+    CdR,
+    CdRw,
+    DvdRom,
+    DvdR,
+    DvdRam,
+    DvdRwRestrictedOverwrite,
+    DvdRwSequential,
+    DvdRDualLayer,
+    DvdPlusRw,
+    DvdPlusR,
+    DvdPlusRDualLayer,
+    BdRom,
+    BdRSequential,
+    BdRRandom,
+    BdRe,
+    Other(u16),
+}
+
+fn to_profile(i: u16) -> MmcProfile {
+    match i {
+        0x0000 => MmcProfile::None,
+        0x0008 => MmcProfile::CdRom,
+        0x0009 => MmcProfile::CdR,
+        0x000a => MmcProfile::CdRw,
+        0x0010 => MmcProfile::DvdRom,
+        0x0011 => MmcProfile::DvdR,
+        0x0012 => MmcProfile::DvdRam,
+        0x0013 => MmcProfile::DvdRwRestrictedOverwrite,
+        0x0014 => MmcProfile::DvdRwSequential,
+        0x0015 => MmcProfile::DvdRDualLayer,
+        0x001a => MmcProfile::DvdPlusRw,
+        0x001b => MmcProfile::DvdPlusR,
+        0x002b => MmcProfile::DvdPlusRDualLayer,
+        0x0040 => MmcProfile::BdRom,
+        0x0041 => MmcProfile::BdRSequential,
+        0x0042 => MmcProfile::BdRRandom,
+        0x0043 => MmcProfile::BdRe,
+        i => MmcProfile::Other(i),
+    }
+}
+
+/// A feature descriptor from GET CONFIGURATION.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmcFeature {
+    pub code: u16,
+    pub version: u8,
+    /// The feature is always current, whatever medium is loaded.
+    pub persistent: bool,
+    /// The feature can be used with the loaded medium.
+    pub current: bool,
+    pub data: Vec<u8>,
+}
+
+/// The features of an optical drive, from GET CONFIGURATION.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MmcConfiguration {
+    /// The profile of the loaded medium.
+    pub current_profile: MmcProfile,
+    pub features: Vec<MmcFeature>,
+}
+
+impl MmcConfiguration {
+    pub fn feature(&self, code: u16) -> Option<&MmcFeature> {
+        self.features.iter().find(|f| f.code == code)
+    }
+
+    /// The profiles the drive supports, from the Profile List feature.
+    pub fn profiles(&self) -> Vec<MmcProfile> {
+        match self.feature(0x0000) {
+            Some(f) => f
+                .data
+                .chunks_exact(4)
+                .map(|p| to_profile(BigEndian::read_u16(&p[0..2])))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+fn parse_configuration(buf: &[u8]) -> MmcConfiguration {
+    let end = buf.len().min(4 + BigEndian::read_u32(&buf[0..4]) as usize);
+    let mut features = Vec::new();
+    let mut offset = 8;
+
+    while offset + 4 <= end {
+        let len = buf[offset + 3] as usize;
+        if offset + 4 + len > end {
+            break;
+        }
+        features.push(MmcFeature {
+            code: BigEndian::read_u16(&buf[offset..offset + 2]),
+            version: (buf[offset + 2] >> 2) & 0x0f,
+            persistent: buf[offset + 2] & 0x02 != 0,
+            current: buf[offset + 2] & 0x01 != 0,
+            data: buf[offset + 4..offset + 4 + len].to_vec(),
+        });
+        offset += 4 + len;
+    }
+
+    MmcConfiguration {
+        current_profile: to_profile(BigEndian::read_u16(&buf[6..8])),
+        features,
+    }
+}
+
+fn get_configuration_cmd<T: SgTransport + ?Sized>(
+    path: &T,
+    current_only: bool,
+    buf: &mut [u8],
+) -> Sg3Result<usize> {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x46;
+    cmd[1] = if current_only { 0x01 } else { 0x00 };
+    BigEndian::write_u16(&mut cmd[7..9], buf.len() as u16);
+
+    sg_io_read(path, &cmd, buf)
+}
+
+// Send SCSI GET CONFIGURATION to the optical drive at the given path,
+// getting all the features it supports, or only those that are current
+// for the loaded medium.
+pub fn get_configuration<T: SgTransport + ?Sized>(
+    path: &T,
+    current_only: bool,
+) -> Sg3Result<MmcConfiguration> {
+    let buf = read_variable_length(
+        8,
+        |buf| get_configuration_cmd(path, current_only, buf),
+        |buf| (4 + BigEndian::read_u32(&buf[0..4]) as usize).min(0xfff8),
+    )?;
+
+    Ok(parse_configuration(&buf))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaEvent {
+    NoChange,
+    /// The user pressed the eject button.
+    EjectRequest,
+    NewMedia,
+    MediaRemoval,
+    MediaChanged,
+    BackgroundFormatCompleted,
+    BackgroundFormatRestarted,
+    Reserved,
+}
+
+fn to_media_event(i: u8) -> MediaEvent {
+    match i & 0x0f {
+        0 => MediaEvent::NoChange,
+        1 => MediaEvent::EjectRequest,
+        2 => MediaEvent::NewMedia,
+        3 => MediaEvent::MediaRemoval,
+        4 => MediaEvent::MediaChanged,
+        5 => MediaEvent::BackgroundFormatCompleted,
+        6 => MediaEvent::BackgroundFormatRestarted,
+        _ => MediaEvent::Reserved,
+    }
+}
+
+/// A media class event, from GET EVENT STATUS NOTIFICATION.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MediaEventStatus {
+    pub event: MediaEvent,
+    /// The tray or door is open.
+    pub tray_open: bool,
+    pub media_present: bool,
+}
+
+fn parse_media_event_status(buf: &[u8]) -> Option<MediaEventStatus> {
+    // NEA: no event of the requested class is available
+    if buf[2] & 0x80 != 0 || buf[2] & 0x07 != 4 || BigEndian::read_u16(&buf[0..2]) < 6 {
+        return None;
+    }
+
+    Some(MediaEventStatus {
+        event: to_media_event(buf[4]),
+        tray_open: buf[5] & 0x01 != 0,
+        media_present: buf[5] & 0x02 != 0,
+    })
+}
+
+// Send SCSI GET EVENT STATUS NOTIFICATION to the optical drive at the
+// given path, polling for media class events. Each call reports and
+// clears the oldest pending event, returning None if the drive doesn't
+// report media events.
+pub fn media_event_status<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<Option<MediaEventStatus>> {
+    let mut cmd = [0u8; 10];
+    let mut buf = [0u8; 8];
+
+    cmd[0] = 0x4a;
+    // POLLED
+    cmd[1] = 0x01;
+    // media class
+    cmd[4] = 0x10;
+    BigEndian::write_u16(&mut cmd[7..9], buf.len() as u16);
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Ok(parse_media_event_status(&buf))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscStatus {
+    Empty,
+    /// More sessions can be recorded.
+    Appendable,
+    /// Closed to further recording.
+    Finalized,
+    /// Random-access media, which don't have a status.
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Empty,
+    Incomplete,
+    Damaged,
+    Complete,
+}
+
+/// Information about the disc in an optical drive, from READ DISC
+/// INFORMATION.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DiscInformation {
+    pub status: DiscStatus,
+    pub last_session: SessionState,
+    pub erasable: bool,
+    pub first_track: u16,
+    pub sessions: u16,
+    pub first_track_in_last_session: u16,
+    pub last_track_in_last_session: u16,
+}
+
+fn parse_disc_information(buf: &[u8]) -> DiscInformation {
+    DiscInformation {
+        status: match buf[2] & 0x03 {
+            0 => DiscStatus::Empty,
+            1 => DiscStatus::Appendable,
+            2 => DiscStatus::Finalized,
+            _ => DiscStatus::Other,
+        },
+        last_session: match (buf[2] >> 2) & 0x03 {
+            0 => SessionState::Empty,
+            1 => SessionState::Incomplete,
+            2 => SessionState::Damaged,
+            _ => SessionState::Complete,
+        },
+        erasable: buf[2] & 0x10 != 0,
+        first_track: u16::from(buf[3]),
+        sessions: u16::from_be_bytes([buf[9], buf[4]]),
+        first_track_in_last_session: u16::from_be_bytes([buf[10], buf[5]]),
+        last_track_in_last_session: u16::from_be_bytes([buf[11], buf[6]]),
+    }
+}
+
+// Send SCSI READ DISC INFORMATION to the optical drive at the given
+// path.
+pub fn read_disc_information<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<DiscInformation> {
+    let mut cmd = [0u8; 10];
+    let mut buf = [0u8; 34];
+
+    cmd[0] = 0x51;
+    BigEndian::write_u16(&mut cmd[7..9], buf.len() as u16);
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Ok(parse_disc_information(&buf))
+}
+
+/// Information about a track on a disc, from READ TRACK INFORMATION.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TrackInformation {
+    pub track: u16,
+    pub session: u16,
+    pub damaged: bool,
+    /// Nothing has been recorded in the track.
+    pub blank: bool,
+    /// The track is reserved for later recording.
+    pub reserved: bool,
+    /// The TRACK MODE, the CD control nibble.
+    pub track_mode: u8,
+    pub data_mode: u8,
+    pub start: u32,
+    /// Where the next write to the track must go, if it can be written.
+    pub next_writable: Option<u32>,
+    pub free_blocks: u32,
+    pub size: u32,
+    pub last_recorded: Option<u32>,
+}
+
+fn parse_track_information(buf: &[u8]) -> TrackInformation {
+    TrackInformation {
+        track: u16::from_be_bytes([buf[32], buf[2]]),
+        session: u16::from_be_bytes([buf[33], buf[3]]),
+        damaged: buf[5] & 0x20 != 0,
+        blank: buf[6] & 0x40 != 0,
+        reserved: buf[6] & 0x80 != 0,
+        track_mode: buf[5] & 0x0f,
+        data_mode: buf[6] & 0x0f,
+        start: BigEndian::read_u32(&buf[8..12]),
+        next_writable: if buf[7] & 0x01 != 0 {
+            Some(BigEndian::read_u32(&buf[12..16]))
+        } else {
+            None
+        },
+        free_blocks: BigEndian::read_u32(&buf[16..20]),
+        size: BigEndian::read_u32(&buf[24..28]),
+        last_recorded: if buf[7] & 0x02 != 0 {
+            Some(BigEndian::read_u32(&buf[28..32]))
+        } else {
+            None
+        },
+    }
+}
+
+// Send SCSI READ TRACK INFORMATION to the optical drive at the given
+// path, getting information about the given track number.
+pub fn read_track_information<T: SgTransport + ?Sized>(
+    path: &T,
+    track: u16,
+) -> Sg3Result<TrackInformation> {
+    let mut cmd = [0u8; 10];
+    let mut buf = [0u8; 48];
+
+    cmd[0] = 0x52;
+    // address is a track number
+    cmd[1] = 0x01;
+    BigEndian::write_u32(&mut cmd[2..6], u32::from(track));
+    BigEndian::write_u16(&mut cmd[7..9], buf.len() as u16);
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Ok(parse_track_information(&buf))
+}
+
+// Get information about every track on the disc in the optical drive
+// at the given path, including the incomplete or invisible track at the
+// end of an appendable disc.
+pub fn disc_tracks<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<TrackInformation>> {
+    let disc = read_disc_information(path)?;
+
+    (disc.first_track..=disc.last_track_in_last_session)
+        .map(|track| read_track_information(path, track))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_configuration() {
+        let buf = [
+            0, 0, 0, 24, 0, 0, 0x00, 0x11, // header: DVD-R loaded
+            0x00, 0x00, 0x03, 8, // Profile List
+            0x00, 0x11, 0x01, 0, 0x00, 0x10, 0x00, 0, // DVD-R, DVD-ROM
+            0x00, 0x1e, 0x09, 0, // CD Read, version 2
+            0x00, 0x1f, // truncated
+        ];
+        let config = parse_configuration(&buf);
+        assert_eq!(config.current_profile, MmcProfile::DvdR);
+        assert_eq!(config.features.len(), 2);
+        assert_eq!(
+            config.profiles(),
+            vec![MmcProfile::DvdR, MmcProfile::DvdRom]
+        );
+        let cd_read = config.feature(0x001e).unwrap();
+        assert_eq!(cd_read.version, 2);
+        assert!(cd_read.current && !cd_read.persistent);
+    }
+
+    #[test]
+    fn test_parse_media_event_status() {
+        let status = parse_media_event_status(&[0, 6, 0x04, 0x10, 0x03, 0x01, 0, 0]).unwrap();
+        assert_eq!(status.event, MediaEvent::MediaRemoval);
+        assert!(status.tray_open && !status.media_present);
+        assert_eq!(
+            parse_media_event_status(&[0, 2, 0x84, 0x10, 0, 0, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_disc_information() {
+        let mut buf = [0u8; 34];
+        // appendable, incomplete last session, erasable
+        buf[2] = 0x15;
+        buf[3] = 1;
+        buf[4] = 2;
+        buf[5] = 3;
+        buf[6] = 3;
+        let disc = parse_disc_information(&buf);
+        assert_eq!(disc.status, DiscStatus::Appendable);
+        assert_eq!(disc.last_session, SessionState::Incomplete);
+        assert!(disc.erasable);
+        assert_eq!(disc.sessions, 2);
+        assert_eq!(disc.last_track_in_last_session, 3);
+    }
+
+    #[test]
+    fn test_parse_track_information() {
+        let mut buf = [0u8; 48];
+        buf[2] = 3;
+        buf[3] = 2;
+        buf[5] = 0x04;
+        buf[6] = 0x41;
+        buf[7] = 0x01;
+        BigEndian::write_u32(&mut buf[8..12], 0x1000);
+        BigEndian::write_u32(&mut buf[12..16], 0x1000);
+        BigEndian::write_u32(&mut buf[16..20], 0x20000);
+        let track = parse_track_information(&buf);
+        assert_eq!((track.track, track.session), (3, 2));
+        assert!(track.blank && !track.reserved);
+        assert_eq!(track.track_mode, 4);
+        assert_eq!(track.next_writable, Some(0x1000));
+        assert_eq!(track.last_recorded, None);
+        assert_eq!(track.free_blocks, 0x20000);
+    }
+}
@@ -0,0 +1,146 @@
+//! Grouping device nodes by the logical unit they lead to, as multipath
+//! tools do.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::{inquiry_vpd_83, Eui64Designator, InquiryVpd83, NaaDesignator, SgTransport};
+
+/// What identifies a logical unit across the paths to it: its NAA
+/// designator, or failing that its EUI-64 based one.
+///
+/// It displays in the SCSI name string form, such as `naa.5000c500a1b2c3d4`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LunIdentity {
+    Naa(NaaDesignator),
+    Eui64(Eui64Designator),
+}
+
+impl LunIdentity {
+    /// The identity given by a Device Identification VPD page, if it has
+    /// a logical unit designator of either kind.
+    pub fn from_vpd83(vpd: &InquiryVpd83) -> Option<LunIdentity> {
+        vpd.lun_naa()
+            .cloned()
+            .map(LunIdentity::Naa)
+            .or_else(|| vpd.lun_eui64().cloned().map(LunIdentity::Eui64))
+    }
+}
+
+impl fmt::Display for LunIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LunIdentity::Naa(naa) => write!(f, "naa.{}", naa),
+            LunIdentity::Eui64(eui) => write!(f, "eui.{}", eui),
+        }
+    }
+}
+
+/// The device nodes that are paths to one logical unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LunPaths {
+    pub identity: LunIdentity,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Device nodes grouped by logical unit.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PathGroups {
+    /// One group per logical unit, in the order each was first seen.
+    pub groups: Vec<LunPaths>,
+    /// Nodes that couldn't be identified, because VPD 83 failed on them
+    /// or has no logical unit designator to go by.
+    pub unidentified: Vec<PathBuf>,
+}
+
+// Group the given devices by the identity VPD 83 gives each.
+fn group<'a, T, I>(devices: I) -> PathGroups
+where
+    T: SgTransport + ?Sized + 'a,
+    I: IntoIterator<Item = (&'a Path, &'a T)>,
+{
+    let mut groups = PathGroups::default();
+
+    for (path, dev) in devices {
+        let identity = match inquiry_vpd_83(dev)
+            .ok()
+            .and_then(|vpd| LunIdentity::from_vpd83(&vpd))
+        {
+            Some(identity) => identity,
+            None => {
+                groups.unidentified.push(path.to_path_buf());
+                continue;
+            }
+        };
+        match groups.groups.iter_mut().find(|g| g.identity == identity) {
+            Some(group) => group.paths.push(path.to_path_buf()),
+            None => groups.groups.push(LunPaths {
+                identity,
+                paths: vec![path.to_path_buf()],
+            }),
+        }
+    }
+
+    groups
+}
+
+// Send SCSI INQUIRY for VPD 83 to each of the given sg or block device
+// nodes, and group them by the logical unit they are paths to. Nodes
+// that fail or can't be identified are reported apart, rather than
+// failing the whole grouping, since failed paths are to be expected.
+pub fn group_paths<P: AsRef<Path>>(paths: &[P]) -> PathGroups {
+    group(paths.iter().map(|p| (p.as_ref(), p.as_ref())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeDevice;
+
+    #[test]
+    fn test_group() {
+        let naa = |last: u8| {
+            let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+            dev.set_vpd_page(
+                0x83,
+                &[
+                    0x01, 0x03, 0x00, 0x08, 0x50, 0x01, 0x40, 0x50, 0, 0, 0, last,
+                ],
+            );
+            dev
+        };
+        let mut eui = FakeDevice::new("NVMe", "disk1", "1.0");
+        eui.set_vpd_page(
+            0x83,
+            &[0x01, 0x02, 0x00, 0x08, 0x00, 0x25, 0x38, 1, 2, 3, 4, 5],
+        );
+        let devices = [
+            (PathBuf::from("/dev/sg0"), naa(1)),
+            (PathBuf::from("/dev/sg1"), naa(2)),
+            (
+                PathBuf::from("/dev/sg2"),
+                FakeDevice::new("ATA", "disk2", "1.0"),
+            ),
+            (PathBuf::from("/dev/sg3"), eui),
+            (PathBuf::from("/dev/sg4"), naa(1)),
+        ];
+
+        let groups = group(devices.iter().map(|(p, d)| (p.as_path(), d)));
+        assert_eq!(groups.groups.len(), 3);
+        assert_eq!(
+            groups.groups[0].identity.to_string(),
+            "naa.5001405000000001"
+        );
+        assert_eq!(
+            groups.groups[0].paths,
+            [PathBuf::from("/dev/sg0"), PathBuf::from("/dev/sg4")]
+        );
+        assert_eq!(groups.groups[1].paths, [PathBuf::from("/dev/sg1")]);
+        assert_eq!(
+            groups.groups[2].identity.to_string(),
+            "eui.0025380102030405"
+        );
+        assert_eq!(groups.unidentified, [PathBuf::from("/dev/sg2")]);
+    }
+}
@@ -0,0 +1,429 @@
+//! End-to-end protection information (T10 DIF): reading and writing it
+//! along with the data with RDPROTECT and WRPROTECT, and generating and
+//! checking the guard, application and reference tags of each block.
+//!
+//! SG_IO has no way to pass protection information in a buffer of its
+//! own, as the block layer's integrity (DIX) support does, bsg included,
+//! so it is transferred interleaved with the data, eight bytes after
+//! each logical block. The functions here split it out into, and join it
+//! from, a buffer apart. Devices formatted with more than one protection
+//! information interval per block are not handled.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    invalid_input, sg_io, BlockCount, BlockSize, DataDirection, Lba, ProtectionType, Sg3Result,
+    SgTransport,
+};
+
+/// The length of the protection information of one block.
+pub const PI_LEN: usize = 8;
+
+/// The protection information of one logical block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtectionInfo {
+    /// The CRC of the block's data, from `crc_t10dif()`.
+    pub guard: u16,
+    pub app_tag: u16,
+    pub ref_tag: u32,
+}
+
+impl ProtectionInfo {
+    /// Parse the eight bytes of protection information for a block.
+    pub fn from_buf(buf: &[u8]) -> Option<ProtectionInfo> {
+        if buf.len() < PI_LEN {
+            return None;
+        }
+        Some(ProtectionInfo {
+            guard: BigEndian::read_u16(&buf[0..2]),
+            app_tag: BigEndian::read_u16(&buf[2..4]),
+            ref_tag: BigEndian::read_u32(&buf[4..8]),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; PI_LEN] {
+        let mut buf = [0u8; PI_LEN];
+        BigEndian::write_u16(&mut buf[0..2], self.guard);
+        BigEndian::write_u16(&mut buf[2..4], self.app_tag);
+        BigEndian::write_u32(&mut buf[4..8], self.ref_tag);
+        buf
+    }
+
+    // Whether the device is to skip checking this block: an
+    // application tag of all ones, and for Type 3 a reference tag of all
+    // ones as well.
+    fn is_escape(&self, protection: ProtectionType) -> bool {
+        match protection {
+            ProtectionType::Type3 => self.app_tag == 0xffff && self.ref_tag == 0xffff_ffff,
+            _ => self.app_tag == 0xffff,
+        }
+    }
+}
+
+/// The CRC that is the guard tag of a block: CRC-16 with polynomial
+/// 8BB7h, as SBC-4 gives it.
+pub fn crc_t10dif(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &b| {
+        (0..8).fold(crc ^ (u16::from(b) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8bb7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// The reference tag of the `i`th block of a transfer whose first block
+// has `ref_tag`: for Type 1 and 2 protection it goes up by one a block,
+// and for Type 3 it is the same for every block.
+fn block_ref_tag(protection: ProtectionType, ref_tag: u32, i: usize) -> u32 {
+    match protection {
+        ProtectionType::Type3 => ref_tag,
+        _ => ref_tag.wrapping_add(i as u32),
+    }
+}
+
+// How many blocks of `block_size` `data` is, checking that it is a
+// whole number of them.
+fn whole_blocks(data: &[u8], block_size: BlockSize) -> Sg3Result<usize> {
+    match block_size.blocks(data.len() as u64) {
+        Some(blocks) => Ok(blocks.0 as usize),
+        None => Err(invalid_input(
+            "data must be a whole number of blocks of a nonzero size",
+        )),
+    }
+}
+
+/// Generate protection information for each block of `data`, with the
+/// given application tag, and reference tags from `ref_tag`. For Type 1
+/// protection that is the low 32 bits of the first block's LBA; for
+/// Type 2 it is the expected initial reference tag that READ(32) or
+/// WRITE(32) gives; for Type 3 any value the application likes.
+///
+/// `data` must be a whole number of blocks.
+pub fn generate_pi(
+    data: &[u8],
+    block_size: BlockSize,
+    protection: ProtectionType,
+    ref_tag: u32,
+    app_tag: u16,
+) -> Sg3Result<Vec<ProtectionInfo>> {
+    whole_blocks(data, block_size)?;
+
+    Ok(data
+        .chunks(block_size.0 as usize)
+        .enumerate()
+        .map(|(i, block)| ProtectionInfo {
+            guard: crc_t10dif(block),
+            app_tag,
+            ref_tag: block_ref_tag(protection, ref_tag, i),
+        })
+        .collect())
+}
+
+/// Which tag of a block's protection information didn't match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiCheck {
+    Guard,
+    ApplicationTag,
+    ReferenceTag,
+}
+
+/// The first block found whose protection information doesn't match its
+/// data, counting from the start of the transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PiMismatch {
+    pub block: usize,
+    pub check: PiCheck,
+}
+
+/// Check the protection information of each block of `data` as a device
+/// would: the guard tag always, the application tag if one is expected,
+/// and for Type 1 and 2 the reference tags from `ref_tag`, as for
+/// `generate_pi()`. Blocks with escape tags are skipped. Returns the
+/// first mismatch, if any.
+///
+/// `data` must be a whole number of blocks, with an entry in `pi` for
+/// each.
+pub fn verify_pi(
+    data: &[u8],
+    pi: &[ProtectionInfo],
+    block_size: BlockSize,
+    protection: ProtectionType,
+    ref_tag: u32,
+    app_tag: Option<u16>,
+) -> Sg3Result<Option<PiMismatch>> {
+    if whole_blocks(data, block_size)? != pi.len() {
+        return Err(invalid_input(
+            "protection information must have an entry for each block",
+        ));
+    }
+
+    Ok(data
+        .chunks(block_size.0 as usize)
+        .zip(pi)
+        .enumerate()
+        .filter(|(_, (_, pi))| !pi.is_escape(protection))
+        .find_map(|(block, (data, pi))| {
+            let check = if pi.guard != crc_t10dif(data) {
+                PiCheck::Guard
+            } else if app_tag.is_some() && app_tag != Some(pi.app_tag) {
+                PiCheck::ApplicationTag
+            } else if protection != ProtectionType::Type3
+                && pi.ref_tag != block_ref_tag(protection, ref_tag, block)
+            {
+                PiCheck::ReferenceTag
+            } else {
+                return None;
+            };
+            Some(PiMismatch { block, check })
+        }))
+}
+
+/// The tags READ(32) and WRITE(32) tell a device formatted with Type 2
+/// protection to expect of the first block of the transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExpectedTags {
+    pub ref_tag: u32,
+    pub app_tag: u16,
+    /// Which bits of the application tag to check.
+    pub app_tag_mask: u16,
+}
+
+// READ(16) or WRITE(16) with the given RDPROTECT or WRPROTECT, or with
+// expected tags, READ(32) or WRITE(32).
+fn protected_cdb(
+    write: bool,
+    lba: Lba,
+    blocks: BlockCount,
+    protect: u8,
+    tags: Option<ExpectedTags>,
+) -> Sg3Result<Vec<u8>> {
+    if protect == 0 || protect > 7 {
+        return Err(invalid_input("protect field must be from 1 to 7"));
+    }
+    if blocks.0 > u64::from(u32::MAX) {
+        return Err(invalid_input("too many blocks for the command"));
+    }
+
+    let cmd = match tags {
+        None => {
+            let mut cmd = vec![0u8; 16];
+            cmd[0] = if write { 0x8a } else { 0x88 };
+            cmd[1] = protect << 5;
+            BigEndian::write_u64(&mut cmd[2..10], lba.0);
+            BigEndian::write_u32(&mut cmd[10..14], blocks.0 as u32);
+            cmd
+        }
+        Some(tags) => {
+            let mut cmd = vec![0u8; 32];
+            cmd[0] = 0x7f;
+            cmd[7] = 0x18;
+            BigEndian::write_u16(&mut cmd[8..10], if write { 0x000b } else { 0x0009 });
+            cmd[10] = protect << 5;
+            BigEndian::write_u64(&mut cmd[12..20], lba.0);
+            BigEndian::write_u32(&mut cmd[20..24], tags.ref_tag);
+            BigEndian::write_u16(&mut cmd[24..26], tags.app_tag);
+            BigEndian::write_u16(&mut cmd[26..28], tags.app_tag_mask);
+            BigEndian::write_u32(&mut cmd[28..32], blocks.0 as u32);
+            cmd
+        }
+    };
+
+    Ok(cmd)
+}
+
+// The block size of a transfer of `blocks` blocks of `data`, checking
+// that `pi` holds one entry per block.
+fn transfer_block_size(data: usize, pi: usize, blocks: BlockCount) -> Sg3Result<usize> {
+    if blocks.0 == 0 || !(data as u64).is_multiple_of(blocks.0) || pi as u64 != blocks.0 {
+        return Err(invalid_input(
+            "data must be whole blocks, with protection information for each",
+        ));
+    }
+    Ok(data / blocks.0 as usize)
+}
+
+// Send SCSI READ(16) with the given RDPROTECT to the SCSI device at the
+// given path, or READ(32) if expected tags are given, as a device with
+// Type 2 protection needs. `blocks` blocks from `lba` are read into
+// `data`, and the protection information of each into `pi`.
+pub fn read_protected<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    rdprotect: u8,
+    tags: Option<ExpectedTags>,
+    data: &mut [u8],
+    pi: &mut [ProtectionInfo],
+) -> Sg3Result<()> {
+    let block_size = transfer_block_size(data.len(), pi.len(), blocks)?;
+    let cmd = protected_cdb(false, lba, blocks, rdprotect, tags)?;
+
+    let mut buf = vec![0u8; data.len() + pi.len() * PI_LEN];
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    for ((chunk, data), pi) in buf
+        .chunks(block_size + PI_LEN)
+        .zip(data.chunks_mut(block_size))
+        .zip(pi.iter_mut())
+    {
+        data.copy_from_slice(&chunk[..block_size]);
+        *pi = ProtectionInfo::from_buf(&chunk[block_size..]).unwrap_or_default();
+    }
+    Ok(())
+}
+
+// Send SCSI WRITE(16) with the given WRPROTECT to the SCSI device at
+// the given path, or WRITE(32) if expected tags are given, writing
+// `data` to the blocks from `lba` along with the protection information
+// for each in `pi`, such as `generate_pi()` gives.
+pub fn write_protected<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    wrprotect: u8,
+    tags: Option<ExpectedTags>,
+    data: &[u8],
+    pi: &[ProtectionInfo],
+) -> Sg3Result<()> {
+    let block_size = transfer_block_size(data.len(), pi.len(), blocks)?;
+    let cmd = protected_cdb(true, lba, blocks, wrprotect, tags)?;
+
+    let mut buf = Vec::with_capacity(data.len() + pi.len() * PI_LEN);
+    for (data, pi) in data.chunks(block_size).zip(pi) {
+        buf.extend_from_slice(data);
+        buf.extend_from_slice(&pi.to_bytes());
+    }
+    sg_io(path, &cmd, DataDirection::ToDevice(&buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeDevice;
+
+    #[test]
+    fn test_crc_t10dif() {
+        assert_eq!(crc_t10dif(b"123456789"), 0xd0db);
+        assert_eq!(crc_t10dif(&[0u8; 512]), 0);
+    }
+
+    #[test]
+    fn test_pi() {
+        let data: Vec<u8> = (0..2048).map(|i| (i * 7) as u8).collect();
+        let size = BlockSize(512);
+
+        let pi = generate_pi(&data, size, ProtectionType::Type1, 100, 0x1234).unwrap();
+        assert_eq!(pi.len(), 4);
+        assert_eq!(pi[3].ref_tag, 103);
+        assert_eq!(pi[0].guard, crc_t10dif(&data[..512]));
+        assert_eq!(ProtectionInfo::from_buf(&pi[1].to_bytes()), Some(pi[1]));
+        assert_eq!(
+            verify_pi(&data, &pi, size, ProtectionType::Type1, 100, Some(0x1234)).unwrap(),
+            None
+        );
+        assert_eq!(
+            verify_pi(&data, &pi, size, ProtectionType::Type1, 101, None).unwrap(),
+            Some(PiMismatch {
+                block: 0,
+                check: PiCheck::ReferenceTag,
+            })
+        );
+        assert_eq!(
+            verify_pi(&data, &pi, size, ProtectionType::Type1, 100, Some(1)).unwrap(),
+            Some(PiMismatch {
+                block: 0,
+                check: PiCheck::ApplicationTag,
+            })
+        );
+
+        let mut bad = pi.clone();
+        bad[2].guard ^= 1;
+        assert_eq!(
+            verify_pi(&data, &bad, size, ProtectionType::Type1, 100, None).unwrap(),
+            Some(PiMismatch {
+                block: 2,
+                check: PiCheck::Guard,
+            })
+        );
+        bad[2].app_tag = 0xffff;
+        assert_eq!(
+            verify_pi(&data, &bad, size, ProtectionType::Type1, 100, None).unwrap(),
+            None
+        );
+
+        // Type 3 reference tags don't count up, and aren't checked
+        let pi = generate_pi(&data, size, ProtectionType::Type3, 7, 0).unwrap();
+        assert!(pi.iter().all(|pi| pi.ref_tag == 7));
+        assert_eq!(
+            verify_pi(&data, &pi, size, ProtectionType::Type3, 0, None).unwrap(),
+            None
+        );
+
+        // partial blocks, a zero block size, and missing entries are
+        // rejected
+        assert!(generate_pi(&data[..100], size, ProtectionType::Type1, 0, 0).is_err());
+        assert!(generate_pi(&data, BlockSize(0), ProtectionType::Type1, 0, 0).is_err());
+        assert!(verify_pi(&data[..100], &pi, size, ProtectionType::Type3, 0, None).is_err());
+        assert!(verify_pi(&data, &pi, BlockSize(0), ProtectionType::Type3, 0, None).is_err());
+        assert!(verify_pi(&data, &pi[..3], size, ProtectionType::Type3, 0, None).is_err());
+    }
+
+    #[test]
+    fn test_protected_cdb() {
+        let cmd = protected_cdb(false, Lba(0x1000), BlockCount(8), 1, None).unwrap();
+        assert_eq!(
+            cmd,
+            [0x88, 0x20, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 8, 0, 0]
+        );
+        let tags = ExpectedTags {
+            ref_tag: 0x1000,
+            app_tag: 0xabcd,
+            app_tag_mask: 0xffff,
+        };
+        let cmd = protected_cdb(true, Lba(0x1000), BlockCount(8), 3, Some(tags)).unwrap();
+        assert_eq!(cmd.len(), 32);
+        assert_eq!(&cmd[7..11], &[0x18, 0x00, 0x0b, 0x60]);
+        assert_eq!(
+            &cmd[20..32],
+            &[0, 0, 0x10, 0, 0xab, 0xcd, 0xff, 0xff, 0, 0, 0, 8]
+        );
+        assert!(protected_cdb(false, Lba(0), BlockCount(1), 0, None).is_err());
+        assert!(protected_cdb(false, Lba(0), BlockCount(1), 8, None).is_err());
+    }
+
+    #[test]
+    fn test_read_protected() {
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        let pi = generate_pi(&data, BlockSize(512), ProtectionType::Type1, 0, 0).unwrap();
+        let mut interleaved = data[..512].to_vec();
+        interleaved.extend_from_slice(&pi[0].to_bytes());
+        interleaved.extend_from_slice(&data[512..]);
+        interleaved.extend_from_slice(&pi[1].to_bytes());
+
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        dev.set_response(0x88, &interleaved);
+        dev.set_response(0x8a, &[]);
+
+        let mut buf = vec![0u8; 1024];
+        let mut buf_pi = [ProtectionInfo::default(); 2];
+        read_protected(&dev, Lba(0), BlockCount(2), 1, None, &mut buf, &mut buf_pi).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(buf_pi[..], pi[..]);
+        assert!(read_protected(
+            &dev,
+            Lba(0),
+            BlockCount(2),
+            1,
+            None,
+            &mut buf,
+            &mut buf_pi[..1]
+        )
+        .is_err());
+
+        write_protected(&dev, Lba(0), BlockCount(2), 1, None, &data, &pi).unwrap();
+        assert_eq!(dev.commands().last().unwrap()[..2], [0x8a, 0x20]);
+    }
+}
@@ -0,0 +1,594 @@
+//! Persistent reservations (PERSISTENT RESERVE IN and OUT).
+
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    invalid_data, invalid_input, read_variable_length, sg_io, sg_io_read, DataDirection, SenseKey,
+    Sg3Error, Sg3Result, SgTransport,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationType {
+    WriteExclusive,
+    ExclusiveAccess,
+    WriteExclusiveRegistrantsOnly,
+    ExclusiveAccessRegistrantsOnly,
+    WriteExclusiveAllRegistrants,
+    ExclusiveAccessAllRegistrants,
+    Reserved,
+}
+
+fn to_reservation_type(i: u8) -> ReservationType {
+    match i {
+        1 => ReservationType::WriteExclusive,
+        3 => ReservationType::ExclusiveAccess,
+        5 => ReservationType::WriteExclusiveRegistrantsOnly,
+        6 => ReservationType::ExclusiveAccessRegistrantsOnly,
+        7 => ReservationType::WriteExclusiveAllRegistrants,
+        8 => ReservationType::ExclusiveAccessAllRegistrants,
+        _ => ReservationType::Reserved,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReservationScope {
+    LogicalUnit,
+    Reserved,
+}
+
+fn to_reservation_scope(i: u8) -> ReservationScope {
+    match i {
+        0 => ReservationScope::LogicalUnit,
+        _ => ReservationScope::Reserved,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrInServiceAction {
+    ReadKeys,
+    ReadReservation,
+    ReportCapabilities,
+    ReadFullStatus,
+}
+
+/// Registered reservation keys, from READ KEYS.
+#[derive(Debug)]
+pub struct PrKeys {
+    pub generation: u32,
+    pub keys: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub struct Reservation {
+    pub key: u64,
+    pub scope: ReservationScope,
+    pub reservation_type: ReservationType,
+}
+
+/// The current reservation, if any, from READ RESERVATION.
+#[derive(Debug)]
+pub struct PrReservation {
+    pub generation: u32,
+    pub reservation: Option<Reservation>,
+}
+
+/// Persistent reservation features supported by the device, from
+/// REPORT CAPABILITIES.
+#[derive(Debug)]
+pub struct PrCapabilities {
+    pub replace_lost_reservation_capable: bool,
+    pub compatible_reservation_handling: bool,
+    pub specify_initiator_ports_capable: bool,
+    pub all_target_ports_capable: bool,
+    pub persist_through_power_loss_capable: bool,
+    pub allow_commands: u8,
+    pub persist_through_power_loss_activated: bool,
+    /// Reservation types the device supports, or empty if it does not
+    /// report them.
+    pub supported_types: Vec<ReservationType>,
+}
+
+/// Registration and reservation status of one I_T nexus, from READ
+/// FULL STATUS.
+#[derive(Debug)]
+pub struct PrStatusDescriptor {
+    pub key: u64,
+    pub all_target_ports: bool,
+    pub reservation_holder: bool,
+    pub scope: ReservationScope,
+    pub reservation_type: ReservationType,
+    pub relative_target_port: u16,
+    pub transport_id: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct PrFullStatus {
+    pub generation: u32,
+    pub descriptors: Vec<PrStatusDescriptor>,
+}
+
+#[derive(Debug)]
+pub enum PrInResponse {
+    Keys(PrKeys),
+    Reservation(PrReservation),
+    Capabilities(PrCapabilities),
+    FullStatus(PrFullStatus),
+}
+
+fn pr_parse_error() -> Sg3Error {
+    invalid_data("Invalid persistent reserve in data")
+}
+
+fn parse_keys(buf: &[u8]) -> PrKeys {
+    PrKeys {
+        generation: BigEndian::read_u32(&buf[0..4]),
+        keys: buf[8..].chunks_exact(8).map(BigEndian::read_u64).collect(),
+    }
+}
+
+fn parse_reservation(buf: &[u8]) -> PrReservation {
+    let reservation = if buf.len() >= 24 {
+        Some(Reservation {
+            key: BigEndian::read_u64(&buf[8..16]),
+            scope: to_reservation_scope(buf[21] >> 4),
+            reservation_type: to_reservation_type(buf[21] & 0x0f),
+        })
+    } else {
+        None
+    };
+
+    PrReservation {
+        generation: BigEndian::read_u32(&buf[0..4]),
+        reservation,
+    }
+}
+
+fn parse_capabilities(buf: &[u8]) -> Sg3Result<PrCapabilities> {
+    if buf.len() < 6 {
+        return Err(pr_parse_error());
+    }
+
+    let mut supported_types = Vec::new();
+    if buf[3] & 0x80 != 0 {
+        let mask = BigEndian::read_u16(&buf[4..6]);
+        for &(bit, code) in &[
+            (0x0200, 1),
+            (0x0800, 3),
+            (0x2000, 5),
+            (0x4000, 6),
+            (0x8000, 7),
+            (0x0001, 8),
+        ] {
+            if mask & bit != 0 {
+                supported_types.push(to_reservation_type(code));
+            }
+        }
+    }
+
+    Ok(PrCapabilities {
+        replace_lost_reservation_capable: buf[2] & 0x80 != 0,
+        compatible_reservation_handling: buf[2] & 0x10 != 0,
+        specify_initiator_ports_capable: buf[2] & 0x08 != 0,
+        all_target_ports_capable: buf[2] & 0x04 != 0,
+        persist_through_power_loss_capable: buf[2] & 0x01 != 0,
+        allow_commands: (buf[3] & 0x70) >> 4,
+        persist_through_power_loss_activated: buf[3] & 0x01 != 0,
+        supported_types,
+    })
+}
+
+fn parse_full_status(buf: &[u8]) -> Sg3Result<PrFullStatus> {
+    let mut descriptors = Vec::new();
+    let mut offset = 8;
+
+    while offset + 24 <= buf.len() {
+        let desc = &buf[offset..];
+        let tid_len = BigEndian::read_u32(&desc[20..24]) as usize;
+        if desc.len() < 24 + tid_len {
+            return Err(pr_parse_error());
+        }
+
+        descriptors.push(PrStatusDescriptor {
+            key: BigEndian::read_u64(&desc[0..8]),
+            all_target_ports: desc[12] & 0x02 != 0,
+            reservation_holder: desc[12] & 0x01 != 0,
+            scope: to_reservation_scope(desc[13] >> 4),
+            reservation_type: to_reservation_type(desc[13] & 0x0f),
+            relative_target_port: BigEndian::read_u16(&desc[18..20]),
+            transport_id: desc[24..24 + tid_len].to_vec(),
+        });
+        offset += 24 + tid_len;
+    }
+
+    Ok(PrFullStatus {
+        generation: BigEndian::read_u32(&buf[0..4]),
+        descriptors,
+    })
+}
+
+fn pr_in_cmd<T: SgTransport + ?Sized>(path: &T, action: u8, buf: &mut [u8]) -> Sg3Result<usize> {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x5e;
+    cmd[1] = action;
+    BigEndian::write_u16(&mut cmd[7..9], buf.len() as u16);
+
+    sg_io_read(path, &cmd, buf)
+}
+
+// Send SCSI PERSISTENT RESERVE IN with the given service action to the
+// SCSI device at the given path, returning the parsed response.
+pub fn persistent_reserve_in<T: SgTransport + ?Sized>(
+    path: &T,
+    action: PrInServiceAction,
+) -> Sg3Result<PrInResponse> {
+    let code = match action {
+        PrInServiceAction::ReadKeys => 0,
+        PrInServiceAction::ReadReservation => 1,
+        PrInServiceAction::ReportCapabilities => 2,
+        PrInServiceAction::ReadFullStatus => 3,
+    };
+
+    if action == PrInServiceAction::ReportCapabilities {
+        let mut buf = [0u8; 8];
+        pr_in_cmd(path, code, &mut buf)?;
+        return Ok(PrInResponse::Capabilities(parse_capabilities(&buf)?));
+    }
+
+    // Everything else has an 8-byte header giving the length of the
+    // rest.
+    let buf = read_variable_length(
+        8,
+        |buf| pr_in_cmd(path, code, buf),
+        // the allocation length is 16 bits
+        |buf| (8 + BigEndian::read_u32(&buf[4..8]) as usize).min(0xffff),
+    )?;
+
+    Ok(match action {
+        PrInServiceAction::ReadKeys => PrInResponse::Keys(parse_keys(&buf)),
+        PrInServiceAction::ReadReservation => PrInResponse::Reservation(parse_reservation(&buf)),
+        _ => PrInResponse::FullStatus(parse_full_status(&buf)?),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrOutServiceAction {
+    Register,
+    Reserve,
+    Release,
+    Clear,
+    Preempt,
+    PreemptAndAbort,
+    RegisterAndIgnoreExistingKey,
+}
+
+fn pr_out_action_code(action: &PrOutServiceAction) -> u8 {
+    match *action {
+        PrOutServiceAction::Register => 0,
+        PrOutServiceAction::Reserve => 1,
+        PrOutServiceAction::Release => 2,
+        PrOutServiceAction::Clear => 3,
+        PrOutServiceAction::Preempt => 4,
+        PrOutServiceAction::PreemptAndAbort => 5,
+        PrOutServiceAction::RegisterAndIgnoreExistingKey => 6,
+    }
+}
+
+fn reservation_type_code(rtype: &ReservationType) -> Sg3Result<u8> {
+    match *rtype {
+        ReservationType::WriteExclusive => Ok(1),
+        ReservationType::ExclusiveAccess => Ok(3),
+        ReservationType::WriteExclusiveRegistrantsOnly => Ok(5),
+        ReservationType::ExclusiveAccessRegistrantsOnly => Ok(6),
+        ReservationType::WriteExclusiveAllRegistrants => Ok(7),
+        ReservationType::ExclusiveAccessAllRegistrants => Ok(8),
+        ReservationType::Reserved => Err(invalid_input("reserved reservation type")),
+    }
+}
+
+/// Parameters for `persistent_reserve_out()`. Which fields matter
+/// depends on the service action.
+#[derive(Debug, Default)]
+pub struct PrOutParams {
+    /// The key this I_T nexus is registered with, or zero if it is not
+    /// registered.
+    pub reservation_key: u64,
+    /// The new key for REGISTER, or the key to preempt for PREEMPT.
+    pub service_action_key: u64,
+    /// Reservation type for RESERVE, RELEASE and PREEMPT.
+    pub reservation_type: Option<ReservationType>,
+    /// Register with all target ports (ALL_TG_PT).
+    pub all_target_ports: bool,
+    /// Persist the registration through power loss (APTPL).
+    pub persist_through_power_loss: bool,
+    /// Also register these initiator ports, given as TransportIDs
+    /// (SPEC_I_PT). Only valid with REGISTER.
+    pub transport_ids: Vec<Vec<u8>>,
+}
+
+fn pr_out_cdb(action: &PrOutServiceAction, params: &PrOutParams) -> Sg3Result<([u8; 10], Vec<u8>)> {
+    let mut buf = vec![0u8; 24];
+
+    BigEndian::write_u64(&mut buf[0..8], params.reservation_key);
+    BigEndian::write_u64(&mut buf[8..16], params.service_action_key);
+    if params.all_target_ports {
+        buf[20] |= 0x04;
+    }
+    if params.persist_through_power_loss {
+        buf[20] |= 0x01;
+    }
+    if !params.transport_ids.is_empty() {
+        buf[20] |= 0x08;
+        let tids = params.transport_ids.concat();
+        let mut len = [0u8; 4];
+        BigEndian::write_u32(&mut len, tids.len() as u32);
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(&tids);
+    }
+
+    let mut cmd = [0u8; 10];
+    cmd[0] = 0x5f;
+    cmd[1] = pr_out_action_code(action);
+    if let Some(ref rtype) = params.reservation_type {
+        cmd[2] = reservation_type_code(rtype)?;
+    }
+    BigEndian::write_u32(&mut cmd[5..9], buf.len() as u32);
+
+    Ok((cmd, buf))
+}
+
+// Send SCSI PERSISTENT RESERVE OUT with the given service action to
+// the SCSI device at the given path. The reservation scope is always
+// the logical unit.
+pub fn persistent_reserve_out<T: SgTransport + ?Sized>(
+    path: &T,
+    action: PrOutServiceAction,
+    params: &PrOutParams,
+) -> Sg3Result<()> {
+    let (cmd, buf) = pr_out_cdb(&action, params)?;
+    sg_io(path, &cmd, DataDirection::ToDevice(&buf))
+}
+
+/// Implements common SCSI-3 persistent reservation fencing patterns for
+/// one node, identified by its reservation key, on a shared device.
+///
+/// Each operation is retried if it fails with a UNIT ATTENTION, which
+/// devices report to every node after reservations change.
+#[derive(Debug)]
+pub struct ReservationManager {
+    path: PathBuf,
+    key: u64,
+    reservation_type: ReservationType,
+    retries: u32,
+}
+
+impl ReservationManager {
+    pub fn new(path: &Path, key: u64, reservation_type: ReservationType) -> ReservationManager {
+        ReservationManager {
+            path: path.to_path_buf(),
+            key,
+            reservation_type,
+            retries: 3,
+        }
+    }
+
+    /// Set how many times an operation is retried after a UNIT
+    /// ATTENTION before giving up.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    fn out(&self, action: PrOutServiceAction, params: PrOutParams) -> Sg3Result<()> {
+        let mut attempts = 0;
+        loop {
+            match persistent_reserve_out(&self.path, action, &params) {
+                Err(ref e) if attempts < self.retries && is_unit_attention(e) => attempts += 1,
+                res => return res,
+            }
+        }
+    }
+
+    fn read(&self, action: PrInServiceAction) -> Sg3Result<PrInResponse> {
+        let mut attempts = 0;
+        loop {
+            match persistent_reserve_in(&self.path, action) {
+                Err(ref e) if attempts < self.retries && is_unit_attention(e) => attempts += 1,
+                res => return res,
+            }
+        }
+    }
+
+    /// Register this node's key, replacing whatever key this I_T nexus
+    /// may already be registered with.
+    pub fn register(&self) -> Sg3Result<()> {
+        self.out(
+            PrOutServiceAction::RegisterAndIgnoreExistingKey,
+            PrOutParams {
+                service_action_key: self.key,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Remove this node's registration, releasing any reservation it
+    /// holds.
+    pub fn unregister(&self) -> Sg3Result<()> {
+        self.out(
+            PrOutServiceAction::RegisterAndIgnoreExistingKey,
+            PrOutParams::default(),
+        )
+    }
+
+    /// Take the reservation. This node must already be registered.
+    pub fn reserve(&self) -> Sg3Result<()> {
+        self.out(
+            PrOutServiceAction::Reserve,
+            PrOutParams {
+                reservation_key: self.key,
+                reservation_type: Some(self.reservation_type),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Release the reservation, if this node holds it.
+    pub fn release(&self) -> Sg3Result<()> {
+        self.out(
+            PrOutServiceAction::Release,
+            PrOutParams {
+                reservation_key: self.key,
+                reservation_type: Some(self.reservation_type),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Fence a failed node: remove its registration, take over its
+    /// reservation if it held one, and abort its outstanding commands.
+    pub fn preempt_and_abort(&self, failed_key: u64) -> Sg3Result<()> {
+        self.out(
+            PrOutServiceAction::PreemptAndAbort,
+            PrOutParams {
+                reservation_key: self.key,
+                service_action_key: failed_key,
+                reservation_type: Some(self.reservation_type),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Check that this node is registered and that the device is
+    /// reserved on its behalf with the expected type.
+    pub fn verify(&self) -> Sg3Result<bool> {
+        let registered = match self.read(PrInServiceAction::ReadKeys)? {
+            PrInResponse::Keys(keys) => keys.keys.contains(&self.key),
+            _ => false,
+        };
+        if !registered {
+            return Ok(false);
+        }
+
+        let reservation = match self.read(PrInServiceAction::ReadReservation)? {
+            PrInResponse::Reservation(res) => res.reservation,
+            _ => None,
+        };
+
+        // All-registrants reservations report a key of zero, and are
+        // held by every registered node.
+        let all_registrants = matches!(
+            self.reservation_type,
+            ReservationType::WriteExclusiveAllRegistrants
+                | ReservationType::ExclusiveAccessAllRegistrants
+        );
+
+        Ok(match reservation {
+            Some(res) => {
+                res.reservation_type == self.reservation_type
+                    && (all_registrants || res.key == self.key)
+            }
+            None => false,
+        })
+    }
+}
+
+fn is_unit_attention(err: &Sg3Error) -> bool {
+    match err.sense() {
+        Some(sense) => sense.sense_key() == SenseKey::UnitAttention,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        let buf = [
+            0, 0, 0, 5, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xab, 0xcd,
+        ];
+        let keys = parse_keys(&buf);
+        assert_eq!(keys.generation, 5);
+        assert_eq!(keys.keys, vec![1, 0xabcd]);
+    }
+
+    #[test]
+    fn test_parse_reservation() {
+        let mut buf = [0u8; 24];
+        buf[7] = 16;
+        buf[15] = 0x42;
+        buf[21] = 0x05;
+        let res = parse_reservation(&buf).reservation.unwrap();
+        assert_eq!(res.key, 0x42);
+        assert_eq!(res.scope, ReservationScope::LogicalUnit);
+        assert_eq!(
+            res.reservation_type,
+            ReservationType::WriteExclusiveRegistrantsOnly
+        );
+        assert!(parse_reservation(&buf[..8]).reservation.is_none());
+    }
+
+    #[test]
+    fn test_parse_capabilities() {
+        let buf = [0, 8, 0x0d, 0x81, 0xea, 0x01, 0, 0];
+        let caps = parse_capabilities(&buf).unwrap();
+        assert!(caps.specify_initiator_ports_capable);
+        assert!(caps.all_target_ports_capable);
+        assert!(caps.persist_through_power_loss_capable);
+        assert!(caps.persist_through_power_loss_activated);
+        assert_eq!(caps.supported_types.len(), 6);
+    }
+
+    #[test]
+    fn test_parse_full_status() {
+        let mut buf = vec![0u8; 8 + 24 + 4];
+        buf[3] = 1;
+        buf[7] = 28;
+        buf[15] = 0x11;
+        buf[20] = 0x01;
+        buf[21] = 0x06;
+        buf[27] = 0x02;
+        buf[31] = 4;
+        buf[32..36].copy_from_slice(&[1, 2, 3, 4]);
+        let status = parse_full_status(&buf).unwrap();
+        assert_eq!(status.descriptors.len(), 1);
+        let desc = &status.descriptors[0];
+        assert_eq!(desc.key, 0x11);
+        assert!(desc.reservation_holder);
+        assert_eq!(
+            desc.reservation_type,
+            ReservationType::ExclusiveAccessRegistrantsOnly
+        );
+        assert_eq!(desc.relative_target_port, 2);
+        assert_eq!(desc.transport_id, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_pr_out_cdb() {
+        let params = PrOutParams {
+            reservation_key: 0x1234,
+            reservation_type: Some(ReservationType::WriteExclusive),
+            ..Default::default()
+        };
+        let (cmd, buf) = pr_out_cdb(&PrOutServiceAction::Reserve, &params).unwrap();
+        assert_eq!(cmd, [0x5f, 0x01, 0x01, 0, 0, 0, 0, 0, 24, 0]);
+        assert_eq!(&buf[6..8], &[0x12, 0x34]);
+
+        let params = PrOutParams {
+            service_action_key: 1,
+            persist_through_power_loss: true,
+            transport_ids: vec![vec![0; 24]],
+            ..Default::default()
+        };
+        let (cmd, buf) = pr_out_cdb(&PrOutServiceAction::Register, &params).unwrap();
+        assert_eq!(cmd[8], 52);
+        assert_eq!(buf[20], 0x09);
+        assert_eq!(&buf[24..28], &[0, 0, 0, 24]);
+    }
+}
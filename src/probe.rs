@@ -0,0 +1,257 @@
+//! Finding out in one call what a device can do, so that callers can
+//! decide which commands to send it in one place.
+
+use crate::{
+    inquiry, inquiry_vpd_b0, inquiry_vpd_page, read_capacity16, report_supported_operation_codes,
+    Capacity, InquiryVpdB0, PeripheralDeviceType, ProtectionType, Sg3Result, SgTransport,
+    StdInquiry, SupportedCommand,
+};
+
+/// What a device supports, gathered from INQUIRY, the Supported VPD
+/// Pages, Block Limits, Logical Block Provisioning and Extended INQUIRY
+/// Data VPD pages, REPORT SUPPORTED OPERATION CODES and READ
+/// CAPACITY(16).
+///
+/// Any of these but INQUIRY that the device doesn't support, or that
+/// fails, leaves the fields it would have filled unset.
+#[derive(Debug)]
+pub struct DeviceCapabilities {
+    pub device_type: PeripheralDeviceType,
+    /// The VPD pages the device supports.
+    pub vpd_pages: Vec<u8>,
+    /// The commands the device supports, if it reports them.
+    pub commands: Option<Vec<SupportedCommand>>,
+    pub capacity: Option<Capacity>,
+    /// The longest transfer, in logical blocks, if the device has a
+    /// limit.
+    pub max_transfer_length: Option<u32>,
+    /// The transfer length, in logical blocks, the device does best
+    /// with, if it says.
+    pub optimal_transfer_length: Option<u32>,
+    /// The most blocks one UNMAP may unmap, if the device has a limit.
+    pub max_unmap_lba_count: Option<u32>,
+    /// The most blocks one WRITE SAME may write, if the device has a
+    /// limit.
+    pub max_write_same_length: Option<u64>,
+    /// UNMAP is supported (LBPU).
+    pub unmap: bool,
+    /// WRITE SAME(16) with UNMAP is supported (LBPWS).
+    pub write_same16_unmap: bool,
+    /// WRITE SAME(10) with UNMAP is supported (LBPWS10).
+    pub write_same10_unmap: bool,
+    /// The protection information types the device can be formatted
+    /// with, empty if it doesn't support protection information.
+    pub protection_types: Vec<ProtectionType>,
+}
+
+impl DeviceCapabilities {
+    /// Whether the device supports the command with the given operation
+    /// code and service action, or None if it doesn't report which
+    /// commands it supports.
+    pub fn supports_command(&self, opcode: u8, service_action: Option<u16>) -> Option<bool> {
+        self.commands.as_ref().map(|commands| {
+            commands
+                .iter()
+                .any(|c| c.opcode == opcode && c.service_action == service_action)
+        })
+    }
+
+    /// Whether the device supports WRITE SAME(10) or (16), or None if it
+    /// doesn't report which commands it supports.
+    pub fn supports_write_same(&self) -> Option<bool> {
+        self.supports_command(0x41, None)
+            .zip(self.supports_command(0x93, None))
+            .map(|(ws10, ws16)| ws10 || ws16)
+    }
+}
+
+// The types from the SPT field of the Extended INQUIRY Data VPD page.
+fn supported_protection_types(spt: u8) -> Vec<ProtectionType> {
+    use crate::ProtectionType::*;
+
+    match spt {
+        0b000 => vec![Type1],
+        0b001 => vec![Type1, Type2],
+        0b010 => vec![Type2],
+        0b011 => vec![Type1, Type3],
+        0b100 => vec![Type3],
+        0b101 => vec![Type2, Type3],
+        0b111 => vec![Type1, Type2, Type3],
+        _ => Vec::new(),
+    }
+}
+
+fn nonzero<N: Default + PartialEq>(n: N) -> Option<N> {
+    if n == N::default() {
+        None
+    } else {
+        Some(n)
+    }
+}
+
+// Put together what each query gave. `provisioning` and `extended` are
+// the whole VPD B2 and 86 pages.
+fn capabilities(
+    inquiry: &StdInquiry,
+    vpd_pages: Vec<u8>,
+    block_limits: Option<InquiryVpdB0>,
+    provisioning: Option<Vec<u8>>,
+    extended: Option<Vec<u8>>,
+    commands: Option<Vec<SupportedCommand>>,
+    capacity: Option<Capacity>,
+) -> DeviceCapabilities {
+    let mut caps = DeviceCapabilities {
+        device_type: inquiry.peripheral_device_type(),
+        vpd_pages,
+        commands,
+        capacity,
+        max_transfer_length: None,
+        optimal_transfer_length: None,
+        max_unmap_lba_count: None,
+        max_write_same_length: None,
+        unmap: false,
+        write_same16_unmap: false,
+        write_same10_unmap: false,
+        protection_types: Vec::new(),
+    };
+
+    if let Some(b0) = block_limits {
+        caps.max_transfer_length = nonzero(b0.max_transfer_length());
+        caps.optimal_transfer_length = nonzero(b0.optimal_transfer_length());
+        caps.max_unmap_lba_count = nonzero(b0.max_unmap_lba_count());
+        caps.max_write_same_length = nonzero(b0.max_write_same_length());
+    }
+
+    if let Some(b2) = provisioning.filter(|b2| b2.len() > 5) {
+        caps.unmap = b2[5] & 0x80 != 0;
+        caps.write_same16_unmap = b2[5] & 0x40 != 0;
+        caps.write_same10_unmap = b2[5] & 0x20 != 0;
+    }
+
+    if inquiry.protect() != 0 {
+        if let Some(ext) = extended.filter(|ext| ext.len() > 4) {
+            caps.protection_types = supported_protection_types((ext[4] >> 3) & 0x07);
+        }
+    }
+
+    caps
+}
+
+// Send SCSI INQUIRY, for standard data and VPD pages 00, B0, B2 and 86,
+// REPORT SUPPORTED OPERATION CODES and READ CAPACITY(16) to the SCSI
+// device at the given path, and gather what it supports. Only INQUIRY
+// failing fails the probe; VPD pages the device doesn't list are not
+// asked for.
+pub fn probe<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<DeviceCapabilities> {
+    let inquiry = inquiry(path)?;
+
+    let vpd_pages = inquiry_vpd_page(path, 0x00)
+        .map(|page| page[4.min(page.len())..].to_vec())
+        .unwrap_or_default();
+    let listed = |page| vpd_pages.contains(&page);
+
+    let block_limits = if listed(0xb0) {
+        inquiry_vpd_b0(path).ok()
+    } else {
+        None
+    };
+    let provisioning = if listed(0xb2) {
+        inquiry_vpd_page(path, 0xb2).ok()
+    } else {
+        None
+    };
+    let extended = if listed(0x86) {
+        inquiry_vpd_page(path, 0x86).ok()
+    } else {
+        None
+    };
+    let commands = report_supported_operation_codes(path).ok();
+    let capacity = read_capacity16(path).ok();
+
+    Ok(capabilities(
+        &inquiry,
+        vpd_pages,
+        block_limits,
+        provisioning,
+        extended,
+        commands,
+        capacity,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockSize, FakeDevice, Lba};
+
+    #[test]
+    fn test_probe() {
+        let mut dev = FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        let mut inquiry = vec![0u8; 36];
+        inquiry[2] = 0x06;
+        inquiry[3] = 0x02;
+        inquiry[4] = 31;
+        // PROTECT
+        inquiry[5] = 0x01;
+        dev.set_inquiry(&inquiry);
+
+        let mut b0 = vec![0u8; 0x3c];
+        // MAXIMUM TRANSFER LENGTH
+        b0[4..8].copy_from_slice(&[0, 0, 0x08, 0]);
+        // MAXIMUM UNMAP LBA COUNT
+        b0[16..20].copy_from_slice(&[0, 0x40, 0, 0]);
+        dev.set_vpd_page(0xb0, &b0);
+        // LBPU, LBPWS
+        dev.set_vpd_page(0xb2, &[0, 0xc0, 0x02, 0]);
+        // SPT 011b: Type 1 and Type 3
+        dev.set_vpd_page(0x86, &[0x18, 0x07, 0, 0]);
+
+        let mut capacity = vec![0u8; 32];
+        capacity[7] = 0xff;
+        capacity[10] = 0x02;
+        capacity[12] = 0x01;
+        dev.set_response(0x9e, &capacity);
+        let mut commands = vec![0, 0, 0, 16];
+        commands.extend_from_slice(&[0x93, 0, 0, 0, 0, 0, 0, 16]);
+        commands.extend_from_slice(&[0x42, 0, 0, 0, 0, 0, 0, 10]);
+        dev.set_response(0xa3, &commands);
+
+        let caps = probe(&dev).unwrap();
+        assert_eq!(caps.device_type, PeripheralDeviceType::DirectAccess);
+        assert_eq!(caps.vpd_pages, [0x00, 0x86, 0xb0, 0xb2]);
+        assert_eq!(caps.max_transfer_length, Some(2048));
+        assert_eq!(caps.optimal_transfer_length, None);
+        assert_eq!(caps.max_unmap_lba_count, Some(0x40_0000));
+        assert!(caps.unmap);
+        assert!(caps.write_same16_unmap);
+        assert!(!caps.write_same10_unmap);
+        assert_eq!(
+            caps.protection_types,
+            [ProtectionType::Type1, ProtectionType::Type3]
+        );
+        let capacity = caps.capacity.as_ref().unwrap();
+        assert_eq!(capacity.last_lba, Lba(255));
+        assert_eq!(capacity.block_size, BlockSize(512));
+        assert_eq!(capacity.protection, ProtectionType::Type1);
+        assert_eq!(caps.supports_command(0x42, None), Some(true));
+        assert_eq!(caps.supports_command(0x89, None), Some(false));
+        assert_eq!(caps.supports_write_same(), Some(true));
+
+        // a device that supports none of it
+        let caps = probe(&FakeDevice::new("ATA", "disk1", "1.0")).unwrap();
+        assert_eq!(caps.vpd_pages, [0x00]);
+        assert!(caps.commands.is_none());
+        assert!(caps.capacity.is_none());
+        assert_eq!(caps.max_transfer_length, None);
+        assert!(!caps.unmap);
+        assert!(caps.protection_types.is_empty());
+        assert_eq!(caps.supports_write_same(), None);
+    }
+
+    #[test]
+    fn test_supported_protection_types() {
+        assert_eq!(supported_protection_types(0), [ProtectionType::Type1]);
+        assert_eq!(supported_protection_types(0b111).len(), 3);
+        assert!(supported_protection_types(0b110).is_empty());
+    }
+}
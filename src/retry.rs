@@ -0,0 +1,164 @@
+//! Retrying commands that fail for transient reasons.
+
+use std::thread;
+use std::time::Duration;
+
+use crate::{SenseKey, Sg3Error, Sg3Result};
+
+const STATUS_BUSY: u8 = 0x08;
+const STATUS_TASK_SET_FULL: u8 = 0x28;
+
+/// When and how often `with_retries()` reissues a failed command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most times the command is issued, including the first.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry after BUSY or TASK SET
+    /// FULL. The wait doubles on each retry, up to `max_delay`.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Retry straight away after a UNIT ATTENTION, such as one reporting
+    /// a reset or new microcode, which the device only reports once.
+    pub retry_unit_attention: bool,
+    /// Retry after BUSY or TASK SET FULL status.
+    pub retry_busy: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            retry_unit_attention: true,
+            retry_busy: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // How long to wait before retrying after the given error, or None if
+    // it shouldn't be retried. `retry` counts from 0.
+    fn delay(&self, err: &Sg3Error, retry: u32) -> Option<Duration> {
+        let status = match *err {
+            Sg3Error::Scsi { status, .. } => status,
+            _ => return None,
+        };
+
+        if self.retry_busy && (status == STATUS_BUSY || status == STATUS_TASK_SET_FULL) {
+            let factor = 1u32.checked_shl(retry).unwrap_or(u32::MAX);
+            return Some(
+                self.initial_delay
+                    .checked_mul(factor)
+                    .unwrap_or(self.max_delay)
+                    .min(self.max_delay),
+            );
+        }
+
+        match err.sense() {
+            Some(ref sense)
+                if self.retry_unit_attention && sense.sense_key() == SenseKey::UnitAttention =>
+            {
+                Some(Duration::from_secs(0))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Call `f`, which issues one or more commands, again for as long as it
+// fails in a way the policy says to retry, up to the policy's limit on
+// attempts. For example:
+//
+//     with_retries(&RetryPolicy::default(), || read_capacity16(path))
+pub fn with_retries<T, F>(policy: &RetryPolicy, mut f: F) -> Sg3Result<T>
+where
+    F: FnMut() -> Sg3Result<T>,
+{
+    let mut retry = 0;
+
+    loop {
+        match f() {
+            Err(e) => match policy.delay(&e, retry) {
+                Some(delay) if retry + 1 < policy.max_attempts => {
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+                _ => return Err(e),
+            },
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn scsi_error(status: u8, sense: Vec<u8>) -> Sg3Error {
+        Sg3Error::Scsi {
+            device: PathBuf::from("/dev/sg0"),
+            cdb: vec![0; 6],
+            status,
+            host_status: 0,
+            driver_status: 0,
+            sense,
+        }
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            ..Default::default()
+        };
+
+        // power on reset, then busy, then success
+        let mut attempts = 0;
+        let res = with_retries(&policy, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(scsi_error(
+                    0x02,
+                    vec![0x70, 0, 0x06, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x29, 0],
+                )),
+                2 => Err(scsi_error(STATUS_BUSY, vec![])),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        // medium errors aren't retried
+        let mut attempts = 0;
+        let res: Sg3Result<()> = with_retries(&policy, || {
+            attempts += 1;
+            Err(scsi_error(
+                0x02,
+                vec![0x70, 0, 0x03, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x11, 0],
+            ))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+
+        // the limit on attempts is kept to
+        let mut attempts = 0;
+        let res: Sg3Result<()> = with_retries(&policy, || {
+            attempts += 1;
+            Err(scsi_error(STATUS_TASK_SET_FULL, vec![]))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, policy.max_attempts);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy::default();
+        let busy = scsi_error(STATUS_BUSY, vec![]);
+        assert_eq!(policy.delay(&busy, 0), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay(&busy, 2), Some(Duration::from_millis(400)));
+        assert_eq!(policy.delay(&busy, 40), Some(Duration::from_secs(2)));
+    }
+}
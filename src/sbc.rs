@@ -0,0 +1,1657 @@
+//! SCSI Block Commands (SBC) for direct-access devices.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    inquiry_vpd, invalid_data, invalid_input, read_variable_length, request_sense, sg_io,
+    sg_io_read, sg_io_status, sg_io_timeout, to_device_type, to_qualifier, BlockCount, BlockSize,
+    DataDirection, Lba, PeripheralDeviceType, PeripheralQualifier, Sense, SenseKey, Sg3Error,
+    Sg3Result, SgTransport, STATUS_CONDITION_MET,
+};
+
+// Narrow an LBA and block count to the widths of a CDB's fields.
+pub(crate) fn cdb_extent(
+    lba: Lba,
+    blocks: BlockCount,
+    lba_max: u64,
+    blocks_max: u64,
+) -> Sg3Result<(u64, u64)> {
+    if lba.0 > lba_max {
+        return Err(invalid_input("LBA is too large for the command"));
+    }
+    if blocks.0 > blocks_max {
+        return Err(invalid_input("too many blocks for the command"));
+    }
+    Ok((lba.0, blocks.0))
+}
+
+fn write10_cdb(lba: u32, blocks: u16, fua: bool, group: u8) -> Sg3Result<[u8; 10]> {
+    if group > 0x1f {
+        return Err(invalid_input("group number must be less than 0x20"));
+    }
+
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x2a;
+    if fua {
+        cmd[1] |= 0x08;
+    }
+    BigEndian::write_u32(&mut cmd[2..6], lba);
+    cmd[6] = group;
+    BigEndian::write_u16(&mut cmd[7..9], blocks);
+
+    Ok(cmd)
+}
+
+pub(crate) fn write16_cdb(lba: u64, blocks: u32, fua: bool, group: u8) -> Sg3Result<[u8; 16]> {
+    if group > 0x3f {
+        return Err(invalid_input("group number must be less than 0x40"));
+    }
+
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x8a;
+    if fua {
+        cmd[1] |= 0x08;
+    }
+    BigEndian::write_u64(&mut cmd[2..10], lba);
+    BigEndian::write_u32(&mut cmd[10..14], blocks);
+    cmd[14] = group;
+
+    Ok(cmd)
+}
+
+// Send SCSI WRITE(10) to the SCSI device at the given path, writing
+// `blocks` logical blocks from `buf` starting at `lba`. `buf` must be
+// exactly `blocks` times the device's logical block size.
+//
+// If `fua` is set, the device must write the data to the medium
+// before completing the command. `group` is the group number
+// (0-0x1f) used to classify the I/O for statistics.
+pub fn write10<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    buf: &[u8],
+    fua: bool,
+    group: u8,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u32::MAX.into(), u16::MAX.into())?;
+    let cmd = write10_cdb(lba as u32, blocks as u16, fua, group)?;
+    sg_io(path, &cmd, DataDirection::ToDevice(buf))
+}
+
+// Send SCSI WRITE(16) to the SCSI device at the given path. Like
+// `write10()`, but with 64-bit LBAs, 32-bit transfer lengths, and
+// group numbers up to 0x3f.
+pub fn write16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    buf: &[u8],
+    fua: bool,
+    group: u8,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+    let cmd = write16_cdb(lba, blocks as u32, fua, group)?;
+    sg_io(path, &cmd, DataDirection::ToDevice(buf))
+}
+
+// Send SCSI READ(10) to the SCSI device at the given path, reading
+// `blocks` logical blocks starting at `lba` into `buf`, which must be
+// exactly `blocks` times the device's logical block size. `fua` and
+// `group` are as for `write10()`; with `fua` set, the data is read
+// from the medium rather than the cache.
+pub fn read10<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    buf: &mut [u8],
+    fua: bool,
+    group: u8,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u32::MAX.into(), u16::MAX.into())?;
+    let mut cmd = write10_cdb(lba as u32, blocks as u16, fua, group)?;
+    cmd[0] = 0x28;
+    sg_io(path, &cmd, DataDirection::FromDevice(buf))
+}
+
+// Send SCSI READ(16) to the SCSI device at the given path. Like
+// `read10()`, but with 64-bit LBAs, 32-bit transfer lengths, and group
+// numbers up to 0x3f.
+pub fn read16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    buf: &mut [u8],
+    fua: bool,
+    group: u8,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+    let mut cmd = write16_cdb(lba, blocks as u32, fua, group)?;
+    cmd[0] = 0x88;
+    sg_io(path, &cmd, DataDirection::FromDevice(buf))
+}
+
+fn orwrite16_cdb(lba: u64, blocks: u32, fua: bool, group: u8) -> Sg3Result<[u8; 16]> {
+    let mut cmd = write16_cdb(lba, blocks, fua, group)?;
+    cmd[0] = 0x8b;
+    Ok(cmd)
+}
+
+// Send SCSI ORWRITE(16) to the SCSI device at the given path, ORing
+// `buf` into the `blocks` logical blocks starting at `lba`, as one
+// atomic read-modify-write. `fua` and `group` are as for `write10()`.
+pub fn orwrite16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    buf: &[u8],
+    fua: bool,
+    group: u8,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+    let cmd = orwrite16_cdb(lba, blocks as u32, fua, group)?;
+    sg_io(path, &cmd, DataDirection::ToDevice(buf))
+}
+
+fn write_atomic16_cdb(
+    lba: u64,
+    blocks: u16,
+    boundary: u16,
+    fua: bool,
+    group: u8,
+) -> Sg3Result<[u8; 16]> {
+    if group > 0x3f {
+        return Err(invalid_input("group number must be less than 0x40"));
+    }
+
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x9c;
+    if fua {
+        cmd[1] |= 0x08;
+    }
+    BigEndian::write_u64(&mut cmd[2..10], lba);
+    BigEndian::write_u16(&mut cmd[10..12], boundary);
+    BigEndian::write_u16(&mut cmd[12..14], blocks);
+    cmd[14] = group;
+
+    Ok(cmd)
+}
+
+// Send SCSI WRITE ATOMIC(16) to the SCSI device at the given path,
+// writing `blocks` logical blocks from `buf` starting at `lba` such
+// that either all of them or none are written. With a nonzero
+// `boundary`, the device may instead write each `boundary` blocks
+// atomically on its own. `InquiryVpdB0::atomic_write_fits()` tells
+// whether the device will take the write.
+pub fn write_atomic16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    boundary: u16,
+    buf: &[u8],
+    fua: bool,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u16::MAX.into())?;
+    let cmd = write_atomic16_cdb(lba, blocks as u16, boundary, fua, 0)?;
+    sg_io(path, &cmd, DataDirection::ToDevice(buf))
+}
+
+/// The response to READ CAPACITY(16).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Capacity {
+    /// The LBA of the last logical block.
+    pub last_lba: Lba,
+    pub block_size: BlockSize,
+    /// Type0 if protection information is not enabled.
+    pub protection: ProtectionType,
+    /// Logical blocks per physical block, as a power of 2.
+    pub logical_per_physical_exponent: u8,
+    /// Logical block provisioning management (thin provisioning) is
+    /// enabled.
+    pub lbpme: bool,
+    /// Unmapped blocks read as zeroes.
+    pub lbprz: bool,
+    pub lowest_aligned_lba: u16,
+}
+
+impl Capacity {
+    /// Parse READ CAPACITY(16) data captured elsewhere.
+    pub fn from_buf(buf: &[u8]) -> Option<Capacity> {
+        if buf.len() < 16 {
+            return None;
+        }
+        Some(parse_capacity16(buf))
+    }
+
+    /// Number of logical blocks on the device, or None if the last LBA
+    /// is the largest a u64 can hold.
+    pub fn blocks(&self) -> Option<BlockCount> {
+        self.last_lba.0.checked_add(1).map(BlockCount)
+    }
+
+    /// Size of the device in bytes, or None if it doesn't fit in a u64.
+    pub fn bytes(&self) -> Option<u64> {
+        self.blocks()?.bytes(self.block_size)
+    }
+}
+
+pub(crate) fn parse_capacity16(buf: &[u8]) -> Capacity {
+    Capacity {
+        last_lba: Lba(BigEndian::read_u64(&buf[0..8])),
+        block_size: BlockSize(BigEndian::read_u32(&buf[8..12])),
+        protection: match buf[12] & 0x0f {
+            0x01 => ProtectionType::Type1,
+            0x03 => ProtectionType::Type2,
+            0x05 => ProtectionType::Type3,
+            _ => ProtectionType::Type0,
+        },
+        logical_per_physical_exponent: buf[13] & 0x0f,
+        lbpme: buf[14] & 0x80 != 0,
+        lbprz: buf[14] & 0x40 != 0,
+        lowest_aligned_lba: BigEndian::read_u16(&buf[14..16]) & 0x3fff,
+    }
+}
+
+pub(crate) fn read_capacity16_cdb(len: u32) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x9e;
+    cmd[1] = 0x10;
+    BigEndian::write_u32(&mut cmd[10..14], len);
+
+    cmd
+}
+
+// Send SCSI READ CAPACITY(16) to the SCSI device at the given path.
+pub fn read_capacity16<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Capacity> {
+    let mut buf = [0u8; 32];
+    let cmd = read_capacity16_cdb(buf.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Ok(parse_capacity16(&buf))
+}
+
+pub struct InquiryVpdB0 {
+    buf: Vec<u8>,
+}
+
+/// Struct containing the Block Limits VPD page, with field accessor
+/// methods. Fields the device does not report read as zero, which the
+/// standard defines as "no limit reported".
+impl InquiryVpdB0 {
+    fn new() -> InquiryVpdB0 {
+        InquiryVpdB0 { buf: vec![0; 64] }
+    }
+
+    /// Wrap a Block Limits VPD page captured elsewhere, header included.
+    /// A short page is padded with zeroes.
+    pub fn from_buf(buf: &[u8]) -> InquiryVpdB0 {
+        let mut inquiry = InquiryVpdB0::new();
+        let len = buf.len().min(inquiry.buf.len());
+        inquiry.buf[..len].copy_from_slice(&buf[..len]);
+        inquiry
+    }
+
+    /// Get the raw return buffer containing the inquiry response.
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn as_mut_buf(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    pub fn peripheral_qualifier(&self) -> PeripheralQualifier {
+        to_qualifier(self.buf[0] >> 5)
+    }
+
+    pub fn peripheral_device_type(&self) -> PeripheralDeviceType {
+        to_device_type(self.buf[0] & 0x1f)
+    }
+
+    pub fn wsnz(&self) -> u8 {
+        self.buf[4] & 0x01
+    }
+
+    pub fn max_compare_and_write_length(&self) -> u8 {
+        self.buf[5]
+    }
+
+    pub fn optimal_transfer_length_granularity(&self) -> u16 {
+        BigEndian::read_u16(&self.buf[6..8])
+    }
+
+    pub fn max_transfer_length(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[8..12])
+    }
+
+    pub fn optimal_transfer_length(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[12..16])
+    }
+
+    pub fn max_prefetch_length(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[16..20])
+    }
+
+    pub fn max_unmap_lba_count(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[20..24])
+    }
+
+    pub fn max_unmap_block_descriptor_count(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[24..28])
+    }
+
+    pub fn optimal_unmap_granularity(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[28..32])
+    }
+
+    pub fn ugavalid(&self) -> u8 {
+        (self.buf[32] & 0x80) >> 7
+    }
+
+    pub fn unmap_granularity_alignment(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[32..36]) & 0x7fff_ffff
+    }
+
+    pub fn max_write_same_length(&self) -> u64 {
+        BigEndian::read_u64(&self.buf[36..44])
+    }
+
+    /// The most blocks one WRITE ATOMIC may write; zero if the device
+    /// doesn't support atomic writes.
+    pub fn max_atomic_transfer_length(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[44..48])
+    }
+
+    /// The LBA of an atomic write must be a multiple of this.
+    pub fn atomic_alignment(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[48..52])
+    }
+
+    /// The length of an atomic write must be a multiple of this.
+    pub fn atomic_transfer_length_granularity(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[52..56])
+    }
+
+    pub fn max_atomic_transfer_length_with_atomic_boundary(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[56..60])
+    }
+
+    /// The largest atomic boundary a WRITE ATOMIC may give; zero if
+    /// boundaries are not supported.
+    pub fn max_atomic_boundary_size(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[60..64])
+    }
+
+    /// Whether WRITE ATOMIC of `blocks` blocks at `lba`, with the given
+    /// atomic boundary or zero for none, keeps to these limits.
+    pub fn atomic_write_fits(&self, lba: Lba, blocks: BlockCount, boundary: u16) -> bool {
+        let max = if boundary == 0 {
+            self.max_atomic_transfer_length()
+        } else if u32::from(boundary) > self.max_atomic_boundary_size() {
+            return false;
+        } else {
+            self.max_atomic_transfer_length_with_atomic_boundary()
+        };
+        let alignment = u64::from(self.atomic_alignment());
+        let granularity = u64::from(self.atomic_transfer_length_granularity());
+
+        blocks.0 != 0
+            && blocks.0 <= u64::from(max)
+            && (alignment == 0 || lba.0.is_multiple_of(alignment))
+            && (granularity == 0 || blocks.0.is_multiple_of(granularity))
+    }
+}
+
+/// Serialized as its decoded fields, along with the raw page as `raw`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for InquiryVpdB0 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("InquiryVpdB0", 20)?;
+        s.serialize_field("peripheral_qualifier", &self.peripheral_qualifier())?;
+        s.serialize_field("peripheral_device_type", &self.peripheral_device_type())?;
+        s.serialize_field("wsnz", &self.wsnz())?;
+        s.serialize_field(
+            "max_compare_and_write_length",
+            &self.max_compare_and_write_length(),
+        )?;
+        s.serialize_field(
+            "optimal_transfer_length_granularity",
+            &self.optimal_transfer_length_granularity(),
+        )?;
+        s.serialize_field("max_transfer_length", &self.max_transfer_length())?;
+        s.serialize_field("optimal_transfer_length", &self.optimal_transfer_length())?;
+        s.serialize_field("max_prefetch_length", &self.max_prefetch_length())?;
+        s.serialize_field("max_unmap_lba_count", &self.max_unmap_lba_count())?;
+        s.serialize_field(
+            "max_unmap_block_descriptor_count",
+            &self.max_unmap_block_descriptor_count(),
+        )?;
+        s.serialize_field(
+            "optimal_unmap_granularity",
+            &self.optimal_unmap_granularity(),
+        )?;
+        s.serialize_field("ugavalid", &self.ugavalid())?;
+        s.serialize_field(
+            "unmap_granularity_alignment",
+            &self.unmap_granularity_alignment(),
+        )?;
+        s.serialize_field("max_write_same_length", &self.max_write_same_length())?;
+        s.serialize_field(
+            "max_atomic_transfer_length",
+            &self.max_atomic_transfer_length(),
+        )?;
+        s.serialize_field("atomic_alignment", &self.atomic_alignment())?;
+        s.serialize_field(
+            "atomic_transfer_length_granularity",
+            &self.atomic_transfer_length_granularity(),
+        )?;
+        s.serialize_field(
+            "max_atomic_transfer_length_with_atomic_boundary",
+            &self.max_atomic_transfer_length_with_atomic_boundary(),
+        )?;
+        s.serialize_field("max_atomic_boundary_size", &self.max_atomic_boundary_size())?;
+        s.serialize_field("raw", &self.buf)?;
+        s.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for InquiryVpdB0 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw: crate::RawBuf = serde::Deserialize::deserialize(deserializer)?;
+        Ok(InquiryVpdB0::from_buf(&raw.raw))
+    }
+}
+
+// Send SCSI INQUIRY for VPD B0 (Block Limits) to the SCSI device at
+// the given path.
+pub fn inquiry_vpd_b0<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<InquiryVpdB0> {
+    let mut inquiry = InquiryVpdB0::new();
+    inquiry_vpd(path, 0xb0, inquiry.as_mut_buf())?;
+    Ok(inquiry)
+}
+
+fn write_same16_cdb(lba: u64, blocks: u32, unmap: bool, ndob: bool) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x93;
+    if unmap {
+        cmd[1] |= 0x08;
+    }
+    if ndob {
+        cmd[1] |= 0x01;
+    }
+    BigEndian::write_u64(&mut cmd[2..10], lba);
+    BigEndian::write_u32(&mut cmd[10..14], blocks);
+
+    cmd
+}
+
+// Send SCSI WRITE SAME(16) to the SCSI device at the given path,
+// writing the single logical block in `buf` to each of the `blocks`
+// blocks starting at `lba`.
+//
+// If `unmap` is set, the device may deallocate the blocks instead of
+// writing them. If `ndob` (no data-out buffer) is set, `buf` must be
+// empty and the device writes zeroes.
+pub fn write_same16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    buf: &[u8],
+    unmap: bool,
+    ndob: bool,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+    let cmd = write_same16_cdb(lba, blocks as u32, unmap, ndob);
+
+    if ndob {
+        if !buf.is_empty() {
+            return Err(invalid_input("buf must be empty when ndob is set"));
+        }
+        sg_io(path, &cmd, DataDirection::None)
+    } else {
+        if buf.is_empty() {
+            return Err(invalid_input("buf must contain one logical block"));
+        }
+        sg_io(path, &cmd, DataDirection::ToDevice(buf))
+    }
+}
+
+// Number of blocks written per WRITE SAME when the device does not
+// report a maximum.
+const DEFAULT_WRITE_SAME_BLOCKS: u64 = 0xffff;
+
+// Clamp a length limit reported in VPD B0 and split `blocks` blocks
+// starting at `lba` into (lba, blocks) chunks of at most that size.
+fn chunk_range(lba: Lba, blocks: BlockCount, max: u64) -> Vec<(Lba, BlockCount)> {
+    let max = BlockCount(match max {
+        0 => DEFAULT_WRITE_SAME_BLOCKS,
+        m => m.min(u64::from(u32::MAX)),
+    });
+
+    let mut chunks = Vec::new();
+    let mut lba = lba;
+    let mut remaining = blocks;
+    while remaining.0 > 0 {
+        let len = remaining.min(max);
+        chunks.push((lba, len));
+        lba += len;
+        remaining = remaining - len;
+    }
+    chunks
+}
+
+// Zero `blocks` logical blocks starting at `lba` on the SCSI device at
+// the given path, using as many WRITE SAME(16) commands as needed to
+// stay within the device's reported maximum WRITE SAME length.
+// `block_size` is the device's logical block size in bytes. If `unmap`
+// is set, the device may deallocate the zeroed blocks.
+pub fn zero_range<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    block_size: BlockSize,
+    unmap: bool,
+) -> Sg3Result<()> {
+    // Devices that don't support the Block Limits page fail the
+    // INQUIRY; treat that as no limit reported.
+    let max = match inquiry_vpd_b0(path) {
+        Ok(limits) => limits.max_write_same_length(),
+        Err(Sg3Error::Scsi { .. }) => 0,
+        Err(e) => return Err(e),
+    };
+
+    let zeroes = vec![0u8; block_size.0 as usize];
+    for (lba, len) in chunk_range(lba, blocks, max) {
+        write_same16(path, lba, len, &zeroes, unmap, false)?;
+    }
+
+    Ok(())
+}
+
+// Largest number of block descriptors that fits in an UNMAP parameter
+// list, whose length field is 16 bits.
+const MAX_UNMAP_DESCRIPTORS: usize = (0xffff - 8) / 16;
+
+// Split `extents` into batches that each fit in one UNMAP command,
+// given the limits reported in VPD B0. All-ones limits mean no limit,
+// and a zero limit means the device doesn't support UNMAP.
+fn unmap_batches(
+    extents: &[(Lba, BlockCount)],
+    max_descriptors: u32,
+    max_lbas: u32,
+) -> Sg3Result<Vec<Vec<(Lba, BlockCount)>>> {
+    if max_descriptors == 0 || max_lbas == 0 {
+        return Err(invalid_input("device does not support UNMAP"));
+    }
+    let max_descriptors = match max_descriptors {
+        0xffff_ffff => MAX_UNMAP_DESCRIPTORS,
+        m => (m as usize).min(MAX_UNMAP_DESCRIPTORS),
+    };
+    let max_lbas = match max_lbas {
+        0xffff_ffff => u64::MAX,
+        m => u64::from(m),
+    };
+
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_lbas = 0u64;
+
+    for &(lba, blocks) in extents {
+        let mut lba = lba;
+        let mut remaining = blocks.0;
+        while remaining > 0 {
+            if batch.len() == max_descriptors || batch_lbas == max_lbas {
+                batches.push(batch);
+                batch = Vec::new();
+                batch_lbas = 0;
+            }
+            let len = BlockCount(
+                remaining
+                    .min(max_lbas - batch_lbas)
+                    .min(u64::from(u32::MAX)),
+            );
+            batch.push((lba, len));
+            batch_lbas += len.0;
+            lba += len;
+            remaining -= len.0;
+        }
+    }
+
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    Ok(batches)
+}
+
+// Each extent's block count must fit in 32 bits, as `unmap_batches()`
+// makes sure.
+fn unmap_param_list(extents: &[(Lba, BlockCount)]) -> Vec<u8> {
+    let desc_len = extents.len() * 16;
+    let mut buf = vec![0u8; 8 + desc_len];
+
+    BigEndian::write_u16(&mut buf[0..2], (6 + desc_len) as u16);
+    BigEndian::write_u16(&mut buf[2..4], desc_len as u16);
+    for (i, &(lba, blocks)) in extents.iter().enumerate() {
+        let desc = &mut buf[8 + i * 16..8 + (i + 1) * 16];
+        BigEndian::write_u64(&mut desc[0..8], lba.0);
+        BigEndian::write_u32(&mut desc[8..12], blocks.0 as u32);
+    }
+
+    buf
+}
+
+// Send SCSI UNMAP to the SCSI device at the given path, deallocating
+// each (lba, blocks) extent in `extents`. The extents are split across
+// as many UNMAP commands as needed to stay within the device's
+// reported maximum block descriptor and LBA counts; a device without
+// the Block Limits page is taken to have none.
+pub fn unmap<T: SgTransport + ?Sized>(path: &T, extents: &[(Lba, BlockCount)]) -> Sg3Result<()> {
+    let (max_descriptors, max_lbas) = match inquiry_vpd_b0(path) {
+        Ok(limits) => (
+            limits.max_unmap_block_descriptor_count(),
+            limits.max_unmap_lba_count(),
+        ),
+        Err(Sg3Error::Scsi { .. }) => (0xffff_ffff, 0xffff_ffff),
+        Err(e) => return Err(e),
+    };
+
+    for batch in unmap_batches(extents, max_descriptors, max_lbas)? {
+        let params = unmap_param_list(&batch);
+        let mut cmd = [0u8; 10];
+
+        cmd[0] = 0x42;
+        BigEndian::write_u16(&mut cmd[7..9], params.len() as u16);
+
+        sg_io(path, &cmd, DataDirection::ToDevice(&params))?;
+    }
+
+    Ok(())
+}
+
+fn synchronize_cache10_cdb(lba: u32, blocks: u16, immed: bool) -> [u8; 10] {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x35;
+    if immed {
+        cmd[1] |= 0x02;
+    }
+    BigEndian::write_u32(&mut cmd[2..6], lba);
+    BigEndian::write_u16(&mut cmd[7..9], blocks);
+
+    cmd
+}
+
+pub(crate) fn synchronize_cache16_cdb(lba: u64, blocks: u32, immed: bool) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x91;
+    if immed {
+        cmd[1] |= 0x02;
+    }
+    BigEndian::write_u64(&mut cmd[2..10], lba);
+    BigEndian::write_u32(&mut cmd[10..14], blocks);
+
+    cmd
+}
+
+// Send SCSI SYNCHRONIZE CACHE(10) to the SCSI device at the given
+// path, flushing `blocks` blocks starting at `lba` from the device's
+// volatile cache to the medium. A `blocks` of zero flushes through
+// the last LBA. If `immed` is set, the command completes as soon as
+// it has been validated rather than when the flush is done.
+pub fn synchronize_cache10<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    immed: bool,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u32::MAX.into(), u16::MAX.into())?;
+    let cmd = synchronize_cache10_cdb(lba as u32, blocks as u16, immed);
+    sg_io(path, &cmd, DataDirection::None)
+}
+
+// Send SCSI SYNCHRONIZE CACHE(16) to the SCSI device at the given
+// path. Like `synchronize_cache10()`, but with 64-bit LBAs and 32-bit
+// block counts.
+pub fn synchronize_cache16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    immed: bool,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+    let cmd = synchronize_cache16_cdb(lba, blocks as u32, immed);
+    sg_io(path, &cmd, DataDirection::None)
+}
+
+fn compare_and_write_cdb(lba: u64, blocks: u8, fua: bool) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x89;
+    if fua {
+        cmd[1] |= 0x08;
+    }
+    BigEndian::write_u64(&mut cmd[2..10], lba);
+    cmd[13] = blocks;
+
+    cmd
+}
+
+// Send SCSI COMPARE AND WRITE to the SCSI device at the given path.
+// The device atomically compares the `blocks` blocks starting at
+// `lba` with `compare`, and only if they match, writes `write` in
+// their place. `compare` and `write` must each be exactly `blocks`
+// logical blocks long.
+//
+// If the data on the medium does not match, the command fails with a
+// MISCOMPARE sense key.
+pub fn compare_and_write<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    compare: &[u8],
+    write: &[u8],
+    fua: bool,
+) -> Sg3Result<()> {
+    if compare.len() != write.len() {
+        return Err(invalid_input(
+            "compare and write buffers must be the same length",
+        ));
+    }
+
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u8::MAX.into())?;
+    let cmd = compare_and_write_cdb(lba, blocks as u8, fua);
+    let mut buf = Vec::with_capacity(compare.len() + write.len());
+    buf.extend_from_slice(compare);
+    buf.extend_from_slice(write);
+
+    sg_io(path, &cmd, DataDirection::ToDevice(&buf))
+}
+
+/// What a VERIFY command checks, selected by the BYTCHK field.
+#[derive(Debug)]
+pub enum ByteCheck<'a> {
+    /// Verify that the blocks can be read from the medium, without
+    /// transferring any data (BYTCHK=0).
+    Medium,
+    /// Compare the blocks against the given data, which must be the
+    /// same length as the blocks being verified (BYTCHK=1).
+    Compare(&'a [u8]),
+    /// Compare every block against the single block of data given
+    /// (BYTCHK=3).
+    CompareSingle(&'a [u8]),
+}
+
+impl<'a> ByteCheck<'a> {
+    fn bytchk(&self) -> u8 {
+        match *self {
+            ByteCheck::Medium => 0,
+            ByteCheck::Compare(_) => 1,
+            ByteCheck::CompareSingle(_) => 3,
+        }
+    }
+
+    fn data_direction(&self) -> DataDirection<'a> {
+        match *self {
+            ByteCheck::Medium => DataDirection::None,
+            ByteCheck::Compare(buf) | ByteCheck::CompareSingle(buf) => DataDirection::ToDevice(buf),
+        }
+    }
+}
+
+fn verify10_cdb(lba: u32, blocks: u16, check: &ByteCheck) -> [u8; 10] {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x2f;
+    cmd[1] = check.bytchk() << 1;
+    BigEndian::write_u32(&mut cmd[2..6], lba);
+    BigEndian::write_u16(&mut cmd[7..9], blocks);
+
+    cmd
+}
+
+fn verify16_cdb(lba: u64, blocks: u32, check: &ByteCheck) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x8f;
+    cmd[1] = check.bytchk() << 1;
+    BigEndian::write_u64(&mut cmd[2..10], lba);
+    BigEndian::write_u32(&mut cmd[10..14], blocks);
+
+    cmd
+}
+
+// Send SCSI VERIFY(10) to the SCSI device at the given path, checking
+// `blocks` blocks starting at `lba` as selected by `check`. A failed
+// comparison is reported with a MISCOMPARE sense key, and unreadable
+// blocks with a MEDIUM ERROR.
+pub fn verify10<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    check: ByteCheck,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u32::MAX.into(), u16::MAX.into())?;
+    let cmd = verify10_cdb(lba as u32, blocks as u16, &check);
+    sg_io(path, &cmd, check.data_direction())
+}
+
+// Send SCSI VERIFY(16) to the SCSI device at the given path. Like
+// `verify10()`, but with 64-bit LBAs and 32-bit block counts.
+pub fn verify16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    check: ByteCheck,
+) -> Sg3Result<()> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+    let cmd = verify16_cdb(lba, blocks as u32, &check);
+    sg_io(path, &cmd, check.data_direction())
+}
+
+fn pre_fetch10_cdb(lba: u32, blocks: u16, immed: bool) -> [u8; 10] {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x34;
+    if immed {
+        cmd[1] |= 0x02;
+    }
+    BigEndian::write_u32(&mut cmd[2..6], lba);
+    BigEndian::write_u16(&mut cmd[7..9], blocks);
+
+    cmd
+}
+
+fn pre_fetch16_cdb(lba: u64, blocks: u32, immed: bool) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x90;
+    if immed {
+        cmd[1] |= 0x02;
+    }
+    BigEndian::write_u64(&mut cmd[2..10], lba);
+    BigEndian::write_u32(&mut cmd[10..14], blocks);
+
+    cmd
+}
+
+// Send SCSI PRE-FETCH(10) to the SCSI device at the given path, asking
+// it to read `blocks` blocks starting at `lba` into its cache. A
+// `blocks` of zero prefetches through the last LBA. If `immed` is set,
+// the command completes as soon as it has been validated.
+//
+// Returns true if the device reported that the cache had room for all
+// of the requested blocks (CONDITION MET status).
+pub fn pre_fetch10<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    immed: bool,
+) -> Sg3Result<bool> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u32::MAX.into(), u16::MAX.into())?;
+    let cmd = pre_fetch10_cdb(lba as u32, blocks as u16, immed);
+    let status = sg_io_status(path, &cmd, DataDirection::None)?;
+    Ok(status == STATUS_CONDITION_MET)
+}
+
+// Send SCSI PRE-FETCH(16) to the SCSI device at the given path. Like
+// `pre_fetch10()`, but with 64-bit LBAs and 32-bit block counts.
+pub fn pre_fetch16<T: SgTransport + ?Sized>(
+    path: &T,
+    lba: Lba,
+    blocks: BlockCount,
+    immed: bool,
+) -> Sg3Result<bool> {
+    let (lba, blocks) = cdb_extent(lba, blocks, u64::MAX, u32::MAX.into())?;
+    let cmd = pre_fetch16_cdb(lba, blocks as u32, immed);
+    let status = sg_io_status(path, &cmd, DataDirection::None)?;
+    Ok(status == STATUS_CONDITION_MET)
+}
+
+/// Type of protection information (T10 DIF) a device is formatted
+/// with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectionType {
+    #[default]
+    Type0,
+    Type1,
+    Type2,
+    Type3,
+}
+
+/// Options for `format_unit()`. The default formats with no
+/// protection information, keeping the current block size, and waits
+/// for the format to complete.
+#[derive(Debug, Default)]
+pub struct FormatOptions {
+    /// Return as soon as the format has started. Use
+    /// `wait_for_format()` to follow its progress.
+    pub immed: bool,
+    /// Protection information type to format with.
+    pub protection: ProtectionType,
+    /// Log2 of the number of protection information intervals per
+    /// logical block. Only meaningful with protection enabled.
+    pub protection_interval_exponent: u8,
+    /// Change the logical block size, in bytes, before formatting.
+    pub block_size: Option<u32>,
+}
+
+// FORMAT UNIT without IMMED can take hours on large drives.
+const FORMAT_TIMEOUT: u32 = 4 * 60 * 60 * 1000;
+
+// Build the FORMAT UNIT CDB and, if any option needs it, the parameter
+// list header. Protection types map to FMTPINFO and PROTECTION FIELD
+// USAGE as given in SBC-3 table 33.
+fn format_unit_cdb(opts: &FormatOptions) -> Sg3Result<([u8; 6], Vec<u8>)> {
+    if opts.protection_interval_exponent > 0x0f {
+        return Err(invalid_input(
+            "protection interval exponent must be less than 0x10",
+        ));
+    }
+
+    let (fmtpinfo, pfu) = match opts.protection {
+        ProtectionType::Type0 => (0, 0),
+        ProtectionType::Type1 => (2, 0),
+        ProtectionType::Type2 => (3, 0),
+        ProtectionType::Type3 => (3, 1),
+    };
+    let longlist = opts.protection_interval_exponent != 0;
+
+    let mut cmd = [0u8; 6];
+    cmd[0] = 0x04;
+    cmd[1] = fmtpinfo << 6;
+
+    let mut params = Vec::new();
+    if opts.immed || pfu != 0 || longlist {
+        // FMTDATA; no defect list follows the header
+        cmd[1] |= 0x10;
+        if longlist {
+            cmd[1] |= 0x20;
+            params.resize(8, 0);
+            params[3] = opts.protection_interval_exponent;
+        } else {
+            params.resize(4, 0);
+        }
+        params[0] = pfu;
+        if opts.immed {
+            // FOV must be set for IMMED to be honored
+            params[1] = 0x80 | 0x02;
+        }
+    }
+
+    Ok((cmd, params))
+}
+
+// Change the logical block size via a MODE SELECT(10) with a single
+// block descriptor. The number of blocks is left zero so the device
+// picks the capacity for the new block size.
+fn set_block_length<T: SgTransport + ?Sized>(path: &T, block_size: u32) -> Sg3Result<()> {
+    if block_size > 0xff_ffff {
+        return Err(invalid_input("block size must fit in 24 bits"));
+    }
+
+    let mut params = [0u8; 16];
+    BigEndian::write_u16(&mut params[6..8], 8);
+    BigEndian::write_u24(&mut params[13..16], block_size);
+
+    let mut cmd = [0u8; 10];
+    cmd[0] = 0x55;
+    cmd[1] = 0x10;
+    BigEndian::write_u16(&mut cmd[7..9], params.len() as u16);
+
+    sg_io(path, &cmd, DataDirection::ToDevice(&params))
+}
+
+// Send SCSI FORMAT UNIT to the SCSI device at the given path. This
+// destroys all data on the device.
+//
+// If `opts.block_size` is set, the new block size is first selected
+// via MODE SELECT. Unless `opts.immed` is set, this does not return
+// until the format has finished.
+pub fn format_unit<T: SgTransport + ?Sized>(path: &T, opts: &FormatOptions) -> Sg3Result<()> {
+    let (cmd, params) = format_unit_cdb(opts)?;
+
+    if let Some(block_size) = opts.block_size {
+        set_block_length(path, block_size)?;
+    }
+
+    let data = if params.is_empty() {
+        DataDirection::None
+    } else {
+        DataDirection::ToDevice(&params)
+    };
+    sg_io_timeout(path, &cmd, data, FORMAT_TIMEOUT)?;
+
+    Ok(())
+}
+
+// Whether REQUEST SENSE says a format is still going: NOT READY,
+// FORMAT IN PROGRESS, or NO SENSE with a progress indication.
+fn format_in_progress(sense: &Sense) -> bool {
+    match sense.sense_key() {
+        SenseKey::NotReady => sense.asc() == 0x04 && sense.ascq() == 0x04,
+        SenseKey::NoSense => sense.progress().is_some(),
+        _ => false,
+    }
+}
+
+// Wait for a FORMAT UNIT started with IMMED to finish, polling the SCSI
+// device at the given path with REQUEST SENSE every `interval`.
+// `progress` is called with each progress indication reported, as the
+// numerator of a fraction of 65536.
+pub fn wait_for_format<T: SgTransport + ?Sized, F>(
+    path: &T,
+    interval: Duration,
+    mut progress: F,
+) -> Sg3Result<()>
+where
+    F: FnMut(u16),
+{
+    loop {
+        let sense = request_sense(path)?;
+        if !format_in_progress(&sense) {
+            return format_result(&sense);
+        }
+        if let Some(p) = sense.progress() {
+            progress(p);
+        }
+        thread::sleep(interval);
+    }
+}
+
+// Whether the sense reported once a format is no longer in progress
+// says it succeeded: NO SENSE, or a UNIT ATTENTION such as for the
+// reset or changed parameters, but not one for a failed or corrupt
+// format (31h).
+fn format_result(sense: &Sense) -> Sg3Result<()> {
+    let ok = match sense.sense_key() {
+        SenseKey::NoSense => true,
+        SenseKey::UnitAttention => sense.asc() != 0x31,
+        _ => false,
+    };
+    if ok {
+        return Ok(());
+    }
+    Err(Sg3Error::Io(io::Error::other(format!(
+        "format failed: sense key {:?}, asc/ascq {:#04x}/{:#04x}",
+        sense.sense_key(),
+        sense.asc(),
+        sense.ascq()
+    ))))
+}
+
+/// Power condition to transition a device to with START STOP UNIT.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PowerCondition {
+    /// Use the START and LOEJ bits instead of a power condition.
+    StartValid,
+    Active,
+    Idle,
+    Standby,
+    /// Return control of power conditions to the device.
+    LuControl,
+    /// Force the idle condition timer to zero.
+    ForceIdle,
+    /// Force the standby condition timer to zero.
+    ForceStandby,
+}
+
+fn power_condition_code(cond: &PowerCondition) -> u8 {
+    match *cond {
+        PowerCondition::StartValid => 0,
+        PowerCondition::Active => 1,
+        PowerCondition::Idle => 2,
+        PowerCondition::Standby => 3,
+        PowerCondition::LuControl => 7,
+        PowerCondition::ForceIdle => 0xa,
+        PowerCondition::ForceStandby => 0xb,
+    }
+}
+
+fn start_stop_unit_cdb(start: bool, loej: bool, power: &PowerCondition, immed: bool) -> [u8; 6] {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x1b;
+    if immed {
+        cmd[1] |= 0x01;
+    }
+    let code = power_condition_code(power);
+    cmd[4] = code << 4;
+    // START and LOEJ only count without a power condition, so aren't
+    // sent with one
+    if code == 0 {
+        if loej {
+            cmd[4] |= 0x02;
+        }
+        if start {
+            cmd[4] |= 0x01;
+        }
+    }
+
+    cmd
+}
+
+// Send SCSI START STOP UNIT to the SCSI device at the given path.
+//
+// With `PowerCondition::StartValid`, `start` spins the medium up (or
+// down if false), and `loej` additionally loads (or ejects) removable
+// media. Other power conditions ignore `start` and `loej` and move the
+// device to that condition, e.g. `Standby` to spin down an idle drive.
+// If `immed` is set, the command completes before the operation does.
+pub fn start_stop_unit<T: SgTransport + ?Sized>(
+    path: &T,
+    start: bool,
+    loej: bool,
+    power: PowerCondition,
+    immed: bool,
+) -> Sg3Result<()> {
+    let cmd = start_stop_unit_cdb(start, loej, &power, immed);
+    sg_io(path, &cmd, DataDirection::None)
+}
+
+// Build the REASSIGN BLOCKS CDB and defect list. 8-byte LBAs are used
+// if any LBA needs them, and the 4-byte list length only if the list
+// is too long for the short header.
+fn reassign_blocks_cdb(lbas: &[Lba]) -> ([u8; 6], Vec<u8>) {
+    let longlba = lbas.iter().any(|&lba| lba.0 > u64::from(u32::MAX));
+    let desc_size = if longlba { 8 } else { 4 };
+    let list_len = lbas.len() * desc_size;
+    let longlist = list_len > 0xffff;
+
+    let mut cmd = [0u8; 6];
+    cmd[0] = 0x07;
+    if longlba {
+        cmd[1] |= 0x02;
+    }
+    if longlist {
+        cmd[1] |= 0x01;
+    }
+
+    let mut params = vec![0u8; 4 + list_len];
+    if longlist {
+        BigEndian::write_u32(&mut params[0..4], list_len as u32);
+    } else {
+        BigEndian::write_u16(&mut params[2..4], list_len as u16);
+    }
+    for (i, &lba) in lbas.iter().enumerate() {
+        let desc = &mut params[4 + i * desc_size..4 + (i + 1) * desc_size];
+        if longlba {
+            BigEndian::write_u64(desc, lba.0);
+        } else {
+            BigEndian::write_u32(desc, lba.0 as u32);
+        }
+    }
+
+    (cmd, params)
+}
+
+// Send SCSI REASSIGN BLOCKS to the SCSI device at the given path,
+// asking it to remap each of the defective `lbas` to spare blocks. The
+// data in reassigned blocks may be lost.
+pub fn reassign_blocks<T: SgTransport + ?Sized>(path: &T, lbas: &[Lba]) -> Sg3Result<()> {
+    let (cmd, params) = reassign_blocks_cdb(lbas);
+    sg_io(path, &cmd, DataDirection::ToDevice(&params))
+}
+
+/// Format of the descriptors in a defect list.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DefectListFormat {
+    ShortBlock,
+    LongBlock,
+    BytesFromIndex,
+    PhysicalSector,
+}
+
+fn defect_list_format_code(format: &DefectListFormat) -> u8 {
+    match *format {
+        DefectListFormat::ShortBlock => 0,
+        DefectListFormat::LongBlock => 3,
+        DefectListFormat::BytesFromIndex => 4,
+        DefectListFormat::PhysicalSector => 5,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Defect {
+    Block(u64),
+    BytesFromIndex {
+        cylinder: u32,
+        head: u8,
+        bytes_from_index: u32,
+    },
+    PhysicalSector {
+        cylinder: u32,
+        head: u8,
+        sector: u32,
+    },
+}
+
+/// A defect list returned by READ DEFECT DATA.
+#[derive(Debug)]
+pub struct DefectList {
+    /// Whether the primary (factory) list was returned.
+    pub plist_valid: bool,
+    /// Whether the grown list was returned.
+    pub glist_valid: bool,
+    pub format: DefectListFormat,
+    pub defects: Vec<Defect>,
+}
+
+fn parse_defect_list(flags: u8, buf: &[u8]) -> Sg3Result<DefectList> {
+    let (format, size) = match flags & 0x07 {
+        0 => (DefectListFormat::ShortBlock, 4),
+        3 => (DefectListFormat::LongBlock, 8),
+        4 => (DefectListFormat::BytesFromIndex, 8),
+        5 => (DefectListFormat::PhysicalSector, 8),
+        _ => return Err(invalid_data("Unknown/unsupported defect list format")),
+    };
+
+    let defects = buf
+        .chunks_exact(size)
+        .map(|d| match format {
+            DefectListFormat::ShortBlock => Defect::Block(u64::from(BigEndian::read_u32(d))),
+            DefectListFormat::LongBlock => Defect::Block(BigEndian::read_u64(d)),
+            DefectListFormat::BytesFromIndex => Defect::BytesFromIndex {
+                cylinder: BigEndian::read_u24(&d[0..3]),
+                head: d[3],
+                bytes_from_index: BigEndian::read_u32(&d[4..8]),
+            },
+            DefectListFormat::PhysicalSector => Defect::PhysicalSector {
+                cylinder: BigEndian::read_u24(&d[0..3]),
+                head: d[3],
+                sector: BigEndian::read_u32(&d[4..8]),
+            },
+        })
+        .collect();
+
+    Ok(DefectList {
+        plist_valid: flags & 0x10 != 0,
+        glist_valid: flags & 0x08 != 0,
+        format,
+        defects,
+    })
+}
+
+fn read_defect_data10_cmd<T: SgTransport + ?Sized>(
+    path: &T,
+    flags: u8,
+    buf: &mut [u8],
+) -> Sg3Result<usize> {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x37;
+    cmd[2] = flags;
+    BigEndian::write_u16(&mut cmd[7..9], buf.len() as u16);
+
+    sg_io_read(path, &cmd, buf)
+}
+
+fn read_defect_data12_cmd<T: SgTransport + ?Sized>(
+    path: &T,
+    flags: u8,
+    buf: &mut [u8],
+) -> Sg3Result<usize> {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xb7;
+    cmd[1] = flags;
+    BigEndian::write_u32(&mut cmd[6..10], buf.len() as u32);
+
+    sg_io_read(path, &cmd, buf)
+}
+
+fn defect_request_flags(plist: bool, glist: bool, format: &DefectListFormat) -> u8 {
+    let mut flags = defect_list_format_code(format);
+    if plist {
+        flags |= 0x10;
+    }
+    if glist {
+        flags |= 0x08;
+    }
+    flags
+}
+
+// Send SCSI READ DEFECT DATA(10) to the SCSI device at the given path,
+// returning the primary list if `plist` is set and the grown list if
+// `glist` is set, in the requested format if the device supports it.
+// The list is limited to 64k bytes; use `read_defect_data12()` for
+// devices with more defects than that.
+pub fn read_defect_data10<T: SgTransport + ?Sized>(
+    path: &T,
+    plist: bool,
+    glist: bool,
+    format: DefectListFormat,
+) -> Sg3Result<DefectList> {
+    let flags = defect_request_flags(plist, glist, &format);
+
+    let buf = read_variable_length(
+        4,
+        |buf| read_defect_data10_cmd(path, flags, buf),
+        // the allocation length is 16 bits
+        |buf| (4 + BigEndian::read_u16(&buf[2..4]) as usize).min(0xffff),
+    )?;
+
+    parse_defect_list(buf[1], &buf[4..])
+}
+
+// Send SCSI READ DEFECT DATA(12) to the SCSI device at the given path.
+// Like `read_defect_data10()`, but with a 32-bit defect list length.
+pub fn read_defect_data12<T: SgTransport + ?Sized>(
+    path: &T,
+    plist: bool,
+    glist: bool,
+    format: DefectListFormat,
+) -> Sg3Result<DefectList> {
+    let flags = defect_request_flags(plist, glist, &format);
+
+    let buf = read_variable_length(
+        8,
+        |buf| read_defect_data12_cmd(path, flags, buf),
+        |buf| 8 + BigEndian::read_u32(&buf[4..8]) as usize,
+    )?;
+
+    parse_defect_list(buf[1], &buf[8..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write10_cdb() {
+        let cmd = write10_cdb(0x1234_5678, 8, true, 3).unwrap();
+        assert_eq!(cmd, [0x2a, 0x08, 0x12, 0x34, 0x56, 0x78, 3, 0, 8, 0]);
+        assert!(write10_cdb(0, 1, false, 0x20).is_err());
+    }
+
+    #[test]
+    fn test_write16_cdb() {
+        let cmd = write16_cdb(0x0102_0304_0506_0708, 0x10000, false, 0x3f).unwrap();
+        assert_eq!(cmd, [0x8a, 0, 1, 2, 3, 4, 5, 6, 7, 8, 0, 1, 0, 0, 0x3f, 0]);
+        assert!(write16_cdb(0, 1, false, 0x40).is_err());
+    }
+
+    #[test]
+    fn test_orwrite16_cdb() {
+        let cmd = orwrite16_cdb(0x1000, 4, true, 0).unwrap();
+        assert_eq!(
+            cmd,
+            [0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 4, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_write_atomic16() {
+        let cmd = write_atomic16_cdb(0x1000, 16, 8, false, 1).unwrap();
+        assert_eq!(cmd, [0x9c, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 8, 0, 16, 1, 0]);
+        assert!(write_atomic16_cdb(0, 1, 0, false, 0x40).is_err());
+
+        let mut page = vec![0u8; 64];
+        page[1] = 0xb0;
+        page[3] = 0x3c;
+        // no atomic writes
+        let b0 = InquiryVpdB0::from_buf(&page);
+        assert!(!b0.atomic_write_fits(Lba(0), BlockCount(1), 0));
+
+        BigEndian::write_u32(&mut page[44..48], 64);
+        BigEndian::write_u32(&mut page[48..52], 8);
+        BigEndian::write_u32(&mut page[52..56], 4);
+        BigEndian::write_u32(&mut page[56..60], 256);
+        BigEndian::write_u32(&mut page[60..64], 16);
+        let b0 = InquiryVpdB0::from_buf(&page);
+        assert_eq!(b0.max_atomic_transfer_length(), 64);
+        assert_eq!(b0.max_atomic_boundary_size(), 16);
+        assert!(b0.atomic_write_fits(Lba(16), BlockCount(64), 0));
+        assert!(!b0.atomic_write_fits(Lba(16), BlockCount(128), 0));
+        assert!(b0.atomic_write_fits(Lba(16), BlockCount(128), 16));
+        assert!(!b0.atomic_write_fits(Lba(16), BlockCount(128), 32));
+        assert!(!b0.atomic_write_fits(Lba(12), BlockCount(8), 0));
+        assert!(!b0.atomic_write_fits(Lba(16), BlockCount(6), 0));
+        assert!(!b0.atomic_write_fits(Lba(16), BlockCount(0), 0));
+    }
+
+    #[test]
+    fn test_parse_capacity16() {
+        let mut buf = [0u8; 32];
+        BigEndian::write_u64(&mut buf[0..8], 0x3a38_602f);
+        BigEndian::write_u32(&mut buf[8..12], 512);
+        buf[12] = 0x03;
+        buf[13] = 0x03;
+        buf[14] = 0xc0;
+        let cap = parse_capacity16(&buf);
+        assert_eq!(cap.blocks(), Some(BlockCount(0x3a38_6030)));
+        assert_eq!(cap.block_size, BlockSize(512));
+        assert_eq!(cap.bytes(), Some(0x3a38_6030 * 512));
+        assert_eq!(cap.protection, ProtectionType::Type2);
+        assert_eq!(cap.logical_per_physical_exponent, 3);
+        assert!(cap.lbpme && cap.lbprz);
+        assert_eq!(cap.lowest_aligned_lba, 0);
+    }
+
+    #[test]
+    fn test_write_same16_cdb() {
+        let cmd = write_same16_cdb(0x10, 0x20, true, true);
+        assert_eq!(
+            cmd,
+            [0x93, 0x09, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0x20, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_chunk_range() {
+        let extents = |e: &[(u64, u64)]| -> Vec<(Lba, BlockCount)> {
+            e.iter().map(|&(l, b)| (Lba(l), BlockCount(b))).collect()
+        };
+        assert_eq!(
+            chunk_range(Lba(100), BlockCount(25), 10),
+            extents(&[(100, 10), (110, 10), (120, 5)])
+        );
+        assert_eq!(
+            chunk_range(Lba(0), BlockCount(0x10000), 0),
+            extents(&[(0, 0xffff), (0xffff, 1)])
+        );
+        assert!(chunk_range(Lba(0), BlockCount(0), 10).is_empty());
+    }
+
+    #[test]
+    fn test_unmap_batches() {
+        let extents = |e: &[(u64, u64)]| -> Vec<(Lba, BlockCount)> {
+            e.iter().map(|&(l, b)| (Lba(l), BlockCount(b))).collect()
+        };
+        let all = extents(&[(0, 10), (100, 25), (200, 5)]);
+        assert_eq!(
+            unmap_batches(&all, 2, 0xffff_ffff).unwrap(),
+            vec![extents(&[(0, 10), (100, 25)]), extents(&[(200, 5)])]
+        );
+        assert_eq!(
+            unmap_batches(&all, 0xffff_ffff, 20).unwrap(),
+            vec![
+                extents(&[(0, 10), (100, 10)]),
+                extents(&[(110, 15), (200, 5)])
+            ]
+        );
+        // zero limits mean UNMAP isn't supported
+        assert!(unmap_batches(&all, 0, 20).is_err());
+        assert!(unmap_batches(&all, 2, 0).is_err());
+    }
+
+    #[test]
+    fn test_unmap_param_list() {
+        let buf = unmap_param_list(&[(Lba(0x1000), BlockCount(8))]);
+        assert_eq!(buf.len(), 24);
+        assert_eq!(&buf[0..4], &[0, 22, 0, 16]);
+        assert_eq!(&buf[8..16], &[0, 0, 0, 0, 0, 0, 0x10, 0]);
+        assert_eq!(&buf[16..20], &[0, 0, 0, 8]);
+    }
+
+    #[test]
+    fn test_synchronize_cache_cdb() {
+        let cmd = synchronize_cache10_cdb(0, 0, true);
+        assert_eq!(cmd, [0x35, 0x02, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let cmd = synchronize_cache16_cdb(0x100, 0x80, false);
+        assert_eq!(cmd, [0x91, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0x80, 0, 0]);
+    }
+
+    #[test]
+    fn test_compare_and_write_cdb() {
+        let cmd = compare_and_write_cdb(0x20, 1, true);
+        assert_eq!(
+            cmd,
+            [0x89, 0x08, 0, 0, 0, 0, 0, 0, 0, 0x20, 0, 0, 0, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_verify_cdb() {
+        let cmd = verify10_cdb(0x10, 4, &ByteCheck::Medium);
+        assert_eq!(cmd, [0x2f, 0, 0, 0, 0, 0x10, 0, 0, 4, 0]);
+        let data = [0u8; 512];
+        let cmd = verify16_cdb(0x10, 4, &ByteCheck::CompareSingle(&data));
+        assert_eq!(
+            cmd,
+            [0x8f, 0x06, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 4, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_pre_fetch_cdb() {
+        let cmd = pre_fetch10_cdb(0x10, 4, true);
+        assert_eq!(cmd, [0x34, 0x02, 0, 0, 0, 0x10, 0, 0, 4, 0]);
+        let cmd = pre_fetch16_cdb(0x10, 4, false);
+        assert_eq!(cmd, [0x90, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 4, 0, 0]);
+    }
+
+    #[test]
+    fn test_format_unit_cdb() {
+        let (cmd, params) = format_unit_cdb(&FormatOptions::default()).unwrap();
+        assert_eq!(cmd, [0x04, 0, 0, 0, 0, 0]);
+        assert!(params.is_empty());
+
+        let opts = FormatOptions {
+            immed: true,
+            protection: ProtectionType::Type3,
+            ..Default::default()
+        };
+        let (cmd, params) = format_unit_cdb(&opts).unwrap();
+        assert_eq!(cmd, [0x04, 0xd0, 0, 0, 0, 0]);
+        assert_eq!(params, vec![0x01, 0x82, 0, 0]);
+
+        let opts = FormatOptions {
+            protection: ProtectionType::Type1,
+            protection_interval_exponent: 3,
+            ..Default::default()
+        };
+        let (cmd, params) = format_unit_cdb(&opts).unwrap();
+        assert_eq!(cmd, [0x04, 0xb0, 0, 0, 0, 0]);
+        assert_eq!(params, vec![0, 0, 0, 3, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_wait_for_format() {
+        let sense = |key: u8, asc: u8, ascq: u8| {
+            let mut buf = vec![0u8; 18];
+            buf[0] = 0x70;
+            buf[2] = key;
+            buf[7] = 10;
+            buf[12] = asc;
+            buf[13] = ascq;
+            buf
+        };
+        let wait = |data: Vec<u8>| {
+            let mut dev = crate::FakeDevice::new("LIO-ORG", "disk0", "4.0");
+            dev.set_response(0x03, &data);
+            wait_for_format(&dev, Duration::from_millis(0), |_| {})
+        };
+
+        assert!(wait(sense(0x00, 0, 0)).is_ok());
+        assert!(wait(sense(0x06, 0x29, 0x00)).is_ok());
+        assert!(wait(sense(0x03, 0x31, 0x01)).is_err());
+        assert!(wait(sense(0x03, 0x31, 0x00)).is_err());
+        assert!(wait(sense(0x06, 0x31, 0x00)).is_err());
+
+        let in_progress = |data: Vec<u8>| format_in_progress(&Sense::from_buf(&data).unwrap());
+        assert!(in_progress(sense(0x02, 0x04, 0x04)));
+        assert!(!in_progress(sense(0x02, 0x04, 0x01)));
+        assert!(!in_progress(sense(0x00, 0, 0)));
+        // NO SENSE with a progress indication
+        let mut formatting = sense(0x00, 0, 0);
+        formatting[15..18].copy_from_slice(&[0x80, 0x40, 0x00]);
+        assert!(in_progress(formatting));
+    }
+
+    #[test]
+    fn test_start_stop_unit_cdb() {
+        let cmd = start_stop_unit_cdb(false, true, &PowerCondition::StartValid, true);
+        assert_eq!(cmd, [0x1b, 0x01, 0, 0, 0x02, 0]);
+        let cmd = start_stop_unit_cdb(false, false, &PowerCondition::Standby, false);
+        assert_eq!(cmd, [0x1b, 0, 0, 0, 0x30, 0]);
+        let cmd = start_stop_unit_cdb(true, true, &PowerCondition::Idle, false);
+        assert_eq!(cmd, [0x1b, 0, 0, 0, 0x20, 0]);
+    }
+
+    #[test]
+    fn test_reassign_blocks_cdb() {
+        let (cmd, params) = reassign_blocks_cdb(&[Lba(0x10), Lba(0x20)]);
+        assert_eq!(cmd, [0x07, 0, 0, 0, 0, 0]);
+        assert_eq!(params, vec![0, 0, 0, 8, 0, 0, 0, 0x10, 0, 0, 0, 0x20]);
+
+        let (cmd, params) = reassign_blocks_cdb(&[Lba(0x1_0000_0000)]);
+        assert_eq!(cmd, [0x07, 0x02, 0, 0, 0, 0]);
+        assert_eq!(params, vec![0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse_defect_list() {
+        let list = parse_defect_list(0x08, &[0, 0, 0x01, 0x00, 0, 0, 0x02, 0x00]).unwrap();
+        assert!(!list.plist_valid);
+        assert!(list.glist_valid);
+        assert_eq!(
+            list.defects,
+            vec![Defect::Block(0x100), Defect::Block(0x200)]
+        );
+
+        let list = parse_defect_list(0x15, &[0, 0x01, 0x02, 3, 0, 0, 0, 9]).unwrap();
+        assert_eq!(list.format, DefectListFormat::PhysicalSector);
+        assert_eq!(
+            list.defects,
+            vec![Defect::PhysicalSector {
+                cylinder: 0x102,
+                head: 3,
+                sector: 9
+            }]
+        );
+
+        assert!(parse_defect_list(0x06, &[]).is_err());
+    }
+
+    #[test]
+    fn test_read_defect_data12() {
+        let mut dev = crate::FakeDevice::new("LIO-ORG", "disk0", "4.0");
+        // a bogus DEFECT LIST LENGTH, and one short block defect
+        dev.set_response(
+            0xb7,
+            &[0, 0x08, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0x01, 0],
+        );
+        let list = read_defect_data12(&dev, false, true, DefectListFormat::ShortBlock).unwrap();
+        assert_eq!(list.defects, vec![Defect::Block(0x100)]);
+
+        let cmds = dev.commands();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(cmds[0][1], 0x08);
+        assert_eq!(&cmds[0][6..10], &[0, 0, 0, 8]);
+        assert_eq!(&cmds[1][6..10], &[0, 0x10, 0, 0]);
+    }
+}
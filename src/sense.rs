@@ -0,0 +1,317 @@
+//! Sense data returned by failed commands and by REQUEST SENSE.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{invalid_data, sg_io, DataDirection, Sg3Result, SgTransport};
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SenseKey {
+    NoSense,
+    RecoveredError,
+    NotReady,
+    MediumError,
+    HardwareError,
+    IllegalRequest,
+    UnitAttention,
+    DataProtect,
+    BlankCheck,
+    VendorSpecific,
+    CopyAborted,
+    AbortedCommand,
+    Reserved,
+    VolumeOverflow,
+    Miscompare,
+    Completed,
+}
+
+fn to_sense_key(i: u8) -> SenseKey {
+    match i {
+        0 => SenseKey::NoSense,
+        1 => SenseKey::RecoveredError,
+        2 => SenseKey::NotReady,
+        3 => SenseKey::MediumError,
+        4 => SenseKey::HardwareError,
+        5 => SenseKey::IllegalRequest,
+        6 => SenseKey::UnitAttention,
+        7 => SenseKey::DataProtect,
+        8 => SenseKey::BlankCheck,
+        9 => SenseKey::VendorSpecific,
+        0xa => SenseKey::CopyAborted,
+        0xb => SenseKey::AbortedCommand,
+        0xd => SenseKey::VolumeOverflow,
+        0xe => SenseKey::Miscompare,
+        0xf => SenseKey::Completed,
+        _ => SenseKey::Reserved,
+    }
+}
+
+impl From<u8> for SenseKey {
+    fn from(i: u8) -> SenseKey {
+        to_sense_key(i)
+    }
+}
+
+#[derive(Debug)]
+pub struct Sense {
+    buf: Vec<u8>,
+}
+
+/// Struct containing fixed or descriptor format sense data, with field
+/// accessor methods. Fields beyond the end of truncated sense data read
+/// as zero.
+impl Sense {
+    /// Parse sense data, returning None if `buf` does not start with a
+    /// valid sense data response code.
+    pub fn from_buf(buf: &[u8]) -> Option<Sense> {
+        if buf.len() < 8 {
+            return None;
+        }
+        match buf[0] & 0x7f {
+            0x70..=0x73 => Some(Sense { buf: buf.to_vec() }),
+            _ => None,
+        }
+    }
+
+    /// Get the raw sense data.
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn byte(&self, idx: usize) -> u8 {
+        self.buf.get(idx).cloned().unwrap_or(0)
+    }
+
+    pub fn response_code(&self) -> u8 {
+        self.buf[0] & 0x7f
+    }
+
+    pub fn is_descriptor_format(&self) -> bool {
+        self.response_code() >= 0x72
+    }
+
+    /// Whether the sense data reports an error from an earlier command
+    /// rather than the one that returned it.
+    pub fn is_deferred(&self) -> bool {
+        self.response_code() & 0x01 != 0
+    }
+
+    pub fn sense_key(&self) -> SenseKey {
+        if self.is_descriptor_format() {
+            to_sense_key(self.byte(1) & 0x0f)
+        } else {
+            to_sense_key(self.byte(2) & 0x0f)
+        }
+    }
+
+    pub fn asc(&self) -> u8 {
+        if self.is_descriptor_format() {
+            self.byte(2)
+        } else {
+            self.byte(12)
+        }
+    }
+
+    pub fn ascq(&self) -> u8 {
+        if self.is_descriptor_format() {
+            self.byte(3)
+        } else {
+            self.byte(13)
+        }
+    }
+
+    /// Find the sense data descriptor of the given type, including its
+    /// two-byte header. Only descriptor format sense data has these.
+    pub fn descriptor(&self, desc_type: u8) -> Option<&[u8]> {
+        if !self.is_descriptor_format() {
+            return None;
+        }
+
+        let end = self.buf.len().min(8 + self.byte(7) as usize);
+        let mut offset = 8;
+        while offset + 2 <= end {
+            let len = 2 + self.buf[offset + 1] as usize;
+            if offset + len > end {
+                break;
+            }
+            if self.buf[offset] == desc_type {
+                return Some(&self.buf[offset..offset + len]);
+            }
+            offset += len;
+        }
+        None
+    }
+
+    /// The INFORMATION field, usually the first LBA in error, if valid.
+    pub fn information(&self) -> Option<u64> {
+        if self.is_descriptor_format() {
+            match self.descriptor(0x00) {
+                Some(desc) if desc.len() >= 12 && desc[2] & 0x80 != 0 => {
+                    Some(BigEndian::read_u64(&desc[4..12]))
+                }
+                _ => None,
+            }
+        } else if self.buf[0] & 0x80 != 0 && self.buf.len() >= 7 {
+            Some(u64::from(BigEndian::read_u32(&self.buf[3..7])))
+        } else {
+            None
+        }
+    }
+
+    /// The three SENSE KEY SPECIFIC bytes, if the SKSV bit is set.
+    pub fn sense_key_specific(&self) -> Option<[u8; 3]> {
+        let sks = if self.is_descriptor_format() {
+            match self.descriptor(0x02) {
+                Some(desc) if desc.len() >= 7 => [desc[4], desc[5], desc[6]],
+                _ => return None,
+            }
+        } else {
+            [self.byte(15), self.byte(16), self.byte(17)]
+        };
+
+        if sks[0] & 0x80 != 0 {
+            Some(sks)
+        } else {
+            None
+        }
+    }
+
+    // The FILEMARK, EOM, and ILI bits, from byte 2 of fixed format
+    // sense data or the stream commands descriptor.
+    fn stream_bits(&self) -> u8 {
+        if self.is_descriptor_format() {
+            match self.descriptor(0x04) {
+                Some(desc) if desc.len() >= 4 => desc[3],
+                _ => 0,
+            }
+        } else {
+            self.byte(2)
+        }
+    }
+
+    /// A sequential-access device read a filemark or setmark.
+    pub fn filemark(&self) -> bool {
+        self.stream_bits() & 0x80 != 0
+    }
+
+    /// A sequential-access device reached end-of-medium or the early
+    /// warning point.
+    pub fn eom(&self) -> bool {
+        self.stream_bits() & 0x40 != 0
+    }
+
+    /// The requested and actual block lengths did not match.
+    pub fn ili(&self) -> bool {
+        self.stream_bits() & 0x20 != 0
+    }
+
+    /// Progress of a long-running operation such as FORMAT UNIT, as a
+    /// numerator of a fraction of 65536, if reported.
+    pub fn progress(&self) -> Option<u16> {
+        match self.sense_key() {
+            SenseKey::NoSense | SenseKey::NotReady => self
+                .sense_key_specific()
+                .map(|sks| BigEndian::read_u16(&sks[1..3])),
+            _ => None,
+        }
+    }
+}
+
+/// Serialized as its decoded fields, along with the raw sense data as
+/// `raw`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Sense {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("Sense", 12)?;
+        s.serialize_field("response_code", &self.response_code())?;
+        s.serialize_field("deferred", &self.is_deferred())?;
+        s.serialize_field("sense_key", &self.sense_key())?;
+        s.serialize_field("asc", &self.asc())?;
+        s.serialize_field("ascq", &self.ascq())?;
+        s.serialize_field("information", &self.information())?;
+        s.serialize_field("sense_key_specific", &self.sense_key_specific())?;
+        s.serialize_field("filemark", &self.filemark())?;
+        s.serialize_field("eom", &self.eom())?;
+        s.serialize_field("ili", &self.ili())?;
+        s.serialize_field("progress", &self.progress())?;
+        s.serialize_field("raw", &self.buf)?;
+        s.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Sense {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw: crate::RawBuf = serde::Deserialize::deserialize(deserializer)?;
+        Sense::from_buf(&raw.raw).ok_or_else(|| serde::de::Error::custom("not valid sense data"))
+    }
+}
+
+// Send SCSI REQUEST SENSE to the SCSI device at the given path,
+// returning its current sense data. Devices report progress of
+// long-running operations this way.
+pub fn request_sense<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Sense> {
+    let mut buf = [0u8; 252];
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x03;
+    cmd[4] = buf.len() as u8;
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Sense::from_buf(&buf).ok_or_else(|| invalid_data("Invalid sense data response code"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_sense() {
+        // NOT READY, FORMAT IN PROGRESS, 50% done
+        let buf = [
+            0x70, 0, 0x02, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x04, 0x04, 0, 0x80, 0x80, 0x00,
+        ];
+        let sense = Sense::from_buf(&buf).unwrap();
+        assert!(!sense.is_descriptor_format());
+        assert_eq!(sense.sense_key(), SenseKey::NotReady);
+        assert_eq!((sense.asc(), sense.ascq()), (0x04, 0x04));
+        assert_eq!(sense.progress(), Some(0x8000));
+        assert_eq!(sense.information(), None);
+    }
+
+    #[test]
+    fn test_descriptor_sense() {
+        // MEDIUM ERROR, UNRECOVERED READ ERROR at LBA 0x1234
+        let buf = [
+            0x72, 0x03, 0x11, 0x00, 0, 0, 0, 12, 0x00, 0x0a, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34,
+        ];
+        let sense = Sense::from_buf(&buf).unwrap();
+        assert!(sense.is_descriptor_format());
+        assert_eq!(sense.sense_key(), SenseKey::MediumError);
+        assert_eq!(sense.asc(), 0x11);
+        assert_eq!(sense.information(), Some(0x1234));
+        assert_eq!(sense.progress(), None);
+    }
+
+    #[test]
+    fn test_stream_sense() {
+        // NO SENSE, FILEMARK DETECTED, ILI, residue 0x100
+        let buf = [
+            0xf0, 0, 0xa0, 0, 0, 0x01, 0, 10, 0, 0, 0, 0, 0x00, 0x01, 0, 0, 0, 0,
+        ];
+        let sense = Sense::from_buf(&buf).unwrap();
+        assert!(sense.filemark());
+        assert!(!sense.eom());
+        assert!(sense.ili());
+        assert_eq!(sense.information(), Some(0x100));
+    }
+
+    #[test]
+    fn test_invalid_sense() {
+        assert!(Sense::from_buf(&[0x00; 18]).is_none());
+        assert!(Sense::from_buf(&[0x70, 0, 0]).is_none());
+    }
+}
@@ -0,0 +1,1496 @@
+//! SCSI Enclosure Services (SES) diagnostic pages.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    invalid_data, invalid_input, receive_diagnostic_results, sas_address_block_devices,
+    send_diagnostic, Sg3Error, Sg3Result, SgTransport, WriteBufferMode,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementType {
+    Unspecified,
+    DeviceSlot,
+    PowerSupply,
+    Cooling,
+    TemperatureSensor,
+    Door,
+    AudibleAlarm,
+    EnclosureServicesController,
+    ScsiServicesController,
+    NonvolatileCache,
+    InvalidOperationReason,
+    UninterruptiblePowerSupply,
+    Display,
+    KeyPad,
+    Enclosure,
+    ScsiPortTransceiver,
+    Language,
+    CommunicationPort,
+    VoltageSensor,
+    CurrentSensor,
+    ScsiTargetPort,
+    ScsiInitiatorPort,
+    SimpleSubenclosure,
+    ArrayDeviceSlot,
+    SasExpander,
+    SasConnector,
+    Other(u8),
+}
+
+fn to_element_type(i: u8) -> ElementType {
+    match i {
+        0x00 => ElementType::Unspecified,
+        0x01 => ElementType::DeviceSlot,
+        0x02 => ElementType::PowerSupply,
+        0x03 => ElementType::Cooling,
+        0x04 => ElementType::TemperatureSensor,
+        0x05 => ElementType::Door,
+        0x06 => ElementType::AudibleAlarm,
+        0x07 => ElementType::EnclosureServicesController,
+        0x08 => ElementType::ScsiServicesController,
+        0x09 => ElementType::NonvolatileCache,
+        0x0a => ElementType::InvalidOperationReason,
+        0x0b => ElementType::UninterruptiblePowerSupply,
+        0x0c => ElementType::Display,
+        0x0d => ElementType::KeyPad,
+        0x0e => ElementType::Enclosure,
+        0x0f => ElementType::ScsiPortTransceiver,
+        0x10 => ElementType::Language,
+        0x11 => ElementType::CommunicationPort,
+        0x12 => ElementType::VoltageSensor,
+        0x13 => ElementType::CurrentSensor,
+        0x14 => ElementType::ScsiTargetPort,
+        0x15 => ElementType::ScsiInitiatorPort,
+        0x16 => ElementType::SimpleSubenclosure,
+        0x17 => ElementType::ArrayDeviceSlot,
+        0x18 => ElementType::SasExpander,
+        0x19 => ElementType::SasConnector,
+        x => ElementType::Other(x),
+    }
+}
+
+/// An enclosure descriptor from the SES Configuration page.
+#[derive(Debug)]
+pub struct EnclosureDescriptor {
+    pub subenclosure_id: u8,
+    pub logical_id: u64,
+    pub vendor: String,
+    pub product: String,
+    pub revision: String,
+}
+
+/// A type descriptor header from the SES Configuration page,
+/// describing one group of elements of the same type.
+#[derive(Debug)]
+pub struct TypeDescriptor {
+    pub element_type: ElementType,
+    pub num_elements: u8,
+    pub subenclosure_id: u8,
+    pub text: String,
+}
+
+/// The SES Configuration diagnostic page (0x01).
+#[derive(Debug)]
+pub struct SesConfiguration {
+    pub generation: u32,
+    pub enclosures: Vec<EnclosureDescriptor>,
+    /// Element groups, in the order their elements appear in the
+    /// status and control pages.
+    pub types: Vec<TypeDescriptor>,
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().to_string()
+}
+
+fn parse_configuration(buf: &[u8]) -> Sg3Result<SesConfiguration> {
+    if buf.len() < 8 || buf[0] != 0x01 {
+        return Err(invalid_data("not an SES Configuration page"));
+    }
+
+    let mut enclosures = Vec::new();
+    let mut num_types = 0;
+    let mut offset = 8;
+    for _ in 0..=buf[1] {
+        if offset + 4 > buf.len() {
+            return Err(invalid_data("truncated SES enclosure descriptor"));
+        }
+        let len = 4 + buf[offset + 3] as usize;
+        if len < 40 || offset + len > buf.len() {
+            return Err(invalid_data("truncated SES enclosure descriptor"));
+        }
+        let desc = &buf[offset..offset + len];
+        enclosures.push(EnclosureDescriptor {
+            subenclosure_id: desc[1],
+            logical_id: BigEndian::read_u64(&desc[4..12]),
+            vendor: ascii(&desc[12..20]),
+            product: ascii(&desc[20..36]),
+            revision: ascii(&desc[36..40]),
+        });
+        num_types += desc[2] as usize;
+        offset += len;
+    }
+
+    if offset + num_types * 4 > buf.len() {
+        return Err(invalid_data("truncated SES type descriptor headers"));
+    }
+    let mut text_offset = offset + num_types * 4;
+    let mut types = Vec::with_capacity(num_types);
+    for hdr in buf[offset..offset + num_types * 4].chunks_exact(4) {
+        let end = (text_offset + hdr[3] as usize).min(buf.len());
+        types.push(TypeDescriptor {
+            element_type: to_element_type(hdr[0]),
+            num_elements: hdr[1],
+            subenclosure_id: hdr[2],
+            text: ascii(&buf[text_offset..end]),
+        });
+        text_offset = end;
+    }
+
+    Ok(SesConfiguration {
+        generation: BigEndian::read_u32(&buf[4..8]),
+        enclosures,
+        types,
+    })
+}
+
+// Get the SES Configuration page from the enclosure services device at
+// the given path.
+pub fn ses_configuration<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<SesConfiguration> {
+    parse_configuration(&receive_diagnostic_results(path, Some(0x01))?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementStatusCode {
+    Unsupported,
+    Ok,
+    Critical,
+    Noncritical,
+    Unrecoverable,
+    NotInstalled,
+    Unknown,
+    NotAvailable,
+    NoAccessAllowed,
+    Reserved,
+}
+
+fn to_element_status_code(i: u8) -> ElementStatusCode {
+    match i {
+        0 => ElementStatusCode::Unsupported,
+        1 => ElementStatusCode::Ok,
+        2 => ElementStatusCode::Critical,
+        3 => ElementStatusCode::Noncritical,
+        4 => ElementStatusCode::Unrecoverable,
+        5 => ElementStatusCode::NotInstalled,
+        6 => ElementStatusCode::Unknown,
+        7 => ElementStatusCode::NotAvailable,
+        8 => ElementStatusCode::NoAccessAllowed,
+        _ => ElementStatusCode::Reserved,
+    }
+}
+
+/// Type-specific fields of an SES status element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElementDetail {
+    /// A device slot or array device slot.
+    DeviceSlot {
+        /// Only device slot elements report a slot address.
+        slot_address: Option<u8>,
+        do_not_remove: bool,
+        ready_to_insert: bool,
+        remove: bool,
+        ident: bool,
+        fault_sensed: bool,
+        fault_requested: bool,
+        device_off: bool,
+    },
+    PowerSupply {
+        ident: bool,
+        do_not_remove: bool,
+        dc_over_voltage: bool,
+        dc_under_voltage: bool,
+        dc_over_current: bool,
+        hot_swap: bool,
+        fail: bool,
+        requested_on: bool,
+        off: bool,
+        over_temperature_fail: bool,
+        temperature_warning: bool,
+        ac_fail: bool,
+        dc_fail: bool,
+    },
+    Cooling {
+        ident: bool,
+        do_not_remove: bool,
+        /// Actual fan speed in RPM.
+        fan_speed: u16,
+        hot_swap: bool,
+        fail: bool,
+        requested_on: bool,
+        off: bool,
+        /// Actual speed code, from 0 (stopped) to 7 (highest).
+        speed_code: u8,
+    },
+    TemperatureSensor {
+        ident: bool,
+        fail: bool,
+        /// Temperature in degrees Celsius, if reported.
+        temperature: Option<i16>,
+        over_temperature_failure: bool,
+        over_temperature_warning: bool,
+        under_temperature_failure: bool,
+        under_temperature_warning: bool,
+    },
+    /// An element type without a typed decoding; see `raw`.
+    Other,
+}
+
+fn parse_element_detail(element_type: ElementType, e: &[u8]) -> ElementDetail {
+    match element_type {
+        ElementType::DeviceSlot | ElementType::ArrayDeviceSlot => ElementDetail::DeviceSlot {
+            slot_address: if element_type == ElementType::DeviceSlot {
+                Some(e[1])
+            } else {
+                None
+            },
+            do_not_remove: e[2] & 0x40 != 0,
+            ready_to_insert: e[2] & 0x08 != 0,
+            remove: e[2] & 0x04 != 0,
+            ident: e[2] & 0x02 != 0,
+            fault_sensed: e[3] & 0x40 != 0,
+            fault_requested: e[3] & 0x20 != 0,
+            device_off: e[3] & 0x10 != 0,
+        },
+        ElementType::PowerSupply => ElementDetail::PowerSupply {
+            ident: e[1] & 0x80 != 0,
+            do_not_remove: e[1] & 0x40 != 0,
+            dc_over_voltage: e[2] & 0x08 != 0,
+            dc_under_voltage: e[2] & 0x04 != 0,
+            dc_over_current: e[2] & 0x02 != 0,
+            hot_swap: e[3] & 0x80 != 0,
+            fail: e[3] & 0x40 != 0,
+            requested_on: e[3] & 0x20 != 0,
+            off: e[3] & 0x10 != 0,
+            over_temperature_fail: e[3] & 0x08 != 0,
+            temperature_warning: e[3] & 0x04 != 0,
+            ac_fail: e[3] & 0x02 != 0,
+            dc_fail: e[3] & 0x01 != 0,
+        },
+        ElementType::Cooling => ElementDetail::Cooling {
+            ident: e[1] & 0x80 != 0,
+            do_not_remove: e[1] & 0x40 != 0,
+            fan_speed: (u16::from(e[1] & 0x07) << 8 | u16::from(e[2])) * 10,
+            hot_swap: e[3] & 0x80 != 0,
+            fail: e[3] & 0x40 != 0,
+            requested_on: e[3] & 0x20 != 0,
+            off: e[3] & 0x10 != 0,
+            speed_code: e[3] & 0x07,
+        },
+        ElementType::TemperatureSensor => ElementDetail::TemperatureSensor {
+            ident: e[1] & 0x80 != 0,
+            fail: e[1] & 0x40 != 0,
+            temperature: if e[2] != 0 {
+                Some(i16::from(e[2]) - 20)
+            } else {
+                None
+            },
+            over_temperature_failure: e[3] & 0x08 != 0,
+            over_temperature_warning: e[3] & 0x04 != 0,
+            under_temperature_failure: e[3] & 0x02 != 0,
+            under_temperature_warning: e[3] & 0x01 != 0,
+        },
+        _ => ElementDetail::Other,
+    }
+}
+
+/// A status element from the SES Enclosure Status page.
+#[derive(Debug, Clone)]
+pub struct ElementStatus {
+    pub element_type: ElementType,
+    pub subenclosure_id: u8,
+    /// Index of the element within its type descriptor's group, or
+    /// None for the group's overall status element.
+    pub index: Option<u8>,
+    pub code: ElementStatusCode,
+    pub predicted_failure: bool,
+    pub disabled: bool,
+    pub swap: bool,
+    pub detail: ElementDetail,
+    pub raw: [u8; 4],
+}
+
+/// The SES Enclosure Status diagnostic page (0x02).
+#[derive(Debug)]
+pub struct EnclosureStatus {
+    pub generation: u32,
+    pub invalid_operation: bool,
+    pub info: bool,
+    pub non_critical: bool,
+    pub critical: bool,
+    pub unrecoverable: bool,
+    /// Status elements in page order: for each type descriptor, its
+    /// overall status element followed by its individual elements.
+    pub elements: Vec<ElementStatus>,
+}
+
+fn parse_enclosure_status(config: &SesConfiguration, buf: &[u8]) -> Sg3Result<EnclosureStatus> {
+    if buf.len() < 8 || buf[0] != 0x02 {
+        return Err(invalid_data("not an SES Enclosure Status page"));
+    }
+
+    let mut elements = Vec::new();
+    let mut raw_elements = buf[8..].chunks_exact(4);
+    for td in &config.types {
+        for index in (0..=td.num_elements).map(|i| i.checked_sub(1)) {
+            let e = raw_elements
+                .next()
+                .ok_or_else(|| invalid_data("truncated SES Enclosure Status page"))?;
+            elements.push(ElementStatus {
+                element_type: td.element_type,
+                subenclosure_id: td.subenclosure_id,
+                index,
+                code: to_element_status_code(e[0] & 0x0f),
+                predicted_failure: e[0] & 0x40 != 0,
+                disabled: e[0] & 0x20 != 0,
+                swap: e[0] & 0x10 != 0,
+                detail: parse_element_detail(td.element_type, e),
+                raw: [e[0], e[1], e[2], e[3]],
+            });
+        }
+    }
+
+    Ok(EnclosureStatus {
+        generation: BigEndian::read_u32(&buf[4..8]),
+        invalid_operation: buf[1] & 0x10 != 0,
+        info: buf[1] & 0x08 != 0,
+        non_critical: buf[1] & 0x04 != 0,
+        critical: buf[1] & 0x02 != 0,
+        unrecoverable: buf[1] & 0x01 != 0,
+        elements,
+    })
+}
+
+// Get the SES Configuration and Enclosure Status pages from the
+// enclosure services device at the given path, returning the typed
+// status of each element. The configuration is re-read if it changes
+// between the two.
+pub fn ses_enclosure_status<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<(SesConfiguration, EnclosureStatus)> {
+    for _ in 0..3 {
+        let config = ses_configuration(path)?;
+        let status =
+            parse_enclosure_status(&config, &receive_diagnostic_results(path, Some(0x02))?)?;
+        if status.generation == config.generation {
+            return Ok((config, status));
+        }
+    }
+
+    Err(invalid_data("SES configuration kept changing"))
+}
+
+/// An element descriptor from the SES Element Descriptor page,
+/// such as a bay or power supply name.
+#[derive(Debug)]
+pub struct ElementDescriptor {
+    pub element_type: ElementType,
+    pub subenclosure_id: u8,
+    /// Index of the element within its type descriptor's group, or
+    /// None for the group's overall descriptor.
+    pub index: Option<u8>,
+    pub text: String,
+}
+
+fn parse_element_descriptors(
+    config: &SesConfiguration,
+    buf: &[u8],
+) -> Sg3Result<Vec<ElementDescriptor>> {
+    if buf.len() < 8 || buf[0] != 0x07 {
+        return Err(invalid_data("not an SES Element Descriptor page"));
+    }
+    if BigEndian::read_u32(&buf[4..8]) != config.generation {
+        return Err(invalid_data("SES configuration changed"));
+    }
+
+    let mut descriptors = Vec::new();
+    let mut offset = 8;
+    for td in &config.types {
+        for index in (0..=td.num_elements).map(|i| i.checked_sub(1)) {
+            if offset + 4 > buf.len() {
+                return Err(invalid_data("truncated SES Element Descriptor page"));
+            }
+            let end = (offset + 4 + BigEndian::read_u16(&buf[offset + 2..offset + 4]) as usize)
+                .min(buf.len());
+            descriptors.push(ElementDescriptor {
+                element_type: td.element_type,
+                subenclosure_id: td.subenclosure_id,
+                index,
+                text: ascii(&buf[offset + 4..end]),
+            });
+            offset = end;
+        }
+    }
+
+    Ok(descriptors)
+}
+
+// Get the SES Element Descriptor page from the enclosure services
+// device at the given path, returning a descriptor for each element of
+// `config` in page order.
+pub fn ses_element_descriptors<T: SgTransport + ?Sized>(
+    path: &T,
+    config: &SesConfiguration,
+) -> Sg3Result<Vec<ElementDescriptor>> {
+    parse_element_descriptors(config, &receive_diagnostic_results(path, Some(0x07))?)
+}
+
+/// A SAS phy descriptor from the SES Additional Element Status page.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SasPhy {
+    /// Attached device type: 1 for an end device, 2 for an expander.
+    pub device_type: u8,
+    pub smp_initiator: bool,
+    pub stp_initiator: bool,
+    pub ssp_initiator: bool,
+    pub sata_port_selector: bool,
+    pub smp_target: bool,
+    pub stp_target: bool,
+    pub ssp_target: bool,
+    pub sata_device: bool,
+    pub attached_sas_address: u64,
+    /// SAS address of the device in the slot.
+    pub sas_address: u64,
+    pub phy_id: u8,
+}
+
+fn parse_sas_phy(d: &[u8]) -> SasPhy {
+    SasPhy {
+        device_type: (d[0] >> 4) & 0x07,
+        ssp_initiator: d[2] & 0x08 != 0,
+        stp_initiator: d[2] & 0x04 != 0,
+        smp_initiator: d[2] & 0x02 != 0,
+        sata_port_selector: d[3] & 0x80 != 0,
+        ssp_target: d[3] & 0x08 != 0,
+        stp_target: d[3] & 0x04 != 0,
+        smp_target: d[3] & 0x02 != 0,
+        sata_device: d[3] & 0x01 != 0,
+        attached_sas_address: BigEndian::read_u64(&d[4..12]),
+        sas_address: BigEndian::read_u64(&d[12..20]),
+        phy_id: d[20],
+    }
+}
+
+/// An additional element status descriptor from the SES Additional
+/// Element Status page.
+#[derive(Debug)]
+pub struct AdditionalElementStatus {
+    pub invalid: bool,
+    /// Protocol identifier; 0x6 is SAS.
+    pub protocol: u8,
+    /// Position of the described element in the Enclosure Status
+    /// page's `elements`.
+    pub element: usize,
+    /// The device slot number, if reported.
+    pub device_slot_number: Option<u8>,
+    /// Phys of a SAS device slot. Empty for other protocols and
+    /// descriptor types.
+    pub phys: Vec<SasPhy>,
+}
+
+// Element types that may have additional element status, in the
+// order descriptors appear in the page when element indexes are not
+// given.
+fn has_additional_status(element_type: ElementType) -> bool {
+    matches!(
+        element_type,
+        ElementType::DeviceSlot
+            | ElementType::ArrayDeviceSlot
+            | ElementType::SasExpander
+            | ElementType::ScsiInitiatorPort
+            | ElementType::ScsiTargetPort
+            | ElementType::EnclosureServicesController
+    )
+}
+
+fn parse_additional_element_status(
+    config: &SesConfiguration,
+    buf: &[u8],
+) -> Sg3Result<Vec<AdditionalElementStatus>> {
+    if buf.len() < 8 || buf[0] != 0x0a {
+        return Err(invalid_data("not an SES Additional Element Status page"));
+    }
+    if BigEndian::read_u32(&buf[4..8]) != config.generation {
+        return Err(invalid_data("SES configuration changed"));
+    }
+
+    // Page positions of individual elements, and of those that have
+    // additional status.
+    let mut individual = Vec::new();
+    let mut eligible = Vec::new();
+    let mut position = 0;
+    for td in &config.types {
+        position += 1;
+        for _ in 0..td.num_elements {
+            individual.push(position);
+            if has_additional_status(td.element_type) {
+                eligible.push(position);
+            }
+            position += 1;
+        }
+    }
+    let mut next_eligible = eligible.into_iter();
+
+    let mut descriptors = Vec::new();
+    let mut offset = 8;
+    while offset + 2 <= buf.len() {
+        let end = offset + 2 + buf[offset + 1] as usize;
+        if end > buf.len() {
+            return Err(invalid_data("truncated SES Additional Element Status page"));
+        }
+        let desc = &buf[offset..end];
+        offset = end;
+
+        let eip = desc[0] & 0x10 != 0;
+        let (element, info) = if eip {
+            if desc.len() < 4 {
+                return Err(invalid_data(
+                    "truncated additional element status descriptor",
+                ));
+            }
+            let index = desc[3] as usize;
+            let element = if desc[2] & 0x01 != 0 {
+                Some(index)
+            } else {
+                individual.get(index).cloned()
+            };
+            (element, &desc[4..])
+        } else {
+            (next_eligible.next(), &desc[2..])
+        };
+        let element =
+            element.ok_or_else(|| invalid_data("additional element status for unknown element"))?;
+
+        let protocol = desc[0] & 0x0f;
+        let mut device_slot_number = None;
+        let mut phys = Vec::new();
+        // SAS descriptor type 0 describes a device slot.
+        if protocol == 0x6 && info.len() >= 4 && info[1] >> 6 == 0 {
+            if eip {
+                device_slot_number = Some(info[3]);
+            }
+            phys = info[4..]
+                .chunks_exact(28)
+                .take(info[0] as usize)
+                .map(parse_sas_phy)
+                .collect();
+        }
+
+        descriptors.push(AdditionalElementStatus {
+            invalid: desc[0] & 0x80 != 0,
+            protocol,
+            element,
+            device_slot_number,
+            phys,
+        });
+    }
+
+    Ok(descriptors)
+}
+
+// Get the SES Additional Element Status page from the enclosure
+// services device at the given path. For SAS enclosures this maps each
+// device slot to the SAS addresses of the drive attached to it.
+pub fn ses_additional_element_status<T: SgTransport + ?Sized>(
+    path: &T,
+    config: &SesConfiguration,
+) -> Sg3Result<Vec<AdditionalElementStatus>> {
+    parse_additional_element_status(config, &receive_diagnostic_results(path, Some(0x0a))?)
+}
+
+/// Warning and critical thresholds of a sensor element. Temperatures
+/// are in degrees Celsius plus 20; voltages and currents are in units
+/// of 0.5% of nominal. Zero disables a threshold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Thresholds {
+    pub high_critical: u8,
+    pub high_warning: u8,
+    pub low_warning: u8,
+    pub low_critical: u8,
+}
+
+/// A threshold element from the SES Threshold In page.
+#[derive(Debug)]
+pub struct ThresholdElement {
+    pub element_type: ElementType,
+    pub subenclosure_id: u8,
+    /// Index of the element within its type descriptor's group, or
+    /// None for the group's overall threshold element.
+    pub index: Option<u8>,
+    pub thresholds: Thresholds,
+}
+
+/// The SES Threshold In diagnostic page (0x05). Modify `elements` and
+/// pass it to `set_ses_thresholds()` to change thresholds.
+#[derive(Debug)]
+pub struct EnclosureThresholds {
+    pub generation: u32,
+    pub invalid_operation: bool,
+    /// Threshold elements in page order, like the Enclosure Status
+    /// page's `elements`.
+    pub elements: Vec<ThresholdElement>,
+}
+
+impl EnclosureThresholds {
+    /// Build the Threshold Out page to send with SEND DIAGNOSTIC.
+    pub fn to_page(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 8 + self.elements.len() * 4];
+
+        buf[0] = 0x05;
+        BigEndian::write_u16(&mut buf[2..4], (4 + self.elements.len() * 4) as u16);
+        BigEndian::write_u32(&mut buf[4..8], self.generation);
+        for (i, e) in self.elements.iter().enumerate() {
+            let t = &e.thresholds;
+            buf[8 + i * 4..12 + i * 4].copy_from_slice(&[
+                t.high_critical,
+                t.high_warning,
+                t.low_warning,
+                t.low_critical,
+            ]);
+        }
+
+        buf
+    }
+}
+
+fn parse_thresholds(config: &SesConfiguration, buf: &[u8]) -> Sg3Result<EnclosureThresholds> {
+    if buf.len() < 8 || buf[0] != 0x05 {
+        return Err(invalid_data("not an SES Threshold In page"));
+    }
+    if BigEndian::read_u32(&buf[4..8]) != config.generation {
+        return Err(invalid_data("SES configuration changed"));
+    }
+
+    let mut elements = Vec::new();
+    let mut raw_elements = buf[8..].chunks_exact(4);
+    for td in &config.types {
+        for index in (0..=td.num_elements).map(|i| i.checked_sub(1)) {
+            let e = raw_elements
+                .next()
+                .ok_or_else(|| invalid_data("truncated SES Threshold In page"))?;
+            elements.push(ThresholdElement {
+                element_type: td.element_type,
+                subenclosure_id: td.subenclosure_id,
+                index,
+                thresholds: Thresholds {
+                    high_critical: e[0],
+                    high_warning: e[1],
+                    low_warning: e[2],
+                    low_critical: e[3],
+                },
+            });
+        }
+    }
+
+    Ok(EnclosureThresholds {
+        generation: BigEndian::read_u32(&buf[4..8]),
+        invalid_operation: buf[1] & 0x10 != 0,
+        elements,
+    })
+}
+
+// Get the SES Threshold In page from the enclosure services device at
+// the given path.
+pub fn ses_thresholds<T: SgTransport + ?Sized>(
+    path: &T,
+    config: &SesConfiguration,
+) -> Sg3Result<EnclosureThresholds> {
+    parse_thresholds(config, &receive_diagnostic_results(path, Some(0x05))?)
+}
+
+// Send an SES Threshold Out page to the enclosure services device at
+// the given path, setting the thresholds of every element.
+pub fn set_ses_thresholds<T: SgTransport + ?Sized>(
+    path: &T,
+    thresholds: &EnclosureThresholds,
+) -> Sg3Result<()> {
+    send_diagnostic(path, None, true, &thresholds.to_page())
+}
+
+// Get the vendor-specific contents of the SES String In page from the
+// enclosure services device at the given path, without the page
+// header.
+pub fn ses_string_in<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<u8>> {
+    let mut buf = receive_diagnostic_results(path, Some(0x04))?;
+    if buf.len() < 4 || buf[0] != 0x04 {
+        return Err(invalid_data("not an SES String In page"));
+    }
+    Ok(buf.split_off(4))
+}
+
+fn string_out_page(data: &[u8]) -> Sg3Result<Vec<u8>> {
+    if data.len() > 0xffff - 4 {
+        return Err(invalid_input("SES String Out data too long"));
+    }
+
+    let mut buf = vec![0u8; 4 + data.len()];
+    buf[0] = 0x04;
+    BigEndian::write_u16(&mut buf[2..4], data.len() as u16);
+    buf[4..].copy_from_slice(data);
+
+    Ok(buf)
+}
+
+// Send vendor-specific `data` in an SES String Out page to the
+// enclosure services device at the given path.
+pub fn ses_string_out<T: SgTransport + ?Sized>(path: &T, data: &[u8]) -> Sg3Result<()> {
+    send_diagnostic(path, None, true, &string_out_page(data)?)
+}
+
+// Status bits that carry over unchanged into control elements of each
+// type when writing back the current state.
+fn control_mask(element_type: ElementType) -> [u8; 4] {
+    match element_type {
+        ElementType::DeviceSlot => [0x40, 0x00, 0x4e, 0x3c],
+        ElementType::ArrayDeviceSlot => [0x40, 0xff, 0x4e, 0x3c],
+        _ => [0x40, 0x00, 0x00, 0x00],
+    }
+}
+
+/// Contents of an SES Enclosure Control page (0x02), built from the
+/// current enclosure status. Only selected elements are changed when
+/// the page is sent.
+#[derive(Debug)]
+pub struct EnclosureControl {
+    generation: u32,
+    current: Vec<[u8; 4]>,
+    elements: Vec<[u8; 4]>,
+    slots: Vec<usize>,
+}
+
+impl EnclosureControl {
+    pub fn new(status: &EnclosureStatus) -> EnclosureControl {
+        EnclosureControl {
+            generation: status.generation,
+            current: status
+                .elements
+                .iter()
+                .map(|e| {
+                    let mask = control_mask(e.element_type);
+                    [
+                        e.raw[0] & mask[0],
+                        e.raw[1] & mask[1],
+                        e.raw[2] & mask[2],
+                        e.raw[3] & mask[3],
+                    ]
+                })
+                .collect(),
+            elements: vec![[0u8; 4]; status.elements.len()],
+            slots: status
+                .elements
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| {
+                    e.index.is_some()
+                        && (e.element_type == ElementType::DeviceSlot
+                            || e.element_type == ElementType::ArrayDeviceSlot)
+                })
+                .map(|(i, _)| i)
+                .collect(),
+        }
+    }
+
+    /// Select the control element at the given position in page order,
+    /// returning it for modification. The first time an element is
+    /// selected, it is initialized from the element's current status,
+    /// so unmodified settings are preserved.
+    pub fn select(&mut self, element: usize) -> Sg3Result<&mut [u8; 4]> {
+        let current = *self
+            .current
+            .get(element)
+            .ok_or_else(|| invalid_input("no such SES element"))?;
+        let e = &mut self.elements[element];
+        if e[0] & 0x80 == 0 {
+            *e = current;
+            e[0] |= 0x80;
+        }
+        Ok(e)
+    }
+
+    fn select_slot(&mut self, slot: usize) -> Sg3Result<&mut [u8; 4]> {
+        let element = *self
+            .slots
+            .get(slot)
+            .ok_or_else(|| invalid_input("no such SES device slot"))?;
+        self.select(element)
+    }
+
+    /// Turn the identify (locate) indicator of the given device slot
+    /// on or off. Slots are numbered from zero across all device slot
+    /// and array device slot elements, in page order.
+    pub fn set_slot_ident(&mut self, slot: usize, on: bool) -> Sg3Result<()> {
+        let e = self.select_slot(slot)?;
+        if on {
+            e[2] |= 0x02;
+        } else {
+            e[2] &= !0x02;
+        }
+        Ok(())
+    }
+
+    /// Turn the fault indicator of the given device slot on or off.
+    pub fn set_slot_fault(&mut self, slot: usize, on: bool) -> Sg3Result<()> {
+        let e = self.select_slot(slot)?;
+        if on {
+            e[3] |= 0x20;
+        } else {
+            e[3] &= !0x20;
+        }
+        Ok(())
+    }
+
+    /// Build the page to send with SEND DIAGNOSTIC.
+    pub fn to_page(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 8 + self.elements.len() * 4];
+
+        buf[0] = 0x02;
+        BigEndian::write_u16(&mut buf[2..4], (4 + self.elements.len() * 4) as u16);
+        BigEndian::write_u32(&mut buf[4..8], self.generation);
+        for (i, e) in self.elements.iter().enumerate() {
+            buf[8 + i * 4..12 + i * 4].copy_from_slice(e);
+        }
+
+        buf
+    }
+}
+
+// Send an SES Enclosure Control page to the enclosure services device
+// at the given path.
+pub fn send_enclosure_control<T: SgTransport + ?Sized>(
+    path: &T,
+    control: &EnclosureControl,
+) -> Sg3Result<()> {
+    send_diagnostic(path, None, true, &control.to_page())
+}
+
+// Read the current enclosure status, let `f` modify the resulting
+// control page, then send it.
+fn modify_enclosure_control<T: SgTransport + ?Sized, F>(path: &T, f: F) -> Sg3Result<()>
+where
+    F: FnOnce(&mut EnclosureControl) -> Sg3Result<()>,
+{
+    let (_, status) = ses_enclosure_status(path)?;
+    let mut control = EnclosureControl::new(&status);
+    f(&mut control)?;
+    send_enclosure_control(path, &control)
+}
+
+// Turn the identify (locate) indicator of a device slot in the
+// enclosure at the given path on or off.
+pub fn set_slot_ident<T: SgTransport + ?Sized>(path: &T, slot: usize, on: bool) -> Sg3Result<()> {
+    modify_enclosure_control(path, |control| control.set_slot_ident(slot, on))
+}
+
+// Turn the fault indicator of a device slot in the enclosure at the
+// given path on or off.
+pub fn set_slot_fault<T: SgTransport + ?Sized>(path: &T, slot: usize, on: bool) -> Sg3Result<()> {
+    modify_enclosure_control(path, |control| control.set_slot_fault(slot, on))
+}
+
+/// A device slot of an enclosure, combining everything the SES pages
+/// report about it.
+#[derive(Debug)]
+pub struct Slot {
+    /// Position of the slot's element in the Enclosure Status page's
+    /// `elements`.
+    pub element: usize,
+    /// Slot number from the Additional Element Status page, or else
+    /// the slot address from the status element, if either is known.
+    pub number: Option<u8>,
+    pub description: Option<String>,
+    pub status: ElementStatus,
+    /// SAS addresses of the drive in the slot, one per phy.
+    pub sas_addresses: Vec<u64>,
+}
+
+/// An enclosure as seen through its SES pages.
+#[derive(Debug)]
+pub struct Enclosure {
+    pub config: SesConfiguration,
+    pub status: EnclosureStatus,
+    /// Empty if the device does not support the Element Descriptor
+    /// page.
+    pub descriptors: Vec<ElementDescriptor>,
+    /// Empty if the device does not support the Additional Element
+    /// Status page.
+    pub additional: Vec<AdditionalElementStatus>,
+    /// Device slots, in the order `EnclosureControl` numbers them.
+    pub slots: Vec<Slot>,
+}
+
+fn enclosure_slots(
+    status: &EnclosureStatus,
+    descriptors: &[ElementDescriptor],
+    additional: &[AdditionalElementStatus],
+) -> Vec<Slot> {
+    status
+        .elements
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| {
+            e.index.is_some()
+                && (e.element_type == ElementType::DeviceSlot
+                    || e.element_type == ElementType::ArrayDeviceSlot)
+        })
+        .map(|(i, e)| {
+            let aes = additional.iter().find(|a| a.element == i && !a.invalid);
+            let slot_address = match e.detail {
+                ElementDetail::DeviceSlot { slot_address, .. } => slot_address,
+                _ => None,
+            };
+            Slot {
+                element: i,
+                number: aes.and_then(|a| a.device_slot_number).or(slot_address),
+                description: descriptors
+                    .get(i)
+                    .map(|d| d.text.clone())
+                    .filter(|t| !t.is_empty()),
+                status: e.clone(),
+                sas_addresses: aes
+                    .map(|a| a.phys.iter().map(|p| p.sas_address).collect())
+                    .unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+// Get a diagnostic page that a device need not support, returning None
+// if the device rejects it.
+fn optional_page<T: SgTransport + ?Sized>(path: &T, page: u8) -> Sg3Result<Option<Vec<u8>>> {
+    match receive_diagnostic_results(path, Some(page)) {
+        Ok(buf) => Ok(Some(buf)),
+        Err(Sg3Error::Scsi { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn same_generation(config: &SesConfiguration, page: &Option<Vec<u8>>) -> bool {
+    match page {
+        Some(buf) if buf.len() >= 8 => BigEndian::read_u32(&buf[4..8]) == config.generation,
+        _ => true,
+    }
+}
+
+// Read the configuration, status, element descriptors, and additional
+// element status of the enclosure services device at the given path,
+// and tie them together by device slot.
+pub fn ses_enclosure<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Enclosure> {
+    for _ in 0..3 {
+        let (config, status) = ses_enclosure_status(path)?;
+        let descriptors = optional_page(path, 0x07)?;
+        let additional = optional_page(path, 0x0a)?;
+        if !same_generation(&config, &descriptors) || !same_generation(&config, &additional) {
+            continue;
+        }
+
+        let descriptors = match descriptors {
+            Some(buf) => parse_element_descriptors(&config, &buf)?,
+            None => Vec::new(),
+        };
+        let additional = match additional {
+            Some(buf) => parse_additional_element_status(&config, &buf)?,
+            None => Vec::new(),
+        };
+        let slots = enclosure_slots(&status, &descriptors, &additional);
+
+        return Ok(Enclosure {
+            config,
+            status,
+            descriptors,
+            additional,
+            slots,
+        });
+    }
+
+    Err(invalid_data("SES configuration kept changing"))
+}
+
+// Map the device slots of the SAS enclosure at the given path to the
+// block devices of the drives in them, by matching the SAS addresses
+// from the Additional Element Status page against the SAS transport
+// class in sysfs. Slots are numbered as `EnclosureControl` numbers
+// them; empty slots and drives without a block device are left out.
+pub fn enclosure_slot_devices<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<BTreeMap<usize, PathBuf>> {
+    let enclosure = ses_enclosure(path)?;
+    let devices = sas_address_block_devices()?;
+
+    Ok(enclosure
+        .slots
+        .iter()
+        .enumerate()
+        .filter_map(|(i, slot)| {
+            slot.sas_addresses
+                .iter()
+                .find_map(|addr| devices.get(addr))
+                .map(|dev| (i, dev.clone()))
+        })
+        .collect())
+}
+
+/// A subenclosure's descriptor from the SES Download Microcode Status
+/// page (0x0e).
+#[derive(Debug, PartialEq, Eq)]
+pub struct MicrocodeStatus {
+    pub subenclosure_id: u8,
+    /// Download microcode status code; see the `is_*` methods.
+    pub status: u8,
+    pub additional_status: u8,
+    /// Largest microcode image the subenclosure accepts, in bytes.
+    pub max_size: u32,
+    pub expected_buffer_id: u8,
+    pub expected_buffer_offset: u32,
+}
+
+impl MicrocodeStatus {
+    /// A download is underway or the new microcode is being saved.
+    pub fn is_in_progress(&self) -> bool {
+        (0x01..=0x0f).contains(&self.status)
+    }
+
+    /// The download completed without error.
+    pub fn is_complete(&self) -> bool {
+        (0x10..=0x1f).contains(&self.status)
+    }
+
+    /// The download failed.
+    pub fn is_error(&self) -> bool {
+        self.status >= 0x80
+    }
+}
+
+fn parse_microcode_status(buf: &[u8]) -> Sg3Result<Vec<MicrocodeStatus>> {
+    if buf.len() < 8 || buf[0] != 0x0e {
+        return Err(invalid_data("not an SES Download Microcode Status page"));
+    }
+
+    Ok(buf[8..]
+        .chunks_exact(16)
+        .take(1 + buf[1] as usize)
+        .map(|d| MicrocodeStatus {
+            subenclosure_id: d[1],
+            status: d[2],
+            additional_status: d[3],
+            max_size: BigEndian::read_u32(&d[4..8]),
+            expected_buffer_id: d[11],
+            expected_buffer_offset: BigEndian::read_u32(&d[12..16]),
+        })
+        .collect())
+}
+
+// Get the SES Download Microcode Status page from the enclosure
+// services device at the given path, with a descriptor for each
+// subenclosure.
+pub fn ses_microcode_status<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<MicrocodeStatus>> {
+    parse_microcode_status(&receive_diagnostic_results(path, Some(0x0e))?)
+}
+
+fn microcode_control_page(
+    generation: u32,
+    subenclosure_id: u8,
+    mode: WriteBufferMode,
+    buffer_id: u8,
+    offset: u32,
+    image_len: u32,
+    data: &[u8],
+) -> Sg3Result<Vec<u8>> {
+    let mode = match mode {
+        WriteBufferMode::DownloadMicrocodeOffsetsSave => 0x07,
+        WriteBufferMode::DownloadMicrocodeOffsetsDefer => 0x0e,
+        WriteBufferMode::ActivateDeferredMicrocode => 0x0f,
+        _ => {
+            return Err(invalid_input(
+                "mode not supported by SES Download Microcode Control",
+            ))
+        }
+    };
+    // Microcode data is padded to a multiple of four bytes.
+    let padded = data.len().div_ceil(4) * 4;
+    if padded > 0xffff - 20 {
+        return Err(invalid_input("SES microcode chunk too long"));
+    }
+
+    let mut buf = vec![0u8; 24 + padded];
+    buf[0] = 0x0e;
+    buf[1] = subenclosure_id;
+    BigEndian::write_u16(&mut buf[2..4], (20 + padded) as u16);
+    BigEndian::write_u32(&mut buf[4..8], generation);
+    buf[8] = mode;
+    buf[11] = buffer_id;
+    BigEndian::write_u32(&mut buf[12..16], offset);
+    BigEndian::write_u32(&mut buf[16..20], image_len);
+    BigEndian::write_u32(&mut buf[20..24], data.len() as u32);
+    buf[24..24 + data.len()].copy_from_slice(data);
+
+    Ok(buf)
+}
+
+// Bytes of microcode to send per Download Microcode Control page.
+const SES_MICROCODE_CHUNK: usize = 4096;
+
+// How long to wait for a subenclosure to finish saving new microcode,
+// and how often to check.
+const SES_MICROCODE_TIMEOUT: Duration = Duration::from_secs(300);
+const SES_MICROCODE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn subenclosure_microcode_status<T: SgTransport + ?Sized>(
+    path: &T,
+    subenclosure_id: u8,
+) -> Sg3Result<MicrocodeStatus> {
+    let status = ses_microcode_status(path)?
+        .into_iter()
+        .find(|s| s.subenclosure_id == subenclosure_id)
+        .ok_or_else(|| invalid_input("no such SES subenclosure"))?;
+    if status.is_error() {
+        return Err(invalid_data("SES microcode download failed"));
+    }
+    Ok(status)
+}
+
+// Download microcode `image` to a subenclosure of the enclosure
+// services device at the given path, using the SES Download Microcode
+// Control page with the given WRITE BUFFER mode. The image is sent in
+// chunks, checking the subenclosure's status after each; `progress` is
+// called with the number of bytes sent so far and the image size. Once
+// all of it is sent, this waits for the subenclosure to finish saving
+// it.
+//
+// With `DownloadMicrocodeOffsetsDefer`, follow this with an empty
+// image in `ActivateDeferredMicrocode` mode to switch to it.
+pub fn ses_download_microcode<T: SgTransport + ?Sized, F>(
+    path: &T,
+    subenclosure_id: u8,
+    mode: WriteBufferMode,
+    buffer_id: u8,
+    image: &[u8],
+    mut progress: F,
+) -> Sg3Result<()>
+where
+    F: FnMut(usize, usize),
+{
+    let generation = ses_configuration(path)?.generation;
+
+    let mut chunks: Vec<&[u8]> = image.chunks(SES_MICROCODE_CHUNK).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let mut offset = 0;
+    for chunk in chunks {
+        let page = microcode_control_page(
+            generation,
+            subenclosure_id,
+            mode,
+            buffer_id,
+            offset as u32,
+            image.len() as u32,
+            chunk,
+        )?;
+        send_diagnostic(path, None, true, &page)?;
+        offset += chunk.len();
+        progress(offset, image.len());
+        subenclosure_microcode_status(path, subenclosure_id)?;
+    }
+
+    let start = Instant::now();
+    loop {
+        let status = subenclosure_microcode_status(path, subenclosure_id)?;
+        if !status.is_in_progress() {
+            return Ok(());
+        }
+        if start.elapsed() > SES_MICROCODE_TIMEOUT {
+            return Err(Sg3Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for SES microcode download",
+            )));
+        }
+        thread::sleep(SES_MICROCODE_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Vec<u8> {
+        let mut buf = vec![0x01, 0, 0, 0, 0, 0, 0, 7];
+        // enclosure descriptor with two type descriptor headers
+        buf.extend_from_slice(&[0x11, 0, 2, 36]);
+        buf.extend_from_slice(&[0x50, 0, 0, 0, 0, 0, 0, 1]);
+        buf.extend_from_slice(b"VENDOR  PRODUCT         0001");
+        // two device slots, one temperature sensor
+        buf.extend_from_slice(&[0x01, 2, 0, 5, 0x04, 1, 0, 0]);
+        buf.extend_from_slice(b"Slots");
+        let len = buf.len() as u16 - 4;
+        BigEndian::write_u16(&mut buf[2..4], len);
+        buf
+    }
+
+    #[test]
+    fn test_parse_configuration() {
+        let config = parse_configuration(&test_config()).unwrap();
+        assert_eq!(config.generation, 7);
+        assert_eq!(config.enclosures.len(), 1);
+        assert_eq!(config.enclosures[0].vendor, "VENDOR");
+        assert_eq!(config.enclosures[0].logical_id, 0x5000_0000_0000_0001);
+        assert_eq!(config.types.len(), 2);
+        assert_eq!(config.types[0].element_type, ElementType::DeviceSlot);
+        assert_eq!(config.types[0].num_elements, 2);
+        assert_eq!(config.types[0].text, "Slots");
+        assert_eq!(config.types[1].element_type, ElementType::TemperatureSensor);
+        assert_eq!(config.types[1].text, "");
+    }
+
+    #[test]
+    fn test_parse_enclosure_status() {
+        let config = parse_configuration(&test_config()).unwrap();
+        let buf = [
+            0x02, 0x02, 0, 24, 0, 0, 0, 7, // header, CRIT set
+            0x00, 0, 0, 0, // device slot overall
+            0x01, 0, 0x02, 0, // slot 0, OK, ident
+            0x02, 1, 0, 0x60, // slot 1, critical, fault sensed and requested
+            0x00, 0, 0, 0, // temperature overall
+            0x01, 0, 60, 0x04, // 40 C, over temperature warning
+        ];
+        let status = parse_enclosure_status(&config, &buf).unwrap();
+        assert!(status.critical);
+        assert_eq!(status.elements.len(), 5);
+        assert_eq!(status.elements[0].index, None);
+        assert_eq!(status.elements[1].index, Some(0));
+        match status.elements[1].detail {
+            ElementDetail::DeviceSlot { ident, .. } => assert!(ident),
+            _ => panic!(),
+        }
+        assert_eq!(status.elements[2].code, ElementStatusCode::Critical);
+        match status.elements[2].detail {
+            ElementDetail::DeviceSlot {
+                slot_address,
+                fault_sensed,
+                ..
+            } => assert_eq!((slot_address, fault_sensed), (Some(1), true)),
+            _ => panic!(),
+        }
+        match status.elements[4].detail {
+            ElementDetail::TemperatureSensor {
+                temperature,
+                over_temperature_warning,
+                ..
+            } => assert_eq!((temperature, over_temperature_warning), (Some(40), true)),
+            _ => panic!(),
+        }
+        assert!(parse_enclosure_status(&config, &buf[..20]).is_err());
+    }
+
+    #[test]
+    fn test_parse_element_descriptors() {
+        let config = parse_configuration(&test_config()).unwrap();
+        let mut buf = vec![0x07, 0, 0, 0, 0, 0, 0, 7];
+        buf.extend_from_slice(&[0, 0, 0, 5]);
+        buf.extend_from_slice(b"Bays ");
+        buf.extend_from_slice(&[0, 0, 0, 4]);
+        buf.extend_from_slice(b"Bay0");
+        buf.extend_from_slice(&[0, 0, 0, 4]);
+        buf.extend_from_slice(b"Bay1");
+        buf.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 4]);
+        buf.extend_from_slice(b"Temp");
+        let descs = parse_element_descriptors(&config, &buf).unwrap();
+        assert_eq!(descs.len(), 5);
+        assert_eq!(descs[0].text, "Bays");
+        assert_eq!(descs[0].index, None);
+        assert_eq!(descs[2].text, "Bay1");
+        assert_eq!(descs[2].index, Some(1));
+        assert_eq!(descs[3].text, "");
+        assert_eq!(descs[4].element_type, ElementType::TemperatureSensor);
+        assert_eq!(descs[4].text, "Temp");
+
+        buf[7] = 8;
+        assert!(parse_element_descriptors(&config, &buf).is_err());
+    }
+
+    #[test]
+    fn test_parse_additional_element_status() {
+        let config = parse_configuration(&test_config()).unwrap();
+        let mut buf = vec![0x0a, 0, 0, 0, 0, 0, 0, 7];
+        // slot 1, by element index, with one phy
+        buf.extend_from_slice(&[0x16, 34, 0, 1, 1, 0, 0, 5]);
+        let mut phy = [0u8; 28];
+        phy[0] = 0x10;
+        // SSP target
+        phy[3] = 0x08;
+        phy[4..12].copy_from_slice(&[0x50, 0, 0, 0, 0, 0, 0, 0xaa]);
+        phy[12..20].copy_from_slice(&[0x50, 0, 0, 0, 0, 0, 0, 0xbb]);
+        phy[20] = 3;
+        buf.extend_from_slice(&phy);
+        let descs = parse_additional_element_status(&config, &buf).unwrap();
+        assert_eq!(descs.len(), 1);
+        assert_eq!(descs[0].protocol, 6);
+        assert_eq!(descs[0].element, 2);
+        assert_eq!(descs[0].device_slot_number, Some(5));
+        assert_eq!(descs[0].phys.len(), 1);
+        assert!(descs[0].phys[0].ssp_target);
+        assert!(!descs[0].phys[0].smp_target);
+        assert_eq!(descs[0].phys[0].sas_address, 0x5000_0000_0000_00bb);
+        assert_eq!(descs[0].phys[0].phy_id, 3);
+
+        // Without element indexes, descriptors follow element order.
+        let buf = [0x0a, 0, 0, 0, 0, 0, 0, 7, 0x06, 2, 0, 0];
+        let descs = parse_additional_element_status(&config, &buf).unwrap();
+        assert_eq!(descs[0].element, 1);
+        assert_eq!(descs[0].device_slot_number, None);
+    }
+
+    #[test]
+    fn test_enclosure_slots() {
+        let config = parse_configuration(&test_config()).unwrap();
+        let buf = [
+            0x02, 0, 0, 24, 0, 0, 0, 7, // header
+            0x00, 0, 0, 0, // device slot overall
+            0x01, 0, 0x02, 0, // slot 0
+            0x01, 1, 0, 0, // slot 1
+            0x00, 0, 0, 0, // temperature overall
+            0x01, 0, 60, 0x04, // temperature
+        ];
+        let status = parse_enclosure_status(&config, &buf).unwrap();
+        let descriptors = vec![
+            ElementDescriptor {
+                element_type: ElementType::DeviceSlot,
+                subenclosure_id: 0,
+                index: None,
+                text: String::new(),
+            },
+            ElementDescriptor {
+                element_type: ElementType::DeviceSlot,
+                subenclosure_id: 0,
+                index: Some(0),
+                text: "Bay0".to_string(),
+            },
+        ];
+        let additional = vec![AdditionalElementStatus {
+            invalid: false,
+            protocol: 6,
+            element: 2,
+            device_slot_number: Some(7),
+            phys: Vec::new(),
+        }];
+        let slots = enclosure_slots(&status, &descriptors, &additional);
+        assert_eq!(slots.len(), 2);
+        assert_eq!(slots[0].element, 1);
+        assert_eq!(slots[0].number, Some(0));
+        assert_eq!(slots[0].description, Some("Bay0".to_string()));
+        assert_eq!(slots[1].element, 2);
+        assert_eq!(slots[1].number, Some(7));
+        assert_eq!(slots[1].description, None);
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let config = parse_configuration(&test_config()).unwrap();
+        let buf = [
+            0x05, 0, 0, 24, 0, 0, 0, 7, // header
+            0, 0, 0, 0, // device slot overall
+            0, 0, 0, 0, // slot 0
+            0, 0, 0, 0, // slot 1
+            0, 0, 0, 0, // temperature overall
+            80, 70, 25, 20, // temperature
+        ];
+        let mut thresholds = parse_thresholds(&config, &buf).unwrap();
+        assert_eq!(thresholds.elements.len(), 5);
+        assert_eq!(thresholds.elements[4].index, Some(0));
+        assert_eq!(thresholds.elements[4].thresholds.high_warning, 70);
+
+        thresholds.elements[4].thresholds.high_warning = 65;
+        let page = thresholds.to_page();
+        assert_eq!(&page[..8], &buf[..8]);
+        assert_eq!(&page[24..], &[80, 65, 25, 20]);
+    }
+
+    #[test]
+    fn test_string_out_page() {
+        assert_eq!(
+            string_out_page(b"help\n").unwrap(),
+            vec![0x04, 0, 0, 5, b'h', b'e', b'l', b'p', b'\n']
+        );
+        assert!(string_out_page(&vec![0u8; 0x10000]).is_err());
+    }
+
+    #[test]
+    fn test_microcode_control_page() {
+        let page = microcode_control_page(
+            7,
+            0,
+            WriteBufferMode::DownloadMicrocodeOffsetsSave,
+            0,
+            0x1000,
+            0x1005,
+            &[1, 2, 3, 4, 5],
+        )
+        .unwrap();
+        assert_eq!(page.len(), 32);
+        assert_eq!(&page[..8], &[0x0e, 0, 0, 28, 0, 0, 0, 7]);
+        assert_eq!(page[8], 0x07);
+        assert_eq!(
+            &page[12..24],
+            &[0, 0, 0x10, 0, 0, 0, 0x10, 0x05, 0, 0, 0, 5]
+        );
+        assert_eq!(&page[24..], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        assert!(microcode_control_page(0, 0, WriteBufferMode::Data, 0, 0, 0, &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_microcode_status() {
+        let buf = [
+            0x0e, 0, 0, 20, 0, 0, 0, 7, // header
+            0, 0, 0x01, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0x20, 0, // in progress
+        ];
+        let status = parse_microcode_status(&buf).unwrap();
+        assert_eq!(status.len(), 1);
+        assert!(status[0].is_in_progress());
+        assert_eq!(status[0].max_size, 0x100000);
+        assert_eq!(status[0].expected_buffer_offset, 0x2000);
+    }
+
+    #[test]
+    fn test_enclosure_control() {
+        let config = parse_configuration(&test_config()).unwrap();
+        let buf = [
+            0x02, 0, 0, 24, 0, 0, 0, 7, // header
+            0x00, 0, 0, 0, // device slot overall
+            0x01, 0, 0x02, 0, // slot 0, ident
+            0x41, 1, 0, 0x60, // slot 1, predicted failure, fault
+            0x00, 0, 0, 0, // temperature overall
+            0x01, 0, 60, 0x04, // temperature
+        ];
+        let status = parse_enclosure_status(&config, &buf).unwrap();
+        let mut control = EnclosureControl::new(&status);
+        control.set_slot_fault(0, true).unwrap();
+        control.set_slot_ident(1, true).unwrap();
+        assert!(control.set_slot_ident(2, true).is_err());
+        assert_eq!(
+            control.to_page(),
+            vec![
+                0x02, 0, 0, 24, 0, 0, 0, 7, // header
+                0, 0, 0, 0, // not selected
+                0x80, 0, 0x02, 0x20, // ident kept, fault set
+                0xc0, 0, 0x02, 0x20, // ident set, fault kept
+                0, 0, 0, 0, // not selected
+                0, 0, 0, 0, // not selected
+            ]
+        );
+    }
+}
@@ -0,0 +1,926 @@
+//! Ioctls of the Linux sg driver, issued on an open sg device.
+
+use std::alloc::{self, Layout};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::time::Instant;
+
+use nix::libc::{self, ioctl as nix_ioctl};
+
+use crate::{
+    check_cdb, ffi, invalid_data, invalid_input, open_device, sg_io_file, sg_io_result,
+    to_device_type, CommandResult, DataDirection, PeripheralDeviceType, ScsiAddress, Sg3Error,
+    Sg3Result, SgTransport,
+};
+
+// From <scsi/scsi_ioctl.h>, which sg also supports.
+const SCSI_IOCTL_GET_IDLUN: u64 = 0x5382;
+const SCSI_IOCTL_GET_BUS_NUMBER: u64 = 0x5386;
+// From <linux/fs.h>. On sg devices it gives bytes, not sectors.
+const BLKSECTGET: u64 = 0x1267;
+
+// Not in glibc's <scsi/sg.h>.
+const SG_FLAG_MMAP_IO: u32 = 0x04;
+const SG_SCSI_RESET_TARGET: i32 = 4;
+const SG_SCSI_RESET_NO_ESCALATE: i32 = 0x100;
+
+/// An open sg device node, such as /dev/sg0, for the ioctls that only
+/// the sg driver supports. Commands sent through it use the same fd,
+/// and so its reserved buffer.
+#[derive(Debug)]
+pub struct SgFile {
+    file: File,
+    path: PathBuf,
+    direct_io: Cell<bool>,
+}
+
+/// The kernel's addressing of the SCSI device behind an sg node, from
+/// the SG_GET_SCSI_ID ioctl.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SgScsiId {
+    pub address: ScsiAddress,
+    pub device_type: PeripheralDeviceType,
+    /// How many commands the host adapter queues per LUN.
+    pub cmd_per_lun: u16,
+    /// How many commands the kernel queues to the device.
+    pub queue_depth: u16,
+}
+
+fn to_sg_scsi_id(id: &ffi::sg_scsi_id) -> SgScsiId {
+    SgScsiId {
+        address: ScsiAddress {
+            host: id.host_no as u32,
+            channel: id.channel as u32,
+            target: id.scsi_id as u32,
+            lun: id.lun as u32 as u64,
+        },
+        device_type: to_device_type(id.scsi_type as u8),
+        cmd_per_lun: id.h_cmd_per_lun as u16,
+        queue_depth: id.d_queue_depth as u16,
+    }
+}
+
+/// The device's address in the form of the older SCSI_IOCTL_GET_IDLUN
+/// ioctl, which only has room for eight bits of each part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScsiIdLun {
+    pub host: u8,
+    pub channel: u8,
+    pub target: u8,
+    pub lun: u8,
+    /// A number unique to the host adapter, as long as it stays loaded.
+    pub host_unique_id: u32,
+}
+
+// struct scsi_idlun, as the kernel fills it in.
+#[repr(C)]
+#[derive(Default)]
+struct RawIdLun {
+    dev_id: u32,
+    host_unique_id: u32,
+}
+
+fn to_scsi_idlun(idlun: &RawIdLun) -> ScsiIdLun {
+    ScsiIdLun {
+        host: (idlun.dev_id >> 24) as u8,
+        channel: (idlun.dev_id >> 16) as u8,
+        lun: (idlun.dev_id >> 8) as u8,
+        target: idlun.dev_id as u8,
+        host_unique_id: idlun.host_unique_id,
+    }
+}
+
+/// The version of the sg driver, e.g. 3.5.36.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SgDriverVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl SgDriverVersion {
+    fn from_num(num: u32) -> SgDriverVersion {
+        SgDriverVersion {
+            major: num / 10000,
+            minor: num / 100 % 100,
+            patch: num % 100,
+        }
+    }
+}
+
+impl fmt::Display for SgDriverVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// What the sg driver behind an open device supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SgCapabilities {
+    pub version: SgDriverVersion,
+    /// Whether the driver accepts the v4 (struct sg_io_v4) interface,
+    /// which it does from version 4.0.
+    pub v4_interface: bool,
+    /// The size of the fd's reserved buffer, in bytes.
+    pub reserved_size: usize,
+}
+
+/// Per-fd settings of the sg driver, for `SgFile::open_with()` and
+/// `SgFile::apply()`. Settings left as None are not changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SgSettings {
+    /// Have read() return the command with the pack id asked for,
+    /// rather than the first to complete (SG_SET_FORCE_PACK_ID).
+    pub force_pack_id: Option<bool>,
+    /// Keep the result of a command interrupted by a signal, for a
+    /// later read(), rather than discarding it (SG_SET_KEEP_ORPHAN).
+    pub keep_orphan: Option<bool>,
+    /// Allow more than one command at a time to be queued on the fd
+    /// (SG_SET_COMMAND_Q).
+    pub command_queuing: Option<bool>,
+    /// The size of the reserved buffer, in bytes
+    /// (SG_SET_RESERVED_SIZE).
+    pub reserved_size: Option<usize>,
+    /// Ask for direct IO on commands sent through the file.
+    pub direct_io: Option<bool>,
+}
+
+impl SgFile {
+    /// Open the sg device at the given path, read-write if allowed.
+    pub fn open(path: &Path) -> Sg3Result<SgFile> {
+        Ok(SgFile {
+            file: open_device(path)?,
+            path: path.to_path_buf(),
+            direct_io: Cell::new(false),
+        })
+    }
+
+    /// Open the sg device at the given path, and apply the given
+    /// settings to it.
+    pub fn open_with(path: &Path, settings: &SgSettings) -> Sg3Result<SgFile> {
+        let file = SgFile::open(path)?;
+        file.apply(settings)?;
+        Ok(file)
+    }
+
+    // Set a boolean per-fd setting of the driver.
+    fn set_flag(&self, req: u32, on: bool) -> Sg3Result<()> {
+        let on = on as i32;
+        unsafe { convert_ioctl_res!(nix_ioctl(self.file.as_raw_fd(), req as u64, &on))? };
+        Ok(())
+    }
+
+    /// Change the settings that are given, leaving the rest alone.
+    pub fn apply(&self, settings: &SgSettings) -> Sg3Result<()> {
+        if let Some(on) = settings.force_pack_id {
+            self.set_flag(ffi::SG_SET_FORCE_PACK_ID, on)?;
+        }
+        if let Some(on) = settings.keep_orphan {
+            self.set_flag(ffi::SG_SET_KEEP_ORPHAN, on)?;
+        }
+        if let Some(on) = settings.command_queuing {
+            self.set_flag(ffi::SG_SET_COMMAND_Q, on)?;
+        }
+        if let Some(size) = settings.reserved_size {
+            self.set_reserved_size(size)?;
+        }
+        if let Some(on) = settings.direct_io {
+            self.set_direct_io(on);
+        }
+        Ok(())
+    }
+
+    /// Get the H:C:T:L address, type and queue depths of the device.
+    pub fn scsi_id(&self) -> Sg3Result<SgScsiId> {
+        let mut id: ffi::sg_scsi_id = Default::default();
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                ffi::SG_GET_SCSI_ID as u64,
+                &mut id
+            ))?
+        };
+        Ok(to_sg_scsi_id(&id))
+    }
+
+    /// Get the version of the sg driver. Nodes that aren't sg devices,
+    /// such as block devices, fail with EINVAL or ENOTTY.
+    pub fn driver_version(&self) -> Sg3Result<SgDriverVersion> {
+        let mut num: i32 = 0;
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                ffi::SG_GET_VERSION_NUM as u64,
+                &mut num
+            ))?
+        };
+        Ok(SgDriverVersion::from_num(num as u32))
+    }
+
+    /// Get the size of the fd's reserved buffer, in bytes. Transfers
+    /// up to this size use the buffer rather than allocating their own.
+    pub fn reserved_size(&self) -> Sg3Result<usize> {
+        let mut size: i32 = 0;
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                ffi::SG_GET_RESERVED_SIZE as u64,
+                &mut size
+            ))?
+        };
+        Ok(size as usize)
+    }
+
+    /// Ask for a reserved buffer of the given size, and return the size
+    /// the driver gave it, which may be smaller.
+    pub fn set_reserved_size(&self, size: usize) -> Sg3Result<usize> {
+        let size = size.min(i32::MAX as usize) as i32;
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                ffi::SG_SET_RESERVED_SIZE as u64,
+                &size
+            ))?
+        };
+        self.reserved_size()
+    }
+
+    /// The largest transfer, in bytes, that both fits the reserved
+    /// buffer and is within the limit of the device's request queue.
+    /// Larger transfers either need buffers of their own or fail.
+    pub fn max_transfer_length(&self) -> Sg3Result<usize> {
+        let mut max_bytes: i32 = 0;
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(self.file.as_raw_fd(), BLKSECTGET, &mut max_bytes))?
+        };
+        Ok(self.reserved_size()?.min(max_bytes as usize))
+    }
+
+    /// Find out what the sg driver supports, so that callers can avoid
+    /// features an old kernel lacks.
+    pub fn capabilities(&self) -> Sg3Result<SgCapabilities> {
+        let version = self.driver_version()?;
+        Ok(SgCapabilities {
+            version,
+            v4_interface: version.major >= 4,
+            reserved_size: self.reserved_size()?,
+        })
+    }
+
+    /// Get the device's address with the older SCSI_IOCTL_GET_IDLUN.
+    pub fn idlun(&self) -> Sg3Result<ScsiIdLun> {
+        let mut idlun: RawIdLun = Default::default();
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                SCSI_IOCTL_GET_IDLUN,
+                &mut idlun
+            ))?
+        };
+        Ok(to_scsi_idlun(&idlun))
+    }
+
+    /// Get the number of the host adapter the device is attached to,
+    /// with SCSI_IOCTL_GET_BUS_NUMBER.
+    pub fn bus_number(&self) -> Sg3Result<u32> {
+        let mut bus: i32 = 0;
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                SCSI_IOCTL_GET_BUS_NUMBER,
+                &mut bus
+            ))?
+        };
+        Ok(bus as u32)
+    }
+
+    /// Whether the device is behind a host that emulates SCSI, such as
+    /// usb-storage, rather than a real SCSI host adapter. Such devices
+    /// often only understand a few commands well, and an ATA device
+    /// behind one may be better driven with ATA PASS-THROUGH.
+    pub fn is_emulated_host(&self) -> Sg3Result<bool> {
+        let mut emulated: i32 = 0;
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                ffi::SG_EMULATED_HOST as u64,
+                &mut emulated
+            ))?
+        };
+        Ok(emulated != 0)
+    }
+
+    // Ask the kernel's SCSI error handler for a reset of the given kind.
+    fn reset(&self, kind: i32, escalate: bool) -> Sg3Result<()> {
+        let arg = if escalate {
+            kind
+        } else {
+            kind | SG_SCSI_RESET_NO_ESCALATE
+        };
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                ffi::SG_SCSI_RESET as u64,
+                &arg
+            ))?
+        };
+        Ok(())
+    }
+
+    /// Reset the logical unit (LU reset). If `escalate` is set and that
+    /// fails, the kernel goes on to reset the target, then the bus, then
+    /// the host; otherwise it stops there, and this gives an error.
+    pub fn reset_device(&self, escalate: bool) -> Sg3Result<()> {
+        self.reset(ffi::SG_SCSI_RESET_DEVICE as i32, escalate)
+    }
+
+    /// Reset the target and all of its logical units, escalating to bus
+    /// and host resets if `escalate` is set.
+    pub fn reset_target(&self, escalate: bool) -> Sg3Result<()> {
+        self.reset(SG_SCSI_RESET_TARGET, escalate)
+    }
+
+    /// Reset the bus the device is on, escalating to a host reset if
+    /// `escalate` is set. This disturbs every device on the bus.
+    pub fn reset_bus(&self, escalate: bool) -> Sg3Result<()> {
+        self.reset(ffi::SG_SCSI_RESET_BUS as i32, escalate)
+    }
+
+    /// Reset the host adapter the device is attached to, which there is
+    /// nothing to escalate beyond. This disturbs every device on it.
+    pub fn reset_host(&self) -> Sg3Result<()> {
+        self.reset(ffi::SG_SCSI_RESET_HOST as i32, false)
+    }
+}
+
+impl SgFile {
+    /// Map the fd's reserved buffer into memory, to transfer data
+    /// through it without the copy SG_IO otherwise makes. Size it first
+    /// with `set_reserved_size()`.
+    pub fn mmap(&self) -> Sg3Result<SgMmap<'_>> {
+        let len = self.reserved_size()?;
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(SgMmap {
+            file: self,
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+}
+
+/// The data phase of a command that transfers through an `SgMmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmapTransfer {
+    None,
+    /// Read this many bytes from the device into the start of the
+    /// buffer.
+    FromDevice(usize),
+    /// Send this many bytes from the start of the buffer to the device.
+    ToDevice(usize),
+}
+
+/// The reserved buffer of an `SgFile`, mapped into memory by
+/// `SgFile::mmap()`. Only one command at a time can use it.
+#[derive(Debug)]
+pub struct SgMmap<'a> {
+    file: &'a SgFile,
+    addr: *mut u8,
+    len: usize,
+}
+
+impl SgMmap<'_> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.addr, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.addr, self.len) }
+    }
+
+    /// Issue the given CDB, with a timeout in milliseconds, with its
+    /// data in the mapped buffer.
+    pub fn execute(
+        &mut self,
+        cmd: &[u8],
+        data: MmapTransfer,
+        timeout: u32,
+    ) -> Sg3Result<CommandResult> {
+        check_cdb(cmd)?;
+
+        let (direction, len) = match data {
+            MmapTransfer::None => (ffi::SG_DXFER_NONE, 0),
+            MmapTransfer::FromDevice(len) => (ffi::SG_DXFER_FROM_DEV, len),
+            MmapTransfer::ToDevice(len) => (ffi::SG_DXFER_TO_DEV, len),
+        };
+        if len > self.len {
+            return Err(invalid_input("transfer is larger than the mapped buffer"));
+        }
+
+        let mut cdb = cmd.to_vec();
+        let mut sb = [0u8; 64];
+        let mut sgbuf = ffi::sg_io_hdr {
+            interface_id: 'S' as i32,
+            dxfer_direction: direction,
+            cmd_len: cdb.len() as u8,
+            cmdp: cdb.as_mut_ptr(),
+            mx_sb_len: sb.len() as u8,
+            sbp: sb.as_mut_ptr(),
+            dxfer_len: len as u32,
+            timeout,
+            flags: SG_FLAG_MMAP_IO,
+            ..Default::default()
+        };
+
+        let submitted = Instant::now();
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.file.as_raw_fd(),
+                ffi::SG_IO as u64,
+                &mut sgbuf
+            ))?
+        };
+
+        sg_io_result(&sgbuf, &self.file.path, cdb, &sb, submitted)
+    }
+}
+
+impl Drop for SgMmap<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr as *mut c_void, self.len) };
+    }
+}
+
+impl SgFile {
+    /// Ask for direct IO, between the device and the caller's buffers,
+    /// for every command sent through this file. See
+    /// `execute_direct_io()`.
+    pub fn set_direct_io(&self, direct_io: bool) {
+        self.direct_io.set(direct_io);
+    }
+
+    /// Issue the given CDB, with a timeout in milliseconds, asking for
+    /// direct IO: data moves between the device and the buffer without
+    /// a copy through the kernel. The buffer should be an `AlignedBuf`.
+    /// The driver quietly copies instead if the buffer isn't aligned as
+    /// the device needs, or if /proc/scsi/sg/allow_dio is 0.
+    pub fn execute_direct_io(
+        &self,
+        cmd: &[u8],
+        data: DataDirection,
+        timeout: u32,
+    ) -> Sg3Result<CommandResult> {
+        sg_io_file(
+            &self.file,
+            &self.path,
+            cmd,
+            data,
+            timeout,
+            ffi::SG_FLAG_DIRECT_IO,
+        )
+    }
+}
+
+/// A zeroed buffer on the heap, aligned to the page size as direct IO
+/// needs.
+#[derive(Debug)]
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    len: usize,
+}
+
+// The buffer is owned, like a Vec.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(len: usize) -> AlignedBuf {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize;
+        let layout = Layout::from_size_align(len.max(1), page_size).expect("buffer is too large");
+        let ptr = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+        AlignedBuf { ptr, layout, len }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+impl SgTransport for SgFile {
+    fn execute(&self, cmd: &[u8], data: DataDirection, timeout: u32) -> Sg3Result<CommandResult> {
+        let flags = if self.direct_io.get() {
+            ffi::SG_FLAG_DIRECT_IO
+        } else {
+            0
+        };
+        sg_io_file(&self.file, &self.path, cmd, data, timeout, flags)
+    }
+}
+
+/// The data phase of a queued command. The queue holds the buffer
+/// while the command is in flight, and hands it back on completion.
+#[derive(Debug)]
+pub enum QueuedData {
+    None,
+    /// Read up to this many bytes from the device.
+    FromDevice(usize),
+    ToDevice(Vec<u8>),
+}
+
+/// A queued command that has finished.
+#[derive(Debug)]
+pub struct Completion {
+    pub pack_id: i32,
+    pub result: Sg3Result<CommandResult>,
+    /// The buffer of the command: the data read from the device, or
+    /// the data that was sent to it.
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    cdb: Vec<u8>,
+    data: Vec<u8>,
+    sense: Vec<u8>,
+    submitted: Instant,
+}
+
+// Whether a failed write of a command means the driver is queueing as
+// many as it will on the fd: it fails the write with EDOM then.
+fn queue_full(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EDOM) || err.kind() == io::ErrorKind::WouldBlock
+}
+
+/// An sg device that commands are queued on with the driver's write()
+/// and read() interface, rather than issued one at a time with SG_IO.
+/// Each command is given a pack id, several can be in flight at once,
+/// and they can be collected in any order.
+///
+/// The driver copies data for reads into their buffers only when they
+/// are collected, so dropping the queue with commands still in flight
+/// is safe; their results are lost.
+#[derive(Debug)]
+pub struct SgQueue {
+    file: SgFile,
+    in_flight: HashMap<i32, InFlight>,
+}
+
+impl SgQueue {
+    /// Queue commands on the given device. This has the driver match
+    /// completions to pack ids, and queue more than one command.
+    pub fn new(file: SgFile) -> Sg3Result<SgQueue> {
+        file.apply(&SgSettings {
+            force_pack_id: Some(true),
+            command_queuing: Some(true),
+            ..Default::default()
+        })?;
+        Ok(SgQueue {
+            file,
+            in_flight: HashMap::new(),
+        })
+    }
+
+    /// The device the commands are queued on.
+    pub fn file(&self) -> &SgFile {
+        &self.file
+    }
+
+    /// How many commands have been submitted and not yet collected.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Whether a command with the given pack id is in flight.
+    pub fn is_in_flight(&self, pack_id: i32) -> bool {
+        self.in_flight.contains_key(&pack_id)
+    }
+
+    /// Queue the given CDB, with a timeout in milliseconds, under a pack
+    /// id that isn't in flight already. This returns once the driver
+    /// has accepted the command, not when it completes.
+    pub fn submit(
+        &mut self,
+        pack_id: i32,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<()> {
+        match self.queue_command(pack_id, cmd, data, timeout)? {
+            Ok(()) => Ok(()),
+            Err((e, _)) => Err(e.into()),
+        }
+    }
+
+    /// Queue the given CDB as `submit()` does, unless the driver already
+    /// has as many commands on the fd as it will take. Then the data is
+    /// handed back, to submit again once a command has been collected.
+    pub fn try_submit(
+        &mut self,
+        pack_id: i32,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<Option<QueuedData>> {
+        match self.queue_command(pack_id, cmd, data, timeout)? {
+            Ok(()) => Ok(None),
+            Err((ref e, data)) if queue_full(e) => Ok(Some(data)),
+            Err((e, _)) => Err(e.into()),
+        }
+    }
+
+    // Write a command to the driver. If the write fails, its error comes
+    // with the command's data, so that it can be submitted again.
+    fn queue_command(
+        &mut self,
+        pack_id: i32,
+        cmd: &[u8],
+        data: QueuedData,
+        timeout: u32,
+    ) -> Sg3Result<Result<(), (io::Error, QueuedData)>> {
+        check_cdb(cmd)?;
+        if pack_id < 0 {
+            return Err(invalid_input("pack id must not be negative"));
+        }
+        if self.is_in_flight(pack_id) {
+            return Err(invalid_input("pack id is already in flight"));
+        }
+
+        let mut sgbuf: ffi::sg_io_hdr = Default::default();
+        let mut req = InFlight {
+            cdb: cmd.to_vec(),
+            data: Vec::new(),
+            sense: vec![0u8; 64],
+            submitted: Instant::now(),
+        };
+
+        let hand_back: fn(Vec<u8>) -> QueuedData = match data {
+            QueuedData::None => |_| QueuedData::None,
+            QueuedData::FromDevice(_) => |buf| QueuedData::FromDevice(buf.len()),
+            QueuedData::ToDevice(_) => QueuedData::ToDevice,
+        };
+        match data {
+            QueuedData::None => {
+                sgbuf.dxfer_direction = ffi::SG_DXFER_NONE;
+            }
+            QueuedData::FromDevice(len) => {
+                sgbuf.dxfer_direction = ffi::SG_DXFER_FROM_DEV;
+                req.data = vec![0u8; len];
+            }
+            QueuedData::ToDevice(buf) => {
+                sgbuf.dxfer_direction = ffi::SG_DXFER_TO_DEV;
+                req.data = buf;
+            }
+        }
+
+        sgbuf.interface_id = 'S' as i32;
+        sgbuf.cmd_len = req.cdb.len() as u8;
+        sgbuf.cmdp = req.cdb.as_mut_ptr();
+        sgbuf.mx_sb_len = req.sense.len() as u8;
+        sgbuf.sbp = req.sense.as_mut_ptr();
+        sgbuf.dxfer_len = req.data.len() as u32;
+        sgbuf.dxferp = req.data.as_mut_ptr() as *mut c_void;
+        sgbuf.timeout = timeout;
+        sgbuf.pack_id = pack_id;
+
+        // The buffers are on the heap, so moving `req` into the map
+        // leaves the pointers to them valid.
+        let hdr = unsafe {
+            slice::from_raw_parts(
+                &sgbuf as *const ffi::sg_io_hdr as *const u8,
+                mem::size_of::<ffi::sg_io_hdr>(),
+            )
+        };
+        if let Err(e) = (&self.file.file).write_all(hdr) {
+            return Ok(Err((e, hand_back(req.data))));
+        }
+        self.in_flight.insert(pack_id, req);
+
+        Ok(Ok(()))
+    }
+
+    /// Make `receive()` return an error of kind WouldBlock rather than
+    /// wait when no command is ready, for use with poll() or epoll.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Sg3Result<()> {
+        let fd = self.file.file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Collect the first command to finish, or None if none has, on a
+    /// non-blocking queue. Call it when the fd polls readable.
+    pub fn try_receive(&mut self) -> Sg3Result<Option<Completion>> {
+        match self.receive(None) {
+            Err(Sg3Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            res => res.map(Some),
+        }
+    }
+
+    /// Collect the command with the given pack id, or the first to
+    /// finish if None, waiting for it to complete unless the fd is
+    /// non-blocking.
+    pub fn receive(&mut self, pack_id: Option<i32>) -> Sg3Result<Completion> {
+        if let Some(id) = pack_id {
+            if !self.is_in_flight(id) {
+                return Err(invalid_input("pack id is not in flight"));
+            }
+        }
+        if self.in_flight.is_empty() {
+            return Err(invalid_input("no commands are in flight"));
+        }
+
+        let mut sgbuf = ffi::sg_io_hdr {
+            interface_id: 'S' as i32,
+            pack_id: pack_id.unwrap_or(-1),
+            ..Default::default()
+        };
+
+        let hdr = unsafe {
+            slice::from_raw_parts_mut(
+                &mut sgbuf as *mut ffi::sg_io_hdr as *mut u8,
+                mem::size_of::<ffi::sg_io_hdr>(),
+            )
+        };
+        (&self.file.file).read_exact(hdr)?;
+
+        let req = match self.in_flight.remove(&sgbuf.pack_id) {
+            Some(req) => req,
+            None => return Err(invalid_data("driver returned an unknown pack id")),
+        };
+
+        Ok(Completion {
+            pack_id: sgbuf.pack_id,
+            result: sg_io_result(&sgbuf, &self.file.path, req.cdb, &req.sense, req.submitted),
+            data: req.data,
+        })
+    }
+}
+
+impl AsRawFd for SgFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for SgFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+// The queue's fd polls readable (POLLIN) when a command has completed
+// and can be collected, and writable (POLLOUT) when the driver will
+// accept another command.
+impl AsRawFd for SgQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for SgQueue {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+// Get the version of the sg driver through the sg device at the given
+// path.
+pub fn sg_driver_version(path: &Path) -> Sg3Result<SgDriverVersion> {
+    SgFile::open(path)?.driver_version()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sg_scsi_id() {
+        let id = ffi::sg_scsi_id {
+            host_no: 3,
+            channel: 0,
+            scsi_id: 2,
+            lun: 1,
+            scsi_type: 0x08,
+            h_cmd_per_lun: 7,
+            d_queue_depth: 32,
+            ..Default::default()
+        };
+        let id = to_sg_scsi_id(&id);
+        assert_eq!(
+            id.address,
+            ScsiAddress {
+                host: 3,
+                channel: 0,
+                target: 2,
+                lun: 1,
+            }
+        );
+        assert_eq!(id.device_type, PeripheralDeviceType::MediaChanger);
+        assert_eq!(id.queue_depth, 32);
+    }
+
+    #[test]
+    fn test_scsi_idlun() {
+        let idlun = to_scsi_idlun(&RawIdLun {
+            dev_id: 0x0300_0102,
+            host_unique_id: 12,
+        });
+        assert_eq!(
+            idlun,
+            ScsiIdLun {
+                host: 3,
+                channel: 0,
+                target: 2,
+                lun: 1,
+                host_unique_id: 12,
+            }
+        );
+    }
+
+    #[test]
+    fn test_driver_version() {
+        let version = SgDriverVersion::from_num(30536);
+        assert_eq!(version.to_string(), "3.5.36");
+        assert!(version < SgDriverVersion::from_num(40045));
+    }
+
+    #[test]
+    fn test_queue_pack_ids() {
+        let mut queue = SgQueue {
+            file: SgFile::open(Path::new("/dev/null")).unwrap(),
+            in_flight: HashMap::new(),
+        };
+        assert!(queue.submit(-1, &[0; 6], QueuedData::None, 0).is_err());
+        assert!(queue.receive(Some(1)).is_err());
+        assert!(queue.receive(None).is_err());
+    }
+
+    #[test]
+    fn test_aligned_buf() {
+        let mut buf = AlignedBuf::new(8192);
+        assert_eq!(buf.len(), 8192);
+        assert_eq!(buf.as_ptr() as usize % 4096, 0);
+        assert!(buf.iter().all(|&b| b == 0));
+        buf[8191] = 1;
+        assert_eq!(AlignedBuf::new(0).len(), 0);
+    }
+}
@@ -0,0 +1,387 @@
+//! SCSI Media Changer Commands (SMC) for medium changers, such as the
+//! robot in a tape library.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{read_variable_length, sg_io_timeout, DataDirection, Sg3Result, SgTransport};
+
+// Moving media takes the robot a while, and reading element status can
+// mean it scanning every slot.
+const CHANGER_TIMEOUT: u32 = 10 * 60 * 1000;
+
+// A full inventory of a large library can take much longer.
+const INVENTORY_TIMEOUT: u32 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangerElementType {
+    /// A robot arm (picker) that moves media.
+    MediumTransport,
+    /// A slot that holds a medium.
+    Storage,
+    /// A mail slot for adding and removing media.
+    ImportExport,
+    /// A drive.
+    DataTransfer,
+    Other(u8),
+}
+
+fn to_changer_element_type(i: u8) -> ChangerElementType {
+    match i & 0x0f {
+        1 => ChangerElementType::MediumTransport,
+        2 => ChangerElementType::Storage,
+        3 => ChangerElementType::ImportExport,
+        4 => ChangerElementType::DataTransfer,
+        i => ChangerElementType::Other(i),
+    }
+}
+
+fn from_changer_element_type(t: ChangerElementType) -> u8 {
+    match t {
+        ChangerElementType::MediumTransport => 1,
+        ChangerElementType::Storage => 2,
+        ChangerElementType::ImportExport => 3,
+        ChangerElementType::DataTransfer => 4,
+        ChangerElementType::Other(i) => i & 0x0f,
+    }
+}
+
+/// The status of an element of a medium changer, from READ ELEMENT
+/// STATUS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangerElement {
+    pub element_type: ChangerElementType,
+    pub address: u16,
+    /// The element holds a medium.
+    pub full: bool,
+    /// The element is in an abnormal state; see `asc` and `ascq`.
+    pub except: bool,
+    /// The medium transport can reach the element.
+    pub access: bool,
+    /// For an import/export element, the medium was put there by an
+    /// operator rather than by the robot.
+    pub imported: bool,
+    pub asc: u8,
+    pub ascq: u8,
+    /// The element the medium was last moved from.
+    pub source: Option<u16>,
+    /// The medium was turned over when it was last moved.
+    pub inverted: bool,
+    /// The primary volume tag, usually the barcode on the medium.
+    pub volume_tag: Option<String>,
+    pub alternate_volume_tag: Option<String>,
+}
+
+fn volume_tag(buf: &[u8]) -> Option<String> {
+    let tag = String::from_utf8_lossy(&buf[..32])
+        .trim_end_matches([' ', '\0'])
+        .to_string();
+    if tag.is_empty() {
+        None
+    } else {
+        Some(tag)
+    }
+}
+
+fn parse_element_status(buf: &[u8]) -> Vec<ChangerElement> {
+    let mut elements = Vec::new();
+    if buf.len() < 8 {
+        return elements;
+    }
+    let end = buf.len().min(8 + BigEndian::read_u24(&buf[5..8]) as usize);
+    let mut offset = 8;
+
+    while offset + 8 <= end {
+        let page = &buf[offset..offset + 8];
+        let element_type = to_changer_element_type(page[0]);
+        let pvoltag = page[1] & 0x80 != 0;
+        let avoltag = page[1] & 0x40 != 0;
+        let desc_len = BigEndian::read_u16(&page[2..4]) as usize;
+        let page_end = end.min(offset + 8 + BigEndian::read_u24(&page[5..8]) as usize);
+        if desc_len < 12 {
+            break;
+        }
+
+        let mut desc_offset = offset + 8;
+        while desc_offset + desc_len <= page_end {
+            let desc = &buf[desc_offset..desc_offset + desc_len];
+            let mut tag_offset = 12;
+            let mut tag = |present: bool| {
+                if present && tag_offset + 36 <= desc.len() {
+                    tag_offset += 36;
+                    volume_tag(&desc[tag_offset - 36..])
+                } else {
+                    None
+                }
+            };
+            let volume_tag = tag(pvoltag);
+            let alternate_volume_tag = tag(avoltag);
+
+            elements.push(ChangerElement {
+                element_type,
+                address: BigEndian::read_u16(&desc[0..2]),
+                full: desc[2] & 0x01 != 0,
+                except: desc[2] & 0x04 != 0,
+                // only reported for some element types; the transport
+                // can always reach itself
+                access: desc[2] & 0x08 != 0 || element_type == ChangerElementType::MediumTransport,
+                imported: element_type == ChangerElementType::ImportExport && desc[2] & 0x02 != 0,
+                asc: desc[4],
+                ascq: desc[5],
+                source: if desc[9] & 0x80 != 0 {
+                    Some(BigEndian::read_u16(&desc[10..12]))
+                } else {
+                    None
+                },
+                inverted: desc[9] & 0x40 != 0,
+                volume_tag,
+                alternate_volume_tag,
+            });
+            desc_offset += desc_len;
+        }
+
+        offset = page_end;
+    }
+
+    elements
+}
+
+fn read_element_status_cdb(
+    element_type: Option<ChangerElementType>,
+    start: u16,
+    count: u16,
+    volume_tags: bool,
+    len: u32,
+) -> [u8; 12] {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xb8;
+    cmd[1] = element_type.map_or(0, from_changer_element_type);
+    if volume_tags {
+        cmd[1] |= 0x10;
+    }
+    BigEndian::write_u16(&mut cmd[2..4], start);
+    BigEndian::write_u16(&mut cmd[4..6], count);
+    BigEndian::write_u24(&mut cmd[7..10], len);
+
+    cmd
+}
+
+// Send SCSI READ ELEMENT STATUS to the medium changer at the given path,
+// getting the status of up to `count` elements of the given type, or of
+// all types, starting at address `start`. With `volume_tags` set, the
+// barcodes of the media are read as well.
+pub fn read_element_status<T: SgTransport + ?Sized>(
+    path: &T,
+    element_type: Option<ChangerElementType>,
+    start: u16,
+    count: u16,
+    volume_tags: bool,
+) -> Sg3Result<Vec<ChangerElement>> {
+    let buf = read_variable_length(
+        8,
+        |buf| {
+            let cmd =
+                read_element_status_cdb(element_type, start, count, volume_tags, buf.len() as u32);
+            Ok(path
+                .execute(&cmd, DataDirection::FromDevice(buf), CHANGER_TIMEOUT)?
+                .transferred)
+        },
+        // the allocation length is 24 bits
+        |buf| (8 + BigEndian::read_u24(&buf[5..8]) as usize).min(0xff_ffff),
+    )?;
+
+    Ok(parse_element_status(&buf))
+}
+
+fn move_medium_cdb(transport: u16, source: u16, dest: u16, invert: bool) -> [u8; 12] {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa5;
+    BigEndian::write_u16(&mut cmd[2..4], transport);
+    BigEndian::write_u16(&mut cmd[4..6], source);
+    BigEndian::write_u16(&mut cmd[6..8], dest);
+    if invert {
+        cmd[10] |= 0x01;
+    }
+
+    cmd
+}
+
+// Send SCSI MOVE MEDIUM to the medium changer at the given path, moving
+// the medium at element address `source` to `dest` with the given
+// medium transport element, turning it over if `invert` is set.
+pub fn move_medium<T: SgTransport + ?Sized>(
+    path: &T,
+    transport: u16,
+    source: u16,
+    dest: u16,
+    invert: bool,
+) -> Sg3Result<()> {
+    let cmd = move_medium_cdb(transport, source, dest, invert);
+
+    sg_io_timeout(path, &cmd, DataDirection::None, CHANGER_TIMEOUT)?;
+    Ok(())
+}
+
+fn exchange_medium_cdb(
+    transport: u16,
+    source: u16,
+    first_dest: u16,
+    second_dest: u16,
+    invert: (bool, bool),
+) -> [u8; 12] {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa6;
+    BigEndian::write_u16(&mut cmd[2..4], transport);
+    BigEndian::write_u16(&mut cmd[4..6], source);
+    BigEndian::write_u16(&mut cmd[6..8], first_dest);
+    BigEndian::write_u16(&mut cmd[8..10], second_dest);
+    if invert.0 {
+        cmd[10] |= 0x02;
+    }
+    if invert.1 {
+        cmd[10] |= 0x01;
+    }
+
+    cmd
+}
+
+// Send SCSI EXCHANGE MEDIUM to the medium changer at the given path,
+// moving the medium at `source` to `first_dest`, and the medium that was
+// at `first_dest` to `second_dest`, which may be `source` to swap the
+// two. `invert` turns over the first and second media respectively.
+pub fn exchange_medium<T: SgTransport + ?Sized>(
+    path: &T,
+    transport: u16,
+    source: u16,
+    first_dest: u16,
+    second_dest: u16,
+    invert: (bool, bool),
+) -> Sg3Result<()> {
+    let cmd = exchange_medium_cdb(transport, source, first_dest, second_dest, invert);
+
+    sg_io_timeout(path, &cmd, DataDirection::None, CHANGER_TIMEOUT)?;
+    Ok(())
+}
+
+fn initialize_element_status_cdb(range: Option<(u16, u16)>, volume_tags: bool) -> Vec<u8> {
+    match range {
+        None if volume_tags => vec![0x07, 0, 0, 0, 0, 0],
+        _ => {
+            let mut cmd = vec![0u8; 10];
+
+            cmd[0] = 0x37;
+            // FAST: only check for media, skipping the barcodes
+            if !volume_tags {
+                cmd[1] |= 0x02;
+            }
+            if let Some((start, count)) = range {
+                cmd[1] |= 0x01;
+                BigEndian::write_u16(&mut cmd[2..4], start);
+                BigEndian::write_u16(&mut cmd[6..8], count);
+            }
+
+            cmd
+        }
+    }
+}
+
+// Send SCSI INITIALIZE ELEMENT STATUS to the medium changer at the
+// given path, making it take inventory of `count` elements from address
+// `start`, or of all of them if `range` is None. With `volume_tags`
+// set, the barcodes of the media are read again as well, e.g. after
+// the library door was opened.
+pub fn initialize_element_status<T: SgTransport + ?Sized>(
+    path: &T,
+    range: Option<(u16, u16)>,
+    volume_tags: bool,
+) -> Sg3Result<()> {
+    let cmd = initialize_element_status_cdb(range, volume_tags);
+
+    sg_io_timeout(path, &cmd, DataDirection::None, INVENTORY_TIMEOUT)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element_status_page(element_type: u8, flags: u8, descs: &[Vec<u8>]) -> Vec<u8> {
+        let desc_len = descs[0].len();
+        let mut page = vec![element_type, flags, 0, desc_len as u8, 0, 0, 0, 0];
+        BigEndian::write_u24(&mut page[5..8], (desc_len * descs.len()) as u32);
+        for desc in descs {
+            page.extend_from_slice(desc);
+        }
+        page
+    }
+
+    #[test]
+    fn test_parse_element_status() {
+        // a full slot with a barcode, moved from address 0x100
+        let mut slot = vec![0x10, 0x00, 0x09, 0, 0, 0, 0, 0, 0, 0x80, 0x01, 0x00];
+        let mut tag = b"000001L8".to_vec();
+        tag.resize(36, b' ');
+        slot.extend_from_slice(&tag);
+        // an empty slot
+        let mut empty = vec![0x10, 0x01, 0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        empty.extend_from_slice(&[b' '; 36]);
+        // a drive, without volume tags
+        let drive = vec![0x01, 0x00, 0x04, 0, 0x3b, 0x0d, 0, 0, 0, 0, 0, 0];
+
+        let mut buf = vec![0u8; 8];
+        buf.extend(element_status_page(2, 0x80, &[slot, empty]));
+        buf.extend(element_status_page(4, 0, &[drive]));
+        let len = buf.len() as u32 - 8;
+        BigEndian::write_u24(&mut buf[5..8], len);
+
+        let elements = parse_element_status(&buf);
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[0].element_type, ChangerElementType::Storage);
+        assert_eq!(elements[0].address, 0x1000);
+        assert!(elements[0].full && elements[0].access);
+        assert_eq!(elements[0].source, Some(0x100));
+        assert_eq!(elements[0].volume_tag.as_deref(), Some("000001L8"));
+        assert!(!elements[1].full);
+        assert_eq!(elements[1].volume_tag, None);
+        assert_eq!(elements[2].element_type, ChangerElementType::DataTransfer);
+        assert!(elements[2].except);
+        assert_eq!((elements[2].asc, elements[2].ascq), (0x3b, 0x0d));
+    }
+
+    #[test]
+    fn test_read_element_status_cdb() {
+        let cmd =
+            read_element_status_cdb(Some(ChangerElementType::Storage), 0x1000, 20, true, 0x1234);
+        assert_eq!(cmd, [0xb8, 0x12, 0x10, 0x00, 0, 20, 0, 0, 0x12, 0x34, 0, 0]);
+    }
+
+    #[test]
+    fn test_move_medium_cdb() {
+        assert_eq!(
+            move_medium_cdb(0, 0x1000, 0x0100, false),
+            [0xa5, 0, 0, 0, 0x10, 0, 0x01, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            exchange_medium_cdb(1, 0x1000, 0x0100, 0x1000, (false, true)),
+            [0xa6, 0, 0, 1, 0x10, 0, 0x01, 0, 0x10, 0, 0x01, 0]
+        );
+    }
+
+    #[test]
+    fn test_initialize_element_status_cdb() {
+        assert_eq!(
+            initialize_element_status_cdb(None, true),
+            [0x07, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            initialize_element_status_cdb(None, false),
+            [0x37, 0x02, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            initialize_element_status_cdb(Some((0x1000, 24)), true),
+            [0x37, 0x01, 0x10, 0, 0, 0, 0, 24, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! SCSI Primary Commands (SPC) common to all device types.
+
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    invalid_input, read_variable_length, sg_io, sg_io_read, sg_io_timeout, DataDirection,
+    Sg3Result, SgTransport, DEFAULT_TIMEOUT,
+};
+
+// Send SCSI TEST UNIT READY to the SCSI device at the given path. This
+// succeeds if the device is ready to accept medium-access commands.
+pub fn test_unit_ready<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<()> {
+    let cmd = [0u8; 6];
+    sg_io(path, &cmd, DataDirection::None)
+}
+
+// Send SCSI PREVENT ALLOW MEDIUM REMOVAL to the SCSI device at the
+// given path. If `prevent` is set, the device locks its removable
+// medium in place; otherwise it allows the medium to be removed.
+pub fn prevent_allow_medium_removal<T: SgTransport + ?Sized>(
+    path: &T,
+    prevent: bool,
+) -> Sg3Result<()> {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x1e;
+    if prevent {
+        cmd[4] = 0x01;
+    }
+
+    sg_io(path, &cmd, DataDirection::None)
+}
+
+/// Task management functions supported by a logical unit, from REPORT
+/// SUPPORTED TASK MANAGEMENT FUNCTIONS.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SupportedTaskManagementFunctions {
+    pub abort_task: bool,
+    pub abort_task_set: bool,
+    pub clear_aca: bool,
+    pub clear_task_set: bool,
+    pub logical_unit_reset: bool,
+    pub query_task: bool,
+    pub target_reset: bool,
+    pub wakeup: bool,
+    pub query_unit_attention: bool,
+    pub query_task_set: bool,
+    pub i_t_nexus_reset: bool,
+}
+
+fn parse_supported_tmfs(buf: &[u8]) -> SupportedTaskManagementFunctions {
+    SupportedTaskManagementFunctions {
+        abort_task: buf[0] & 0x80 != 0,
+        abort_task_set: buf[0] & 0x40 != 0,
+        clear_aca: buf[0] & 0x20 != 0,
+        clear_task_set: buf[0] & 0x10 != 0,
+        logical_unit_reset: buf[0] & 0x08 != 0,
+        query_task: buf[0] & 0x04 != 0,
+        target_reset: buf[0] & 0x02 != 0,
+        wakeup: buf[0] & 0x01 != 0,
+        query_unit_attention: buf[1] & 0x04 != 0,
+        query_task_set: buf[1] & 0x02 != 0,
+        i_t_nexus_reset: buf[1] & 0x01 != 0,
+    }
+}
+
+// Send SCSI REPORT SUPPORTED TASK MANAGEMENT FUNCTIONS to the SCSI
+// device at the given path.
+pub fn report_supported_task_management_functions<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<SupportedTaskManagementFunctions> {
+    let mut buf = [0u8; 4];
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa3;
+    cmd[1] = 0x0d;
+    BigEndian::write_u32(&mut cmd[6..10], buf.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Ok(parse_supported_tmfs(&buf))
+}
+
+/// A command listed by REPORT SUPPORTED OPERATION CODES.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupportedCommand {
+    pub opcode: u8,
+    /// The service action, for commands that have one.
+    pub service_action: Option<u16>,
+    pub cdb_len: u16,
+}
+
+fn parse_supported_commands(buf: &[u8]) -> Vec<SupportedCommand> {
+    let end = buf.len().min(4 + BigEndian::read_u32(&buf[0..4]) as usize);
+    let mut commands = Vec::new();
+
+    let mut offset = 4;
+    while offset + 8 <= end {
+        let desc = &buf[offset..offset + 8];
+        commands.push(SupportedCommand {
+            opcode: desc[0],
+            service_action: if desc[5] & 0x01 != 0 {
+                Some(BigEndian::read_u16(&desc[2..4]))
+            } else {
+                None
+            },
+            cdb_len: BigEndian::read_u16(&desc[6..8]),
+        });
+        // CTDP: a command timeouts descriptor follows
+        offset += if desc[5] & 0x02 != 0 { 20 } else { 8 };
+    }
+
+    commands
+}
+
+fn report_supported_opcodes_cdb(len: usize) -> [u8; 12] {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa3;
+    cmd[1] = 0x0c;
+    BigEndian::write_u32(&mut cmd[6..10], len as u32);
+
+    cmd
+}
+
+// Send SCSI REPORT SUPPORTED OPERATION CODES to the SCSI device at the
+// given path, listing every command it supports.
+pub fn report_supported_operation_codes<T: SgTransport + ?Sized>(
+    path: &T,
+) -> Sg3Result<Vec<SupportedCommand>> {
+    let buf = read_variable_length(
+        4,
+        |buf| sg_io_read(path, &report_supported_opcodes_cdb(buf.len()), buf),
+        |buf| 4 + BigEndian::read_u32(&buf[0..4]) as usize,
+    )?;
+
+    Ok(parse_supported_commands(&buf))
+}
+
+fn security_protocol_cdb(opcode: u8, protocol: u8, sp_specific: u16, len: usize) -> [u8; 12] {
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = opcode;
+    cmd[1] = protocol;
+    BigEndian::write_u16(&mut cmd[2..4], sp_specific);
+    BigEndian::write_u32(&mut cmd[6..10], len as u32);
+
+    cmd
+}
+
+// Send SCSI SECURITY PROTOCOL IN to the SCSI device at the given path,
+// reading the response of the given security protocol (e.g. 0x01 for
+// TCG) and protocol-specific field into `buf`.
+pub fn security_protocol_in<T: SgTransport + ?Sized>(
+    path: &T,
+    protocol: u8,
+    sp_specific: u16,
+    buf: &mut [u8],
+) -> Sg3Result<()> {
+    let cmd = security_protocol_cdb(0xa2, protocol, sp_specific, buf.len());
+    sg_io(path, &cmd, DataDirection::FromDevice(buf))
+}
+
+// Send SCSI SECURITY PROTOCOL OUT to the SCSI device at the given path,
+// sending `buf` as the payload of the given security protocol and
+// protocol-specific field.
+pub fn security_protocol_out<T: SgTransport + ?Sized>(
+    path: &T,
+    protocol: u8,
+    sp_specific: u16,
+    buf: &[u8],
+) -> Sg3Result<()> {
+    let cmd = security_protocol_cdb(0xb5, protocol, sp_specific, buf.len());
+    sg_io(path, &cmd, DataDirection::ToDevice(buf))
+}
+
+// Get the list of security protocols supported by the SCSI device at
+// the given path, via security protocol 0.
+pub fn supported_security_protocols<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<Vec<u8>> {
+    let buf = read_variable_length(
+        8,
+        |buf| sg_io_read(path, &security_protocol_cdb(0xa2, 0, 0, buf.len()), buf),
+        |buf| 8 + BigEndian::read_u16(&buf[6..8]) as usize,
+    )?;
+
+    Ok(buf[8..].to_vec())
+}
+
+/// A self-test for SEND DIAGNOSTIC to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTest {
+    /// The default self-test (SELFTEST bit), run in the foreground.
+    Default,
+    BackgroundShort,
+    BackgroundExtended,
+    /// Abort a self-test running in the background.
+    AbortBackground,
+    ForegroundShort,
+    ForegroundExtended,
+}
+
+// Foreground extended self-tests of large drives can take hours.
+const SELF_TEST_TIMEOUT: u32 = 8 * 60 * 60 * 1000;
+
+fn send_diagnostic_cdb(self_test: Option<SelfTest>, pf: bool, len: usize) -> Sg3Result<[u8; 6]> {
+    if len > 0xffff {
+        return Err(invalid_input("diagnostic parameter list too long"));
+    }
+
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x1d;
+    cmd[1] = match self_test {
+        None => 0,
+        Some(SelfTest::Default) => 0x04,
+        Some(SelfTest::BackgroundShort) => 1 << 5,
+        Some(SelfTest::BackgroundExtended) => 2 << 5,
+        Some(SelfTest::AbortBackground) => 4 << 5,
+        Some(SelfTest::ForegroundShort) => 5 << 5,
+        Some(SelfTest::ForegroundExtended) => 6 << 5,
+    };
+    if pf {
+        cmd[1] |= 0x10;
+    }
+    BigEndian::write_u16(&mut cmd[3..5], len as u16);
+
+    Ok(cmd)
+}
+
+// Send SCSI SEND DIAGNOSTIC to the SCSI device at the given path,
+// either starting `self_test` or, with `pf` set, sending `params` as a
+// diagnostic page (such as an SES control page). Foreground self-tests
+// complete before this returns.
+pub fn send_diagnostic<T: SgTransport + ?Sized>(
+    path: &T,
+    self_test: Option<SelfTest>,
+    pf: bool,
+    params: &[u8],
+) -> Sg3Result<()> {
+    let cmd = send_diagnostic_cdb(self_test, pf, params.len())?;
+    let data = if params.is_empty() {
+        DataDirection::None
+    } else {
+        DataDirection::ToDevice(params)
+    };
+    let timeout = match self_test {
+        Some(SelfTest::Default)
+        | Some(SelfTest::ForegroundShort)
+        | Some(SelfTest::ForegroundExtended) => SELF_TEST_TIMEOUT,
+        _ => DEFAULT_TIMEOUT,
+    };
+    sg_io_timeout(path, &cmd, data, timeout)?;
+    Ok(())
+}
+
+fn receive_diagnostic_results_cmd<T: SgTransport + ?Sized>(
+    path: &T,
+    page: Option<u8>,
+    buf: &mut [u8],
+) -> Sg3Result<usize> {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x1c;
+    if let Some(page) = page {
+        cmd[1] = 0x01;
+        cmd[2] = page;
+    }
+    BigEndian::write_u16(&mut cmd[3..5], buf.len() as u16);
+
+    sg_io_read(path, &cmd, buf)
+}
+
+// Send SCSI RECEIVE DIAGNOSTIC RESULTS to the SCSI device at the given
+// path, returning the given diagnostic page including its four-byte
+// header. With no page, this returns the results of the last SEND
+// DIAGNOSTIC instead.
+pub fn receive_diagnostic_results<T: SgTransport + ?Sized>(
+    path: &T,
+    page: Option<u8>,
+) -> Sg3Result<Vec<u8>> {
+    read_variable_length(
+        4,
+        |buf| receive_diagnostic_results_cmd(path, page, buf),
+        // the allocation length is 16 bits
+        |buf| (4 + BigEndian::read_u16(&buf[2..4]) as usize).min(0xffff),
+    )
+}
+
+/// How a device's timestamp was last set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampOrigin {
+    /// Set to zero at power on or hard reset, so counting up from then.
+    PowerOn,
+    /// Set by SET TIMESTAMP.
+    SetTimestamp,
+    /// Set some other way, such as by an automation interface.
+    Other,
+    Reserved,
+}
+
+fn to_timestamp_origin(i: u8) -> TimestampOrigin {
+    match i {
+        0 => TimestampOrigin::PowerOn,
+        2 => TimestampOrigin::SetTimestamp,
+        3 => TimestampOrigin::Other,
+        _ => TimestampOrigin::Reserved,
+    }
+}
+
+/// A device's timestamp, as REPORT TIMESTAMP returns it. Timestamps in
+/// log pages and sense data are in the same terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceTimestamp {
+    pub origin: TimestampOrigin,
+    /// Milliseconds since 1970-01-01 UTC, or since power on if it was
+    /// never set.
+    pub millis: u64,
+}
+
+impl DeviceTimestamp {
+    /// The time of day the timestamp gives, if it has been set.
+    pub fn system_time(&self) -> Option<SystemTime> {
+        match self.origin {
+            TimestampOrigin::SetTimestamp | TimestampOrigin::Other => {
+                Some(UNIX_EPOCH + Duration::from_millis(self.millis))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_timestamp(buf: &[u8]) -> DeviceTimestamp {
+    DeviceTimestamp {
+        origin: to_timestamp_origin(buf[2] & 0x07),
+        millis: BigEndian::read_u48(&buf[4..10]),
+    }
+}
+
+// Send SCSI REPORT TIMESTAMP to the SCSI device at the given path.
+pub fn report_timestamp<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<DeviceTimestamp> {
+    let mut buf = [0u8; 12];
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa3;
+    cmd[1] = 0x0f;
+    BigEndian::write_u32(&mut cmd[6..10], buf.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Ok(parse_timestamp(&buf))
+}
+
+fn set_timestamp_params(time: SystemTime) -> Sg3Result<[u8; 12]> {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|d| u64::try_from(d.as_millis()).ok())
+        .filter(|&ms| ms < 1 << 48)
+        .ok_or_else(|| invalid_input("time cannot be given as a device timestamp"))?;
+
+    let mut params = [0u8; 12];
+    BigEndian::write_u48(&mut params[4..10], millis);
+    Ok(params)
+}
+
+// Send SCSI SET TIMESTAMP to the SCSI device at the given path, setting
+// its clock to `time`, such as `SystemTime::now()` to synchronize it.
+pub fn set_timestamp<T: SgTransport + ?Sized>(path: &T, time: SystemTime) -> Sg3Result<()> {
+    let params = set_timestamp_params(time)?;
+    let mut cmd = [0u8; 12];
+
+    cmd[0] = 0xa4;
+    cmd[1] = 0x0f;
+    BigEndian::write_u32(&mut cmd[6..10], params.len() as u32);
+
+    sg_io(path, &cmd, DataDirection::ToDevice(&params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_diagnostic_cdb() {
+        let cmd = send_diagnostic_cdb(Some(SelfTest::BackgroundShort), false, 0).unwrap();
+        assert_eq!(cmd, [0x1d, 0x20, 0, 0, 0, 0]);
+        let cmd = send_diagnostic_cdb(Some(SelfTest::Default), false, 0).unwrap();
+        assert_eq!(cmd, [0x1d, 0x04, 0, 0, 0, 0]);
+        let cmd = send_diagnostic_cdb(None, true, 0x104).unwrap();
+        assert_eq!(cmd, [0x1d, 0x10, 0, 0x01, 0x04, 0]);
+        assert!(send_diagnostic_cdb(None, true, 0x10000).is_err());
+    }
+
+    #[test]
+    fn test_parse_supported_tmfs() {
+        let tmfs = parse_supported_tmfs(&[0xc8, 0x01, 0, 0]);
+        assert_eq!(
+            tmfs,
+            SupportedTaskManagementFunctions {
+                abort_task: true,
+                abort_task_set: true,
+                logical_unit_reset: true,
+                i_t_nexus_reset: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_supported_commands() {
+        let mut buf = vec![0, 0, 0, 36];
+        // READ(16)
+        buf.extend_from_slice(&[0x88, 0, 0, 0, 0, 0x00, 0, 16]);
+        // READ CAPACITY(16), with a timeouts descriptor
+        buf.extend_from_slice(&[0x9e, 0, 0, 0x10, 0, 0x03, 0, 16]);
+        buf.extend_from_slice(&[0, 10, 0, 0, 0, 0, 0, 30, 0, 0, 0, 60]);
+        // UNMAP
+        buf.extend_from_slice(&[0x42, 0, 0, 0, 0, 0x00, 0, 10]);
+        // past the reported length
+        buf.extend_from_slice(&[0x35, 0, 0, 0, 0, 0x00, 0, 10]);
+
+        let commands = parse_supported_commands(&buf);
+        assert_eq!(
+            commands,
+            [
+                SupportedCommand {
+                    opcode: 0x88,
+                    service_action: None,
+                    cdb_len: 16,
+                },
+                SupportedCommand {
+                    opcode: 0x9e,
+                    service_action: Some(0x10),
+                    cdb_len: 16,
+                },
+                SupportedCommand {
+                    opcode: 0x42,
+                    service_action: None,
+                    cdb_len: 10,
+                },
+            ]
+        );
+        assert_eq!(
+            report_supported_opcodes_cdb(4),
+            [0xa3, 0x0c, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_security_protocol_cdb() {
+        let cmd = security_protocol_cdb(0xa2, 0x01, 0x0001, 2048);
+        assert_eq!(cmd, [0xa2, 0x01, 0, 0x01, 0, 0, 0, 0, 0x08, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let ts = parse_timestamp(&[0, 10, 0x02, 0, 0x01, 0x8f, 0x3a, 0x1b, 0x2c, 0x3d, 0, 0]);
+        assert_eq!(ts.origin, TimestampOrigin::SetTimestamp);
+        assert_eq!(ts.millis, 0x018f_3a1b_2c3d);
+        assert_eq!(
+            ts.system_time(),
+            Some(UNIX_EPOCH + Duration::from_millis(0x018f_3a1b_2c3d))
+        );
+        let ts = parse_timestamp(&[0, 10, 0, 0, 0, 0, 0, 0, 0x03, 0xe8, 0, 0]);
+        assert_eq!(ts.origin, TimestampOrigin::PowerOn);
+        assert_eq!(ts.millis, 1000);
+        assert_eq!(ts.system_time(), None);
+
+        let params =
+            set_timestamp_params(UNIX_EPOCH + Duration::from_millis(0x018f_3a1b_2c3d)).unwrap();
+        assert_eq!(
+            params,
+            [0, 0, 0, 0, 0x01, 0x8f, 0x3a, 0x1b, 0x2c, 0x3d, 0, 0]
+        );
+        assert!(set_timestamp_params(UNIX_EPOCH - Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,647 @@
+//! SCSI Stream Commands (SSC) for sequential-access (tape) devices.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    invalid_data, invalid_input, sg_io, sg_io_timeout, DataDirection, Sense, SenseKey, Sg3Error,
+    Sg3Result, SgTransport,
+};
+
+// Rewinding, erasing, and spacing over a whole tape can take hours.
+const TAPE_TIMEOUT: u32 = 4 * 60 * 60 * 1000;
+
+// Work out the TRANSFER LENGTH and FIXED bit for a READ(6) or WRITE(6)
+// of `len` bytes, in blocks of `block_size` bytes or, with None, as a
+// single variable-length block.
+fn transfer_length(len: usize, block_size: Option<u32>) -> Sg3Result<(u32, bool)> {
+    let (count, fixed) = match block_size {
+        Some(0) => return Err(invalid_input("fixed block size must not be zero")),
+        Some(size) => {
+            if !len.is_multiple_of(size as usize) {
+                return Err(invalid_input(
+                    "buffer must be a multiple of the fixed block size",
+                ));
+            }
+            (len / size as usize, true)
+        }
+        None => (len, false),
+    };
+    if count > 0xff_ffff {
+        return Err(invalid_input("transfer length must fit in 24 bits"));
+    }
+    Ok((count as u32, fixed))
+}
+
+fn read6_cdb(count: u32, fixed: bool, sili: bool) -> [u8; 6] {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x08;
+    if sili {
+        cmd[1] |= 0x02;
+    }
+    if fixed {
+        cmd[1] |= 0x01;
+    }
+    BigEndian::write_u24(&mut cmd[2..5], count);
+
+    cmd
+}
+
+/// The outcome of a READ(6) from a tape.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TapeRead {
+    /// Number of bytes read into the buffer.
+    pub len: usize,
+    /// The read stopped at a filemark, which is now passed.
+    pub filemark: bool,
+    /// The read reached end-of-medium.
+    pub end_of_medium: bool,
+    /// There was no more data recorded on the tape.
+    pub end_of_data: bool,
+    /// A variable-length block was longer than the buffer, and the
+    /// rest of it was discarded.
+    pub overlength: bool,
+}
+
+// The INFORMATION field of the sense data from a tape command holds the
+// signed difference between the requested and actual count, in bytes,
+// blocks, or filemarks.
+fn tape_residue(sense: &Sense) -> Option<i64> {
+    sense.information().map(|info| {
+        if sense.is_descriptor_format() {
+            info as i64
+        } else {
+            i64::from(info as u32 as i32)
+        }
+    })
+}
+
+// Get the sense data from an error that only reports a tape stopping
+// early at a filemark, end of data, or end of medium, or return the
+// error if it was a real failure.
+fn tape_condition_sense(err: Sg3Error) -> Sg3Result<Sense> {
+    let sense = match err.sense() {
+        Some(sense) => sense,
+        None => return Err(err),
+    };
+
+    match sense.sense_key() {
+        SenseKey::NoSense | SenseKey::BlankCheck => Ok(sense),
+        SenseKey::MediumError if sense.eom() => Ok(sense),
+        _ => Err(err),
+    }
+}
+
+// Work out what a READ(6) that ended in CHECK CONDITION actually read,
+// or return the error if it was a real failure.
+fn tape_read_result(err: Sg3Error, len: usize, block_size: Option<u32>) -> Sg3Result<TapeRead> {
+    let sense = tape_condition_sense(err)?;
+    let end_of_data = sense.sense_key() == SenseKey::BlankCheck;
+
+    let residue = match tape_residue(&sense) {
+        Some(residue) => residue,
+        None if end_of_data => len as i64,
+        None => 0,
+    };
+    let unit = block_size.unwrap_or(1) as i64;
+    let read = (len as i64 - residue.max(0) * unit).max(0) as usize;
+
+    Ok(TapeRead {
+        len: read,
+        filemark: sense.filemark(),
+        end_of_medium: sense.eom(),
+        end_of_data,
+        overlength: residue < 0,
+    })
+}
+
+// Send SCSI READ(6) to the tape device at the given path, reading into
+// `buf` either a single variable-length block, or with a fixed block
+// size, as many blocks of that size as fill it. Hitting a filemark or
+// the end of recorded data is not an error; see `TapeRead`.
+pub fn read6<T: SgTransport + ?Sized>(
+    path: &T,
+    buf: &mut [u8],
+    block_size: Option<u32>,
+) -> Sg3Result<TapeRead> {
+    let len = buf.len();
+    let (count, fixed) = transfer_length(len, block_size)?;
+    let cmd = read6_cdb(count, fixed, false);
+
+    match sg_io(path, &cmd, DataDirection::FromDevice(buf)) {
+        Ok(()) => Ok(TapeRead {
+            len,
+            ..Default::default()
+        }),
+        Err(e) => tape_read_result(e, len, block_size),
+    }
+}
+
+fn write6_cdb(count: u32, fixed: bool) -> [u8; 6] {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x0a;
+    if fixed {
+        cmd[1] |= 0x01;
+    }
+    BigEndian::write_u24(&mut cmd[2..5], count);
+
+    cmd
+}
+
+// Returns true if the error only reports that a write to a tape
+// completed past the early warning point.
+fn is_early_warning(err: &Sg3Error) -> bool {
+    match err.sense() {
+        Some(sense) => sense.sense_key() == SenseKey::NoSense && sense.eom(),
+        None => false,
+    }
+}
+
+// Send SCSI WRITE(6) to the tape device at the given path, writing
+// `buf` as a single variable-length block, or with a fixed block size,
+// as blocks of that size. Returns true if the tape is past the early
+// warning point near its end, and writing should stop soon.
+pub fn write6<T: SgTransport + ?Sized>(
+    path: &T,
+    buf: &[u8],
+    block_size: Option<u32>,
+) -> Sg3Result<bool> {
+    let (count, fixed) = transfer_length(buf.len(), block_size)?;
+    let cmd = write6_cdb(count, fixed);
+
+    match sg_io(path, &cmd, DataDirection::ToDevice(buf)) {
+        Ok(()) => Ok(false),
+        Err(ref e) if is_early_warning(e) => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+// Send SCSI WRITE FILEMARKS(6) to the tape device at the given path,
+// writing `count` filemarks. A `count` of zero just flushes buffered
+// writes to the tape. If `immed` is set, the command completes before
+// the filemarks are written.
+pub fn write_filemarks<T: SgTransport + ?Sized>(
+    path: &T,
+    count: u32,
+    immed: bool,
+) -> Sg3Result<()> {
+    if count > 0xff_ffff {
+        return Err(invalid_input("filemark count must fit in 24 bits"));
+    }
+
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x10;
+    if immed {
+        cmd[1] |= 0x01;
+    }
+    BigEndian::write_u24(&mut cmd[2..5], count);
+
+    match sg_io_timeout(path, &cmd, DataDirection::None, TAPE_TIMEOUT) {
+        Ok(_) => Ok(()),
+        Err(ref e) if is_early_warning(e) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Send SCSI REWIND to the tape device at the given path, moving to the
+// beginning of the current partition. If `immed` is set, the command
+// completes before the rewind does.
+pub fn rewind<T: SgTransport + ?Sized>(path: &T, immed: bool) -> Sg3Result<()> {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x01;
+    if immed {
+        cmd[1] |= 0x01;
+    }
+
+    sg_io_timeout(path, &cmd, DataDirection::None, TAPE_TIMEOUT)?;
+    Ok(())
+}
+
+// Send SCSI ERASE(6) to the tape device at the given path, erasing from
+// the current position. With `long` set, everything to the end of the
+// partition is erased, which can take hours; otherwise only a gap is
+// written. If `immed` is set, the command completes before the erase
+// does.
+pub fn erase<T: SgTransport + ?Sized>(path: &T, long: bool, immed: bool) -> Sg3Result<()> {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x19;
+    if immed {
+        cmd[1] |= 0x02;
+    }
+    if long {
+        cmd[1] |= 0x01;
+    }
+
+    sg_io_timeout(path, &cmd, DataDirection::None, TAPE_TIMEOUT)?;
+    Ok(())
+}
+
+/// What LOAD UNLOAD does with the medium.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadAction {
+    /// Load the medium and position at the beginning of partition 0.
+    Load,
+    /// Rewind and unload the medium, ejecting it if the drive can.
+    Unload,
+    /// Move to the end of the medium and back before loading, to
+    /// even out the tape tension.
+    Retension,
+    /// Load, but keep the medium where it is in the drive without
+    /// threading it, e.g. so its MAM can be read.
+    Hold,
+}
+
+fn load_unload_cdb(action: LoadAction, immed: bool) -> [u8; 6] {
+    let mut cmd = [0u8; 6];
+
+    cmd[0] = 0x1b;
+    if immed {
+        cmd[1] |= 0x01;
+    }
+    cmd[4] = match action {
+        LoadAction::Load => 0x01,
+        LoadAction::Unload => 0x00,
+        LoadAction::Retension => 0x03,
+        LoadAction::Hold => 0x09,
+    };
+
+    cmd
+}
+
+// Send SCSI LOAD UNLOAD to the tape device at the given path. If `immed`
+// is set, the command completes before the medium has moved.
+pub fn load_unload<T: SgTransport + ?Sized>(
+    path: &T,
+    action: LoadAction,
+    immed: bool,
+) -> Sg3Result<()> {
+    let cmd = load_unload_cdb(action, immed);
+
+    sg_io_timeout(path, &cmd, DataDirection::None, TAPE_TIMEOUT)?;
+    Ok(())
+}
+
+/// The block sizes a tape drive can read and write.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockLimits {
+    /// Block sizes must be a multiple of 2 to the power of this.
+    pub granularity: u8,
+    /// The largest block length, or 0 if not specified.
+    pub max_block_length: u32,
+    pub min_block_length: u16,
+}
+
+impl BlockLimits {
+    /// Whether the drive only supports a single, fixed block length.
+    pub fn is_fixed(&self) -> bool {
+        self.max_block_length != 0 && self.max_block_length == u32::from(self.min_block_length)
+    }
+}
+
+fn parse_block_limits(buf: &[u8]) -> BlockLimits {
+    BlockLimits {
+        granularity: buf[0] & 0x1f,
+        max_block_length: BigEndian::read_u24(&buf[1..4]),
+        min_block_length: BigEndian::read_u16(&buf[4..6]),
+    }
+}
+
+// Send SCSI READ BLOCK LIMITS to the tape device at the given path.
+pub fn read_block_limits<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<BlockLimits> {
+    let mut cmd = [0u8; 6];
+    let mut buf = [0u8; 6];
+
+    cmd[0] = 0x05;
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    Ok(parse_block_limits(&buf))
+}
+
+/// What a SPACE command moves over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceCode {
+    Blocks,
+    Filemarks,
+    /// Runs of the given number of consecutive filemarks.
+    SequentialFilemarks,
+    /// Straight to the end of recorded data; the count is ignored.
+    EndOfData,
+}
+
+fn space_cdb(code: SpaceCode, count: i64) -> Vec<u8> {
+    let code = match code {
+        SpaceCode::Blocks => 0,
+        SpaceCode::Filemarks => 1,
+        SpaceCode::SequentialFilemarks => 2,
+        SpaceCode::EndOfData => 3,
+    };
+
+    // SPACE(6) only has a signed 24-bit count
+    if (-0x80_0000..0x80_0000).contains(&count) {
+        let mut cmd = vec![0u8; 6];
+        cmd[0] = 0x11;
+        cmd[1] = code;
+        BigEndian::write_i24(&mut cmd[2..5], count as i32);
+        cmd
+    } else {
+        let mut cmd = vec![0u8; 16];
+        cmd[0] = 0x91;
+        cmd[1] = code;
+        BigEndian::write_i64(&mut cmd[4..12], count);
+        cmd
+    }
+}
+
+/// The outcome of a SPACE command.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TapeSpace {
+    /// How many of the requested blocks or filemarks were not spaced
+    /// over, with the same sign as the requested count.
+    pub residue: i64,
+    /// Spacing over blocks stopped at a filemark.
+    pub filemark: bool,
+    /// Spacing stopped at the beginning of the partition, or at or
+    /// past end-of-medium.
+    pub end_of_medium: bool,
+    /// Spacing stopped at the end of recorded data.
+    pub end_of_data: bool,
+}
+
+// Send SCSI SPACE to the tape device at the given path, moving forward
+// over `count` blocks or filemarks, or backward if `count` is negative.
+// Running into a filemark, the end of data, or either end of the
+// partition first is not an error; see `TapeSpace`.
+pub fn space<T: SgTransport + ?Sized>(
+    path: &T,
+    code: SpaceCode,
+    count: i64,
+) -> Sg3Result<TapeSpace> {
+    let cmd = space_cdb(code, count);
+
+    let err = match sg_io_timeout(path, &cmd, DataDirection::None, TAPE_TIMEOUT) {
+        Ok(_) => return Ok(TapeSpace::default()),
+        Err(e) => e,
+    };
+    let sense = tape_condition_sense(err)?;
+    let end_of_data = sense.sense_key() == SenseKey::BlankCheck;
+
+    Ok(TapeSpace {
+        residue: tape_residue(&sense).unwrap_or(0),
+        filemark: sense.filemark(),
+        end_of_medium: sense.eom(),
+        end_of_data,
+    })
+}
+
+/// The kind of logical object a LOCATE moves to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocateTarget {
+    /// A logical object (block or filemark) number.
+    Block(u64),
+    /// The position just after the given filemark number.
+    File(u64),
+    /// The end of recorded data.
+    EndOfData,
+}
+
+fn locate10_cdb(block: u32, partition: Option<u8>, immed: bool) -> [u8; 10] {
+    let mut cmd = [0u8; 10];
+
+    cmd[0] = 0x2b;
+    if let Some(partition) = partition {
+        cmd[1] |= 0x02;
+        cmd[8] = partition;
+    }
+    if immed {
+        cmd[1] |= 0x01;
+    }
+    BigEndian::write_u32(&mut cmd[3..7], block);
+
+    cmd
+}
+
+fn locate16_cdb(target: LocateTarget, partition: Option<u8>, immed: bool) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    let (dest_type, id) = match target {
+        LocateTarget::Block(n) => (0, n),
+        LocateTarget::File(n) => (1, n),
+        LocateTarget::EndOfData => (3, 0),
+    };
+
+    cmd[0] = 0x92;
+    cmd[1] = dest_type << 3;
+    if let Some(partition) = partition {
+        cmd[1] |= 0x02;
+        cmd[3] = partition;
+    }
+    if immed {
+        cmd[1] |= 0x01;
+    }
+    BigEndian::write_u64(&mut cmd[4..12], id);
+
+    cmd
+}
+
+// Send SCSI LOCATE to the tape device at the given path, moving to the
+// given target, first changing to `partition` if one is given. LOCATE(10)
+// is used for block numbers that fit in it, and LOCATE(16) otherwise.
+// If `immed` is set, the command completes before the tape has moved.
+pub fn locate<T: SgTransport + ?Sized>(
+    path: &T,
+    target: LocateTarget,
+    partition: Option<u8>,
+    immed: bool,
+) -> Sg3Result<()> {
+    match target {
+        LocateTarget::Block(n) if n <= u64::from(u32::MAX) => {
+            let cmd = locate10_cdb(n as u32, partition, immed);
+            sg_io_timeout(path, &cmd, DataDirection::None, TAPE_TIMEOUT)?;
+        }
+        _ => {
+            let cmd = locate16_cdb(target, partition, immed);
+            sg_io_timeout(path, &cmd, DataDirection::None, TAPE_TIMEOUT)?;
+        }
+    }
+    Ok(())
+}
+
+/// The current position of a tape, from READ POSITION.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TapePosition {
+    pub partition: u32,
+    /// The number of the next logical object (block or filemark).
+    pub block: u64,
+    /// The number of filemarks between the beginning of the partition
+    /// and the current position.
+    pub file: u64,
+    /// At the beginning of the partition.
+    pub beginning_of_partition: bool,
+    /// At or past the early warning point near the end of the partition.
+    pub end_of_partition: bool,
+    /// The position is past the programmable early warning point.
+    pub programmable_early_warning: bool,
+}
+
+// Parse READ POSITION data in the long form.
+fn parse_position_long(buf: &[u8]) -> Sg3Result<TapePosition> {
+    // The drive doesn't know where it is, e.g. after a failed LOCATE.
+    if buf[0] & 0x0c != 0 {
+        return Err(invalid_data("tape position is unknown"));
+    }
+
+    Ok(TapePosition {
+        partition: BigEndian::read_u32(&buf[4..8]),
+        block: BigEndian::read_u64(&buf[8..16]),
+        file: BigEndian::read_u64(&buf[16..24]),
+        beginning_of_partition: buf[0] & 0x80 != 0,
+        end_of_partition: buf[0] & 0x40 != 0,
+        programmable_early_warning: buf[0] & 0x01 != 0,
+    })
+}
+
+// Send SCSI READ POSITION to the tape device at the given path, getting
+// the current position in the long form.
+pub fn read_position<T: SgTransport + ?Sized>(path: &T) -> Sg3Result<TapePosition> {
+    let mut cmd = [0u8; 10];
+    let mut buf = [0u8; 32];
+
+    cmd[0] = 0x34;
+    // long form
+    cmd[1] = 0x06;
+
+    sg_io(path, &cmd, DataDirection::FromDevice(&mut buf))?;
+
+    parse_position_long(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn check_condition(sense: Vec<u8>) -> Sg3Error {
+        Sg3Error::Scsi {
+            device: PathBuf::from("/dev/sg0"),
+            cdb: vec![0x08, 0, 0, 0x04, 0, 0],
+            status: 0x02,
+            host_status: 0,
+            driver_status: 0x08,
+            sense,
+        }
+    }
+
+    #[test]
+    fn test_transfer_length() {
+        assert_eq!(transfer_length(65536, None).unwrap(), (65536, false));
+        assert_eq!(transfer_length(65536, Some(512)).unwrap(), (128, true));
+        assert!(transfer_length(1000, Some(512)).is_err());
+        assert!(transfer_length(0x100_0000, None).is_err());
+        assert_eq!(read6_cdb(128, true, false), [0x08, 0x01, 0, 0, 0x80, 0]);
+        assert_eq!(write6_cdb(0x10000, false), [0x0a, 0, 0x01, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_tape_read_result() {
+        // short variable-length block: ILI, 0x100 bytes not read
+        let err = check_condition(vec![
+            0xf0, 0, 0x20, 0, 0, 0x01, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        let read = tape_read_result(err, 0x400, None).unwrap();
+        assert_eq!(read.len, 0x300);
+        assert!(!read.filemark && !read.overlength);
+
+        // filemark after two of four fixed blocks
+        let err = check_condition(vec![
+            0xf0, 0, 0x80, 0, 0, 0, 2, 10, 0, 0, 0, 0, 0, 0x01, 0, 0, 0, 0,
+        ]);
+        let read = tape_read_result(err, 2048, Some(512)).unwrap();
+        assert_eq!(read.len, 1024);
+        assert!(read.filemark);
+
+        // end of data
+        let err = check_condition(vec![
+            0x70, 0, 0x08, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0x05, 0, 0, 0, 0,
+        ]);
+        let read = tape_read_result(err, 2048, None).unwrap();
+        assert_eq!(read.len, 0);
+        assert!(read.end_of_data);
+
+        // medium error is a real failure
+        let err = check_condition(vec![
+            0x70, 0, 0x03, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x11, 0, 0, 0, 0, 0,
+        ]);
+        assert!(tape_read_result(err, 2048, None).is_err());
+    }
+
+    #[test]
+    fn test_space_cdb() {
+        assert_eq!(
+            space_cdb(SpaceCode::Filemarks, -1),
+            [0x11, 1, 0xff, 0xff, 0xff, 0]
+        );
+        let cmd = space_cdb(SpaceCode::Blocks, 0x100_0000);
+        assert_eq!(cmd.len(), 16);
+        assert_eq!(cmd[0], 0x91);
+        assert_eq!(&cmd[4..12], &[0, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_locate_cdb() {
+        assert_eq!(
+            locate10_cdb(0x1234, Some(1), true),
+            [0x2b, 0x03, 0, 0, 0, 0x12, 0x34, 0, 1, 0]
+        );
+        let cmd = locate16_cdb(LocateTarget::EndOfData, None, false);
+        assert_eq!(cmd[0], 0x92);
+        assert_eq!(cmd[1], 0x18);
+        let cmd = locate16_cdb(LocateTarget::File(3), Some(2), false);
+        assert_eq!(cmd[1], 0x0a);
+        assert_eq!(cmd[3], 2);
+        assert_eq!(cmd[11], 3);
+    }
+
+    #[test]
+    fn test_parse_position_long() {
+        let mut buf = [0u8; 32];
+        buf[0] = 0x80;
+        buf[7] = 1;
+        buf[15] = 0x20;
+        buf[23] = 2;
+        let pos = parse_position_long(&buf).unwrap();
+        assert_eq!(pos.partition, 1);
+        assert_eq!(pos.block, 0x20);
+        assert_eq!(pos.file, 2);
+        assert!(pos.beginning_of_partition);
+        assert!(!pos.end_of_partition);
+
+        buf[0] = 0x04;
+        assert!(parse_position_long(&buf).is_err());
+    }
+
+    #[test]
+    fn test_load_unload() {
+        assert_eq!(
+            load_unload_cdb(LoadAction::Load, false),
+            [0x1b, 0, 0, 0, 0x01, 0]
+        );
+        assert_eq!(
+            load_unload_cdb(LoadAction::Unload, true),
+            [0x1b, 0x01, 0, 0, 0, 0]
+        );
+        assert_eq!(load_unload_cdb(LoadAction::Hold, false)[4], 0x09);
+
+        let limits = parse_block_limits(&[0x02, 0x80, 0, 0, 0, 1]);
+        assert_eq!(limits.granularity, 2);
+        assert_eq!(limits.max_block_length, 0x80_0000);
+        assert_eq!(limits.min_block_length, 1);
+        assert!(!limits.is_fixed());
+        assert!(parse_block_limits(&[0, 0, 0x02, 0, 0x02, 0]).is_fixed());
+    }
+}
@@ -0,0 +1,336 @@
+//! Finding devices through sysfs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{to_device_type, PeripheralDeviceType, Sg3Result};
+
+const SYSFS_ROOT: &str = "/sys";
+
+// How far below a device's sysfs directory to look for its block
+// device, e.g. end_device-1:0:0/target1:0:0/1:0:0:0/block/sda.
+const MAX_BLOCK_DEPTH: usize = 4;
+
+// Find the names of block devices below the given sysfs directory.
+// Symlinks are not followed, since sysfs links back up the tree.
+fn find_block_devices(dir: &Path, depth: usize, names: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if entry.file_name() == "block" {
+            for dev in fs::read_dir(entry.path())? {
+                names.push(dev?.file_name().to_string_lossy().into_owned());
+            }
+        } else if depth > 0 {
+            find_block_devices(&entry.path(), depth - 1, names)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_sas_address(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn sas_address_block_devices_in(sysfs: &Path) -> io::Result<HashMap<u64, PathBuf>> {
+    let mut devices = HashMap::new();
+
+    let class = sysfs.join("class/sas_device");
+    if !class.exists() {
+        return Ok(devices);
+    }
+    for entry in fs::read_dir(class)? {
+        let entry = entry?;
+        let addr = match fs::read_to_string(entry.path().join("sas_address"))
+            .ok()
+            .and_then(|s| parse_sas_address(&s))
+        {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let dev = match fs::canonicalize(entry.path().join("device")) {
+            Ok(dev) => dev,
+            Err(_) => continue,
+        };
+
+        let mut names = Vec::new();
+        find_block_devices(&dev, MAX_BLOCK_DEPTH, &mut names)?;
+        if let Some(name) = names.into_iter().min() {
+            devices.insert(addr, Path::new("/dev").join(name));
+        }
+    }
+
+    Ok(devices)
+}
+
+// Map the SAS address of each SAS end device known to the SAS
+// transport class to its block device, e.g. /dev/sdb.
+pub fn sas_address_block_devices() -> Sg3Result<HashMap<u64, PathBuf>> {
+    Ok(sas_address_block_devices_in(Path::new(SYSFS_ROOT))?)
+}
+
+/// The address of a SCSI device on the system: the host adapter,
+/// channel (or bus), target and LUN, as in "H:C:T:L".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScsiAddress {
+    pub host: u32,
+    pub channel: u32,
+    pub target: u32,
+    pub lun: u64,
+}
+
+impl fmt::Display for ScsiAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.host, self.channel, self.target, self.lun
+        )
+    }
+}
+
+fn parse_scsi_address(s: &str) -> Option<ScsiAddress> {
+    let mut parts = s.split(':');
+    let addr = ScsiAddress {
+        host: parts.next()?.parse().ok()?,
+        channel: parts.next()?.parse().ok()?,
+        target: parts.next()?.parse().ok()?,
+        lun: parts.next()?.parse().ok()?,
+    };
+    match parts.next() {
+        Some(_) => None,
+        None => Some(addr),
+    }
+}
+
+/// A SCSI generic device, as found by `enumerate()`.
+#[derive(Debug)]
+pub struct SgDevice {
+    /// The sg device node, e.g. /dev/sg0.
+    pub path: PathBuf,
+    pub address: ScsiAddress,
+    pub device_type: PeripheralDeviceType,
+    /// The INQUIRY vendor, product and revision, as the kernel read
+    /// them, without trailing spaces.
+    pub vendor: String,
+    pub model: String,
+    pub revision: String,
+}
+
+fn read_attribute(dir: &Path, name: &str) -> String {
+    fs::read_to_string(dir.join(name))
+        .map(|s| s.trim_end().to_string())
+        .unwrap_or_default()
+}
+
+// The number of an sg device name, such as 12 for sg12, for sorting.
+fn sg_number(name: &str) -> Option<u32> {
+    name.strip_prefix("sg")?.parse().ok()
+}
+
+fn enumerate_in(sysfs: &Path) -> io::Result<Vec<SgDevice>> {
+    let mut devices = Vec::new();
+
+    let class = sysfs.join("class/scsi_generic");
+    if !class.exists() {
+        return Ok(devices);
+    }
+    for entry in fs::read_dir(class)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let dev = match fs::canonicalize(entry.path().join("device")) {
+            Ok(dev) => dev,
+            Err(_) => continue,
+        };
+        let address = match dev
+            .file_name()
+            .and_then(|n| parse_scsi_address(&n.to_string_lossy()))
+        {
+            Some(address) => address,
+            None => continue,
+        };
+
+        devices.push(SgDevice {
+            path: Path::new("/dev").join(&name),
+            address,
+            device_type: to_device_type(read_attribute(&dev, "type").parse().unwrap_or(0x1f)),
+            vendor: read_attribute(&dev, "vendor"),
+            model: read_attribute(&dev, "model"),
+            revision: read_attribute(&dev, "rev"),
+        });
+    }
+    devices.sort_by_key(|d| {
+        d.path
+            .file_name()
+            .and_then(|n| sg_number(&n.to_string_lossy()))
+    });
+
+    Ok(devices)
+}
+
+// List the SCSI generic devices on the system, from
+// /sys/class/scsi_generic, in order of their sg number.
+pub fn enumerate() -> Sg3Result<Vec<SgDevice>> {
+    Ok(enumerate_in(Path::new(SYSFS_ROOT))?)
+}
+
+// The one entry in the given sysfs directory, e.g. the sg0 in
+// .../1:0:0:0/scsi_generic, as a path under /dev.
+fn single_device_in(dir: &Path) -> io::Result<PathBuf> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        names.push(entry?.file_name());
+    }
+    names.sort();
+    match names.into_iter().next() {
+        Some(name) => Ok(Path::new("/dev").join(name)),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no device in {}", dir.display()),
+        )),
+    }
+}
+
+fn device_name(dev: &Path) -> io::Result<String> {
+    match dev.file_name() {
+        Some(name) => Ok(name.to_string_lossy().into_owned()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a device path", dev.display()),
+        )),
+    }
+}
+
+fn block_to_sg_in(sysfs: &Path, dev: &Path) -> io::Result<PathBuf> {
+    let name = device_name(dev)?;
+    single_device_in(
+        &sysfs
+            .join("class/block")
+            .join(name)
+            .join("device/scsi_generic"),
+    )
+}
+
+fn sg_to_block_in(sysfs: &Path, dev: &Path) -> io::Result<PathBuf> {
+    let name = device_name(dev)?;
+    single_device_in(
+        &sysfs
+            .join("class/scsi_generic")
+            .join(name)
+            .join("device/block"),
+    )
+}
+
+// Find the sg device for the given block device, e.g. /dev/sg0 for
+// /dev/sda. Only whole disks have one, not partitions.
+pub fn block_to_sg(dev: &Path) -> Sg3Result<PathBuf> {
+    Ok(block_to_sg_in(Path::new(SYSFS_ROOT), dev)?)
+}
+
+// Find the block device for the given sg device, e.g. /dev/sda for
+// /dev/sg0. Devices such as tapes and enclosures have none, and give
+// an error of kind NotFound.
+pub fn sg_to_block(dev: &Path) -> Sg3Result<PathBuf> {
+    Ok(sg_to_block_in(Path::new(SYSFS_ROOT), dev)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    #[test]
+    fn test_sas_address_block_devices() {
+        let root = env::temp_dir().join(format!("sg3-sysfs-{}", process::id()));
+        let dev = root.join("devices/end_device-1:0:0");
+        let class = root.join("class/sas_device/end_device-1:0:0");
+        fs::create_dir_all(dev.join("target1:0:0/1:0:0:0/block/sdb")).unwrap();
+        fs::create_dir_all(&class).unwrap();
+        fs::write(class.join("sas_address"), "0x5000c500a1b2c3d4\n").unwrap();
+        symlink(&dev, class.join("device")).unwrap();
+
+        let devices = sas_address_block_devices_in(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(
+            devices.get(&0x5000_c500_a1b2_c3d4),
+            Some(&PathBuf::from("/dev/sdb"))
+        );
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let root = env::temp_dir().join(format!("sg3-enumerate-{}", process::id()));
+        let class = root.join("class/scsi_generic");
+        for &(sg, hctl, devtype, model) in &[
+            ("sg10", "2:0:1:0", "1", "ULT3580-TD8"),
+            ("sg2", "0:0:0:0", "0", "Virtual disk"),
+        ] {
+            let dev = root.join("devices/host0/target0:0:0").join(hctl);
+            fs::create_dir_all(&dev).unwrap();
+            fs::write(dev.join("type"), format!("{}\n", devtype)).unwrap();
+            fs::write(dev.join("vendor"), "IBM     \n").unwrap();
+            fs::write(dev.join("model"), format!("{}\n", model)).unwrap();
+            fs::write(dev.join("rev"), "K4K1\n").unwrap();
+            fs::create_dir_all(class.join(sg)).unwrap();
+            symlink(&dev, class.join(sg).join("device")).unwrap();
+        }
+
+        let devices = enumerate_in(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].path, PathBuf::from("/dev/sg2"));
+        assert_eq!(devices[0].device_type, PeripheralDeviceType::DirectAccess);
+        assert_eq!(devices[0].model, "Virtual disk");
+        assert_eq!(devices[1].path, PathBuf::from("/dev/sg10"));
+        assert_eq!(
+            devices[1].address,
+            ScsiAddress {
+                host: 2,
+                channel: 0,
+                target: 1,
+                lun: 0,
+            }
+        );
+        assert_eq!(
+            devices[1].device_type,
+            PeripheralDeviceType::SequentialAccess
+        );
+        assert_eq!(devices[1].vendor, "IBM");
+        assert_eq!(devices[1].revision, "K4K1");
+    }
+
+    #[test]
+    fn test_block_sg_mapping() {
+        let root = env::temp_dir().join(format!("sg3-mapping-{}", process::id()));
+        let dev = root.join("devices/host1/target1:0:0/1:0:0:0");
+        fs::create_dir_all(dev.join("block/sdb")).unwrap();
+        fs::create_dir_all(dev.join("scsi_generic/sg1")).unwrap();
+        fs::create_dir_all(root.join("class/block/sdb")).unwrap();
+        fs::create_dir_all(root.join("class/scsi_generic/sg1")).unwrap();
+        fs::create_dir_all(root.join("class/scsi_generic/sg2/device")).unwrap();
+        symlink(&dev, root.join("class/block/sdb/device")).unwrap();
+        symlink(&dev, root.join("class/scsi_generic/sg1/device")).unwrap();
+
+        let sg = block_to_sg_in(&root, Path::new("/dev/sdb"));
+        let block = sg_to_block_in(&root, Path::new("/dev/sg1"));
+        let tape = sg_to_block_in(&root, Path::new("/dev/sg2"));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(sg.unwrap(), PathBuf::from("/dev/sg1"));
+        assert_eq!(block.unwrap(), PathBuf::from("/dev/sdb"));
+        assert!(tape.is_err());
+    }
+}
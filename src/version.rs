@@ -0,0 +1,145 @@
+//! Version descriptors from standard INQUIRY data, naming the standards
+//! a device claims to conform to.
+
+use std::fmt;
+
+/// A version descriptor code, as listed in SPC-4 Annex E.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VersionDescriptor(pub u16);
+
+// Standards by the first code of the block of 32 codes assigned to
+// each, whose other codes give particular revisions of it.
+const STANDARDS: &[(u16, &str)] = &[
+    (0x0020, "SAM"),
+    (0x0040, "SAM-2"),
+    (0x0060, "SAM-3"),
+    (0x0080, "SAM-4"),
+    (0x00a0, "SAM-5"),
+    (0x00c0, "SAM-6"),
+    (0x0120, "SPC"),
+    (0x0140, "MMC"),
+    (0x0160, "SCC"),
+    (0x0180, "SBC"),
+    (0x01a0, "SMC"),
+    (0x01c0, "SES"),
+    (0x01e0, "SCC-2"),
+    (0x0200, "SSC"),
+    (0x0220, "RBC"),
+    (0x0240, "MMC-2"),
+    (0x0260, "SPC-2"),
+    (0x0280, "OCRW"),
+    (0x02a0, "MMC-3"),
+    (0x02c0, "RMC"),
+    (0x02e0, "SMC-2"),
+    (0x0300, "SPC-3"),
+    (0x0320, "SBC-2"),
+    (0x0340, "OSD"),
+    (0x0360, "SSC-2"),
+    (0x0380, "BCC"),
+    (0x03a0, "MMC-4"),
+    (0x03c0, "ADC"),
+    (0x03e0, "SES-2"),
+    (0x0400, "SSC-3"),
+    (0x0420, "MMC-5"),
+    (0x0440, "OSD-2"),
+    (0x0460, "SPC-4"),
+    (0x0480, "SMC-3"),
+    (0x04a0, "ADC-2"),
+    (0x04c0, "SBC-3"),
+    (0x04e0, "MMC-6"),
+    (0x0500, "ADC-3"),
+    (0x0520, "SSC-4"),
+    (0x0560, "OSD-3"),
+    (0x0580, "SES-3"),
+    (0x05a0, "SSC-5"),
+    (0x05c0, "SPC-5"),
+    (0x05e0, "SFSC"),
+    (0x0600, "SBC-4"),
+    (0x0620, "ZBC"),
+    (0x0640, "ADC-4"),
+    (0x0660, "ZBC-2"),
+    (0x0680, "SES-4"),
+    (0x0820, "SSA-TL2"),
+    (0x0840, "SSA-TL1"),
+    (0x0860, "SSA-S3P"),
+    (0x0880, "SSA-S2P"),
+    (0x08a0, "SIP"),
+    (0x08c0, "FCP"),
+    (0x08e0, "SBP-2"),
+    (0x0900, "FCP-2"),
+    (0x0920, "SST"),
+    (0x0940, "SRP"),
+    (0x0960, "iSCSI"),
+    (0x0980, "SBP-3"),
+    (0x09a0, "SRP-2"),
+    (0x09c0, "ADP"),
+    (0x09e0, "ADT"),
+    (0x0a00, "FCP-3"),
+    (0x0a20, "ADT-2"),
+    (0x0a40, "FCP-4"),
+    (0x0a60, "ADT-3"),
+    (0x0aa0, "SPI"),
+    (0x0ac0, "Fast-20"),
+    (0x0ae0, "SPI-2"),
+    (0x0b00, "SPI-3"),
+    (0x0b20, "EPI"),
+    (0x0b40, "SPI-4"),
+    (0x0b60, "SPI-5"),
+    (0x0be0, "SAS"),
+    (0x0c00, "SAS-1.1"),
+    (0x0c20, "SAS-2"),
+    (0x0c40, "SAS-2.1"),
+    (0x0c60, "SAS-3"),
+    (0x0c80, "SAS-4"),
+    (0x1740, "UAS"),
+    (0x1780, "UAS-2"),
+    (0x1ea0, "SAT"),
+    (0x1ec0, "SAT-2"),
+    (0x1ee0, "SAT-3"),
+    (0x1f00, "SAT-4"),
+    (0x20a0, "SPL"),
+    (0x20c0, "SPL-2"),
+    (0x20e0, "SPL-3"),
+    (0x2100, "SPL-4"),
+    (0x21e0, "SOP"),
+    (0x2200, "PQI"),
+    (0x2220, "SOP-2"),
+    (0x2240, "PQI-2"),
+];
+
+impl VersionDescriptor {
+    /// The name of the standard, such as "SPC-4", whichever revision of
+    /// it the code gives, or None if it isn't one listed here.
+    pub fn standard(self) -> Option<&'static str> {
+        STANDARDS
+            .iter()
+            .find(|&&(base, _)| base == self.0 & !0x1f)
+            .map(|&(_, name)| name)
+    }
+}
+
+/// The standard's name, or the code in hex if it isn't known.
+impl fmt::Display for VersionDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.standard() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{:#06x}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_descriptor() {
+        assert_eq!(VersionDescriptor(0x0460).standard(), Some("SPC-4"));
+        // SBC-3 T10/BSR INCITS 514 revision 35
+        assert_eq!(VersionDescriptor(0x04c3).standard(), Some("SBC-3"));
+        assert_eq!(VersionDescriptor(0x0c75).to_string(), "SAS-3");
+        assert_eq!(VersionDescriptor(0xfff0).standard(), None);
+        assert_eq!(VersionDescriptor(0xfff0).to_string(), "0xfff0");
+    }
+}
@@ -0,0 +1,534 @@
+//! Zoned Block Commands (ZBC) for host-managed and host-aware SMR
+//! devices.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{sg_io, sg_io_read, DataDirection, Sg3Result, SgTransport, MAX_RESPONSE_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneType {
+    Conventional,
+    SequentialWriteRequired,
+    SequentialWritePreferred,
+    SequentialOrBeforeRequired,
+    Gap,
+    Reserved,
+}
+
+fn to_zone_type(i: u8) -> ZoneType {
+    match i {
+        1 => ZoneType::Conventional,
+        2 => ZoneType::SequentialWriteRequired,
+        3 => ZoneType::SequentialWritePreferred,
+        4 => ZoneType::SequentialOrBeforeRequired,
+        5 => ZoneType::Gap,
+        _ => ZoneType::Reserved,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneCondition {
+    NotWritePointer,
+    Empty,
+    ImplicitlyOpened,
+    ExplicitlyOpened,
+    Closed,
+    Inactive,
+    ReadOnly,
+    Full,
+    Offline,
+    Reserved,
+}
+
+fn to_zone_condition(i: u8) -> ZoneCondition {
+    match i {
+        0x0 => ZoneCondition::NotWritePointer,
+        0x1 => ZoneCondition::Empty,
+        0x2 => ZoneCondition::ImplicitlyOpened,
+        0x3 => ZoneCondition::ExplicitlyOpened,
+        0x4 => ZoneCondition::Closed,
+        0x5 => ZoneCondition::Inactive,
+        0xd => ZoneCondition::ReadOnly,
+        0xe => ZoneCondition::Full,
+        0xf => ZoneCondition::Offline,
+        _ => ZoneCondition::Reserved,
+    }
+}
+
+/// Which zones REPORT ZONES should report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneReportingOptions {
+    All,
+    Empty,
+    ImplicitlyOpened,
+    ExplicitlyOpened,
+    Closed,
+    Full,
+    ReadOnly,
+    Offline,
+    Inactive,
+    /// Zones with the RESET WRITE POINTER RECOMMENDED bit set.
+    ResetRecommended,
+    /// Zones with the NON_SEQ bit set.
+    NonSequential,
+    NotWritePointer,
+}
+
+fn reporting_options_code(options: ZoneReportingOptions) -> u8 {
+    match options {
+        ZoneReportingOptions::All => 0x00,
+        ZoneReportingOptions::Empty => 0x01,
+        ZoneReportingOptions::ImplicitlyOpened => 0x02,
+        ZoneReportingOptions::ExplicitlyOpened => 0x03,
+        ZoneReportingOptions::Closed => 0x04,
+        ZoneReportingOptions::Full => 0x05,
+        ZoneReportingOptions::ReadOnly => 0x06,
+        ZoneReportingOptions::Offline => 0x07,
+        ZoneReportingOptions::Inactive => 0x08,
+        ZoneReportingOptions::ResetRecommended => 0x10,
+        ZoneReportingOptions::NonSequential => 0x11,
+        ZoneReportingOptions::NotWritePointer => 0x3f,
+    }
+}
+
+/// A zone descriptor from REPORT ZONES.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Zone {
+    pub zone_type: ZoneType,
+    pub condition: ZoneCondition,
+    /// Writes to a sequential write preferred zone were not sequential.
+    pub non_sequential: bool,
+    /// The device recommends resetting the zone's write pointer.
+    pub reset_recommended: bool,
+    pub length: u64,
+    pub start_lba: u64,
+    pub write_pointer: u64,
+}
+
+/// The response to REPORT ZONES.
+#[derive(Debug)]
+pub struct ZoneReport {
+    /// Number of zones matching the reporting options from the start
+    /// LBA on, which may be more than were returned.
+    pub num_zones: u32,
+    /// The SAME field, saying which zone fields are shared by all
+    /// zones in the list.
+    pub same: u8,
+    pub max_lba: u64,
+    pub zones: Vec<Zone>,
+}
+
+fn parse_zone_report(buf: &[u8]) -> ZoneReport {
+    let list_len = BigEndian::read_u32(&buf[0..4]) as usize;
+    let end = buf.len().min(64 + list_len);
+
+    ZoneReport {
+        num_zones: (list_len / 64) as u32,
+        same: buf[4] & 0x0f,
+        max_lba: BigEndian::read_u64(&buf[8..16]),
+        zones: buf[64..end]
+            .chunks_exact(64)
+            .map(|d| Zone {
+                zone_type: to_zone_type(d[0] & 0x0f),
+                condition: to_zone_condition(d[1] >> 4),
+                non_sequential: d[1] & 0x02 != 0,
+                reset_recommended: d[1] & 0x01 != 0,
+                length: BigEndian::read_u64(&d[8..16]),
+                start_lba: BigEndian::read_u64(&d[16..24]),
+                write_pointer: BigEndian::read_u64(&d[24..32]),
+            })
+            .collect(),
+    }
+}
+
+// A buffer for the 64-byte header of a ZBC IN report and `count`
+// descriptors of `desc_len` bytes, capped at MAX_RESPONSE_LEN.
+fn report_buf(count: u32, desc_len: usize) -> Vec<u8> {
+    let len = (count as usize)
+        .saturating_mul(desc_len)
+        .saturating_add(64)
+        .min(MAX_RESPONSE_LEN);
+    vec![0u8; len]
+}
+
+// Read a ZBC IN report into `buf`, cutting it to what the device
+// returned, but never shorter than the header.
+fn read_report<T: SgTransport + ?Sized>(
+    path: &T,
+    cmd: &[u8],
+    mut buf: Vec<u8>,
+) -> Sg3Result<Vec<u8>> {
+    let transferred = sg_io_read(path, cmd, &mut buf)?;
+    buf.truncate(transferred.max(64));
+    Ok(buf)
+}
+
+fn report_zones_cdb(start_lba: u64, options: ZoneReportingOptions, len: usize) -> [u8; 16] {
+    zbc_in_cdb(0x00, start_lba, len, reporting_options_code(options))
+}
+
+// Send ZBC REPORT ZONES to the SCSI device at the given path, returning
+// up to `max_zones` zones matching `options`, starting with the zone
+// containing `start_lba`. Fewer are returned if they don't all fit in
+// one response.
+pub fn report_zones<T: SgTransport + ?Sized>(
+    path: &T,
+    start_lba: u64,
+    options: ZoneReportingOptions,
+    max_zones: u32,
+) -> Sg3Result<ZoneReport> {
+    let buf = report_buf(max_zones, 64);
+    let cmd = report_zones_cdb(start_lba, options, buf.len());
+    let buf = read_report(path, &cmd, buf)?;
+
+    Ok(parse_zone_report(&buf))
+}
+
+fn zbc_in_cdb(service_action: u8, locator: u64, len: usize, options: u8) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x95;
+    cmd[1] = service_action;
+    BigEndian::write_u64(&mut cmd[2..10], locator);
+    BigEndian::write_u32(&mut cmd[10..14], len as u32);
+    cmd[14] = options & 0x3f;
+
+    cmd
+}
+
+/// A zone domain descriptor from REPORT ZONE DOMAINS.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ZoneDomain {
+    pub id: u8,
+    pub num_zones: u64,
+    pub start_lba: u64,
+    pub end_lba: u64,
+    /// The type of all zones in the domain, if they share one.
+    pub zone_type: Option<ZoneType>,
+}
+
+/// The response to REPORT ZONE DOMAINS.
+#[derive(Debug)]
+pub struct ZoneDomainReport {
+    pub num_supported: u8,
+    pub num_reported: u8,
+    pub domains: Vec<ZoneDomain>,
+}
+
+fn parse_zone_domains(buf: &[u8]) -> ZoneDomainReport {
+    let list_len = BigEndian::read_u32(&buf[0..4]) as usize;
+    let end = buf.len().min(64 + list_len);
+
+    ZoneDomainReport {
+        num_supported: buf[8],
+        num_reported: buf[10],
+        domains: buf[64..end]
+            .chunks_exact(96)
+            .map(|d| ZoneDomain {
+                id: d[0],
+                num_zones: BigEndian::read_u64(&d[16..24]),
+                start_lba: BigEndian::read_u64(&d[24..32]),
+                end_lba: BigEndian::read_u64(&d[32..40]),
+                zone_type: if d[42] & 0x01 != 0 {
+                    Some(to_zone_type(d[40] & 0x0f))
+                } else {
+                    None
+                },
+            })
+            .collect(),
+    }
+}
+
+// Send ZBC REPORT ZONE DOMAINS to the SCSI device at the given path,
+// returning up to `max_domains` zone domains starting with the one
+// containing `locator`. Fewer are returned if they don't all fit
+// in one response. `options` is the REPORTING OPTIONS field; zero
+// reports all domains.
+pub fn report_zone_domains<T: SgTransport + ?Sized>(
+    path: &T,
+    locator: u64,
+    options: u8,
+    max_domains: u32,
+) -> Sg3Result<ZoneDomainReport> {
+    let buf = report_buf(max_domains, 96);
+    let cmd = zbc_in_cdb(0x07, locator, buf.len(), options);
+    let buf = read_report(path, &cmd, buf)?;
+
+    Ok(parse_zone_domains(&buf))
+}
+
+/// A realm descriptor from REPORT REALMS.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Realm {
+    pub id: u32,
+    /// The REALM RESTRICTIONS field.
+    pub restrictions: u16,
+    pub active_domain: u8,
+    /// The (start, end) LBAs of the realm in each zone domain.
+    pub domain_ranges: Vec<(u64, u64)>,
+}
+
+/// The response to REPORT REALMS.
+#[derive(Debug)]
+pub struct RealmReport {
+    pub num_realms: u32,
+    /// Where to continue from to get the rest of the realms.
+    pub next_locator: u64,
+    pub realms: Vec<Realm>,
+}
+
+// Realm descriptors are this long unless the device says otherwise.
+const DEFAULT_REALM_DESCRIPTOR_LEN: usize = 128;
+
+fn parse_realms(buf: &[u8]) -> RealmReport {
+    let num_realms = BigEndian::read_u32(&buf[0..4]);
+    let desc_len = match BigEndian::read_u32(&buf[4..8]) as usize {
+        0 => DEFAULT_REALM_DESCRIPTOR_LEN,
+        len => len.max(16),
+    };
+
+    RealmReport {
+        num_realms,
+        next_locator: BigEndian::read_u64(&buf[8..16]),
+        realms: buf[64..]
+            .chunks_exact(desc_len)
+            .take(num_realms as usize)
+            .map(|d| Realm {
+                id: BigEndian::read_u32(&d[0..4]),
+                restrictions: BigEndian::read_u16(&d[4..6]),
+                active_domain: d[7],
+                domain_ranges: d[16..]
+                    .chunks_exact(16)
+                    .map(|r| {
+                        (
+                            BigEndian::read_u64(&r[0..8]),
+                            BigEndian::read_u64(&r[8..16]),
+                        )
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+// Send ZBC REPORT REALMS to the SCSI device at the given path,
+// returning up to `max_realms` realms starting with the one containing
+// `locator`. Fewer are returned if they don't all fit in one
+// response. `options` is the REPORTING OPTIONS field; zero reports
+// all realms.
+pub fn report_realms<T: SgTransport + ?Sized>(
+    path: &T,
+    locator: u64,
+    options: u8,
+    max_realms: u32,
+) -> Sg3Result<RealmReport> {
+    let buf = report_buf(max_realms, DEFAULT_REALM_DESCRIPTOR_LEN);
+    let cmd = zbc_in_cdb(0x06, locator, buf.len(), options);
+    let buf = read_report(path, &cmd, buf)?;
+
+    Ok(parse_realms(&buf))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneAction {
+    Close,
+    Finish,
+    Open,
+    ResetWritePointer,
+}
+
+fn zone_action_cdb(action: ZoneAction, zone: Option<u64>) -> [u8; 16] {
+    let mut cmd = [0u8; 16];
+
+    cmd[0] = 0x94;
+    cmd[1] = match action {
+        ZoneAction::Close => 0x01,
+        ZoneAction::Finish => 0x02,
+        ZoneAction::Open => 0x03,
+        ZoneAction::ResetWritePointer => 0x04,
+    };
+    match zone {
+        Some(zone_id) => BigEndian::write_u64(&mut cmd[2..10], zone_id),
+        None => cmd[14] = 0x01,
+    }
+
+    cmd
+}
+
+// Send a ZBC OUT zone operation to the SCSI device at the given path,
+// applying it to the zone starting at the given LBA, or with None, to
+// every zone it applies to (the ALL bit).
+pub fn zone_action<T: SgTransport + ?Sized>(
+    path: &T,
+    action: ZoneAction,
+    zone: Option<u64>,
+) -> Sg3Result<()> {
+    let cmd = zone_action_cdb(action, zone);
+    sg_io(path, &cmd, DataDirection::None)
+}
+
+// Send ZBC OPEN ZONE to the SCSI device at the given path, explicitly
+// opening the given zone, or every closed zone if None.
+pub fn open_zone<T: SgTransport + ?Sized>(path: &T, zone: Option<u64>) -> Sg3Result<()> {
+    zone_action(path, ZoneAction::Open, zone)
+}
+
+// Send ZBC CLOSE ZONE to the SCSI device at the given path, closing the
+// given zone, or every open zone if None.
+pub fn close_zone<T: SgTransport + ?Sized>(path: &T, zone: Option<u64>) -> Sg3Result<()> {
+    zone_action(path, ZoneAction::Close, zone)
+}
+
+// Send ZBC FINISH ZONE to the SCSI device at the given path, making the
+// given zone full, or every open and closed zone if None.
+pub fn finish_zone<T: SgTransport + ?Sized>(path: &T, zone: Option<u64>) -> Sg3Result<()> {
+    zone_action(path, ZoneAction::Finish, zone)
+}
+
+// Send ZBC RESET WRITE POINTER to the SCSI device at the given path,
+// emptying the given zone, or every open, closed, and full zone if
+// None.
+pub fn reset_write_pointer<T: SgTransport + ?Sized>(path: &T, zone: Option<u64>) -> Sg3Result<()> {
+    zone_action(path, ZoneAction::ResetWritePointer, zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_zones_cdb() {
+        let cmd = report_zones_cdb(0x80000, ZoneReportingOptions::Full, 128);
+        assert_eq!(
+            cmd,
+            [0x95, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0, 0, 0, 0x80, 0x05, 0]
+        );
+    }
+
+    #[test]
+    fn test_report_zones() {
+        let mut dev = crate::FakeDevice::new("LIO-ORG", "zbc0", "4.0");
+        let mut data = vec![0u8; 64 + 64];
+        BigEndian::write_u32(&mut data[0..4], 3 * 64);
+        data[64] = 0x02;
+        dev.set_response(0x95, &data);
+
+        let report = report_zones(&dev, 0, ZoneReportingOptions::All, u32::MAX).unwrap();
+        assert_eq!(report.num_zones, 3);
+        assert_eq!(report.zones.len(), 1);
+        let cmd = &dev.commands()[0];
+        assert_eq!(
+            BigEndian::read_u32(&cmd[10..14]) as usize,
+            crate::MAX_RESPONSE_LEN
+        );
+    }
+
+    #[test]
+    fn test_parse_zone_domains() {
+        let mut buf = vec![0u8; 64 + 96];
+        BigEndian::write_u32(&mut buf[0..4], 96);
+        buf[8] = 2;
+        buf[10] = 1;
+        let d = &mut buf[64..];
+        d[0] = 1;
+        BigEndian::write_u64(&mut d[16..24], 100);
+        BigEndian::write_u64(&mut d[32..40], 0xffff);
+        d[40] = 0x04;
+        d[42] = 0x01;
+        let report = parse_zone_domains(&buf);
+        assert_eq!(report.num_supported, 2);
+        assert_eq!(report.domains.len(), 1);
+        assert_eq!(report.domains[0].num_zones, 100);
+        assert_eq!(report.domains[0].end_lba, 0xffff);
+        assert_eq!(
+            report.domains[0].zone_type,
+            Some(ZoneType::SequentialOrBeforeRequired)
+        );
+    }
+
+    #[test]
+    fn test_report_realms() {
+        // two realms reported, but only one sent
+        let mut dev = crate::FakeDevice::new("LIO-ORG", "zbc0", "4.0");
+        let mut data = vec![0u8; 64 + 128];
+        BigEndian::write_u32(&mut data[0..4], 2);
+        BigEndian::write_u32(&mut data[64..68], 7);
+        dev.set_response(0x95, &data);
+
+        let report = report_realms(&dev, 0, 0, u32::MAX).unwrap();
+        assert_eq!(report.num_realms, 2);
+        assert_eq!(report.realms.len(), 1);
+        assert_eq!(report.realms[0].id, 7);
+        let cmd = &dev.commands()[0];
+        assert_eq!(
+            BigEndian::read_u32(&cmd[10..14]) as usize,
+            crate::MAX_RESPONSE_LEN
+        );
+    }
+
+    #[test]
+    fn test_parse_realms() {
+        let mut buf = vec![0u8; 64 + 2 * 48];
+        BigEndian::write_u32(&mut buf[0..4], 1);
+        BigEndian::write_u32(&mut buf[4..8], 48);
+        let d = &mut buf[64..112];
+        BigEndian::write_u32(&mut d[0..4], 7);
+        d[7] = 1;
+        BigEndian::write_u64(&mut d[16..24], 0x1000);
+        BigEndian::write_u64(&mut d[24..32], 0x1fff);
+        BigEndian::write_u64(&mut d[32..40], 0x2000);
+        BigEndian::write_u64(&mut d[40..48], 0x2fff);
+        let report = parse_realms(&buf);
+        assert_eq!(report.realms.len(), 1);
+        assert_eq!(report.realms[0].id, 7);
+        assert_eq!(report.realms[0].active_domain, 1);
+        assert_eq!(
+            report.realms[0].domain_ranges,
+            vec![(0x1000, 0x1fff), (0x2000, 0x2fff)]
+        );
+    }
+
+    #[test]
+    fn test_zone_action_cdb() {
+        let cmd = zone_action_cdb(ZoneAction::ResetWritePointer, Some(0x80000));
+        assert_eq!(
+            cmd,
+            [0x94, 0x04, 0, 0, 0, 0, 0, 0x08, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        let cmd = zone_action_cdb(ZoneAction::Close, None);
+        assert_eq!(
+            cmd,
+            [0x94, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0]
+        );
+    }
+
+    #[test]
+    fn test_parse_zone_report() {
+        let mut buf = vec![0u8; 64 + 2 * 64];
+        BigEndian::write_u32(&mut buf[0..4], 3 * 64);
+        buf[4] = 0x01;
+        BigEndian::write_u64(&mut buf[8..16], 0x2ff_ffff);
+        let d = &mut buf[64..128];
+        d[0] = 0x01;
+        BigEndian::write_u64(&mut d[8..16], 0x80000);
+        BigEndian::write_u64(&mut d[24..32], u64::MAX);
+        let d = &mut buf[128..192];
+        d[0] = 0x02;
+        d[1] = 0x21;
+        BigEndian::write_u64(&mut d[8..16], 0x80000);
+        BigEndian::write_u64(&mut d[16..24], 0x80000);
+        BigEndian::write_u64(&mut d[24..32], 0x80100);
+
+        let report = parse_zone_report(&buf);
+        assert_eq!(report.num_zones, 3);
+        assert_eq!(report.same, 1);
+        assert_eq!(report.max_lba, 0x2ff_ffff);
+        assert_eq!(report.zones.len(), 2);
+        assert_eq!(report.zones[0].zone_type, ZoneType::Conventional);
+        assert_eq!(report.zones[0].condition, ZoneCondition::NotWritePointer);
+        assert_eq!(report.zones[1].zone_type, ZoneType::SequentialWriteRequired);
+        assert_eq!(report.zones[1].condition, ZoneCondition::ImplicitlyOpened);
+        assert!(report.zones[1].reset_recommended);
+        assert_eq!(report.zones[1].write_pointer, 0x80100);
+    }
+}
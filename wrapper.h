@@ -1 +1,2 @@
 #include <scsi/sg.h>
+#include <linux/bsg.h>